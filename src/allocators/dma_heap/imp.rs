@@ -1,30 +1,140 @@
-use std::os::unix::io::IntoRawFd;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd};
+use std::path::Path;
+use std::sync::Mutex;
 
 use gstreamer::glib;
-use gstreamer::prelude::Cast;
+use gstreamer::prelude::{Cast, ObjectType, ParamSpecBuilderExt, ToValue};
 use gstreamer::subclass::prelude::*;
 use gstreamer_allocators::subclass::prelude::*;
 use gstreamer_allocators::DmaBufAllocator;
+use once_cell::sync::Lazy;
+
+use super::DmaHeapKind;
+use crate::allocators::DmaHeap;
+
+static CAT: Lazy<gstreamer::DebugCategory> = Lazy::new(|| {
+    gstreamer::DebugCategory::new(
+        "dmaheapallocator",
+        gstreamer::DebugColorFlags::empty(),
+        Some("dma-buf heap allocator"),
+    )
+});
+
+/// Mirrors `struct dma_heap_allocation_data` from `linux/dma-heap.h`
+#[repr(C)]
+struct DmaHeapAllocationData {
+    len: u64,
+    fd: u32,
+    fd_flags: u32,
+    heap_flags: u64,
+}
+
+nix::ioctl_readwrite!(dma_heap_ioctl_alloc, b'H', 0x0, DmaHeapAllocationData);
+
+/// Directory the kernel creates the heap device nodes in
+const DMA_HEAP_DIR: &str = "/dev/dma_heap";
+/// Size of the test allocation of [`probe`]
+const PROBE_SIZE: usize = 4096;
+
+fn allocate(file: &std::fs::File, size: usize) -> nix::Result<OwnedFd> {
+    let mut data = DmaHeapAllocationData {
+        len: size as u64,
+        fd: 0,
+        fd_flags: (nix::fcntl::OFlag::O_RDWR | nix::fcntl::OFlag::O_CLOEXEC).bits() as u32,
+        heap_flags: 0,
+    };
+    unsafe {
+        dma_heap_ioctl_alloc(file.as_raw_fd(), &mut data)?;
+        Ok(OwnedFd::from_raw_fd(data.fd as i32))
+    }
+}
+
+/// Open the heap at `path` and allocate a page from it, heaps can exist without
+/// being accessible or without memory left to allocate.
+fn probe(path: &Path) -> std::io::Result<()> {
+    let file = std::fs::File::open(path)?;
+    allocate(&file, PROBE_SIZE)?;
+    Ok(())
+}
 
 #[derive(Debug)]
+struct Heap {
+    kind: DmaHeapKind,
+    file: std::fs::File,
+}
+
+impl Heap {
+    fn open(kind: DmaHeapKind) -> std::io::Result<Self> {
+        let file = std::fs::File::open(kind.path())?;
+        Ok(Heap { kind, file })
+    }
+
+    fn allocate(&self, size: usize) -> nix::Result<OwnedFd> {
+        allocate(&self.file, size)
+    }
+}
+
+fn default_heaps() -> String {
+    DmaHeapKind::DEFAULT_ORDER
+        .iter()
+        .map(|heap| heap.name())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+#[derive(Debug, Default)]
+struct Settings {
+    heaps: Option<String>,
+}
+
+#[derive(Debug, Default)]
 pub struct DmaHeapMemoryAllocator {
-    heap: dma_heap::Heap,
+    settings: Mutex<Settings>,
+    heaps: Mutex<Vec<Heap>>,
 }
 
 impl DmaHeapMemoryAllocator {
     pub fn is_available() -> bool {
-        dma_heap::Heap::new(dma_heap::HeapKind::Cma)
-            .or_else(|_| dma_heap::Heap::new(dma_heap::HeapKind::System))
-            .is_ok()
+        Self::usable_kinds().next().is_some()
     }
-}
 
-impl Default for DmaHeapMemoryAllocator {
-    fn default() -> Self {
-        Self {
-            heap: dma_heap::Heap::new(dma_heap::HeapKind::Cma)
-                .unwrap_or_else(|_| dma_heap::Heap::new(dma_heap::HeapKind::System).unwrap()),
-        }
+    /// The heaps in `/dev/dma_heap` sorted by name.
+    pub fn probe_heaps() -> Vec<DmaHeap> {
+        let entries = match std::fs::read_dir(DMA_HEAP_DIR) {
+            Ok(entries) => entries,
+            Err(err) => {
+                gstreamer::debug!(CAT, "no dma-buf heaps: {}", err);
+                return Vec::new();
+            }
+        };
+
+        let mut heaps = entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                let path = entry.path();
+                let usable = match probe(&path) {
+                    Ok(()) => true,
+                    Err(err) => {
+                        gstreamer::debug!(CAT, "heap {} not usable: {}", name, err);
+                        false
+                    }
+                };
+                DmaHeap { name, path, usable }
+            })
+            .collect::<Vec<_>>();
+        heaps.sort_by(|a, b| a.name.cmp(&b.name));
+        heaps
+    }
+
+    /// Usable heaps the allocator knows in the default order
+    pub fn usable_kinds() -> impl Iterator<Item = DmaHeapKind> {
+        let heaps = Self::probe_heaps();
+        DmaHeapKind::DEFAULT_ORDER.into_iter().filter(move |kind| {
+            heaps
+                .iter()
+                .any(|heap| heap.usable && heap.path == kind.path())
+        })
     }
 }
 
@@ -36,7 +146,79 @@ impl ObjectSubclass for DmaHeapMemoryAllocator {
     type Interfaces = ();
 }
 
-impl ObjectImpl for DmaHeapMemoryAllocator {}
+impl ObjectImpl for DmaHeapMemoryAllocator {
+    fn properties() -> &'static [glib::ParamSpec] {
+        static PROPERTIES: Lazy<Vec<glib::ParamSpec>> = Lazy::new(|| {
+            vec![glib::ParamSpecString::builder("heaps")
+                .nick("dma-buf heaps")
+                .blurb("comma separated list of heaps to allocate from in order of priority")
+                .default_value(default_heaps().as_str())
+                .construct_only()
+                .build()]
+        });
+
+        PROPERTIES.as_ref()
+    }
+
+    fn set_property(&self, _id: usize, value: &glib::Value, pspec: &glib::ParamSpec) {
+        match pspec.name() {
+            "heaps" => {
+                let mut settings = self.settings.lock().unwrap();
+                let heaps = value
+                    .get::<Option<String>>()
+                    .expect("type checked upstream");
+                settings.heaps = heaps;
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn property(&self, _id: usize, pspec: &glib::ParamSpec) -> glib::Value {
+        match pspec.name() {
+            "heaps" => {
+                let settings = self.settings.lock().unwrap();
+                settings.heaps.to_value()
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn constructed(&self) {
+        self.parent_constructed();
+
+        unsafe {
+            crate::allocators::dmabuf_sync::install(
+                self.obj().as_ptr() as *mut gstreamer::ffi::GstAllocator
+            );
+        }
+
+        let heap_order = self
+            .settings
+            .lock()
+            .unwrap()
+            .heaps
+            .clone()
+            .unwrap_or_else(default_heaps);
+
+        let mut heaps = self.heaps.lock().unwrap();
+        for name in heap_order.split(',').filter(|name| !name.trim().is_empty()) {
+            let kind = match name.parse::<DmaHeapKind>() {
+                Ok(kind) => kind,
+                Err(err) => {
+                    gstreamer::warning!(CAT, imp: self, "{}", err);
+                    continue;
+                }
+            };
+
+            match Heap::open(kind) {
+                Ok(heap) => heaps.push(heap),
+                Err(err) => {
+                    gstreamer::debug!(CAT, imp: self, "heap {} not available: {}", kind.name(), err)
+                }
+            }
+        }
+    }
+}
 
 impl GstObjectImpl for DmaHeapMemoryAllocator {}
 
@@ -49,8 +231,33 @@ impl AllocatorImpl for DmaHeapMemoryAllocator {
         let obj = self.obj();
         let dmabuf_allocator: &DmaBufAllocator = obj.upcast_ref();
 
-        let fd = self.heap.allocate(size).unwrap();
-        unsafe { dmabuf_allocator.alloc(fd.into_raw_fd(), size) }
+        let heaps = self.heaps.lock().unwrap();
+        for heap in heaps.iter() {
+            match heap.allocate(size) {
+                Ok(fd) => {
+                    // Only hand over the fd once the memory owns it, otherwise it
+                    // is closed when dropped
+                    let memory = unsafe { dmabuf_allocator.alloc(fd.as_raw_fd(), size)? };
+                    let _ = fd.into_raw_fd();
+                    return Ok(memory);
+                }
+                Err(err) => {
+                    gstreamer::debug!(
+                        CAT,
+                        imp: self,
+                        "failed to allocate {} bytes from heap {}: {}",
+                        size,
+                        heap.kind.name(),
+                        err
+                    );
+                }
+            }
+        }
+
+        Err(glib::bool_error!(
+            "failed to allocate {} bytes from any dma-buf heap",
+            size
+        ))
     }
 
     fn free(&self, memory: gstreamer::Memory) {
@@ -1,3 +1,6 @@
+use std::path::Path;
+use std::str::FromStr;
+
 use gstreamer::glib;
 
 mod imp;
@@ -6,10 +9,79 @@ glib::wrapper! {
     pub struct DmaHeapMemoryAllocator(ObjectSubclass<imp::DmaHeapMemoryAllocator>) @extends gstreamer_allocators::DmaBufAllocator, gstreamer_allocators::FdAllocator, gstreamer::Allocator, gstreamer::Object;
 }
 
+/// The dma-buf heaps known to the allocator, see `/dev/dma_heap`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DmaHeapKind {
+    Reserved,
+    Cma,
+    System,
+}
+
+impl DmaHeapKind {
+    /// Default order in which the heaps are tried
+    pub const DEFAULT_ORDER: [DmaHeapKind; 3] =
+        [DmaHeapKind::Reserved, DmaHeapKind::Cma, DmaHeapKind::System];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            DmaHeapKind::Reserved => "reserved",
+            DmaHeapKind::Cma => "cma",
+            DmaHeapKind::System => "system",
+        }
+    }
+
+    pub fn path(self) -> &'static Path {
+        let path = match self {
+            DmaHeapKind::Reserved => "/dev/dma_heap/reserved",
+            DmaHeapKind::Cma => "/dev/dma_heap/linux,cma",
+            DmaHeapKind::System => "/dev/dma_heap/system",
+        };
+        Path::new(path)
+    }
+}
+
+impl FromStr for DmaHeapKind {
+    type Err = glib::BoolError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "reserved" => Ok(DmaHeapKind::Reserved),
+            "cma" | "linux,cma" => Ok(DmaHeapKind::Cma),
+            "system" => Ok(DmaHeapKind::System),
+            other => Err(glib::bool_error!("unknown dma-buf heap {}", other)),
+        }
+    }
+}
+
 impl DmaHeapMemoryAllocator {
+    /// Create an allocator trying the heaps in the given order.
+    pub fn new(heaps: &[DmaHeapKind]) -> Self {
+        let heaps = heaps
+            .iter()
+            .map(|heap| heap.name())
+            .collect::<Vec<_>>()
+            .join(",");
+        glib::Object::builder().property("heaps", &heaps).build()
+    }
+
+    /// Whether any of the known heaps can be allocated from
     pub fn is_available() -> bool {
         imp::DmaHeapMemoryAllocator::is_available()
     }
+
+    /// Enumerate `/dev/dma_heap`, including heaps the allocator does not know.
+    ///
+    /// Every heap is opened and allocated from once to find out whether it is usable.
+    pub fn probe_heaps() -> Vec<crate::allocators::DmaHeap> {
+        imp::DmaHeapMemoryAllocator::probe_heaps()
+    }
+
+    /// Create an allocator for the usable heaps in the default order, `None` if
+    /// there are none.
+    pub fn for_usable_heaps() -> Option<Self> {
+        let heaps = imp::DmaHeapMemoryAllocator::usable_kinds().collect::<Vec<_>>();
+        (!heaps.is_empty()).then(|| Self::new(&heaps))
+    }
 }
 
 impl Default for DmaHeapMemoryAllocator {
@@ -0,0 +1,128 @@
+//! Cache synchronization for CPU access to dmabuf memory.
+//!
+//! The fd allocator maps dmabufs with a plain `mmap`, which is not enough on
+//! non-coherent systems. The map functions of the allocator are wrapped to
+//! bracket every CPU access with `DMA_BUF_IOCTL_SYNC`.
+//!
+//! GPU consumers do not map the memory, for them [`wait_fences`] waits for the
+//! implicit fences the compositor attached while writing.
+
+// Only the dmabuf allocators install the map functions
+#![cfg_attr(not(any(feature = "dma-heap", feature = "gbm")), allow(dead_code))]
+
+use std::os::unix::io::RawFd;
+use std::time::{Duration, Instant};
+
+use gstreamer::glib;
+use once_cell::sync::OnceCell;
+
+const DMA_BUF_SYNC_READ: u64 = 0x1;
+const DMA_BUF_SYNC_WRITE: u64 = 0x2;
+const DMA_BUF_SYNC_START: u64 = 0x0;
+const DMA_BUF_SYNC_END: u64 = 0x4;
+
+/// Mirrors `struct dma_buf_sync` from `linux/dma-buf.h`
+#[repr(C)]
+struct DmaBufSync {
+    flags: u64,
+}
+
+nix::ioctl_write_ptr!(dma_buf_ioctl_sync, b'b', 0, DmaBufSync);
+
+struct ParentMapFunctions {
+    map: gstreamer::ffi::GstMemoryMapFunction,
+    unmap: gstreamer::ffi::GstMemoryUnmapFunction,
+}
+
+static PARENT: OnceCell<ParentMapFunctions> = OnceCell::new();
+
+fn sync(fd: RawFd, flags: gstreamer::ffi::GstMapFlags, stage: u64) {
+    let mut sync_flags = stage;
+    if flags & gstreamer::ffi::GST_MAP_READ != 0 {
+        sync_flags |= DMA_BUF_SYNC_READ;
+    }
+    if flags & gstreamer::ffi::GST_MAP_WRITE != 0 {
+        sync_flags |= DMA_BUF_SYNC_WRITE;
+    }
+
+    let sync = DmaBufSync { flags: sync_flags };
+    // Interrupted syncs have to be restarted, any other error is not fatal
+    // as the data is still accessible, just possibly stale
+    while let Err(nix::errno::Errno::EINTR | nix::errno::Errno::EAGAIN) =
+        unsafe { dma_buf_ioctl_sync(fd, &sync) }
+    {}
+}
+
+unsafe extern "C" fn map_full(
+    mem: *mut gstreamer::ffi::GstMemory,
+    info: *mut gstreamer::ffi::GstMapInfo,
+    maxsize: usize,
+) -> glib::ffi::gpointer {
+    let parent = PARENT.get().expect("parent map functions");
+    let flags = (*info).flags;
+    let data = match parent.map {
+        Some(map) => map(mem, maxsize, flags),
+        None => return std::ptr::null_mut(),
+    };
+
+    if !data.is_null() {
+        sync(
+            gstreamer_allocators::ffi::gst_fd_memory_get_fd(mem),
+            flags,
+            DMA_BUF_SYNC_START,
+        );
+    }
+
+    data
+}
+
+unsafe extern "C" fn unmap_full(
+    mem: *mut gstreamer::ffi::GstMemory,
+    info: *mut gstreamer::ffi::GstMapInfo,
+) {
+    let parent = PARENT.get().expect("parent map functions");
+    sync(
+        gstreamer_allocators::ffi::gst_fd_memory_get_fd(mem),
+        (*info).flags,
+        DMA_BUF_SYNC_END,
+    );
+
+    if let Some(unmap) = parent.unmap {
+        unmap(mem);
+    }
+}
+
+/// Wait until all writes to the dmabuf `fd` have finished.
+///
+/// A dmabuf polls readable once its implicit write fences are signaled. Returns
+/// `false` if the fences did not signal within `timeout`.
+pub(super) fn wait_fences(fd: RawFd, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let mut fds = [nix::poll::PollFd::new(fd, nix::poll::PollFlags::POLLIN)];
+        match nix::poll::poll(&mut fds, remaining.as_millis() as nix::libc::c_int) {
+            Ok(0) => return false,
+            Ok(_) => return true,
+            Err(nix::errno::Errno::EINTR) | Err(nix::errno::Errno::EAGAIN) => continue,
+            // Not pollable, nothing to wait for
+            Err(_) => return true,
+        }
+    }
+}
+
+/// Install the synchronizing map functions on a dmabuf allocator instance.
+///
+/// # Safety
+///
+/// `allocator` has to point to a valid, initialized `GstDmaBufAllocator`.
+pub(super) unsafe fn install(allocator: *mut gstreamer::ffi::GstAllocator) {
+    // All fd allocators share the same map functions, so it is fine to only
+    // remember the first ones
+    PARENT.get_or_init(|| ParentMapFunctions {
+        map: (*allocator).mem_map,
+        unmap: (*allocator).mem_unmap,
+    });
+    (*allocator).mem_map_full = Some(map_full);
+    (*allocator).mem_unmap_full = Some(unmap_full);
+}
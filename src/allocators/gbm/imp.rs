@@ -1,17 +1,27 @@
-use std::os::fd::AsRawFd;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
 use std::os::unix::io::{AsFd, BorrowedFd};
+use std::path::{Path, PathBuf};
 use std::sync::Mutex;
 
 use gstreamer::glib;
-use gstreamer::prelude::{Cast, ParamSpecBuilderExt, ToValue};
+use gstreamer::prelude::{Cast, ObjectType, ParamSpecBuilderExt, ToValue};
 use gstreamer::subclass::prelude::*;
 use gstreamer_allocators::subclass::prelude::*;
-use gstreamer_allocators::DmaBufAllocator;
+use gstreamer_allocators::{DmaBufAllocator, FdAllocator, FdMemoryFlags};
 use nix::unistd;
 use once_cell::sync::Lazy;
 
 use crate::utils::gst_video_format_to_drm_fourcc;
 
+static CAT: Lazy<gstreamer::DebugCategory> = Lazy::new(|| {
+    gstreamer::DebugCategory::new(
+        "gbmallocator",
+        gstreamer::DebugColorFlags::empty(),
+        Some("GBM allocator"),
+    )
+});
+
 /// A simple wrapper for a device node.
 #[derive(Debug)]
 pub struct Card(std::fs::File);
@@ -27,38 +37,218 @@ impl AsFd for Card {
 
 /// Simple helper methods for opening a `Card`.
 impl Card {
-    pub fn open(path: &str) -> Self {
+    pub fn open<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
         let mut options = std::fs::OpenOptions::new();
         options.read(true);
         options.write(true);
-        Card(options.open(path).unwrap())
+        options.open(path).map(Card)
+    }
+
+    /// Wrap a duplicate of an already opened DRM fd, the caller keeps ownership of `fd`.
+    pub fn from_fd(fd: RawFd) -> std::io::Result<Self> {
+        let fd = unistd::dup(fd).map_err(std::io::Error::from)?;
+        Ok(Card(unsafe { std::fs::File::from_raw_fd(fd) }))
     }
 }
 
-#[derive(Debug, Default)]
+/// All render nodes in `/dev/dri`, sorted by name.
+fn render_nodes() -> Vec<PathBuf> {
+    let mut nodes = std::fs::read_dir("/dev/dri")
+        .map(|entries| {
+            entries
+                .filter_map(Result::ok)
+                .map(|entry| entry.path())
+                .filter(|path| {
+                    path.file_name()
+                        .and_then(|name| name.to_str())
+                        .map(|name| name.starts_with("renderD"))
+                        .unwrap_or(false)
+                })
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    nodes.sort();
+    nodes
+}
+
+/// The render node of the DRM device `device`, which can be a primary or a
+/// render node itself.
+pub(super) fn render_node_for_device(device: u64) -> Option<PathBuf> {
+    let nodes = render_nodes();
+    if let Some(node) = nodes.iter().find(|node| {
+        nix::sys::stat::stat(node.as_path())
+            .map(|stat| stat.st_rdev == device)
+            .unwrap_or(false)
+    }) {
+        return Some(node.clone());
+    }
+
+    // Primary nodes list the other nodes of the same device in sysfs
+    let drm_dir = format!(
+        "/sys/dev/char/{}:{}/device/drm",
+        nix::sys::stat::major(device),
+        nix::sys::stat::minor(device)
+    );
+    let mut names = std::fs::read_dir(drm_dir)
+        .ok()?
+        .filter_map(Result::ok)
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| name.starts_with("renderD"))
+        .collect::<Vec<_>>();
+    names.sort();
+    names
+        .into_iter()
+        .map(|name| Path::new("/dev/dri").join(name))
+        .find(|node| nodes.contains(node))
+}
+
+fn open_device<P: AsRef<Path>>(path: P) -> std::io::Result<gbm::Device<Card>> {
+    gbm::Device::new(Card::open(path)?)
+}
+
+#[derive(Debug)]
 struct Settings {
     device_path: Option<String>,
+    fd: RawFd,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            device_path: None,
+            fd: -1,
+        }
+    }
+}
+
+/// Maximum number of freed buffer objects kept around for reuse
+const MAX_CACHED_BOS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct BoKey {
+    width: u32,
+    height: u32,
+    format: u32,
+    modifier: u64,
+}
+
+/// A buffer object together with its exported fd and plane layout
+struct CachedBo {
+    key: BoKey,
+    _bo: gbm::BufferObject<()>,
+    fd: OwnedFd,
+    size: usize,
+    offsets: Vec<usize>,
+    strides: Vec<i32>,
+}
+
+impl std::fmt::Debug for CachedBo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CachedBo")
+            .field("key", &self.key)
+            .field("fd", &self.fd)
+            .field("size", &self.size)
+            .finish()
+    }
+}
+
+#[derive(Debug, Default)]
+struct BoCache {
+    /// Buffer objects backing alive memories, keyed by the exported fd
+    live: HashMap<RawFd, CachedBo>,
+    /// Buffer objects of freed memories, oldest first
+    free: VecDeque<CachedBo>,
+    /// Exported fds of live memories the pool discarded, their buffer objects are
+    /// dropped on free instead of being reused
+    discarded: HashSet<RawFd>,
+}
+
+impl BoCache {
+    fn take(&mut self, f: impl Fn(&BoKey) -> bool) -> Option<CachedBo> {
+        let index = self.free.iter().position(|cached| f(&cached.key))?;
+        self.free.remove(index)
+    }
 }
 
 #[derive(Debug, Default)]
 pub struct GbmMemoryAllocator {
     settings: Mutex<Settings>,
     device: Mutex<Option<gbm::Device<Card>>>,
+    cache: Mutex<BoCache>,
 }
 
 impl GbmMemoryAllocator {
+    pub fn has_device(&self) -> bool {
+        self.device.lock().unwrap().is_some()
+    }
+
+    pub fn discard(&self, memory: &gstreamer::MemoryRef) {
+        let Some(memory) = memory.downcast_memory_ref::<gstreamer_allocators::FdMemory>() else {
+            return;
+        };
+        let mut cache = self.cache.lock().unwrap();
+        if cache.live.contains_key(&memory.fd()) {
+            cache.discarded.insert(memory.fd());
+        }
+    }
+
     pub fn alloc(
         &self,
         video_info: &gstreamer_video::VideoInfo,
-    ) -> Result<gstreamer::Memory, glib::BoolError> {
-        let obj = self.obj();
-        let dmabuf_allocator: &DmaBufAllocator = obj.upcast_ref();
+        modifiers: &[gbm::Modifier],
+    ) -> Result<super::GbmAllocation, glib::BoolError> {
+        let Some(format) = gst_video_format_to_drm_fourcc(video_info.format()) else {
+            return Err(glib::bool_error!(
+                "unsupported format {}",
+                video_info.format()
+            ));
+        };
 
-        let guard = self.device.lock().unwrap();
-        let device = guard.as_ref().unwrap();
+        // Without any preference from the compositor linear is the only safe choice
+        let modifiers = if modifiers.is_empty() {
+            &[gbm::Modifier::Linear][..]
+        } else {
+            modifiers
+        };
 
-        let Some(format) = gst_video_format_to_drm_fourcc(video_info.format()) else {
-            unreachable!()
+        let cached = self.cache.lock().unwrap().take(|key| {
+            key.width == video_info.width()
+                && key.height == video_info.height()
+                && key.format == format as u32
+                && modifiers
+                    .iter()
+                    .any(|modifier| u64::from(*modifier) == key.modifier)
+        });
+        let cached = match cached {
+            Some(cached) => {
+                gstreamer::trace!(CAT, imp: self, "reusing cached bo {:?}", cached);
+                cached
+            }
+            None => self.create_video_bo(video_info, format, modifiers)?,
+        };
+
+        let modifier = gbm::Modifier::from(cached.key.modifier);
+        let offsets = cached.offsets.clone();
+        let strides = cached.strides.clone();
+        let memory = self.wrap_bo(cached)?;
+
+        Ok(super::GbmAllocation {
+            memory,
+            modifier,
+            offsets,
+            strides,
+        })
+    }
+
+    fn create_video_bo(
+        &self,
+        video_info: &gstreamer_video::VideoInfo,
+        format: gbm::Format,
+        modifiers: &[gbm::Modifier],
+    ) -> Result<CachedBo, glib::BoolError> {
+        let guard = self.device.lock().unwrap();
+        let Some(device) = guard.as_ref() else {
+            return Err(glib::bool_error!("no gbm device available"));
         };
 
         let bo = device
@@ -66,24 +256,79 @@ impl GbmMemoryAllocator {
                 video_info.width(),
                 video_info.height(),
                 format,
-                [gbm::Modifier::Linear].into_iter(),
+                modifiers.iter().copied(),
                 gbm::BufferObjectFlags::RENDERING,
             )
-            .expect("failed to create bo");
-        let fd = bo.fd().expect("no fd");
+            .map_err(|err| glib::bool_error!("failed to create bo: {}", err))?;
+        let modifier = bo.modifier().expect("no modifier");
 
-        let fd_size = unistd::lseek(fd.as_raw_fd(), 0, unistd::Whence::SeekEnd).unwrap();
+        // The driver is free to choose its own plane layout, tiled modifiers
+        // and planar formats in particular do not match the gstreamer defaults
+        let plane_count = bo.plane_count().expect("no plane count") as usize;
+        if plane_count < video_info.n_planes() as usize {
+            return Err(glib::bool_error!(
+                "bo has {} planes, format requires {}",
+                plane_count,
+                video_info.n_planes()
+            ));
+        }
+        let mut offsets = Vec::with_capacity(plane_count);
+        let mut strides = Vec::with_capacity(plane_count);
+        for plane in 0..video_info.n_planes() as i32 {
+            offsets.push(bo.offset(plane).expect("no offset") as usize);
+            strides.push(bo.stride_for_plane(plane).expect("no stride") as i32);
+        }
+
+        let fd = bo
+            .fd()
+            .map_err(|err| glib::bool_error!("failed to export bo: {}", err))?;
+
+        let fd_size = unistd::lseek(fd.as_raw_fd(), 0, unistd::Whence::SeekEnd)
+            .map_err(|err| glib::bool_error!("failed to query bo size: {}", err))?;
         let _ = unistd::lseek(fd.as_raw_fd(), 0, unistd::Whence::SeekSet);
 
-        if (fd_size as usize) < video_info.size() {
-            panic!("bo too small");
+        let format_info = video_info.format_info();
+        let required_size = (0..video_info.n_planes() as usize)
+            .map(|plane| {
+                let height = format_info.scale_height(plane as u8, video_info.height()) as usize;
+                offsets[plane] + strides[plane] as usize * height
+            })
+            .max()
+            .unwrap_or(0);
+        if (fd_size as usize) < required_size {
+            return Err(glib::bool_error!(
+                "bo too small, {} bytes but {} required",
+                fd_size,
+                required_size
+            ));
         }
 
+        Ok(CachedBo {
+            key: BoKey {
+                width: video_info.width(),
+                height: video_info.height(),
+                format: format as u32,
+                modifier: modifier.into(),
+            },
+            _bo: bo,
+            fd,
+            size: fd_size as usize,
+            offsets,
+            strides,
+        })
+    }
+
+    /// Wrap a bo in a memory, the fd stays owned by the cache so the bo can be
+    /// reused once the memory is freed
+    fn wrap_bo(&self, cached: CachedBo) -> Result<gstreamer::Memory, glib::BoolError> {
+        let obj = self.obj();
+        let fd_allocator: &FdAllocator = obj.upcast_ref();
+
+        let fd = cached.fd.as_raw_fd();
         let memory = unsafe {
-            dmabuf_allocator
-                .alloc(fd, video_info.size())
-                .expect("failed to allocate dmabuf memory")
+            FdAllocator::alloc(fd_allocator, fd, cached.size, FdMemoryFlags::DONT_CLOSE)?
         };
+        self.cache.lock().unwrap().live.insert(fd, cached);
 
         Ok(memory)
     }
@@ -102,9 +347,15 @@ impl ObjectImpl for GbmMemoryAllocator {
         static PROPERTIES: Lazy<Vec<glib::ParamSpec>> = Lazy::new(|| {
             vec![glib::ParamSpecString::builder("device")
                 .nick("drm device")
-                .blurb("device path to allocator buffers from")
-                .default_value("/dev/dri/renderD128")
+                .blurb("device path to allocator buffers from, detected automatically if not set")
                 .construct()
+                .build(),
+            glib::ParamSpecInt::builder("fd")
+                .nick("drm fd")
+                .blurb("already opened drm fd to allocate buffers from, takes precedence over device")
+                .minimum(-1)
+                .default_value(-1)
+                .construct_only()
                 .build()]
         });
 
@@ -120,6 +371,10 @@ impl ObjectImpl for GbmMemoryAllocator {
                     .expect("type checked upstream");
                 settings.device_path = device_path;
             }
+            "fd" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.fd = value.get::<i32>().expect("type checked upstream");
+            }
             _ => unreachable!(),
         }
     }
@@ -130,13 +385,60 @@ impl ObjectImpl for GbmMemoryAllocator {
                 let settings = self.settings.lock().unwrap();
                 settings.device_path.to_value()
             }
+            "fd" => {
+                let settings = self.settings.lock().unwrap();
+                settings.fd.to_value()
+            }
             _ => unreachable!(),
         }
     }
 
     fn constructed(&self) {
-        let device_path = self.settings.lock().unwrap().device_path.clone().unwrap();
-        *self.device.lock().unwrap() = Some(gbm::Device::new(Card::open(&device_path)).unwrap());
+        self.parent_constructed();
+
+        unsafe {
+            crate::allocators::dmabuf_sync::install(
+                self.obj().as_ptr() as *mut gstreamer::ffi::GstAllocator
+            );
+        }
+
+        let mut settings = self.settings.lock().unwrap();
+        let device = if settings.fd >= 0 {
+            match Card::from_fd(settings.fd).and_then(gbm::Device::new) {
+                Ok(device) => Some(device),
+                Err(err) => {
+                    gstreamer::warning!(CAT, imp: self, "failed to use fd {}: {}", settings.fd, err);
+                    None
+                }
+            }
+        } else if let Some(device_path) = settings.device_path.as_deref() {
+            match open_device(device_path) {
+                Ok(device) => Some(device),
+                Err(err) => {
+                    gstreamer::warning!(CAT, imp: self, "failed to open {}: {}", device_path, err);
+                    None
+                }
+            }
+        } else {
+            render_nodes()
+                .into_iter()
+                .find_map(|path| match open_device(&path) {
+                    Ok(device) => {
+                        gstreamer::debug!(CAT, imp: self, "using render node {}", path.display());
+                        settings.device_path = path.to_str().map(String::from);
+                        Some(device)
+                    }
+                    Err(err) => {
+                        gstreamer::debug!(CAT, imp: self, "skipping {}: {}", path.display(), err);
+                        None
+                    }
+                })
+        };
+
+        if device.is_none() {
+            gstreamer::warning!(CAT, imp: self, "no usable render node found");
+        }
+        *self.device.lock().unwrap() = device;
     }
 }
 
@@ -144,4 +446,82 @@ impl GstObjectImpl for GbmMemoryAllocator {}
 
 impl DmaBufAllocatorImpl for GbmMemoryAllocator {}
 impl FdAllocatorImpl for GbmMemoryAllocator {}
-impl AllocatorImpl for GbmMemoryAllocator {}
+impl AllocatorImpl for GbmMemoryAllocator {
+    fn alloc(
+        &self,
+        size: usize,
+        _params: Option<&gstreamer::AllocationParams>,
+    ) -> Result<gstreamer::Memory, glib::BoolError> {
+        // Generic allocations without video info are backed by a linear R8 bo
+        const WIDTH: u32 = 4096;
+
+        let height = std::cmp::max(size.div_ceil(WIDTH as usize), 1) as u32;
+        let key = BoKey {
+            width: WIDTH,
+            height,
+            format: gbm::Format::R8 as u32,
+            modifier: gbm::Modifier::Linear.into(),
+        };
+
+        if let Some(cached) = self
+            .cache
+            .lock()
+            .unwrap()
+            .take(|candidate| *candidate == key)
+        {
+            gstreamer::trace!(CAT, imp: self, "reusing cached bo {:?}", cached);
+            return self.wrap_bo(cached);
+        }
+
+        let guard = self.device.lock().unwrap();
+        let Some(device) = guard.as_ref() else {
+            return Err(glib::bool_error!("no gbm device available"));
+        };
+
+        let bo = device
+            .create_buffer_object::<()>(
+                WIDTH,
+                height,
+                gbm::Format::R8,
+                gbm::BufferObjectFlags::LINEAR,
+            )
+            .map_err(|err| glib::bool_error!("failed to create bo: {}", err))?;
+        let fd = bo
+            .fd()
+            .map_err(|err| glib::bool_error!("failed to export bo: {}", err))?;
+        drop(guard);
+
+        self.wrap_bo(CachedBo {
+            key,
+            _bo: bo,
+            fd,
+            size,
+            offsets: vec![0],
+            strides: vec![WIDTH as i32],
+        })
+    }
+
+    fn free(&self, memory: gstreamer::Memory) {
+        let fd = memory
+            .downcast_memory_ref::<gstreamer_allocators::FdMemory>()
+            .map(|memory| memory.fd());
+        self.parent_free(memory);
+
+        let Some(fd) = fd else {
+            return;
+        };
+        let mut cache = self.cache.lock().unwrap();
+        let discarded = cache.discarded.remove(&fd);
+        if let Some(cached) = cache.live.remove(&fd) {
+            if discarded {
+                gstreamer::trace!(CAT, imp: self, "dropping discarded bo {:?}", cached);
+                return;
+            }
+            cache.free.push_back(cached);
+            if cache.free.len() > MAX_CACHED_BOS {
+                let evicted = cache.free.pop_front();
+                gstreamer::trace!(CAT, imp: self, "evicting cached bo {:?}", evicted);
+            }
+        }
+    }
+}
@@ -1,9 +1,22 @@
+use std::os::unix::io::{AsRawFd, BorrowedFd};
 use std::path::Path;
 
 use gstreamer::{glib, subclass::prelude::ObjectSubclassIsExt};
 
 mod imp;
 
+/// A buffer object allocated by [`GbmMemoryAllocator`] together with the layout
+/// chosen by the driver.
+#[derive(Debug)]
+pub struct GbmAllocation {
+    pub memory: gstreamer::Memory,
+    pub modifier: gbm::Modifier,
+    /// Per-plane offsets into the memory
+    pub offsets: Vec<usize>,
+    /// Per-plane strides
+    pub strides: Vec<i32>,
+}
+
 glib::wrapper! {
     pub struct GbmMemoryAllocator(ObjectSubclass<imp::GbmMemoryAllocator>) @extends gstreamer_allocators::DmaBufAllocator, gstreamer_allocators::FdAllocator, gstreamer::Allocator, gstreamer::Object;
 }
@@ -16,11 +29,43 @@ impl GbmMemoryAllocator {
             .build()
     }
 
+    /// Create an allocator on the render node of the DRM device `device`, as
+    /// announced by the dmabuf feedback of the compositor. `None` if the device has
+    /// no usable render node.
+    pub fn for_device(device: u64) -> Option<Self> {
+        let node = imp::render_node_for_device(device)?;
+        let allocator = Self::new(Some(node));
+        allocator.has_device().then_some(allocator)
+    }
+
+    /// Create an allocator for an already opened DRM device, for example one handed
+    /// out by logind. The fd is duplicated, so the caller keeps ownership.
+    pub fn with_fd(fd: BorrowedFd<'_>) -> Self {
+        glib::Object::builder()
+            .property("fd", fd.as_raw_fd())
+            .build()
+    }
+
+    /// Whether a DRM device could be opened, allocations fail otherwise.
+    pub fn has_device(&self) -> bool {
+        self.imp().has_device()
+    }
+
+    /// Drop the buffer object of `memory` once it is freed instead of reusing it.
+    pub fn discard(&self, memory: &gstreamer::MemoryRef) {
+        self.imp().discard(memory)
+    }
+
+    /// Allocate a buffer object for the video info with one of the modifiers.
+    ///
+    /// The plane layout of the returned allocation is queried from the buffer object
+    /// and can differ from the default layout of the video info.
     pub fn alloc(
         &self,
         video_info: &gstreamer_video::VideoInfo,
-    ) -> Result<gstreamer::Memory, glib::BoolError> {
-        self.imp().alloc(video_info)
+        modifiers: &[gbm::Modifier],
+    ) -> Result<GbmAllocation, glib::BoolError> {
+        self.imp().alloc(video_info, modifiers)
     }
 }
 
@@ -1,22 +1,160 @@
-use std::os::unix::io::IntoRawFd;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
 
 use gstreamer::glib;
-use gstreamer::prelude::Cast;
+use gstreamer::prelude::{Cast, ParamSpecBuilderExt, ToValue};
 use gstreamer::subclass::prelude::*;
-use gstreamer_allocators::{subclass::prelude::FdAllocatorImpl, FdAllocator, FdMemoryFlags};
+use gstreamer_allocators::{
+    subclass::prelude::FdAllocatorImpl, FdAllocator, FdMemory, FdMemoryFlags,
+};
+use once_cell::sync::Lazy;
+
+static CAT: Lazy<gstreamer::DebugCategory> = Lazy::new(|| {
+    gstreamer::DebugCategory::new(
+        "memfdallocator",
+        gstreamer::DebugColorFlags::empty(),
+        Some("memfd allocator"),
+    )
+});
+
+const DEFAULT_HUGEPAGE_SIZE: u32 = 2048;
+/// Largest huge page size known to memfd, 16 GiB
+const MAX_HUGEPAGE_SIZE: u32 = 16777216;
+
+/// Map a page size in KiB to the matching memfd hugetlb size.
+fn hugetlb_size(size_kib: u32) -> Option<memfd::HugetlbSize> {
+    let size = match size_kib {
+        64 => memfd::HugetlbSize::Huge64KB,
+        512 => memfd::HugetlbSize::Huge512KB,
+        1024 => memfd::HugetlbSize::Huge1MB,
+        2048 => memfd::HugetlbSize::Huge2MB,
+        8192 => memfd::HugetlbSize::Huge8MB,
+        16384 => memfd::HugetlbSize::Huge16MB,
+        262144 => memfd::HugetlbSize::Huge256MB,
+        1048576 => memfd::HugetlbSize::Huge1GB,
+        2097152 => memfd::HugetlbSize::Huge2GB,
+        16777216 => memfd::HugetlbSize::Huge16GB,
+        _ => return None,
+    };
+    Some(size)
+}
+
+fn memfd_options() -> memfd::MemfdOptions {
+    memfd::MemfdOptions::default()
+        .allow_sealing(true)
+        .close_on_exec(true)
+}
 
 #[derive(Debug)]
-pub struct MemfdMemoryAllocator {
-    mem_fd_opts: memfd::MemfdOptions,
+struct Settings {
+    use_hugepages: bool,
+    hugepage_size: u32,
 }
 
-impl Default for MemfdMemoryAllocator {
+impl Default for Settings {
     fn default() -> Self {
         Self {
-            mem_fd_opts: memfd::MemfdOptions::default()
-                .allow_sealing(true)
-                .close_on_exec(true),
+            use_hugepages: false,
+            hugepage_size: DEFAULT_HUGEPAGE_SIZE,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MemfdMemoryAllocator {
+    settings: Mutex<Settings>,
+    /// Number of memories currently alive, each one owns exactly one fd
+    allocated: AtomicUsize,
+}
+
+impl MemfdMemoryAllocator {
+    pub fn allocated(&self) -> usize {
+        self.allocated.load(Ordering::SeqCst)
+    }
+
+    pub fn grow(
+        &self,
+        memory: &gstreamer::MemoryRef,
+        size: usize,
+    ) -> Result<gstreamer::Memory, glib::BoolError> {
+        let Some(fd_memory) = memory.downcast_memory_ref::<FdMemory>() else {
+            return Err(glib::bool_error!("not a memfd memory"));
+        };
+
+        let len = {
+            let settings = self.settings.lock().unwrap();
+            if settings.use_hugepages {
+                (size as u64).next_multiple_of(settings.hugepage_size as u64 * 1024)
+            } else {
+                size as u64
+            }
+        };
+        nix::unistd::ftruncate(fd_memory.fd(), len as i64)
+            .map_err(|err| glib::bool_error!("failed to grow memfd: {}", err))?;
+
+        // The new memory needs its own fd, the old memory may outlive it
+        let fd = nix::unistd::dup(fd_memory.fd())
+            .map_err(|err| glib::bool_error!("failed to dup memfd: {}", err))?;
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let obj = self.obj();
+        let fd_allocator: &FdAllocator = obj.upcast_ref();
+        let memory =
+            unsafe { FdAllocator::alloc(fd_allocator, fd.as_raw_fd(), size, FdMemoryFlags::NONE)? };
+        let _ = fd.into_raw_fd();
+
+        let allocated = self.allocated.fetch_add(1, Ordering::SeqCst) + 1;
+        gstreamer::trace!(CAT, imp: self, "grew memory to {} bytes, {} alive", size, allocated);
+
+        Ok(memory)
+    }
+
+    fn create_memfd(&self, size: usize) -> Result<(memfd::Memfd, u64), glib::BoolError> {
+        let settings = self.settings.lock().unwrap();
+
+        if settings.use_hugepages {
+            let (hugepage_size, page_size_kib) = match hugetlb_size(settings.hugepage_size) {
+                Some(hugepage_size) => (hugepage_size, settings.hugepage_size),
+                None => {
+                    gstreamer::warning!(
+                        CAT,
+                        imp: self,
+                        "unsupported hugepage size {} KiB, using {} KiB",
+                        settings.hugepage_size,
+                        DEFAULT_HUGEPAGE_SIZE
+                    );
+                    (memfd::HugetlbSize::Huge2MB, DEFAULT_HUGEPAGE_SIZE)
+                }
+            };
+
+            // hugetlbfs requires the size to be a multiple of the page size
+            let len = (size as u64).next_multiple_of(page_size_kib as u64 * 1024);
+
+            match memfd_options()
+                .hugetlb(Some(hugepage_size))
+                .create("gst-shm-memory-allocator")
+            {
+                Ok(mem_fd) => match mem_fd.as_file().set_len(len) {
+                    Ok(()) => return Ok((mem_fd, len)),
+                    Err(err) => {
+                        gstreamer::warning!(CAT, imp: self, "failed to size hugepage memfd: {}", err)
+                    }
+                },
+                Err(err) => {
+                    gstreamer::warning!(CAT, imp: self, "failed to create hugepage memfd: {}", err)
+                }
+            }
         }
+
+        let mem_fd = memfd_options()
+            .create("gst-shm-memory-allocator")
+            .map_err(|err| glib::bool_error!("failed to create memfd: {}", err))?;
+        mem_fd
+            .as_file()
+            .set_len(size as u64)
+            .map_err(|err| glib::bool_error!("failed to set size: {}", err))?;
+        Ok((mem_fd, size as u64))
     }
 }
 
@@ -28,7 +166,66 @@ impl ObjectSubclass for MemfdMemoryAllocator {
     type Interfaces = ();
 }
 
-impl ObjectImpl for MemfdMemoryAllocator {}
+impl ObjectImpl for MemfdMemoryAllocator {
+    fn properties() -> &'static [glib::ParamSpec] {
+        static PROPERTIES: Lazy<Vec<glib::ParamSpec>> = Lazy::new(|| {
+            vec![
+                glib::ParamSpecBoolean::builder("use-hugepages")
+                    .nick("Use hugepages")
+                    .blurb("back the memory with huge pages (MFD_HUGETLB)")
+                    .default_value(false)
+                    .build(),
+                glib::ParamSpecUInt::builder("hugepage-size")
+                    .nick("Hugepage size")
+                    .blurb("huge page size in KiB, a power of two supported by the kernel")
+                    .minimum(64)
+                    .maximum(MAX_HUGEPAGE_SIZE)
+                    .default_value(DEFAULT_HUGEPAGE_SIZE)
+                    .build(),
+            ]
+        });
+
+        PROPERTIES.as_ref()
+    }
+
+    fn set_property(&self, _id: usize, value: &glib::Value, pspec: &glib::ParamSpec) {
+        match pspec.name() {
+            "use-hugepages" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.use_hugepages = value.get::<bool>().expect("type checked upstream");
+            }
+            "hugepage-size" => {
+                let mut settings = self.settings.lock().unwrap();
+                let hugepage_size = value.get::<u32>().expect("type checked upstream");
+                if hugepage_size.is_power_of_two() {
+                    settings.hugepage_size = hugepage_size;
+                } else {
+                    gstreamer::warning!(
+                        CAT,
+                        imp: self,
+                        "ignoring hugepage size {} KiB, not a power of two",
+                        hugepage_size
+                    );
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn property(&self, _id: usize, pspec: &glib::ParamSpec) -> glib::Value {
+        match pspec.name() {
+            "use-hugepages" => {
+                let settings = self.settings.lock().unwrap();
+                settings.use_hugepages.to_value()
+            }
+            "hugepage-size" => {
+                let settings = self.settings.lock().unwrap();
+                settings.hugepage_size.to_value()
+            }
+            _ => unreachable!(),
+        }
+    }
+}
 
 impl GstObjectImpl for MemfdMemoryAllocator {}
 
@@ -41,34 +238,33 @@ impl AllocatorImpl for MemfdMemoryAllocator {
         let obj = self.obj();
         let fd_allocator: &FdAllocator = obj.upcast_ref();
 
-        let mem_fd = self
-            .mem_fd_opts
-            .create("gst-shm-memory-allocator")
-            .expect("failed to create memfd");
-
-        mem_fd
-            .as_file()
-            .set_len(size as u64)
-            .expect("failed to set size");
+        let (mem_fd, _len) = self.create_memfd(size)?;
 
         let mut seals = memfd::SealsHashSet::new();
         seals.insert(memfd::FileSeal::SealShrink);
         let _ = mem_fd.add_seals(&seals);
         let _ = mem_fd.add_seal(memfd::FileSeal::SealSeal);
 
-        // FIXME: if alloc fails we will have a dangling fd
-        unsafe {
-            FdAllocator::alloc(
-                fd_allocator,
-                mem_fd.into_raw_fd(),
-                size,
-                FdMemoryFlags::NONE,
-            )
-        }
+        // Keep ownership until the memory has been created, so the fd gets closed
+        // if the allocation fails
+        let fd: OwnedFd = mem_fd.into_file().into();
+        let memory =
+            unsafe { FdAllocator::alloc(fd_allocator, fd.as_raw_fd(), size, FdMemoryFlags::NONE)? };
+        // The memory closes the fd when it is freed
+        let _ = fd.into_raw_fd();
+
+        let allocated = self.allocated.fetch_add(1, Ordering::SeqCst) + 1;
+        gstreamer::trace!(CAT, imp: self, "allocated memory, {} alive", allocated);
+
+        Ok(memory)
     }
 
     fn free(&self, memory: gstreamer::Memory) {
-        self.parent_free(memory)
+        // The parent fd allocator closes the fd of the memory
+        self.parent_free(memory);
+
+        let allocated = self.allocated.fetch_sub(1, Ordering::SeqCst) - 1;
+        gstreamer::trace!(CAT, imp: self, "freed memory, {} alive", allocated);
     }
 }
 
@@ -1,4 +1,4 @@
-use gstreamer::glib;
+use gstreamer::{glib, subclass::prelude::ObjectSubclassIsExt};
 
 mod imp;
 
@@ -6,6 +6,23 @@ glib::wrapper! {
     pub struct MemfdMemoryAllocator(ObjectSubclass<imp::MemfdMemoryAllocator>) @extends gstreamer_allocators::FdAllocator, gstreamer::Allocator, gstreamer::Object;
 }
 
+impl MemfdMemoryAllocator {
+    /// Number of memories allocated by this allocator that have not been freed yet.
+    pub fn allocated(&self) -> usize {
+        self.imp().allocated()
+    }
+
+    /// Grow the memfd backing `memory` to `size` bytes and return a new memory
+    /// covering all of it. `memory` stays valid.
+    pub fn grow(
+        &self,
+        memory: &gstreamer::MemoryRef,
+        size: usize,
+    ) -> Result<gstreamer::Memory, glib::BoolError> {
+        self.imp().grow(memory, size)
+    }
+}
+
 impl Default for MemfdMemoryAllocator {
     fn default() -> Self {
         glib::Object::new()
@@ -1,7 +1,129 @@
+#[cfg(feature = "dma-heap")]
+mod dma_heap;
+#[cfg(feature = "dmabuf")]
+mod dmabuf_sync;
+#[cfg(feature = "gbm")]
 mod gbm;
 mod memfd;
-mod dma_heap;
 
+#[cfg(feature = "dma-heap")]
+pub use self::dma_heap::{DmaHeapKind, DmaHeapMemoryAllocator};
+#[cfg(feature = "gbm")]
 pub use self::gbm::GbmMemoryAllocator;
 pub use self::memfd::MemfdMemoryAllocator;
-pub use self::dma_heap::DmaHeapMemoryAllocator;
+
+use std::path::PathBuf;
+
+use gstreamer::glib;
+#[cfg(any(feature = "dma-heap", feature = "gbm"))]
+use gstreamer::prelude::Cast;
+
+/// Names the allocators are registered with, see [`gstreamer::Allocator::find`]
+pub const MEMFD_ALLOCATOR_NAME: &str = "MemfdMemory";
+pub const DMA_HEAP_ALLOCATOR_NAME: &str = "DmaHeapMemory";
+pub const GBM_ALLOCATOR_NAME: &str = "GbmMemory";
+
+/// A dma-buf heap found in `/dev/dma_heap`, see [`dma_heaps`]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DmaHeap {
+    /// Name of the device node, like `system` or `linux,cma`
+    pub name: String,
+    pub path: PathBuf,
+    /// Whether the heap could be opened and allocated from
+    pub usable: bool,
+}
+
+/// The dma-buf heaps of the system and whether they are usable, empty if built
+/// without dma-heap support.
+pub fn dma_heaps() -> Vec<DmaHeap> {
+    #[cfg(feature = "dma-heap")]
+    {
+        DmaHeapMemoryAllocator::probe_heaps()
+    }
+    #[cfg(not(feature = "dma-heap"))]
+    {
+        Vec::new()
+    }
+}
+
+/// A dma-buf heap allocator for the usable heaps, if built with dma-heap support
+/// and any heap can be allocated from.
+pub fn dma_heap_allocator() -> Option<gstreamer::Allocator> {
+    #[cfg(feature = "dma-heap")]
+    {
+        DmaHeapMemoryAllocator::for_usable_heaps().map(|allocator| allocator.upcast())
+    }
+    #[cfg(not(feature = "dma-heap"))]
+    {
+        None
+    }
+}
+
+/// A GBM allocator, if built with GBM support and a DRM device could be opened.
+pub fn gbm_allocator() -> Option<gstreamer::Allocator> {
+    #[cfg(feature = "gbm")]
+    {
+        let allocator = GbmMemoryAllocator::default();
+        allocator.has_device().then(|| allocator.upcast())
+    }
+    #[cfg(not(feature = "gbm"))]
+    {
+        None
+    }
+}
+
+/// A GBM allocator on the DRM device `device`, so multi-GPU systems allocate
+/// buffers on the GPU the compositor renders with.
+pub fn gbm_allocator_for_device(device: u64) -> Option<gstreamer::Allocator> {
+    #[cfg(feature = "gbm")]
+    {
+        GbmMemoryAllocator::for_device(device).map(|allocator| allocator.upcast())
+    }
+    #[cfg(not(feature = "gbm"))]
+    {
+        let _ = device;
+        None
+    }
+}
+
+/// Wait for the implicit fences of all dmabuf memories in `buffer`, so the
+/// compositor finished writing before the buffer is handed to a GPU consumer.
+///
+/// Returns `false` if any fence did not signal within `timeout`.
+pub fn wait_dmabuf_fences(buffer: &gstreamer::BufferRef, timeout: std::time::Duration) -> bool {
+    #[cfg(feature = "dmabuf")]
+    {
+        use std::time::Instant;
+
+        let deadline = Instant::now() + timeout;
+        buffer.iter_memories().all(|memory| {
+            match memory.downcast_memory_ref::<gstreamer_allocators::DmaBufMemory>() {
+                Some(memory) => dmabuf_sync::wait_fences(
+                    memory.fd(),
+                    deadline.saturating_duration_since(Instant::now()),
+                ),
+                None => true,
+            }
+        })
+    }
+    #[cfg(not(feature = "dmabuf"))]
+    {
+        let _ = (buffer, timeout);
+        true
+    }
+}
+
+/// Register the allocators that are usable on this system by name.
+pub fn register() -> Result<(), glib::BoolError> {
+    gstreamer::Allocator::register(MEMFD_ALLOCATOR_NAME, MemfdMemoryAllocator::default());
+
+    if let Some(allocator) = dma_heap_allocator() {
+        gstreamer::Allocator::register(DMA_HEAP_ALLOCATOR_NAME, allocator);
+    }
+
+    if let Some(allocator) = gbm_allocator() {
+        gstreamer::Allocator::register(GBM_ALLOCATOR_NAME, allocator);
+    }
+
+    Ok(())
+}
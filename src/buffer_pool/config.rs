@@ -0,0 +1,194 @@
+//! Typed access to the [`WaylandBufferPool`](super::WaylandBufferPool) specific
+//! fields of a buffer pool config.
+//!
+//! The pool also honors the generic video options, [`BUFFER_POOL_OPTION_VIDEO_META`]
+//! adds video metas and [`BUFFER_POOL_OPTION_VIDEO_ALIGNMENT`] pads and aligns
+//! every plane exactly as requested. The allocator of the config selects how the memory is
+//! allocated, a memfd allocator by default.
+//!
+//! [`BUFFER_POOL_OPTION_VIDEO_META`]: gstreamer_video::BUFFER_POOL_OPTION_VIDEO_META
+//! [`BUFFER_POOL_OPTION_VIDEO_ALIGNMENT`]: gstreamer_video::BUFFER_POOL_OPTION_VIDEO_ALIGNMENT
+
+use super::{
+    BUFFER_POOL_CONFIG_ACQUIRE_TIMEOUT, BUFFER_POOL_CONFIG_DMABUF_MODIFIERS,
+    BUFFER_POOL_CONFIG_MEMORY_PER_PLANE, BUFFER_POOL_CONFIG_MEMORY_POOL,
+    BUFFER_POOL_CONFIG_MEMORY_TYPE, BUFFER_POOL_CONFIG_SHM_STRIDE, BUFFER_POOL_CONFIG_TRIM_TIMEOUT,
+};
+
+/// Memory the buffers of a pool are backed by
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WaylandMemoryType {
+    /// Decided by the configured allocator
+    #[default]
+    Auto,
+    /// Shared memory imported through wl_shm, requires a non-dmabuf fd allocator
+    Shm,
+    /// Dmabufs imported through zwp_linux_dmabuf_v1, requires a dmabuf allocator
+    Dmabuf,
+}
+
+impl WaylandMemoryType {
+    pub(super) fn as_str(self) -> &'static str {
+        match self {
+            WaylandMemoryType::Auto => "auto",
+            WaylandMemoryType::Shm => "shm",
+            WaylandMemoryType::Dmabuf => "dmabuf",
+        }
+    }
+
+    pub(super) fn from_str(memory_type: &str) -> Option<Self> {
+        match memory_type {
+            "auto" => Some(WaylandMemoryType::Auto),
+            "shm" => Some(WaylandMemoryType::Shm),
+            "dmabuf" => Some(WaylandMemoryType::Dmabuf),
+            _ => None,
+        }
+    }
+}
+
+/// Setters and getters for the fields of a [`WaylandBufferPool`](super::WaylandBufferPool)
+/// config, unset fields use the documented defaults.
+pub trait WaylandBufferPoolConfig {
+    /// Require the buffers to be backed by `memory_type`, setting the config fails
+    /// if the allocator can not provide it. Defaults to [`WaylandMemoryType::Auto`].
+    fn set_memory_type(&mut self, memory_type: WaylandMemoryType);
+    fn memory_type(&self) -> WaylandMemoryType;
+
+    /// Override the stride of single plane shm buffers, for compositors requiring a
+    /// stride different from the default stride of the format.
+    fn set_shm_stride(&mut self, stride: Option<u32>);
+    fn shm_stride(&self) -> Option<u32>;
+
+    /// DRM modifiers the consumer of the buffers accepts, used for GBM
+    /// allocations. Empty means linear only.
+    fn set_dmabuf_modifiers(&mut self, modifiers: &[u64]);
+    fn dmabuf_modifiers(&self) -> Vec<u64>;
+
+    /// Allocate one memory (and thus one fd) per plane for multi-planar dmabuf
+    /// buffers instead of a single memory, as expected by most hardware encoders.
+    /// Defaults to `false`.
+    fn set_memory_per_plane(&mut self, memory_per_plane: bool);
+    fn memory_per_plane(&self) -> bool;
+
+    /// Free buffers beyond the peak usage of the last `timeout` while the pool
+    /// is idle, `None` keeps all buffers until the pool is stopped.
+    fn set_trim_timeout(&mut self, timeout: Option<std::time::Duration>);
+    fn trim_timeout(&self) -> Option<std::time::Duration>;
+
+    /// Fail acquires without `DONTWAIT` with [`gstreamer::FlowError::Eos`] if no
+    /// buffer was returned within `timeout`, `None` waits forever.
+    fn set_acquire_timeout(&mut self, timeout: Option<std::time::Duration>);
+    fn acquire_timeout(&self) -> Option<std::time::Duration>;
+
+    /// Take the memory of the buffers from `pool` instead of the allocator, the
+    /// buffers have to be dmabufs laid out as described by their video meta.
+    /// The allocator is still required for the memory type checks.
+    fn set_memory_pool(&mut self, pool: Option<&gstreamer::BufferPool>);
+    fn memory_pool(&self) -> Option<gstreamer::BufferPool>;
+}
+
+impl WaylandBufferPoolConfig for gstreamer::BufferPoolConfigRef {
+    fn set_memory_type(&mut self, memory_type: WaylandMemoryType) {
+        self.set(BUFFER_POOL_CONFIG_MEMORY_TYPE, memory_type.as_str());
+    }
+
+    fn memory_type(&self) -> WaylandMemoryType {
+        self.get_optional::<String>(BUFFER_POOL_CONFIG_MEMORY_TYPE)
+            .ok()
+            .flatten()
+            .and_then(|memory_type| WaylandMemoryType::from_str(&memory_type))
+            .unwrap_or_default()
+    }
+
+    fn set_shm_stride(&mut self, stride: Option<u32>) {
+        match stride {
+            Some(stride) => self.set(BUFFER_POOL_CONFIG_SHM_STRIDE, stride),
+            None => {
+                self.remove_field(BUFFER_POOL_CONFIG_SHM_STRIDE);
+            }
+        }
+    }
+
+    fn shm_stride(&self) -> Option<u32> {
+        self.get_optional::<u32>(BUFFER_POOL_CONFIG_SHM_STRIDE)
+            .ok()
+            .flatten()
+    }
+
+    fn set_dmabuf_modifiers(&mut self, modifiers: &[u64]) {
+        self.set(
+            BUFFER_POOL_CONFIG_DMABUF_MODIFIERS,
+            gstreamer::Array::new(modifiers.iter().copied()),
+        );
+    }
+
+    fn dmabuf_modifiers(&self) -> Vec<u64> {
+        self.get_optional::<gstreamer::Array>(BUFFER_POOL_CONFIG_DMABUF_MODIFIERS)
+            .ok()
+            .flatten()
+            .map(|modifiers| {
+                modifiers
+                    .as_slice()
+                    .iter()
+                    .filter_map(|modifier| modifier.get::<u64>().ok())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default()
+    }
+
+    fn set_memory_per_plane(&mut self, memory_per_plane: bool) {
+        self.set(BUFFER_POOL_CONFIG_MEMORY_PER_PLANE, memory_per_plane);
+    }
+
+    fn memory_per_plane(&self) -> bool {
+        self.get_optional::<bool>(BUFFER_POOL_CONFIG_MEMORY_PER_PLANE)
+            .ok()
+            .flatten()
+            .unwrap_or(false)
+    }
+
+    fn set_trim_timeout(&mut self, timeout: Option<std::time::Duration>) {
+        let timeout_ms = timeout
+            .map(|timeout| timeout.as_millis().min(u32::MAX as u128) as u32)
+            .unwrap_or(0);
+        self.set(BUFFER_POOL_CONFIG_TRIM_TIMEOUT, timeout_ms);
+    }
+
+    fn trim_timeout(&self) -> Option<std::time::Duration> {
+        self.get_optional::<u32>(BUFFER_POOL_CONFIG_TRIM_TIMEOUT)
+            .ok()
+            .flatten()
+            .filter(|timeout_ms| *timeout_ms > 0)
+            .map(|timeout_ms| std::time::Duration::from_millis(timeout_ms as u64))
+    }
+
+    fn set_acquire_timeout(&mut self, timeout: Option<std::time::Duration>) {
+        let timeout_ms = timeout
+            .map(|timeout| timeout.as_millis().min(u32::MAX as u128) as u32)
+            .unwrap_or(0);
+        self.set(BUFFER_POOL_CONFIG_ACQUIRE_TIMEOUT, timeout_ms);
+    }
+
+    fn acquire_timeout(&self) -> Option<std::time::Duration> {
+        self.get_optional::<u32>(BUFFER_POOL_CONFIG_ACQUIRE_TIMEOUT)
+            .ok()
+            .flatten()
+            .filter(|timeout_ms| *timeout_ms > 0)
+            .map(|timeout_ms| std::time::Duration::from_millis(timeout_ms as u64))
+    }
+
+    fn set_memory_pool(&mut self, pool: Option<&gstreamer::BufferPool>) {
+        match pool {
+            Some(pool) => self.set(BUFFER_POOL_CONFIG_MEMORY_POOL, pool),
+            None => {
+                self.remove_field(BUFFER_POOL_CONFIG_MEMORY_POOL);
+            }
+        }
+    }
+
+    fn memory_pool(&self) -> Option<gstreamer::BufferPool> {
+        self.get_optional::<gstreamer::BufferPool>(BUFFER_POOL_CONFIG_MEMORY_POOL)
+            .ok()
+            .flatten()
+    }
+}
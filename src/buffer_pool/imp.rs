@@ -1,16 +1,29 @@
-use std::sync::{Arc, Mutex};
+use std::collections::{HashMap, HashSet};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
 
-use gstreamer::glib;
-use gstreamer::prelude::Cast;
+use gstreamer::glib::{self, translate::IntoGlib};
+use gstreamer::prelude::{
+    AllocatorExt, BufferPoolExt, BufferPoolExtManual, Cast, ObjectExt, ParamSpecBuilderExt, ToValue,
+};
 use gstreamer::subclass::prelude::*;
 
-use gstreamer_video::{VideoInfo, VideoBufferPoolConfig};
+use gstreamer_video::{VideoBufferPoolConfig, VideoInfo};
 use once_cell::sync::Lazy;
 use wayland_client::backend::{ObjectData, ObjectId};
-use wayland_client::{Proxy, WEnum};
+use wayland_client::Proxy;
 
-use crate::allocators::{GbmMemoryAllocator, MemfdMemoryAllocator};
-use crate::utils::{gst_video_format_to_wl_shm, gst_video_format_to_drm_fourcc};
+#[cfg(feature = "gbm")]
+use crate::allocators::GbmMemoryAllocator;
+use crate::allocators::MemfdMemoryAllocator;
+use crate::session::protocol_log;
+use crate::utils::{
+    gst_video_format_to_drm_fourcc_code, gst_video_format_to_wl_shm, DRM_FORMAT_MOD_LINEAR,
+};
+
+use super::{WaylandBufferPoolConfig, WaylandMemoryType};
 
 static CAT: Lazy<gstreamer::DebugCategory> = Lazy::new(|| {
     gstreamer::DebugCategory::new(
@@ -26,180 +39,880 @@ pub struct State {
         wayland_protocols::wp::linux_dmabuf::zv1::client::zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1,
     >,
     pub wl_shm: Option<wayland_client::protocol::wl_shm::WlShm>,
-    video_info: Option<VideoInfo>,
+    pub(super) video_info: Option<VideoInfo>,
     allocator: Option<gstreamer::Allocator>,
     allocation_params: Option<Option<gstreamer::AllocationParams>>,
+    modifiers: Vec<u64>,
+    size: usize,
+    memory_per_plane: bool,
     add_video_meta: bool,
+    /// Bytes of alignment padding in front of the visible region of each plane
+    plane_padding: Vec<usize>,
+    min_buffers: u32,
+    trim_timeout: Option<Duration>,
+    acquire_timeout: Option<Duration>,
+    /// Pool providing the dmabufs instead of the allocator
+    memory_pool: Option<gstreamer::BufferPool>,
+    /// Modifier of the last dmabuf the compositor imported
+    pub(super) dmabuf_modifier: Option<u64>,
+}
+
+/// How long to wait for the compositor to release a buffer before reusing it anyway
+const RELEASE_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Maximum number of buffers if the configuration does not limit it
+const DEFAULT_MAX_BUFFERS: u32 = 8;
+
+/// How often a wait for released buffers checks whether the pool is flushing
+const FLUSH_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How long to wait for the compositor to answer a dmabuf import
+const DMABUF_IMPORT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Lifecycle counters of the pool, exposed through the `stats` property
+#[derive(Debug, Default)]
+pub(super) struct Counters {
+    acquired: AtomicU64,
+    released: AtomicU64,
+    copies: AtomicU64,
+    compositor_releases: AtomicU64,
+    release_timeouts: AtomicU64,
+    wl_buffers_created: AtomicU64,
+    wl_buffers_destroyed: AtomicU64,
+}
+
+impl Counters {
+    fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Usage of the pool since the trimming window started
+#[derive(Debug)]
+struct TrimWindow {
+    start: Instant,
+    /// Most buffers acquired at the same time within the window
+    peak: u64,
+}
+
+impl Default for TrimWindow {
+    fn default() -> Self {
+        TrimWindow {
+            start: Instant::now(),
+            peak: 0,
+        }
+    }
+}
+
+/// Tracks the wl_buffers currently in use by the compositor
+#[derive(Debug, Default)]
+pub(super) struct ReleaseTracker {
+    /// Busy wl_buffers and since when they are in use by the compositor
+    busy: Mutex<HashMap<ObjectId, Instant>>,
+    /// wl_buffers not released in time, not waited for until the compositor
+    /// releases them after all
+    unreleased: Mutex<HashSet<ObjectId>>,
+    /// wl_buffers of abandoned copies, freed instead of handed out again
+    discarded: Mutex<HashSet<ObjectId>>,
+    counters: Counters,
+}
+
+impl ReleaseTracker {
+    pub(super) fn mark_busy(&self, id: ObjectId) {
+        Counters::inc(&self.counters.copies);
+        gstreamer::trace!(CAT, "copy into {}", id);
+        if self.never_releases() || self.unreleased.lock().unwrap().contains(&id) {
+            return;
+        }
+        self.busy.lock().unwrap().insert(id, Instant::now());
+    }
+
+    /// Whether a wait for a release timed out before the compositor released any
+    /// buffer, it is then assumed to not send release events at all
+    fn never_releases(&self) -> bool {
+        self.counters.compositor_releases.load(Ordering::Relaxed) == 0
+            && self.counters.release_timeouts.load(Ordering::Relaxed) > 0
+    }
+
+    fn is_busy(&self, id: &ObjectId) -> bool {
+        self.busy.lock().unwrap().contains_key(id)
+    }
+
+    fn busy_count(&self) -> usize {
+        self.busy.lock().unwrap().len()
+    }
+
+    fn release(&self, id: &ObjectId) {
+        self.busy.lock().unwrap().remove(id);
+        self.unreleased.lock().unwrap().remove(id);
+    }
+
+    pub(super) fn discard(&self, id: ObjectId) {
+        gstreamer::trace!(CAT, "discarding {}", id);
+        self.discarded.lock().unwrap().insert(id);
+    }
+
+    fn is_discarded(&self, id: &ObjectId) -> bool {
+        self.discarded.lock().unwrap().contains(id)
+    }
+
+    /// `wl_buffer.release` was received for `id`
+    fn compositor_release(&self, id: &ObjectId) {
+        Counters::inc(&self.counters.compositor_releases);
+        if self.unreleased.lock().unwrap().remove(id) {
+            gstreamer::trace!(CAT, "{} released by the compositor late", id);
+        } else if let Some(since) = self.busy.lock().unwrap().remove(id) {
+            gstreamer::trace!(
+                CAT,
+                "{} released by the compositor after {:?}",
+                id,
+                since.elapsed()
+            );
+        } else {
+            gstreamer::trace!(CAT, "{} released by the compositor", id);
+        }
+    }
+
+    /// Stop waiting for `id`, it is tracked again once the compositor released it
+    fn time_out(&self, id: &ObjectId) {
+        Counters::inc(&self.counters.release_timeouts);
+        let mut busy = self.busy.lock().unwrap();
+        if self.never_releases() {
+            // None of the other busy buffers is going to be released either
+            busy.clear();
+        } else {
+            busy.remove(id);
+            self.unreleased.lock().unwrap().insert(id.clone());
+        }
+    }
+}
+
+/// One memfd and wl_shm_pool shared by all shm buffers of the pool, buffers
+/// get fixed size slots of it.
+#[derive(Debug)]
+struct ShmArena {
+    wl_shm_pool: wayland_client::protocol::wl_shm_pool::WlShmPool,
+    /// Memory covering the whole memfd, buffers hold shared sub-memories of it
+    memory: gstreamer::Memory,
+    slot_size: usize,
+    /// Offsets of slots whose buffers have been freed
+    free_slots: Vec<usize>,
+    /// Slot offsets of alive buffers
+    slots: HashMap<ObjectId, usize>,
+    /// End of the used part of the memfd
+    used: usize,
+}
+
+impl ShmArena {
+    fn destroy(self) {
+        // Buffers created from the pool stay valid after the pool is destroyed
+        self.wl_shm_pool.destroy();
+    }
+}
+
+/// A wl_buffer bound to the lifetime of the memory backing it
+#[derive(Debug)]
+struct MemoryWlBuffer {
+    wl_buffer: wayland_client::protocol::wl_buffer::WlBuffer,
+    /// Layout the wl_buffer was created with, to restore the metas of the buffer
+    layout: PlaneLayout,
+    release_tracker: Arc<ReleaseTracker>,
+    shm_arena: Arc<Mutex<Option<ShmArena>>>,
+}
+
+impl Drop for MemoryWlBuffer {
+    fn drop(&mut self) {
+        let id = self.wl_buffer.id();
+        let discarded = self.release_tracker.discarded.lock().unwrap().remove(&id);
+        self.release_tracker.release(&id);
+        Counters::inc(&self.release_tracker.counters.wl_buffers_destroyed);
+        gstreamer::trace!(CAT, "destroying {}", id);
+        protocol_log::request(&self.wl_buffer, format_args!("destroy()"));
+        // The slot can only be handed out again once nobody references the memory,
+        // the compositor may still write into the slot of a discarded buffer
+        if let Some(arena) = self.shm_arena.lock().unwrap().as_mut() {
+            if let Some(offset) = arena.slots.remove(&id) {
+                if !discarded {
+                    arena.free_slots.push(offset);
+                }
+            }
+        }
+        self.wl_buffer.destroy();
+    }
+}
+
+static MEMORY_WL_BUFFER_QUARK: Lazy<glib::Quark> =
+    Lazy::new(|| glib::Quark::from_str("WaylandBufferPoolWlBuffer"));
+
+unsafe extern "C" fn drop_memory_wl_buffer(data: glib::ffi::gpointer) {
+    drop(Box::from_raw(data as *mut MemoryWlBuffer));
+}
+
+/// The wl_buffer bound to `memory` by [`WaylandBufferPool::bind_wl_buffer`], if any
+fn memory_wl_buffer(
+    memory: &gstreamer::MemoryRef,
+) -> Option<wayland_client::protocol::wl_buffer::WlBuffer> {
+    memory_wl_buffer_data(memory, |data| data.wl_buffer.clone())
+}
+
+/// The wl_buffer and layout bound to `memory`, if any
+fn memory_wl_buffer_layout(
+    memory: &gstreamer::MemoryRef,
+) -> Option<(wayland_client::protocol::wl_buffer::WlBuffer, PlaneLayout)> {
+    memory_wl_buffer_data(memory, |data| (data.wl_buffer.clone(), data.layout.clone()))
+}
+
+fn memory_wl_buffer_data<T>(
+    memory: &gstreamer::MemoryRef,
+    f: impl FnOnce(&MemoryWlBuffer) -> T,
+) -> Option<T> {
+    unsafe {
+        let data = gstreamer::ffi::gst_mini_object_get_qdata(
+            memory.as_ptr() as *mut gstreamer::ffi::GstMiniObject,
+            MEMORY_WL_BUFFER_QUARK.into_glib(),
+        ) as *const MemoryWlBuffer;
+        data.as_ref().map(f)
+    }
+}
+
+/// Add a [`WaylandBufferMeta`](super::meta::WaylandBufferMeta) that survives resetting the buffer
+fn add_pooled_meta(
+    buffer: &mut gstreamer::BufferRef,
+    wl_buffer: wayland_client::protocol::wl_buffer::WlBuffer,
+) {
+    let mut meta = super::meta::WaylandBufferMeta::add(buffer, wl_buffer);
+    mark_pooled(&mut meta);
+}
+
+/// Keep `meta` when the buffer is reset, metas added outside of the allocation are
+/// removed otherwise
+fn mark_pooled<T: gstreamer::MetaAPI>(
+    meta: &mut gstreamer::MetaRefMut<T, gstreamer::meta::Standalone>,
+) {
+    unsafe {
+        (*(meta.as_mut_ptr() as *mut gstreamer::ffi::GstMeta)).flags |=
+            gstreamer::ffi::GST_META_FLAG_POOLED;
+    }
+}
+
+/// Describe the dmabuf planes of `buffer` for importers without `DMA_DRM` caps.
+fn add_dmabuf_layout_meta(
+    buffer: &mut gstreamer::BufferRef,
+    video_info: &gstreamer_video::VideoInfo,
+    layout: &PlaneLayout,
+) {
+    let Some(fourcc) = gst_video_format_to_drm_fourcc_code(video_info.format()) else {
+        return;
+    };
+    let planes = (0..video_info.n_planes() as usize)
+        .map_while(|plane| {
+            let (mem_idx, _, skip) = buffer.find_memory(layout.offsets[plane], Some(1))?;
+            let mem = buffer
+                .peek_memory(mem_idx)
+                .downcast_memory_ref::<gstreamer_allocators::DmaBufMemory>()?;
+            Some(super::meta::DmabufLayoutPlane {
+                fd: mem.fd(),
+                offset: (mem.offset() + skip) as u32,
+                stride: layout.strides[plane] as u32,
+            })
+        })
+        .collect::<Vec<_>>();
+    if planes.len() == video_info.n_planes() as usize {
+        let mut meta = super::meta::DmabufLayoutMeta::add(buffer, fourcc, layout.modifier, planes);
+        mark_pooled(&mut meta);
+    }
 }
 
 #[derive(Debug)]
 pub struct WaylandBufferPool {
     pub state: Mutex<State>,
+    shm_arena: Arc<Mutex<Option<ShmArena>>>,
+    pub(super) release_tracker: Arc<ReleaseTracker>,
+    flushing: AtomicBool,
+    /// Number of buffers returned to the pool, acquires with a timeout wait for it
+    /// to change
+    returned: Mutex<u64>,
+    returned_cond: Condvar,
+    /// The compositor failed to import dmabufs with any of the configured modifiers
+    pub(super) dmabuf_rejected: AtomicBool,
+    /// Number of buffers allocated and not yet freed
+    allocated: AtomicU64,
+    trim_window: Mutex<TrimWindow>,
     dummy_object_data: Arc<DummyObjectData>,
+    wl_buffer_data: Arc<WlBufferData>,
 }
 
 impl Default for WaylandBufferPool {
     fn default() -> Self {
+        let release_tracker = Arc::new(ReleaseTracker::default());
         Self {
             state: Default::default(),
+            shm_arena: Default::default(),
+            wl_buffer_data: WlBufferData::new(release_tracker.clone()),
+            release_tracker,
+            flushing: AtomicBool::new(false),
+            returned: Mutex::new(0),
+            returned_cond: Condvar::new(),
+            dmabuf_rejected: AtomicBool::new(false),
+            allocated: AtomicU64::new(0),
+            trim_window: Default::default(),
             dummy_object_data: DummyObjectData::new(),
         }
     }
 }
 
-#[glib::object_subclass]
-impl ObjectSubclass for WaylandBufferPool {
-    const NAME: &'static str = "WaylandBufferPool";
-    type Type = super::WaylandBufferPool;
-    type ParentType = gstreamer::BufferPool;
-    type Interfaces = ();
-}
+impl WaylandBufferPool {
+    /// Tie the lifetime of `wl_buffer` to `memory`, it is destroyed once the memory
+    /// is freed instead of with the buffer. Buffers with multiple memories bind
+    /// the wl_buffer to the first one.
+    fn bind_wl_buffer(
+        &self,
+        memory: &gstreamer::MemoryRef,
+        wl_buffer: &wayland_client::protocol::wl_buffer::WlBuffer,
+        layout: &PlaneLayout,
+    ) {
+        Counters::inc(&self.release_tracker.counters.wl_buffers_created);
+        gstreamer::trace!(CAT, imp: self, "created {} for memory {:?}", wl_buffer.id(), memory.as_ptr());
+        let data = Box::new(MemoryWlBuffer {
+            wl_buffer: wl_buffer.clone(),
+            layout: layout.clone(),
+            release_tracker: self.release_tracker.clone(),
+            shm_arena: self.shm_arena.clone(),
+        });
+        unsafe {
+            gstreamer::ffi::gst_mini_object_set_qdata(
+                memory.as_ptr() as *mut gstreamer::ffi::GstMiniObject,
+                MEMORY_WL_BUFFER_QUARK.into_glib(),
+                Box::into_raw(data) as glib::ffi::gpointer,
+                Some(drop_memory_wl_buffer),
+            );
+        }
+    }
 
-impl ObjectImpl for WaylandBufferPool {}
+    /// Import the dmabuf described by `params` with the `create` request, returns
+    /// `None` if the compositor rejected it.
+    ///
+    /// Unlike `create_immed` an unsupported format or modifier is not a protocol
+    /// error, so other modifiers or shm can be tried afterwards.
+    fn import_dmabuf(
+        &self,
+        params: &wayland_protocols::wp::linux_dmabuf::zv1::client::zwp_linux_buffer_params_v1::ZwpLinuxBufferParamsV1,
+        params_data: &BufferParamsData,
+        video_info: &VideoInfo,
+        format: u32,
+    ) -> Result<Option<wayland_client::protocol::wl_buffer::WlBuffer>, gstreamer::FlowError> {
+        let Some(connection) = self.connection() else {
+            return Err(gstreamer::FlowError::Error);
+        };
 
-impl GstObjectImpl for WaylandBufferPool {}
+        protocol_log::request(
+            params,
+            format_args!(
+                "create({}, {}, {:#x}, 0)",
+                video_info.width(),
+                video_info.height(),
+                format
+            ),
+        );
+        params.create(
+            video_info.width() as i32,
+            video_info.height() as i32,
+            format,
+            wayland_protocols::wp::linux_dmabuf::zv1::client::zwp_linux_buffer_params_v1::Flags::empty(),
+        );
+        let answered = self.dispatch_until(
+            &connection,
+            Instant::now() + DMABUF_IMPORT_TIMEOUT,
+            false,
+            || params_data.result.lock().unwrap().is_some(),
+        )?;
+        if !answered {
+            gstreamer::warning!(
+                CAT,
+                imp: self,
+                "compositor did not answer the dmabuf import within {:?}",
+                DMABUF_IMPORT_TIMEOUT
+            );
+            return Err(gstreamer::FlowError::Error);
+        }
 
-impl BufferPoolImpl for WaylandBufferPool {
-    fn options() -> &'static [&'static str] {
-        static OPTIONS: Lazy<Vec<&'static str>> = Lazy::new(|| vec![&*gstreamer_video::BUFFER_POOL_OPTION_VIDEO_META, &*gstreamer_video::BUFFER_POOL_OPTION_VIDEO_ALIGNMENT]);
+        Ok(params_data.result.lock().unwrap().take().flatten())
+    }
 
-        OPTIONS.as_ref()
+    /// Number of buffers currently acquired from the pool
+    fn outstanding(&self) -> u64 {
+        let counters = &self.release_tracker.counters;
+        counters
+            .acquired
+            .load(Ordering::Relaxed)
+            .saturating_sub(counters.released.load(Ordering::Relaxed))
     }
 
-    fn alloc_buffer(
+    /// Mark `buffer` to be freed when it is released, together with the buffer
+    /// objects of its memories.
+    pub(super) fn discard(&self, buffer: &gstreamer::BufferRef) {
+        let Some(meta) = buffer.meta::<super::meta::WaylandBufferMeta>() else {
+            return;
+        };
+        self.release_tracker.discard(meta.wl_buffer().id());
+        #[cfg(feature = "gbm")]
+        if let Some(gbm_allocator) = self
+            .state
+            .lock()
+            .unwrap()
+            .allocator
+            .as_ref()
+            .and_then(|allocator| allocator.downcast_ref::<GbmMemoryAllocator>())
+        {
+            for memory in buffer.iter_memories() {
+                gbm_allocator.discard(memory);
+            }
+        }
+    }
+
+    /// Whether a buffer being released should be freed instead of kept, because
+    /// more buffers are allocated than were in use during the last trim timeout.
+    /// Excess buffers are freed one per release.
+    fn should_trim(&self) -> bool {
+        let (trim_timeout, min_buffers) = {
+            let state = self.state.lock().unwrap();
+            (state.trim_timeout, state.min_buffers)
+        };
+        let Some(trim_timeout) = trim_timeout else {
+            return false;
+        };
+
+        let mut trim_window = self.trim_window.lock().unwrap();
+        if trim_window.start.elapsed() < trim_timeout {
+            return false;
+        }
+
+        let needed = std::cmp::max(
+            std::cmp::max(trim_window.peak, self.outstanding()),
+            min_buffers as u64,
+        );
+        if self.allocated.load(Ordering::SeqCst) > needed {
+            return true;
+        }
+
+        *trim_window = TrimWindow {
+            start: Instant::now(),
+            peak: self.outstanding(),
+        };
+        false
+    }
+
+    /// Allocate a slot of `size` bytes from the shared shm arena, creating or
+    /// growing the arena as needed.
+    fn alloc_shm_slot(
         &self,
-        params: Option<&gstreamer::BufferPoolAcquireParams>,
-    ) -> Result<gstreamer::Buffer, gstreamer::FlowError> {
-        let state = self.state.lock().unwrap();
-        let video_info = state.video_info.as_ref().unwrap();
-        let allocator = state.allocator.as_ref().unwrap();
+        wl_shm: &wayland_client::protocol::wl_shm::WlShm,
+        allocator: &MemfdMemoryAllocator,
+        size: usize,
+    ) -> Result<
+        (
+            gstreamer::Memory,
+            wayland_client::protocol::wl_shm_pool::WlShmPool,
+            usize,
+        ),
+        gstreamer::FlowError,
+    > {
+        let mut guard = self.shm_arena.lock().unwrap();
 
-        let mut buffer = if let Some(gbm_allocator) = allocator.downcast_ref::<GbmMemoryAllocator>() {
-            let mem = match gbm_allocator.alloc(video_info) {
-                Ok(mem) => mem,
-                Err(_) => {
-                    return Err(gstreamer::FlowError::Error);
+        // Slots of a previous configuration can not be reused
+        if guard
+            .as_ref()
+            .map(|arena| arena.slot_size != size)
+            .unwrap_or(false)
+        {
+            guard.take().unwrap().destroy();
+        }
+
+        let arena = match guard.as_mut() {
+            Some(arena) => arena,
+            None => {
+                let memory = allocator.alloc(size, None).map_err(|err| {
+                    gstreamer::warning!(CAT, imp: self, "failed to allocate shm memory: {}", err);
+                    gstreamer::FlowError::Error
+                })?;
+                let fd = memory
+                    .downcast_memory_ref::<gstreamer_allocators::FdMemory>()
+                    .unwrap()
+                    .fd();
+                let wl_shm_pool = wl_shm
+                    .send_constructor::<wayland_client::protocol::wl_shm_pool::WlShmPool>(
+                        wayland_client::protocol::wl_shm::Request::CreatePool {
+                            fd,
+                            size: size as i32,
+                        },
+                        self.dummy_object_data.clone(),
+                    )
+                    .map_err(|err| {
+                        gstreamer::warning!(CAT, imp: self, "failed to create shm pool: {}", err);
+                        gstreamer::FlowError::Error
+                    })?;
+                protocol_log::request(
+                    wl_shm,
+                    format_args!("create_pool(fd {}, {}) -> {}", fd, size, wl_shm_pool.id()),
+                );
+                guard.insert(ShmArena {
+                    wl_shm_pool,
+                    memory,
+                    slot_size: size,
+                    free_slots: Vec::new(),
+                    slots: HashMap::new(),
+                    used: 0,
+                })
+            }
+        };
+
+        let offset = match arena.free_slots.pop() {
+            Some(offset) => offset,
+            None => {
+                let offset = arena.used;
+                if offset + size > arena.memory.size() {
+                    let new_size = std::cmp::max(arena.memory.size() * 2, offset + size);
+                    gstreamer::debug!(CAT, imp: self, "growing shm pool to {} bytes", new_size);
+                    arena.memory = allocator.grow(&arena.memory, new_size).map_err(|err| {
+                        gstreamer::warning!(CAT, imp: self, "failed to grow shm pool: {}", err);
+                        gstreamer::FlowError::Error
+                    })?;
+                    arena.wl_shm_pool.resize(new_size as i32);
                 }
-            };
+                arena.used += size;
+                offset
+            }
+        };
 
-            let mut buffer = gstreamer::Buffer::new();
-            let buffer_mut = buffer.make_mut();
-            buffer_mut.insert_memory(None, mem);
-            buffer
-        } else {
-            self.parent_alloc_buffer(params)?
+        let memory = arena.memory.share(offset as isize, Some(size));
+        Ok((memory, arena.wl_shm_pool.clone(), offset))
+    }
+
+    fn connection(&self) -> Option<wayland_client::Connection> {
+        let backend = self
+            .state
+            .lock()
+            .unwrap()
+            .wl_shm
+            .as_ref()
+            .and_then(|wl_shm| wl_shm.backend().upgrade());
+        if backend.is_none() {
+            gstreamer::warning!(CAT, imp: self, "wayland connection gone");
+        }
+        backend.map(wayland_client::Connection::from_backend)
+    }
+
+    fn flush_connection(&self) -> bool {
+        let Some(connection) = self.connection() else {
+            return false;
         };
 
-        let mem = buffer.memory(0).unwrap();
+        match connection.flush() {
+            Ok(()) => true,
+            Err(err) => {
+                gstreamer::warning!(CAT, imp: self, "failed to flush connection: {}", err);
+                false
+            }
+        }
+    }
 
-        if mem.downcast_memory_ref::<gstreamer_allocators::DmaBufMemory>().is_some() {
-            let zwp_linux_dmabuf = state.zwp_linux_dmabuf.as_ref().unwrap();
+    /// Block until the compositor released `wl_buffer`, reading events from the
+    /// connection in the meantime.
+    fn wait_for_release(
+        &self,
+        wl_buffer: &wayland_client::protocol::wl_buffer::WlBuffer,
+    ) -> Result<(), gstreamer::FlowError> {
+        let id = wl_buffer.id();
+        if !self.release_tracker.is_busy(&id) {
+            return Ok(());
+        }
 
-            let params = zwp_linux_dmabuf.send_constructor::<wayland_protocols::wp::linux_dmabuf::zv1::client::zwp_linux_buffer_params_v1::ZwpLinuxBufferParamsV1>(wayland_protocols::wp::linux_dmabuf::zv1::client::zwp_linux_dmabuf_v1::Request::CreateParams {  }, self.dummy_object_data.clone()).expect("failed to create params");
-            
-            for plane in 0..video_info.n_planes() {
-                let offset = video_info.offset()[plane as usize];
-                let stride= video_info.stride()[plane as usize];
+        let Some(backend) = wl_buffer.backend().upgrade() else {
+            gstreamer::warning!(CAT, imp: self, "wayland connection gone");
+            return Err(gstreamer::FlowError::Error);
+        };
+        let connection = wayland_client::Connection::from_backend(backend);
 
-                let (mem_idx, _, skip) = buffer.find_memory(offset, Some(1)).expect("memory does not seem to contain enough data for the specified format");
-                let mem = buffer.peek_memory(mem_idx).downcast_memory_ref::<gstreamer_allocators::DmaBufMemory>().unwrap();
-                params.add(
-                    mem.fd(),
-                    plane,
-                    (mem.offset() + skip) as u32,
-                    stride as u32,
-                    0,
-                    0,
+        gstreamer::trace!(CAT, imp: self, "waiting for release of {}", id);
+        let released =
+            self.dispatch_until(&connection, Instant::now() + RELEASE_TIMEOUT, true, || {
+                !self.release_tracker.is_busy(&id)
+            })?;
+        if !released {
+            self.release_tracker.time_out(&id);
+            if self.release_tracker.never_releases() {
+                gstreamer::warning!(
+                    CAT,
+                    imp: self,
+                    "{} not released within {:?}, assuming the compositor does not release buffers",
+                    id,
+                    RELEASE_TIMEOUT
+                );
+            } else {
+                gstreamer::debug!(
+                    CAT,
+                    imp: self,
+                    "{} not released within {:?}, reusing it",
+                    id,
+                    RELEASE_TIMEOUT
                 );
             }
+        }
 
-            let Some(format) = gst_video_format_to_drm_fourcc(video_info.format()) else {
-                params.destroy();
-                return Err(gstreamer::FlowError::Error);
-            };
-            let wl_buffer = params.send_constructor::<wayland_client::protocol::wl_buffer::WlBuffer>(
-                wayland_protocols::wp::linux_dmabuf::zv1::client::zwp_linux_buffer_params_v1::Request::CreateImmed { 
-                    width: video_info.width() as i32,
-                    height: video_info.height() as i32,
-                    format: format as u32,
-                    flags: WEnum::Value(wayland_protocols::wp::linux_dmabuf::zv1::client::zwp_linux_buffer_params_v1::Flags::empty())
-                }, 
-                self.dummy_object_data.clone()).expect("failed to create buffer");
-            params.destroy();
+        Ok(())
+    }
 
-            let buffer_mut = buffer.make_mut();
-            super::meta::WaylandBufferMeta::add(buffer_mut, wl_buffer);
-            if state.add_video_meta {
-                gstreamer_video::VideoMeta::add_full(
-                    buffer_mut,
-                    gstreamer_video::VideoFrameFlags::empty(),
-                    video_info.format(),
-                    video_info.width(),
-                    video_info.height(),
-                    video_info.offset(),
-                    video_info.stride(),
-                ).map_err(|err| {
-                    gstreamer::warning!(CAT, imp: self, "failed to add video meta: {:?}", err);
-                    gstreamer::FlowError::Error
-                })?;
+    /// Acquire a buffer, waiting at most `timeout` for one to be returned once all
+    /// are in use. Fails with [`gstreamer::FlowError::Eos`] like `DONTWAIT` then.
+    fn acquire_within(
+        &self,
+        params: Option<&gstreamer::BufferPoolAcquireParams>,
+        timeout: Duration,
+    ) -> Result<gstreamer::Buffer, gstreamer::FlowError> {
+        let flags = params
+            .map(|params| params.flags())
+            .unwrap_or_else(gstreamer::BufferPoolAcquireFlags::empty);
+        let dont_wait_params = gstreamer::BufferPoolAcquireParams::with_flags(
+            flags | gstreamer::BufferPoolAcquireFlags::DONTWAIT,
+        );
+        let deadline = Instant::now() + timeout;
+        loop {
+            if self.flushing.load(Ordering::SeqCst) {
+                return Err(gstreamer::FlowError::Flushing);
+            }
+            // Read before trying so a buffer returned in between is not missed
+            let returned = *self.returned.lock().unwrap();
+            match self.parent_acquire_buffer(Some(&dont_wait_params)) {
+                Err(gstreamer::FlowError::Eos) => (),
+                result => return result,
             }
-            buffer_mut.unset_flags(gstreamer::BufferFlags::TAG_MEMORY);
 
-            return Ok(buffer);
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                gstreamer::debug!(CAT, imp: self, "no buffer returned within {:?}", timeout);
+                return Err(gstreamer::FlowError::Eos);
+            }
+            let guard = self.returned.lock().unwrap();
+            let _ = self
+                .returned_cond
+                .wait_timeout_while(guard, remaining, |count| {
+                    *count == returned && !self.flushing.load(Ordering::SeqCst)
+                })
+                .unwrap();
         }
+    }
 
-        if let Some(fd_memory) = mem.downcast_memory_ref::<gstreamer_allocators::FdMemory>() {
-            let wl_shm = state.wl_shm.as_ref().unwrap();
-            let pool = wl_shm
-                .send_constructor::<wayland_client::protocol::wl_shm_pool::WlShmPool>(
-                    wayland_client::protocol::wl_shm::Request::CreatePool {
-                        fd: fd_memory.fd(),
-                        size: buffer.size() as i32,
-                    },
-                    self.dummy_object_data.clone(),
-                )
-                .expect("failed to create pool");
+    /// Read events from the connection until `done` returns `true` or `deadline` passed.
+    ///
+    /// Returns `Ok(false)` on timeout. An `interruptible` wait is aborted with
+    /// [`gstreamer::FlowError::Flushing`] once the pool starts flushing.
+    fn dispatch_until(
+        &self,
+        connection: &wayland_client::Connection,
+        deadline: Instant,
+        interruptible: bool,
+        done: impl Fn() -> bool,
+    ) -> Result<bool, gstreamer::FlowError> {
+        while !done() {
+            if interruptible && self.flushing.load(Ordering::SeqCst) {
+                return Err(gstreamer::FlowError::Flushing);
+            }
 
-            let Some(format) = gst_video_format_to_wl_shm(video_info.format()) else {
-                pool.destroy();
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(false);
+            }
+
+            if let Err(err) = connection.flush() {
+                gstreamer::warning!(CAT, imp: self, "failed to flush connection: {}", err);
+                return Err(gstreamer::FlowError::Error);
+            }
+            let guard = match connection.prepare_read() {
+                Ok(guard) => guard,
+                Err(err) => {
+                    gstreamer::warning!(CAT, imp: self, "failed to prepare reading events: {}", err);
                     return Err(gstreamer::FlowError::Error);
+                }
             };
 
-            let wl_buffer = pool
-                .send_constructor::<wayland_client::protocol::wl_buffer::WlBuffer>(
-                    wayland_client::protocol::wl_shm_pool::Request::CreateBuffer {
-                        offset: 0,
-                        width: video_info.width() as i32,
-                        height: video_info.height() as i32,
-                        stride: video_info.stride()[0],
-                        format: wayland_client::WEnum::Value(format),
-                    },
-                    self.dummy_object_data.clone(),
-                )
-                .expect("failed to create buffer");
-            pool.destroy();
-            
-            let buffer_mut = buffer.make_mut();
-            super::meta::WaylandBufferMeta::add(buffer_mut, wl_buffer);
-            if state.add_video_meta {
-                gstreamer_video::VideoMeta::add_full(
-                    buffer_mut,
-                    gstreamer_video::VideoFrameFlags::empty(),
-                    video_info.format(),
-                    video_info.width(),
-                    video_info.height(),
-                    video_info.offset(),
-                    video_info.stride(),
-                ).map_err(|err| {
-                    gstreamer::warning!(CAT, imp: self, "failed to add video meta: {:?}", err);
-                    gstreamer::FlowError::Error
-                })?;
+            // Wake up regularly to notice flushing
+            let timeout = std::cmp::min(remaining, FLUSH_POLL_INTERVAL);
+            let fd: RawFd = guard.connection_fd().as_raw_fd();
+            let mut fds = [nix::poll::PollFd::new(fd, nix::poll::PollFlags::POLLIN)];
+            match nix::poll::poll(&mut fds, timeout.as_millis() as i32) {
+                Ok(0) | Err(nix::errno::Errno::EINTR) => continue,
+                Ok(_) => {
+                    if let Err(err) = guard.read() {
+                        gstreamer::warning!(CAT, imp: self, "failed to read events: {}", err);
+                        return Err(gstreamer::FlowError::Error);
+                    }
+                }
+                Err(err) => {
+                    gstreamer::warning!(CAT, imp: self, "failed to poll connection: {}", err);
+                    return Err(gstreamer::FlowError::Error);
+                }
             }
-            buffer_mut.unset_flags(gstreamer::BufferFlags::TAG_MEMORY);
+        }
+
+        Ok(true)
+    }
+}
+
+#[glib::object_subclass]
+impl ObjectSubclass for WaylandBufferPool {
+    const NAME: &'static str = "WaylandBufferPool";
+    type Type = super::WaylandBufferPool;
+    type ParentType = gstreamer::BufferPool;
+    type Interfaces = ();
+}
+
+impl ObjectImpl for WaylandBufferPool {
+    fn properties() -> &'static [glib::ParamSpec] {
+        static PROPERTIES: Lazy<Vec<glib::ParamSpec>> = Lazy::new(|| {
+            vec![
+                glib::ParamSpecBoxed::builder::<gstreamer::Structure>("stats")
+                    .nick("Statistics")
+                    .blurb("Buffer lifecycle counters of the pool")
+                    .read_only()
+                    .build(),
+            ]
+        });
+
+        PROPERTIES.as_ref()
+    }
+
+    fn property(&self, _id: usize, pspec: &glib::ParamSpec) -> glib::Value {
+        match pspec.name() {
+            "stats" => {
+                let counters = &self.release_tracker.counters;
+                let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+                gstreamer::Structure::builder("waylandbufferpool-stats")
+                    .field("acquired", get(&counters.acquired))
+                    .field("released", get(&counters.released))
+                    .field("copies", get(&counters.copies))
+                    .field("compositor-releases", get(&counters.compositor_releases))
+                    .field("release-timeouts", get(&counters.release_timeouts))
+                    .field("busy", self.release_tracker.busy_count() as u64)
+                    .field("wl-buffers-created", get(&counters.wl_buffers_created))
+                    .field("wl-buffers-destroyed", get(&counters.wl_buffers_destroyed))
+                    .build()
+                    .to_value()
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl GstObjectImpl for WaylandBufferPool {}
+
+impl BufferPoolImpl for WaylandBufferPool {
+    fn options() -> &'static [&'static str] {
+        static OPTIONS: Lazy<Vec<&'static str>> = Lazy::new(|| {
+            vec![
+                &*gstreamer_video::BUFFER_POOL_OPTION_VIDEO_META,
+                &*gstreamer_video::BUFFER_POOL_OPTION_VIDEO_ALIGNMENT,
+            ]
+        });
+
+        OPTIONS.as_ref()
+    }
+
+    fn acquire_buffer(
+        &self,
+        params: Option<&gstreamer::BufferPoolAcquireParams>,
+    ) -> Result<gstreamer::Buffer, gstreamer::FlowError> {
+        let dont_wait = params
+            .map(|params| {
+                params
+                    .flags()
+                    .contains(gstreamer::BufferPoolAcquireFlags::DONTWAIT)
+            })
+            .unwrap_or(false);
+        let acquire_timeout = self.state.lock().unwrap().acquire_timeout;
+        // Blocks until a buffer is returned once max-buffers are in use, or
+        // fails with EOS for DONTWAIT
+        let buffer = match acquire_timeout {
+            Some(timeout) if !dont_wait => self.acquire_within(params, timeout)?,
+            _ => self.parent_acquire_buffer(params)?,
+        };
+
+        let Some(wayland_buffer_meta) = buffer.meta::<super::meta::WaylandBufferMeta>() else {
             return Ok(buffer);
+        };
+
+        if dont_wait
+            && self
+                .release_tracker
+                .is_busy(&wayland_buffer_meta.wl_buffer().id())
+        {
+            self.parent_release_buffer(buffer);
+            return Err(gstreamer::FlowError::Eos);
         }
 
-        Err(gstreamer::FlowError::Error)
+        // Re-using a buffer the compositor still reads from or writes to
+        // would corrupt the frame
+        let wait = self.wait_for_release(wayland_buffer_meta.wl_buffer());
+        if let Err(err) = wait {
+            self.parent_release_buffer(buffer);
+            return Err(err);
+        }
+
+        Counters::inc(&self.release_tracker.counters.acquired);
+        {
+            let mut trim_window = self.trim_window.lock().unwrap();
+            trim_window.peak = std::cmp::max(trim_window.peak, self.outstanding());
+        }
+        gstreamer::trace!(
+            CAT,
+            imp: self,
+            "acquired buffer {:?} with {}",
+            buffer.as_ptr(),
+            wayland_buffer_meta.wl_buffer().id()
+        );
+
+        Ok(buffer)
+    }
+
+    fn release_buffer(&self, mut buffer: gstreamer::Buffer) {
+        Counters::inc(&self.release_tracker.counters.released);
+        let discarded = buffer
+            .meta::<super::meta::WaylandBufferMeta>()
+            .map(|meta| self.release_tracker.is_discarded(&meta.wl_buffer().id()))
+            .unwrap_or(false);
+        if discarded {
+            gstreamer::debug!(CAT, imp: self, "freeing discarded buffer {:?}", buffer.as_ptr());
+            buffer
+                .make_mut()
+                .set_flags(gstreamer::BufferFlags::TAG_MEMORY);
+        } else if self.should_trim() {
+            gstreamer::debug!(CAT, imp: self, "freeing idle buffer {:?}", buffer.as_ptr());
+            // The base class frees released buffers with tagged memory
+            buffer
+                .make_mut()
+                .set_flags(gstreamer::BufferFlags::TAG_MEMORY);
+        }
+        if let Some(meta) = buffer.meta::<super::meta::WaylandBufferMeta>() {
+            gstreamer::trace!(
+                CAT,
+                imp: self,
+                "released buffer {:?} with {}",
+                buffer.as_ptr(),
+                meta.wl_buffer().id()
+            );
+        }
+        self.parent_release_buffer(buffer);
+
+        *self.returned.lock().unwrap() += 1;
+        self.returned_cond.notify_all();
+    }
+
+    fn alloc_buffer(
+        &self,
+        params: Option<&gstreamer::BufferPoolAcquireParams>,
+    ) -> Result<gstreamer::Buffer, gstreamer::FlowError> {
+        let buffer = self.alloc_wayland_buffer(params)?;
+        self.allocated.fetch_add(1, Ordering::SeqCst);
+        Ok(buffer)
     }
 
     fn set_config(&self, config: &mut gstreamer::BufferPoolConfigRef) -> bool {
@@ -231,73 +944,711 @@ impl BufferPoolImpl for WaylandBufferPool {
                 return false;
             }
         };
-                
-        let (allocator, mut allocation_params) = if let Some((allocator, allocation_params)) = config.allocator() {
+
+        if let Some(shm_stride) = config.shm_stride() {
+            if video_info.n_planes() != 1 {
+                gstreamer::warning!(CAT, imp: self, "shm stride override is only supported for single plane formats");
+                return false;
+            }
+
+            video_info = match VideoInfo::builder(
+                video_info.format(),
+                video_info.width(),
+                video_info.height(),
+            )
+            .fps(video_info.fps())
+            .par(video_info.par())
+            .stride(&[shm_stride as i32])
+            .offset(&[0])
+            .build()
+            {
+                Ok(info) => info,
+                Err(err) => {
+                    gstreamer::warning!(CAT, imp: self, "failed to apply shm stride: {}", err);
+                    return false;
+                }
+            };
+        }
+
+        let (allocator, mut allocation_params) = if let Some((allocator, allocation_params)) =
+            config.allocator()
+        {
             let allocator = allocator.unwrap_or_else(|| MemfdMemoryAllocator::default().upcast());
             (allocator, Some(allocation_params))
         } else {
             (MemfdMemoryAllocator::default().upcast(), None)
         };
 
+        let is_dmabuf_allocator = allocator.is::<gstreamer_allocators::DmaBufAllocator>();
+        match config.memory_type() {
+            WaylandMemoryType::Dmabuf if !is_dmabuf_allocator => {
+                gstreamer::warning!(CAT, imp: self, "dmabuf memory requires a dmabuf allocator, got {}", allocator.type_().name());
+                return false;
+            }
+            WaylandMemoryType::Shm if is_dmabuf_allocator => {
+                gstreamer::warning!(CAT, imp: self, "shm memory can not be allocated from dmabuf allocator {}", allocator.type_().name());
+                return false;
+            }
+            _ => (),
+        }
+        if is_dmabuf_allocator && self.state.lock().unwrap().zwp_linux_dmabuf.is_none() {
+            gstreamer::warning!(CAT, imp: self, "dmabuf allocator configured, but the pool has no zwp_linux_dmabuf_v1");
+            return false;
+        }
+
+        let memory_pool = config.memory_pool();
+        if memory_pool.is_some() && config.memory_type() == WaylandMemoryType::Shm {
+            gstreamer::warning!(CAT, imp: self, "a memory pool only provides dmabuf memory");
+            return false;
+        }
+        if memory_pool.is_some() && self.state.lock().unwrap().zwp_linux_dmabuf.is_none() {
+            gstreamer::warning!(CAT, imp: self, "memory pool configured, but the pool has no zwp_linux_dmabuf_v1");
+            return false;
+        }
+
+        let modifiers = config.dmabuf_modifiers();
+        let memory_per_plane = config.memory_per_plane();
+
         let mut guard = self.state.lock().unwrap();
-        guard.add_video_meta = config.has_option(gstreamer_video::BUFFER_POOL_OPTION_VIDEO_META.as_ref());
-        let need_alignment = config.has_option(gstreamer_video::BUFFER_POOL_OPTION_VIDEO_ALIGNMENT.as_ref());
+        guard.modifiers = modifiers;
+        guard.dmabuf_modifier = None;
+        guard.memory_per_plane = memory_per_plane;
+        guard.add_video_meta =
+            config.has_option(gstreamer_video::BUFFER_POOL_OPTION_VIDEO_META.as_ref());
+        let need_alignment =
+            config.has_option(gstreamer_video::BUFFER_POOL_OPTION_VIDEO_ALIGNMENT.as_ref());
+        guard.plane_padding = vec![0; video_info.n_planes() as usize];
 
         if need_alignment && guard.add_video_meta {
             let video_align = config.video_alignment();
 
-            if let Some(video_align) = video_align {
-                let align = allocation_params.as_ref().map(|params| params.align()).unwrap_or_default();
-                let mut max_align = align;
-
-                for plane in 0..video_info.n_planes() {
-                    max_align |= unsafe { *video_align.stride_align().get_unchecked(plane as usize) as usize };
-                }
-
-                let mut stride_align: [u32; gstreamer_video::ffi::GST_VIDEO_MAX_PLANES as usize] = [0; gstreamer_video::ffi::GST_VIDEO_MAX_PLANES as usize];
-                for plane in 0..video_info.n_planes() {
-                    stride_align[plane as usize] = max_align as u32;
-                }
+            if let Some(mut video_align) = video_align {
+                // Every plane keeps the stride alignment it asked for, only the
+                // memory has to satisfy the strictest of them
+                let stride_align = *video_align.stride_align();
+                let align = allocation_params
+                    .as_ref()
+                    .map(|params| params.align())
+                    .unwrap_or_default();
+                let max_align = stride_align[..video_info.n_planes() as usize]
+                    .iter()
+                    .fold(align, |max_align, stride_align| {
+                        max_align | *stride_align as usize
+                    });
 
-                let mut video_align = gstreamer_video::VideoAlignment::new(video_align.padding_top(), video_align.padding_bottom(), video_align.padding_left(), video_align.padding_right(), &stride_align);
                 if let Err(err) = video_info.align(&mut video_align) {
                     gstreamer::warning!(CAT, imp: self, "failed to align video info: {}", err);
                     return false;
                 }
+                for (plane, (stride, stride_align)) in
+                    video_info.stride().iter().zip(&stride_align).enumerate()
+                {
+                    if *stride as u32 & stride_align != 0 {
+                        gstreamer::warning!(CAT, imp: self, "stride {} of plane {} does not match alignment {:#x}", stride, plane, stride_align);
+                        return false;
+                    }
+                }
 
                 config.set_video_alignment(&video_align);
+                // The aligned offsets point past the padding, the video meta
+                // describes the visible region without a crop
+                guard.plane_padding = plane_padding(&video_info, &video_align);
 
                 if align < max_align {
                     gstreamer::warning!(CAT, imp: self, "allocation params alignment {} is smaller than the max specified video stride alignment {}, fixing", align, max_align);
-                    allocation_params = allocation_params.as_ref().map(|params| gstreamer::AllocationParams::new(params.flags(), max_align, params.prefix(), params.padding()));
+                    allocation_params = allocation_params.as_ref().map(|params| {
+                        gstreamer::AllocationParams::new(
+                            params.flags(),
+                            max_align,
+                            params.prefix(),
+                            params.padding(),
+                        )
+                    });
                     config.set_allocator(Some(&allocator), allocation_params.as_ref());
                 }
             }
         }
 
-        let size = std::cmp::max(size, video_info.size() as u32);
+        let size = std::cmp::max(size, video_size(&video_info) as u32);
         guard.video_info = Some(video_info);
+        guard.size = size as usize;
 
-        config.set_params(
-            Some(&caps),
-            size,
-            min_buffers,
-            max_buffers,
-        );
+        // An unlimited pool would grow without bounds while the compositor or
+        // downstream holds on to buffers
+        let max_buffers = if max_buffers == 0 {
+            let max_buffers = std::cmp::max(min_buffers, DEFAULT_MAX_BUFFERS);
+            gstreamer::debug!(CAT, imp: self, "limiting pool to {} buffers", max_buffers);
+            max_buffers
+        } else {
+            max_buffers
+        };
+
+        config.set_params(Some(&caps), size, min_buffers, max_buffers);
 
         guard.allocator = Some(allocator);
         guard.allocation_params = Some(allocation_params);
+        guard.min_buffers = min_buffers;
+        guard.trim_timeout = config.trim_timeout();
+        guard.acquire_timeout = config.acquire_timeout();
+        guard.memory_pool = memory_pool;
 
         self.parent_set_config(config)
     }
 
     fn free_buffer(&self, buffer: gstreamer::Buffer) {
-        if let Some(wayland_buffer_meta) = buffer.meta::<super::meta::WaylandBufferMeta>() {
-            wayland_buffer_meta.wl_buffer().destroy();
+        self.allocated.fetch_sub(1, Ordering::SeqCst);
+        self.parent_free_buffer(buffer)
+    }
+
+    fn start(&self) -> bool {
+        let memory_pool = self.state.lock().unwrap().memory_pool.clone();
+        if let Some(memory_pool) = memory_pool {
+            if let Err(err) = memory_pool.set_active(true) {
+                gstreamer::warning!(CAT, imp: self, "failed to activate memory pool: {}", err);
+                return false;
+            }
+        }
+
+        // The default implementation preallocates the configured minimum number
+        // of buffers through alloc_buffer, including their wl_buffer objects
+        if !self.parent_start() {
+            gstreamer::warning!(CAT, imp: self, "failed to preallocate buffers");
+            return false;
+        }
+
+        let min_buffers = self
+            .obj()
+            .config()
+            .params()
+            .map(|(_, _, min_buffers, _)| min_buffers)
+            .unwrap_or(0);
+        gstreamer::debug!(CAT, imp: self, "preallocated {} buffers", min_buffers);
+
+        // Send the buffer creation requests now instead of with the first copy
+        self.flush_connection()
+    }
+
+    fn flush_start(&self) {
+        // Wake up acquires waiting for the compositor to release a buffer
+        self.flushing.store(true, Ordering::SeqCst);
+        self.returned_cond.notify_all();
+        self.parent_flush_start();
+    }
+
+    fn flush_stop(&self) {
+        self.flushing.store(false, Ordering::SeqCst);
+        self.parent_flush_stop();
+    }
+
+    fn stop(&self) -> bool {
+        // Buffers the compositor still copies into must not be destroyed under it
+        let busy = self.release_tracker.busy_count();
+        if busy > 0 {
+            if let Some(connection) = self.connection() {
+                gstreamer::debug!(CAT, imp: self, "waiting for {} buffers in use by the compositor", busy);
+                let released = self
+                    .dispatch_until(&connection, Instant::now() + RELEASE_TIMEOUT, false, || {
+                        self.release_tracker.busy_count() == 0
+                    })
+                    .unwrap_or(false);
+                if !released {
+                    gstreamer::warning!(
+                        CAT,
+                        imp: self,
+                        "{} buffers still in use by the compositor",
+                        self.release_tracker.busy_count()
+                    );
+                }
+            }
+        }
+
+        if !self.parent_stop() {
+            return false;
+        }
+
+        // Freeing our buffers returned theirs to the memory pool
+        let memory_pool = self.state.lock().unwrap().memory_pool.clone();
+        if let Some(memory_pool) = memory_pool {
+            let _ = memory_pool.set_active(false);
+        }
+
+        // Buffers still alive downstream keep the arena until the next configuration
+        let mut shm_arena = self.shm_arena.lock().unwrap();
+        if shm_arena
+            .as_ref()
+            .map(|arena| arena.slots.is_empty())
+            .unwrap_or(false)
+        {
+            shm_arena.take().unwrap().destroy();
+        }
+        drop(shm_arena);
+
+        // Make sure the destruction of the wl_buffers reaches the compositor
+        self.flush_connection()
+    }
+
+    fn reset_buffer(&self, buffer: &mut gstreamer::BufferRef) {
+        // Clears the timestamps, offsets and flags except TAG_MEMORY and removes every
+        // meta added after the allocation, like the damage and frame metas of the source
+        self.parent_reset_buffer(buffer);
+
+        // Buffers with replaced memory are freed on release
+        if buffer.flags().contains(gstreamer::BufferFlags::TAG_MEMORY) || buffer.n_memory() == 0 {
+            return;
+        }
+
+        // Downstream may have removed the metas of the allocation, the wl_buffer and
+        // its layout are still bound to the memory
+        let Some((wl_buffer, layout)) = memory_wl_buffer_layout(buffer.peek_memory(0)) else {
+            return;
+        };
+        if buffer.meta::<super::meta::WaylandBufferMeta>().is_none() {
+            add_pooled_meta(buffer, wl_buffer);
+        }
+        let state = self.state.lock().unwrap();
+        let Some(video_info) = state.video_info.as_ref() else {
+            return;
+        };
+        let is_dmabuf = buffer
+            .peek_memory(0)
+            .downcast_memory_ref::<gstreamer_allocators::DmaBufMemory>()
+            .is_some();
+        if is_dmabuf && buffer.meta::<super::meta::DmabufLayoutMeta>().is_none() {
+            add_dmabuf_layout_meta(buffer, video_info, &layout);
+        }
+        if buffer.meta::<gstreamer_video::VideoMeta>().is_none() {
+            let _ = self.add_video_meta(&state, buffer, video_info, &layout);
         }
     }
 }
 
+impl WaylandBufferPool {
+    /// Add the video meta describing `layout` if downstream asked for it
+    fn add_video_meta(
+        &self,
+        state: &State,
+        buffer: &mut gstreamer::BufferRef,
+        video_info: &VideoInfo,
+        layout: &PlaneLayout,
+    ) -> Result<(), gstreamer::FlowError> {
+        if !state.add_video_meta {
+            return Ok(());
+        }
+        let mut meta = gstreamer_video::VideoMeta::add_full(
+            buffer,
+            gstreamer_video::VideoFrameFlags::empty(),
+            video_info.format(),
+            video_info.width(),
+            video_info.height(),
+            &layout.offsets,
+            &layout.strides,
+        )
+        .map_err(|err| {
+            gstreamer::warning!(CAT, imp: self, "failed to add video meta: {:?}", err);
+            gstreamer::FlowError::Error
+        })?;
+        mark_pooled(&mut meta);
+        Ok(())
+    }
+
+    /// Allocate a buffer with a wl_buffer attached, retrying with the remaining
+    /// modifiers if the compositor rejects a dmabuf.
+    fn alloc_wayland_buffer(
+        &self,
+        params: Option<&gstreamer::BufferPoolAcquireParams>,
+    ) -> Result<gstreamer::Buffer, gstreamer::FlowError> {
+        let mut state = self.state.lock().unwrap();
+        let video_info = state.video_info.clone().unwrap();
+        let video_info = &video_info;
+        let allocator = state.allocator.clone().unwrap();
+
+        let mut shm_slot = None;
+        let mut layout = PlaneLayout {
+            modifier: DRM_FORMAT_MOD_LINEAR,
+            offsets: video_info.offset().to_vec(),
+            strides: video_info.stride().to_vec(),
+        };
+        let mut buffer = if let Some(memory_pool) = state.memory_pool.clone() {
+            self.alloc_pool_buffer(&memory_pool, params, &mut layout)?
+        } else if let Some(buffer) = self.alloc_gbm_buffer(&state, &mut layout)? {
+            buffer
+        } else if state.memory_per_plane
+            && video_info.n_planes() > 1
+            && allocator
+                .downcast_ref::<gstreamer_allocators::DmaBufAllocator>()
+                .is_some()
+        {
+            let allocation_params = state.allocation_params.clone().flatten();
+            let mut buffer = gstreamer::Buffer::new();
+            let buffer_mut = buffer.make_mut();
+            for plane in 0..video_info.n_planes() {
+                let mem = allocator
+                    .alloc(plane_size(video_info, &state.plane_padding, plane), allocation_params.as_ref())
+                    .map_err(|err| {
+                        gstreamer::warning!(CAT, imp: self, "failed to allocate plane {}: {}", plane, err);
+                        gstreamer::FlowError::Error
+                    })?;
+                buffer_mut.append_memory(mem);
+            }
+            buffer
+        } else if let Some(memfd_allocator) = allocator.downcast_ref::<MemfdMemoryAllocator>() {
+            let (memory, wl_shm_pool, offset) =
+                self.alloc_shm_slot(state.wl_shm.as_ref().unwrap(), memfd_allocator, state.size)?;
+            shm_slot = Some((wl_shm_pool, offset));
+
+            let mut buffer = gstreamer::Buffer::new();
+            buffer.make_mut().append_memory(memory);
+            buffer
+        } else {
+            self.parent_alloc_buffer(params)?
+        };
+
+        let mem = buffer.memory(0).unwrap();
+
+        // Memories that already carry a wl_buffer only need a new meta
+        if let Some(wl_buffer) = memory_wl_buffer(&mem) {
+            gstreamer::trace!(CAT, imp: self, "reusing {}", wl_buffer.id());
+            let is_dmabuf = mem
+                .downcast_memory_ref::<gstreamer_allocators::DmaBufMemory>()
+                .is_some();
+            if is_dmabuf {
+                state.dmabuf_modifier = Some(layout.modifier);
+            }
+            let buffer_mut = buffer.make_mut();
+            add_pooled_meta(buffer_mut, wl_buffer);
+            if is_dmabuf {
+                add_dmabuf_layout_meta(buffer_mut, video_info, &layout);
+            }
+            self.add_video_meta(&state, buffer_mut, video_info, &layout)?;
+            buffer_mut.unset_flags(gstreamer::BufferFlags::TAG_MEMORY);
+            return Ok(buffer);
+        }
+
+        if mem
+            .downcast_memory_ref::<gstreamer_allocators::DmaBufMemory>()
+            .is_some()
+        {
+            let zwp_linux_dmabuf = state.zwp_linux_dmabuf.as_ref().unwrap();
+
+            let params_data = BufferParamsData::new(self.wl_buffer_data.clone());
+            let dmabuf_params = zwp_linux_dmabuf.send_constructor::<wayland_protocols::wp::linux_dmabuf::zv1::client::zwp_linux_buffer_params_v1::ZwpLinuxBufferParamsV1>(wayland_protocols::wp::linux_dmabuf::zv1::client::zwp_linux_dmabuf_v1::Request::CreateParams {  }, params_data.clone()).expect("failed to create params");
+            protocol_log::request(
+                zwp_linux_dmabuf,
+                format_args!("create_params() -> {}", dmabuf_params.id()),
+            );
+
+            for plane in 0..video_info.n_planes() {
+                let offset = layout.offsets[plane as usize];
+                let stride = layout.strides[plane as usize];
+
+                let (mem_idx, _, skip) = buffer
+                    .find_memory(offset, Some(1))
+                    .expect("memory does not seem to contain enough data for the specified format");
+                let mem = buffer
+                    .peek_memory(mem_idx)
+                    .downcast_memory_ref::<gstreamer_allocators::DmaBufMemory>()
+                    .unwrap();
+                let modifier = layout.modifier;
+                protocol_log::request(
+                    &dmabuf_params,
+                    format_args!(
+                        "add(fd {}, {}, {}, {}, {:#x})",
+                        mem.fd(),
+                        plane,
+                        mem.offset() + skip,
+                        stride,
+                        modifier
+                    ),
+                );
+                dmabuf_params.add(
+                    mem.fd(),
+                    plane,
+                    (mem.offset() + skip) as u32,
+                    stride as u32,
+                    (modifier >> 32) as u32,
+                    (modifier & 0xffff_ffff) as u32,
+                );
+            }
+
+            let Some(format) = gst_video_format_to_drm_fourcc_code(video_info.format()) else {
+                dmabuf_params.destroy();
+                return Err(gstreamer::FlowError::Error);
+            };
+            let wl_buffer = self.import_dmabuf(&dmabuf_params, &params_data, video_info, format);
+            dmabuf_params.destroy();
+            let wl_buffer = match wl_buffer? {
+                Some(wl_buffer) => wl_buffer,
+                None => {
+                    let modifier = layout.modifier;
+                    gstreamer::warning!(CAT, imp: self, "compositor rejected dmabuf with modifier {:#x}", modifier);
+                    // Try the remaining modifiers before giving up on dmabuf
+                    state.modifiers.retain(|m| *m != modifier);
+                    if modifier != DRM_FORMAT_MOD_LINEAR && !state.modifiers.is_empty() {
+                        drop(buffer);
+                        drop(state);
+                        return self.alloc_wayland_buffer(params);
+                    }
+                    self.dmabuf_rejected.store(true, Ordering::SeqCst);
+                    return Err(gstreamer::FlowError::NotSupported);
+                }
+            };
+            self.bind_wl_buffer(&mem, &wl_buffer, &layout);
+            state.dmabuf_modifier = Some(layout.modifier);
+
+            let buffer_mut = buffer.make_mut();
+            super::meta::WaylandBufferMeta::add(buffer_mut, wl_buffer);
+            add_dmabuf_layout_meta(buffer_mut, video_info, &layout);
+            self.add_video_meta(&state, buffer_mut, video_info, &layout)?;
+            buffer_mut.unset_flags(gstreamer::BufferFlags::TAG_MEMORY);
+
+            return Ok(buffer);
+        }
+
+        if let Some(fd_memory) = mem.downcast_memory_ref::<gstreamer_allocators::FdMemory>() {
+            let Some(format) = gst_video_format_to_wl_shm(video_info.format()) else {
+                return Err(gstreamer::FlowError::Error);
+            };
+
+            // Buffers from other fd allocators get a pool of their own
+            let (pool, offset, owned_pool) = match shm_slot {
+                Some((pool, offset)) => (pool, offset, false),
+                None => {
+                    let wl_shm = state.wl_shm.as_ref().unwrap();
+                    let pool = wl_shm
+                        .send_constructor::<wayland_client::protocol::wl_shm_pool::WlShmPool>(
+                            wayland_client::protocol::wl_shm::Request::CreatePool {
+                                fd: fd_memory.fd(),
+                                size: buffer.size() as i32,
+                            },
+                            self.dummy_object_data.clone(),
+                        )
+                        .map_err(|err| {
+                            gstreamer::warning!(CAT, imp: self, "failed to create shm pool: {}", err);
+                            gstreamer::FlowError::Error
+                        })?;
+                    protocol_log::request(
+                        wl_shm,
+                        format_args!(
+                            "create_pool(fd {}, {}) -> {}",
+                            fd_memory.fd(),
+                            buffer.size(),
+                            pool.id()
+                        ),
+                    );
+                    (pool, 0, true)
+                }
+            };
+
+            let wl_buffer = pool
+                .send_constructor::<wayland_client::protocol::wl_buffer::WlBuffer>(
+                    wayland_client::protocol::wl_shm_pool::Request::CreateBuffer {
+                        offset: offset as i32,
+                        width: video_info.width() as i32,
+                        height: video_info.height() as i32,
+                        stride: video_info.stride()[0],
+                        format: wayland_client::WEnum::Value(format),
+                    },
+                    self.wl_buffer_data.clone(),
+                )
+                .expect("failed to create buffer");
+            protocol_log::request(
+                &pool,
+                format_args!(
+                    "create_buffer({}, {}, {}, {}, {:?}) -> {}",
+                    offset,
+                    video_info.width(),
+                    video_info.height(),
+                    video_info.stride()[0],
+                    format,
+                    wl_buffer.id()
+                ),
+            );
+            if owned_pool {
+                pool.destroy();
+            } else if let Some(arena) = self.shm_arena.lock().unwrap().as_mut() {
+                arena.slots.insert(wl_buffer.id(), offset);
+            }
+            self.bind_wl_buffer(&mem, &wl_buffer, &layout);
+
+            let buffer_mut = buffer.make_mut();
+            super::meta::WaylandBufferMeta::add(buffer_mut, wl_buffer);
+            self.add_video_meta(&state, buffer_mut, video_info, &layout)?;
+            buffer_mut.unset_flags(gstreamer::BufferFlags::TAG_MEMORY);
+            return Ok(buffer);
+        }
+
+        Err(gstreamer::FlowError::Error)
+    }
+}
+
+impl WaylandBufferPool {
+    /// Take the memories of a buffer from the memory pool, the buffer is kept as
+    /// parent so `memory_pool` does not hand it out again while it is ours.
+    fn alloc_pool_buffer(
+        &self,
+        memory_pool: &gstreamer::BufferPool,
+        params: Option<&gstreamer::BufferPoolAcquireParams>,
+        layout: &mut PlaneLayout,
+    ) -> Result<gstreamer::Buffer, gstreamer::FlowError> {
+        let pool_buffer = memory_pool.acquire_buffer(params)?;
+        let is_dmabuf = pool_buffer.iter_memories().all(|memory| {
+            memory
+                .downcast_memory_ref::<gstreamer_allocators::DmaBufMemory>()
+                .is_some()
+        });
+        if !is_dmabuf {
+            gstreamer::warning!(CAT, imp: self, "memory pool does not provide dmabufs");
+            return Err(gstreamer::FlowError::NotSupported);
+        }
+        if let Some(video_meta) = pool_buffer.meta::<gstreamer_video::VideoMeta>() {
+            let n_planes = video_meta.n_planes() as usize;
+            layout.offsets = video_meta.offset()[..n_planes].to_vec();
+            layout.strides = video_meta.stride()[..n_planes].to_vec();
+        }
+        gstreamer::trace!(CAT, imp: self, "importing buffer {:?} of the memory pool", pool_buffer.as_ptr());
+
+        let mut buffer = gstreamer::Buffer::new();
+        let buffer_mut = buffer.make_mut();
+        for memory in pool_buffer.iter_memories_owned() {
+            buffer_mut.append_memory(memory);
+        }
+        let mut meta = gstreamer::ParentBufferMeta::add(buffer_mut, &pool_buffer);
+        unsafe {
+            (*(meta.as_mut_ptr() as *mut gstreamer::ffi::GstMeta)).flags |=
+                gstreamer::ffi::GST_META_FLAG_POOLED;
+        }
+        Ok(buffer)
+    }
+}
+
+/// Layout of the planes of a dmabuf buffer
+#[derive(Debug, Clone)]
+struct PlaneLayout {
+    modifier: u64,
+    offsets: Vec<usize>,
+    strides: Vec<i32>,
+}
+
+impl WaylandBufferPool {
+    /// Allocate a buffer from the gbm allocator, returns `None` if the pool uses a different allocator.
+    #[cfg(feature = "gbm")]
+    fn alloc_gbm_buffer(
+        &self,
+        state: &State,
+        layout: &mut PlaneLayout,
+    ) -> Result<Option<gstreamer::Buffer>, gstreamer::FlowError> {
+        let video_info = state.video_info.as_ref().unwrap();
+        let Some(gbm_allocator) = state
+            .allocator
+            .as_ref()
+            .and_then(|allocator| allocator.downcast_ref::<GbmMemoryAllocator>())
+        else {
+            return Ok(None);
+        };
+
+        let modifiers = state
+            .modifiers
+            .iter()
+            .copied()
+            .map(gbm::Modifier::from)
+            .collect::<Vec<_>>();
+        let allocation = match gbm_allocator.alloc(video_info, &modifiers) {
+            Ok(allocation) => allocation,
+            Err(err) => {
+                gstreamer::warning!(CAT, imp: self, "failed to allocate bo: {}", err);
+                return Err(gstreamer::FlowError::Error);
+            }
+        };
+        layout.modifier = allocation.modifier.into();
+        layout.offsets = allocation.offsets;
+        layout.strides = allocation.strides;
+
+        let mut buffer = gstreamer::Buffer::new();
+        let buffer_mut = buffer.make_mut();
+        if state.memory_per_plane && layout.offsets.len() > 1 {
+            // All planes live in the same bo, split it into one shared memory per plane
+            let memory_size = allocation.memory.size();
+            // The memories have to cover the bo from the start, so buffer offsets
+            // still match the plane offsets
+            for (plane, offset) in layout.offsets.iter().enumerate() {
+                let start = if plane == 0 { 0 } else { *offset };
+                let end = layout
+                    .offsets
+                    .get(plane + 1)
+                    .copied()
+                    .unwrap_or(memory_size);
+                if end <= start {
+                    gstreamer::warning!(CAT, imp: self, "bo planes are not laid out in order");
+                    return Err(gstreamer::FlowError::Error);
+                }
+                buffer_mut
+                    .append_memory(allocation.memory.share(start as isize, Some(end - start)));
+            }
+        } else {
+            buffer_mut.insert_memory(None, allocation.memory);
+        }
+        Ok(Some(buffer))
+    }
+
+    #[cfg(not(feature = "gbm"))]
+    fn alloc_gbm_buffer(
+        &self,
+        _state: &State,
+        _layout: &mut PlaneLayout,
+    ) -> Result<Option<gstreamer::Buffer>, gstreamer::FlowError> {
+        Ok(None)
+    }
+}
+
+/// Size of a single plane, assuming the planes are laid out in order.
+fn plane_size(video_info: &VideoInfo, plane_padding: &[usize], plane: u32) -> usize {
+    // A plane starts with its padding, the offset points past it
+    let plane_start =
+        |plane: usize| video_info.offset()[plane] - plane_padding.get(plane).copied().unwrap_or(0);
+    let plane = plane as usize;
+    if plane + 1 < video_info.n_planes() as usize {
+        plane_start(plane + 1) - plane_start(plane)
+    } else {
+        video_size(video_info) - plane_start(plane)
+    }
+}
+
+/// Bytes between the start of each plane and its visible region, the top and
+/// left padding [`VideoInfo::align`] added to the offsets.
+fn plane_padding(
+    video_info: &VideoInfo,
+    video_align: &gstreamer_video::VideoAlignment,
+) -> Vec<usize> {
+    let format_info = video_info.format_info();
+    (0..video_info.n_planes() as usize)
+        .map(|plane| {
+            // The padding is scaled like the first component of the plane
+            let Some(component) = (0..format_info.n_components() as usize)
+                .find(|component| format_info.plane()[*component] as usize == plane)
+            else {
+                return 0;
+            };
+            let top = format_info.scale_height(component as u8, video_align.padding_top()) as usize;
+            let left = format_info.scale_width(component as u8, video_align.padding_left())
+                as usize
+                * format_info.pixel_stride()[component] as usize;
+            top * video_info.stride()[plane] as usize + left
+        })
+        .collect()
+}
+
+/// Size of a frame, which can be larger than [`VideoInfo::size`] if the stride of
+/// the video info has been overridden.
+fn video_size(video_info: &VideoInfo) -> usize {
+    let last_plane = video_info.n_planes() as usize - 1;
+    let last_plane_height = video_info
+        .format_info()
+        .scale_height(last_plane as u8, video_info.height()) as usize;
+    let last_plane_end = video_info.offset()[last_plane]
+        + video_info.stride()[last_plane] as usize * last_plane_height;
+    std::cmp::max(video_info.size(), last_plane_end)
+}
+
 #[derive(Debug)]
 struct DummyObjectData;
 
@@ -311,13 +1662,102 @@ impl ObjectData for DummyObjectData {
     fn event(
         self: Arc<Self>,
         _backend: &wayland_client::backend::Backend,
-        _msg: wayland_client::backend::protocol::Message<
+        msg: wayland_client::backend::protocol::Message<
             ObjectId,
             wayland_client::backend::io_lifetimes::OwnedFd,
         >,
     ) -> Option<Arc<dyn ObjectData>> {
+        protocol_log::message(&msg);
         None
     }
 
     fn destroyed(&self, _object_id: ObjectId) {}
 }
+
+/// Object data for zwp_linux_buffer_params_v1, records the result of a `create`
+#[derive(Debug)]
+struct BufferParamsData {
+    /// `Some(None)` after the import failed
+    result: Mutex<Option<Option<wayland_client::protocol::wl_buffer::WlBuffer>>>,
+    wl_buffer_data: Arc<WlBufferData>,
+}
+
+impl BufferParamsData {
+    fn new(wl_buffer_data: Arc<WlBufferData>) -> Arc<Self> {
+        Arc::new(BufferParamsData {
+            result: Mutex::new(None),
+            wl_buffer_data,
+        })
+    }
+}
+
+impl ObjectData for BufferParamsData {
+    fn event(
+        self: Arc<Self>,
+        backend: &wayland_client::backend::Backend,
+        msg: wayland_client::backend::protocol::Message<
+            ObjectId,
+            wayland_client::backend::io_lifetimes::OwnedFd,
+        >,
+    ) -> Option<Arc<dyn ObjectData>> {
+        use wayland_protocols::wp::linux_dmabuf::zv1::client::zwp_linux_buffer_params_v1;
+
+        protocol_log::message(&msg);
+
+        match (msg.opcode, msg.args.first()) {
+            (
+                zwp_linux_buffer_params_v1::EVT_CREATED_OPCODE,
+                Some(wayland_client::backend::protocol::Argument::NewId(id)),
+            ) => {
+                let connection = wayland_client::Connection::from_backend(backend.clone());
+                let wl_buffer =
+                    wayland_client::protocol::wl_buffer::WlBuffer::from_id(&connection, id.clone())
+                        .ok();
+                *self.result.lock().unwrap() = Some(wl_buffer);
+                // The wl_buffer is created by this event and needs object data
+                Some(self.wl_buffer_data.clone())
+            }
+            (zwp_linux_buffer_params_v1::EVT_FAILED_OPCODE, _) => {
+                *self.result.lock().unwrap() = Some(None);
+                None
+            }
+            _ => None,
+        }
+    }
+
+    fn destroyed(&self, _object_id: ObjectId) {}
+}
+
+/// Object data for wl_buffers, marks the buffer as released in the tracker
+#[derive(Debug)]
+struct WlBufferData {
+    release_tracker: Arc<ReleaseTracker>,
+}
+
+impl WlBufferData {
+    fn new(release_tracker: Arc<ReleaseTracker>) -> Arc<Self> {
+        Arc::new(WlBufferData { release_tracker })
+    }
+}
+
+impl ObjectData for WlBufferData {
+    fn event(
+        self: Arc<Self>,
+        _backend: &wayland_client::backend::Backend,
+        msg: wayland_client::backend::protocol::Message<
+            ObjectId,
+            wayland_client::backend::io_lifetimes::OwnedFd,
+        >,
+    ) -> Option<Arc<dyn ObjectData>> {
+        protocol_log::message(&msg);
+        // wl_buffer.release is the only event of wl_buffer
+        if msg.opcode == wayland_client::protocol::wl_buffer::EVT_RELEASE_OPCODE {
+            self.release_tracker.compositor_release(&msg.sender_id);
+        }
+        None
+    }
+
+    fn destroyed(&self, object_id: ObjectId) {
+        self.release_tracker.release(&object_id);
+    }
+}
@@ -18,11 +18,20 @@ pub struct WaylandBufferMeta {
     pub(super) wl_buffer: WlBuffer,
 }
 
+struct Tags([*const std::os::raw::c_char; 2]);
+unsafe impl Sync for Tags {}
+
+static MEMORY_TAGS: Tags = Tags([
+    gstreamer::ffi::GST_META_TAG_MEMORY_STR.as_ptr() as *const std::os::raw::c_char,
+    ptr::null(),
+]);
+
 pub(super) fn custom_meta_api_get_type() -> glib::Type {
     static TYPE: Lazy<glib::Type> = Lazy::new(|| unsafe {
         let t = from_glib(gstreamer::ffi::gst_meta_api_type_register(
             b"WaylandBufferMetaAPI\0".as_ptr() as *const _,
-            [ptr::null::<std::os::raw::c_char>()].as_ptr() as *mut *const _,
+            // Tagged as memory meta so transforms writing new memory drop it by default
+            MEMORY_TAGS.0.as_ptr() as *mut *const _,
         ));
 
         assert_ne!(t, glib::Type::INVALID);
@@ -61,27 +70,56 @@ unsafe extern "C" fn custom_meta_free(
 }
 
 // Transform function for our meta. This needs to get it from the old buffer to the new one
-// in a way that is compatible with the transformation type. In this case we just always
-// copy it over.
+// in a way that is compatible with the transformation type. The meta describes the memory
+// of the buffer, so only copies sharing all of it keep it. Deep or partial copies and
+// transforms like scaling or cropping into new memory no longer correspond to the
+// wl_buffer and drop it.
 unsafe extern "C" fn custom_meta_transform(
     dest: *mut gstreamer::ffi::GstBuffer,
     meta: *mut gstreamer::ffi::GstMeta,
-    _buffer: *mut gstreamer::ffi::GstBuffer,
-    _type_: glib::ffi::GQuark,
-    _data: glib::ffi::gpointer,
+    buffer: *mut gstreamer::ffi::GstBuffer,
+    type_: glib::ffi::GQuark,
+    data: glib::ffi::gpointer,
 ) -> glib::ffi::gboolean {
     let meta = &*(meta as *mut WaylandBufferMeta);
 
-    // We simply copy over our meta here. Other metas might have to look at the type
-    // and do things conditional on that, or even just drop the meta.
-    super::WaylandBufferMeta::add(
-        gstreamer::BufferRef::from_mut_ptr(dest),
-        meta.wl_buffer.clone(),
-    );
+    if is_shared_copy(dest, buffer, type_, data) {
+        super::WaylandBufferMeta::add(
+            gstreamer::BufferRef::from_mut_ptr(dest),
+            meta.wl_buffer.clone(),
+        );
+    }
 
     true.into_glib()
 }
 
+/// Whether a transform of `buffer` into `dest` is a full copy sharing all of its memory
+unsafe fn is_shared_copy(
+    dest: *mut gstreamer::ffi::GstBuffer,
+    buffer: *mut gstreamer::ffi::GstBuffer,
+    type_: glib::ffi::GQuark,
+    data: glib::ffi::gpointer,
+) -> bool {
+    let type_: glib::Quark = from_glib(type_);
+    if type_ != glib::Quark::from_str("gst-copy") {
+        return false;
+    }
+    let copy = &*(data as *const gstreamer::ffi::GstMetaTransformCopy);
+    if copy.region != glib::ffi::GFALSE {
+        return false;
+    }
+
+    // Memory is copied before the metas, deep copies and copies of unshareable
+    // memory end up with new memory
+    let dest = gstreamer::BufferRef::from_ptr(dest);
+    let buffer = gstreamer::BufferRef::from_ptr(buffer);
+    dest.n_memory() == buffer.n_memory()
+        && dest
+            .iter_memories()
+            .zip(buffer.iter_memories())
+            .all(|(dest_memory, memory)| dest_memory.as_ptr() == memory.as_ptr())
+}
+
 // Register the meta itself with its functions.
 pub(super) fn custom_meta_get_info() -> *const gstreamer::ffi::GstMetaInfo {
     struct MetaInfo(ptr::NonNull<gstreamer::ffi::GstMetaInfo>);
@@ -92,7 +130,7 @@ pub(super) fn custom_meta_get_info() -> *const gstreamer::ffi::GstMetaInfo {
         MetaInfo(
             ptr::NonNull::new(gstreamer::ffi::gst_meta_register(
                 custom_meta_api_get_type().into_glib(),
-                b"DmabufMeta\0".as_ptr() as *const _,
+                b"WaylandBufferMeta\0".as_ptr() as *const _,
                 std::mem::size_of::<WaylandBufferMeta>(),
                 Some(custom_meta_init),
                 Some(custom_meta_free),
@@ -104,3 +142,103 @@ pub(super) fn custom_meta_get_info() -> *const gstreamer::ffi::GstMetaInfo {
 
     META_INFO.0.as_ptr()
 }
+
+pub(super) struct DmabufLayoutMetaParams {
+    pub fourcc: u32,
+    pub modifier: u64,
+    pub planes: Vec<super::DmabufLayoutPlane>,
+}
+
+#[repr(C)]
+pub struct DmabufLayoutMeta {
+    parent: gstreamer::ffi::GstMeta,
+    pub(super) fourcc: u32,
+    pub(super) modifier: u64,
+    pub(super) planes: Vec<super::DmabufLayoutPlane>,
+}
+
+pub(super) fn dmabuf_layout_meta_api_get_type() -> glib::Type {
+    static TYPE: Lazy<glib::Type> = Lazy::new(|| unsafe {
+        let t = from_glib(gstreamer::ffi::gst_meta_api_type_register(
+            b"DmabufLayoutMetaAPI\0".as_ptr() as *const _,
+            MEMORY_TAGS.0.as_ptr() as *mut *const _,
+        ));
+
+        assert_ne!(t, glib::Type::INVALID);
+
+        t
+    });
+
+    *TYPE
+}
+
+unsafe extern "C" fn dmabuf_layout_meta_init(
+    meta: *mut gstreamer::ffi::GstMeta,
+    params: glib::ffi::gpointer,
+    _buffer: *mut gstreamer::ffi::GstBuffer,
+) -> glib::ffi::gboolean {
+    assert!(!params.is_null());
+
+    let meta = &mut *(meta as *mut DmabufLayoutMeta);
+    let params = ptr::read(params as *const DmabufLayoutMetaParams);
+
+    meta.fourcc = params.fourcc;
+    meta.modifier = params.modifier;
+    ptr::write(&mut meta.planes, params.planes);
+
+    true.into_glib()
+}
+
+unsafe extern "C" fn dmabuf_layout_meta_free(
+    meta: *mut gstreamer::ffi::GstMeta,
+    _buffer: *mut gstreamer::ffi::GstBuffer,
+) {
+    let meta = &mut *(meta as *mut DmabufLayoutMeta);
+
+    ptr::drop_in_place(&mut meta.planes);
+}
+
+// The fds belong to the memory of the buffer, like the wl_buffer the layout only
+// follows copies sharing it.
+unsafe extern "C" fn dmabuf_layout_meta_transform(
+    dest: *mut gstreamer::ffi::GstBuffer,
+    meta: *mut gstreamer::ffi::GstMeta,
+    buffer: *mut gstreamer::ffi::GstBuffer,
+    type_: glib::ffi::GQuark,
+    data: glib::ffi::gpointer,
+) -> glib::ffi::gboolean {
+    let meta = &*(meta as *mut DmabufLayoutMeta);
+
+    if is_shared_copy(dest, buffer, type_, data) {
+        super::DmabufLayoutMeta::add(
+            gstreamer::BufferRef::from_mut_ptr(dest),
+            meta.fourcc,
+            meta.modifier,
+            meta.planes.clone(),
+        );
+    }
+
+    true.into_glib()
+}
+
+pub(super) fn dmabuf_layout_meta_get_info() -> *const gstreamer::ffi::GstMetaInfo {
+    struct MetaInfo(ptr::NonNull<gstreamer::ffi::GstMetaInfo>);
+    unsafe impl Send for MetaInfo {}
+    unsafe impl Sync for MetaInfo {}
+
+    static META_INFO: Lazy<MetaInfo> = Lazy::new(|| unsafe {
+        MetaInfo(
+            ptr::NonNull::new(gstreamer::ffi::gst_meta_register(
+                dmabuf_layout_meta_api_get_type().into_glib(),
+                b"DmabufLayoutMeta\0".as_ptr() as *const _,
+                std::mem::size_of::<DmabufLayoutMeta>(),
+                Some(dmabuf_layout_meta_init),
+                Some(dmabuf_layout_meta_free),
+                Some(dmabuf_layout_meta_transform),
+            ) as *mut gstreamer::ffi::GstMetaInfo)
+            .expect("Failed to register meta API"),
+        )
+    });
+
+    META_INFO.0.as_ptr()
+}
@@ -1,8 +1,30 @@
+use std::os::unix::io::RawFd;
+
 use gstreamer::{glib, MetaAPI};
 use wayland_client::protocol::wl_buffer::WlBuffer;
 
 mod imp;
 
+/// Name the meta API type is registered with, for lookups from C or other bindings
+/// via `gst_meta_api_type_get_tags` / `g_type_from_name`.
+pub const WAYLAND_BUFFER_META_API_NAME: &str = "WaylandBufferMetaAPI";
+
+/// Name the [`DmabufLayoutMeta`] API type is registered with.
+pub const DMABUF_LAYOUT_META_API_NAME: &str = "DmabufLayoutMetaAPI";
+
+/// The `wl_buffer` the compositor copied the frame into.
+///
+/// Every buffer produced by `wlrscreencopysrc` from a Wayland buffer pool carries
+/// this meta. It allows an element sharing the same `wl_display` connection, like a
+/// preview sink, to attach the `wl_buffer` to a surface without copying.
+///
+/// The `wl_buffer` stays valid as long as the [`gstreamer::Buffer`] is alive, it must
+/// not be destroyed by users of the meta. The compositor only reads from the buffer
+/// after the source received the matching `ready` event, so the content is complete
+/// when the buffer is pushed downstream.
+///
+/// Only copies sharing the memory of the buffer keep the meta, deep or partial
+/// copies and buffers scaled or cropped from it drop it.
 #[repr(transparent)]
 pub struct WaylandBufferMeta(imp::WaylandBufferMeta);
 
@@ -10,7 +32,7 @@ unsafe impl Send for WaylandBufferMeta {}
 unsafe impl Sync for WaylandBufferMeta {}
 
 impl WaylandBufferMeta {
-    // Add a new custom meta to the buffer with the given label.
+    /// Add a meta referencing `wl_buffer` to `buffer`.
     pub fn add(
         buffer: &mut gstreamer::BufferRef,
         wl_buffer: WlBuffer,
@@ -31,8 +53,7 @@ impl WaylandBufferMeta {
         }
     }
 
-    // Retrieve the stored [`WlBuffer`].
-    #[doc(alias = "get_dma_buffer")]
+    /// The `wl_buffer` backing the buffer, created on the connection of the source.
     pub fn wl_buffer(&self) -> &WlBuffer {
         &self.0.wl_buffer
     }
@@ -54,3 +75,92 @@ impl std::fmt::Debug for WaylandBufferMeta {
             .finish()
     }
 }
+
+/// A plane of a buffer described by [`DmabufLayoutMeta`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DmabufLayoutPlane {
+    /// The dmabuf fd, owned by the memory of the buffer
+    pub fd: RawFd,
+    /// Offset of the plane into the dmabuf
+    pub offset: u32,
+    /// Stride of the plane in bytes
+    pub stride: u32,
+}
+
+/// The layout the compositor imported a dmabuf buffer with.
+///
+/// Every dmabuf buffer of a Wayland buffer pool carries this meta, so consumers
+/// without `DMA_DRM` caps, like custom appsinks or Vulkan importers, can import
+/// the buffer with the DRM fourcc, modifier and plane layout the frame was
+/// copied in instead of guessing it from the video meta.
+///
+/// The fds stay valid as long as the [`gstreamer::Buffer`] is alive, importers have
+/// to dup them to keep them longer. Like [`WaylandBufferMeta`] the meta is only
+/// kept by copies sharing the memory of the buffer.
+#[repr(transparent)]
+pub struct DmabufLayoutMeta(imp::DmabufLayoutMeta);
+
+unsafe impl Send for DmabufLayoutMeta {}
+unsafe impl Sync for DmabufLayoutMeta {}
+
+impl DmabufLayoutMeta {
+    /// Add a meta describing `planes` of `fourcc` with `modifier` to `buffer`.
+    pub fn add(
+        buffer: &mut gstreamer::BufferRef,
+        fourcc: u32,
+        modifier: u64,
+        planes: Vec<DmabufLayoutPlane>,
+    ) -> gstreamer::MetaRefMut<Self, gstreamer::meta::Standalone> {
+        unsafe {
+            let mut params = std::mem::ManuallyDrop::new(imp::DmabufLayoutMetaParams {
+                fourcc,
+                modifier,
+                planes,
+            });
+
+            let meta = gstreamer::ffi::gst_buffer_add_meta(
+                buffer.as_mut_ptr(),
+                imp::dmabuf_layout_meta_get_info(),
+                &mut *params as *mut imp::DmabufLayoutMetaParams as glib::ffi::gpointer,
+            ) as *mut imp::DmabufLayoutMeta;
+
+            Self::from_mut_ptr(buffer, meta)
+        }
+    }
+
+    /// DRM fourcc of the buffer
+    pub fn fourcc(&self) -> u32 {
+        self.0.fourcc
+    }
+
+    /// DRM modifier of the buffer, `DRM_FORMAT_MOD_LINEAR` for linear buffers
+    pub fn modifier(&self) -> u64 {
+        self.0.modifier
+    }
+
+    /// The planes in the order of the format
+    pub fn planes(&self) -> &[DmabufLayoutPlane] {
+        &self.0.planes
+    }
+}
+
+unsafe impl MetaAPI for DmabufLayoutMeta {
+    type GstType = imp::DmabufLayoutMeta;
+
+    fn meta_api() -> glib::Type {
+        imp::dmabuf_layout_meta_api_get_type()
+    }
+}
+
+impl std::fmt::Debug for DmabufLayoutMeta {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("DmabufLayoutMeta")
+            .field(
+                "fourcc",
+                &crate::utils::drm_format_code_to_string(self.0.fourcc, 0),
+            )
+            .field("modifier", &format_args!("{:#x}", self.0.modifier))
+            .field("planes", &self.0.planes)
+            .finish()
+    }
+}
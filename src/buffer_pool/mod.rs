@@ -1,15 +1,60 @@
 use gstreamer::{glib, subclass::prelude::ObjectSubclassIsExt};
+use wayland_client::Proxy;
 
+mod config;
 mod imp;
 mod meta;
 
-pub use meta::WaylandBufferMeta;
+pub use config::{WaylandBufferPoolConfig, WaylandMemoryType};
+pub use meta::{
+    DmabufLayoutMeta, DmabufLayoutPlane, WaylandBufferMeta, DMABUF_LAYOUT_META_API_NAME,
+    WAYLAND_BUFFER_META_API_NAME,
+};
+
+/// Buffer pool config field holding the nick of the required [`WaylandMemoryType`],
+/// `"auto"`, `"shm"` or `"dmabuf"`. Defaults to `"auto"`.
+pub const BUFFER_POOL_CONFIG_MEMORY_TYPE: &str = "wayland-memory-type";
+
+/// Buffer pool config field overriding the stride of shm buffers, used when the
+/// compositor requires a stride different from the default stride of the format.
+pub const BUFFER_POOL_CONFIG_SHM_STRIDE: &str = "wayland-shm-stride";
+
+/// Buffer pool config field holding a [`gstreamer::Array`] of `u64` DRM modifiers
+/// the compositor accepts for the configured format, used for GBM allocations.
+pub const BUFFER_POOL_CONFIG_DMABUF_MODIFIERS: &str = "wayland-dmabuf-modifiers";
+
+/// Buffer pool config field selecting one memory (and thus one fd) per plane for
+/// multi-planar dmabuf buffers instead of a single memory, as expected by most
+/// hardware encoders. Defaults to `false`.
+pub const BUFFER_POOL_CONFIG_MEMORY_PER_PLANE: &str = "wayland-memory-per-plane";
+
+/// Buffer pool config field holding the idle time in milliseconds after which
+/// free buffers beyond the recent peak usage are freed. `0` or unset never frees
+/// buffers before the pool is stopped.
+pub const BUFFER_POOL_CONFIG_TRIM_TIMEOUT: &str = "wayland-trim-timeout";
+
+/// Buffer pool config field holding the time in milliseconds an acquire waits for
+/// a buffer to be returned once all are in use, it fails with
+/// [`gstreamer::FlowError::Eos`] like `DONTWAIT` afterwards. `0` or unset waits
+/// forever.
+pub const BUFFER_POOL_CONFIG_ACQUIRE_TIMEOUT: &str = "wayland-acquire-timeout";
+
+/// Buffer pool config field holding a [`gstreamer::BufferPool`] with dmabuf
+/// buffers, usually proposed by downstream, whose memories are imported as
+/// wl_buffers instead of allocating new ones. The pool is activated together
+/// with the wayland pool.
+pub const BUFFER_POOL_CONFIG_MEMORY_POOL: &str = "wayland-memory-pool";
 
 glib::wrapper! {
+    /// Buffer pool handing out buffers with an attached wl_buffer, see
+    /// [`WaylandBufferPoolConfig`] for the supported config fields.
     pub struct WaylandBufferPool(ObjectSubclass<imp::WaylandBufferPool>) @extends gstreamer::BufferPool, gstreamer::Object;
 }
 
 impl WaylandBufferPool {
+    /// Create a pool importing its buffers through `wl_shm` and, for dmabuf
+    /// allocators, `zwp_linux_dmabuf`. Both have to belong to the connection
+    /// the buffers are used on.
     pub fn new(
         wl_shm: &wayland_client::protocol::wl_shm::WlShm,
         zwp_linux_dmabuf: Option<&wayland_protocols::wp::linux_dmabuf::zv1::client::zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1>,
@@ -22,4 +67,38 @@ impl WaylandBufferPool {
         std::mem::drop(guard);
         obj
     }
+
+    /// Mark the wl_buffer of `buffer` as in use by the compositor, the buffer
+    /// will not be handed out again by the pool before the compositor released it.
+    pub fn mark_busy(&self, buffer: &gstreamer::BufferRef) {
+        if let Some(meta) = buffer.meta::<WaylandBufferMeta>() {
+            self.imp().release_tracker.mark_busy(meta.wl_buffer().id());
+        }
+    }
+
+    /// Free `buffer` once it is returned to the pool instead of handing it out again,
+    /// for buffers the compositor may still copy into after their frame was destroyed.
+    pub fn discard(&self, buffer: &gstreamer::BufferRef) {
+        self.imp().discard(buffer)
+    }
+
+    /// Whether the compositor rejected importing the dmabufs of the pool with every
+    /// configured modifier, allocations fail with `NotSupported` then.
+    pub fn dmabuf_rejected(&self) -> bool {
+        self.imp()
+            .dmabuf_rejected
+            .load(std::sync::atomic::Ordering::SeqCst)
+    }
+
+    /// Modifier of the last dmabuf imported by the compositor, `None` if the pool
+    /// did not import any dmabuf since it was configured.
+    pub fn dmabuf_modifier(&self) -> Option<u64> {
+        self.imp().state.lock().unwrap().dmabuf_modifier
+    }
+
+    /// The video info of the buffers as configured, including alignment and
+    /// stride overrides.
+    pub fn video_info(&self) -> Option<gstreamer_video::VideoInfo> {
+        self.imp().state.lock().unwrap().video_info.clone()
+    }
 }
@@ -0,0 +1,117 @@
+use gstreamer::glib;
+use gstreamer::prelude::*;
+use gstreamer::subclass::prelude::*;
+use once_cell::sync::{Lazy, OnceCell};
+
+use crate::session::ScreencopySession;
+use crate::wlrscreencopysrc::{output_caps, Presentation};
+
+static CAT: Lazy<gstreamer::DebugCategory> = Lazy::new(|| {
+    gstreamer::DebugCategory::new(
+        "wlrscreencopydeviceprovider",
+        gstreamer::DebugColorFlags::empty(),
+        Some("Wayland Screencopy Device Provider"),
+    )
+});
+
+#[derive(Debug, Default)]
+pub struct WlrScreencopyDeviceProvider;
+
+impl WlrScreencopyDeviceProvider {
+    /// Capture one frame of `output_name` to learn the formats the compositor
+    /// offers for it.
+    fn probe_output(&self, output_name: &str) -> Option<gstreamer::Device> {
+        let session = match ScreencopySession::connect(None, Some(output_name), false) {
+            Ok(session) => session,
+            Err(err) => {
+                gstreamer::warning!(CAT, imp: self, "failed to probe {}: {}", output_name, err);
+                return None;
+            }
+        };
+        let output_info = session.output_info()?;
+        let caps = output_caps(
+            &session.buffer_formats(),
+            &output_info,
+            Presentation::Physical,
+        );
+        gstreamer::debug!(CAT, imp: self, "{} supports {}", output_name, caps);
+
+        Some(super::WlrScreencopyDevice::new(&output_info, &caps).upcast())
+    }
+}
+
+#[glib::object_subclass]
+impl ObjectSubclass for WlrScreencopyDeviceProvider {
+    const NAME: &'static str = "GstWlrScreencopyDeviceProvider";
+    type Type = super::WlrScreencopyDeviceProvider;
+    type ParentType = gstreamer::DeviceProvider;
+}
+
+impl ObjectImpl for WlrScreencopyDeviceProvider {}
+
+impl GstObjectImpl for WlrScreencopyDeviceProvider {}
+
+impl DeviceProviderImpl for WlrScreencopyDeviceProvider {
+    fn metadata() -> Option<&'static gstreamer::subclass::DeviceProviderMetadata> {
+        static METADATA: Lazy<gstreamer::subclass::DeviceProviderMetadata> = Lazy::new(|| {
+            gstreamer::subclass::DeviceProviderMetadata::new(
+                "Wayland Screencopy Device Provider",
+                "Source/Video",
+                "List the outputs of the wayland compositor",
+                "Christian Meissl <meissl.christian@gmail.com>",
+            )
+        });
+
+        Some(&*METADATA)
+    }
+
+    fn probe(&self) -> Vec<gstreamer::Device> {
+        // The outputs are only known after connecting, the probe sessions are
+        // dropped again once the caps are known
+        let outputs = match ScreencopySession::connect(None, None, false) {
+            Ok(session) => session.outputs(),
+            Err(err) => {
+                gstreamer::debug!(CAT, imp: self, "no compositor to probe: {}", err);
+                return Vec::new();
+            }
+        };
+
+        outputs
+            .iter()
+            .filter_map(|output_info| self.probe_output(&output_info.name))
+            .collect()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct WlrScreencopyDevice {
+    pub(super) output_name: OnceCell<String>,
+}
+
+#[glib::object_subclass]
+impl ObjectSubclass for WlrScreencopyDevice {
+    const NAME: &'static str = "GstWlrScreencopyDevice";
+    type Type = super::WlrScreencopyDevice;
+    type ParentType = gstreamer::Device;
+}
+
+impl ObjectImpl for WlrScreencopyDevice {}
+
+impl GstObjectImpl for WlrScreencopyDevice {}
+
+impl DeviceImpl for WlrScreencopyDevice {
+    fn create_element(
+        &self,
+        name: Option<&str>,
+    ) -> Result<gstreamer::Element, gstreamer::LoggableError> {
+        let output_name = self.output_name.get().expect("output name set on creation");
+        let mut builder = gstreamer::ElementFactory::make("wlrscreencopysrc")
+            .property("output-name", output_name.as_str());
+        if let Some(name) = name {
+            builder = builder.name(name);
+        }
+        builder.build().map_err(|err| {
+            gstreamer::loggable_error!(CAT, "failed to create wlrscreencopysrc: {}", err)
+        })
+    }
+}
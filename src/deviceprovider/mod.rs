@@ -0,0 +1,67 @@
+use gstreamer::glib;
+use gstreamer::prelude::*;
+use gstreamer::subclass::prelude::ObjectSubclassIsExt;
+
+mod imp;
+
+glib::wrapper! {
+    /// Lists every output of the compositor as a device, with the caps a probe
+    /// capture of the output reported.
+    pub struct WlrScreencopyDeviceProvider(ObjectSubclass<imp::WlrScreencopyDeviceProvider>) @extends gstreamer::DeviceProvider, gstreamer::Object;
+}
+
+glib::wrapper! {
+    /// One output of the compositor, creates a `wlrscreencopysrc` capturing it.
+    pub struct WlrScreencopyDevice(ObjectSubclass<imp::WlrScreencopyDevice>) @extends gstreamer::Device, gstreamer::Object;
+}
+
+impl WlrScreencopyDevice {
+    fn new(output_info: &crate::session::OutputInfo, caps: &gstreamer::Caps) -> Self {
+        let display_name = if output_info.description.is_empty() {
+            output_info.name.clone()
+        } else {
+            output_info.description.clone()
+        };
+        let properties = gstreamer::Structure::builder("wlrscreencopy-proplist")
+            .field("wayland.output.name", output_info.name.as_str())
+            .field(
+                "wayland.output.description",
+                output_info.description.as_str(),
+            )
+            .field("wayland.output.width", output_info.mode.width)
+            .field("wayland.output.height", output_info.mode.height)
+            .field("wayland.output.refresh", output_info.mode.refresh)
+            .field("wayland.output.scale", output_info.scale)
+            .build();
+
+        let device: WlrScreencopyDevice = glib::Object::builder()
+            .property("display-name", display_name)
+            .property("device-class", "Source/Video")
+            .property("caps", caps.clone())
+            .property("properties", properties)
+            .build();
+        device
+            .imp()
+            .output_name
+            .set(output_info.name.clone())
+            .expect("output name only set once");
+        device
+    }
+
+    /// Name of the output captured by elements of this device
+    pub fn output_name(&self) -> &str {
+        self.imp()
+            .output_name
+            .get()
+            .expect("output name set on creation")
+    }
+}
+
+pub fn register(plugin: &gstreamer::Plugin) -> Result<(), glib::BoolError> {
+    gstreamer::DeviceProvider::register(
+        Some(plugin),
+        "wlrscreencopydeviceprovider",
+        gstreamer::Rank::Marginal,
+        WlrScreencopyDeviceProvider::static_type(),
+    )
+}
@@ -0,0 +1,424 @@
+use std::sync::Mutex;
+
+use glow::HasContext;
+use gstreamer::glib;
+use gstreamer::prelude::ObjectExt;
+use gstreamer_video::VideoFormat;
+use once_cell::sync::Lazy;
+
+use super::egl::{Dmabuf, DmabufPlane, EglContext};
+use crate::allocators::GbmMemoryAllocator;
+use crate::utils::{gst_video_format_to_drm_fourcc_code, DRM_FORMAT_MOD_LINEAR};
+
+static CAT: Lazy<gstreamer::DebugCategory> = Lazy::new(|| {
+    gstreamer::DebugCategory::new(
+        "wlrgpuconverter",
+        gstreamer::DebugColorFlags::empty(),
+        Some("GPU converter"),
+    )
+});
+
+/// `GL_TEXTURE_EXTERNAL_OES`, samples any importable format as RGB
+const TEXTURE_EXTERNAL_OES: u32 = 0x8d65;
+
+const VERTEX_SHADER: &str = r#"
+attribute vec2 position;
+varying vec2 v_texcoord;
+
+void main() {
+    v_texcoord = position * 0.5 + 0.5;
+    gl_Position = vec4(position, 0.0, 1.0);
+}
+"#;
+
+const COPY_FRAGMENT_SHADER: &str = r#"
+#extension GL_OES_EGL_image_external : require
+precision mediump float;
+uniform samplerExternalOES tex;
+varying vec2 v_texcoord;
+
+void main() {
+    gl_FragColor = texture2D(tex, v_texcoord);
+}
+"#;
+
+/// Writes up to two YUV components of the sampled RGB into the red and green
+/// channel, each given as RGB weights and offset
+const YUV_FRAGMENT_SHADER: &str = r#"
+#extension GL_OES_EGL_image_external : require
+precision mediump float;
+uniform samplerExternalOES tex;
+uniform vec4 first;
+uniform vec4 second;
+varying vec2 v_texcoord;
+
+void main() {
+    vec3 rgb = texture2D(tex, v_texcoord).rgb;
+    gl_FragColor = vec4(dot(rgb, first.rgb) + first.a, dot(rgb, second.rgb) + second.a, 0.0, 1.0);
+}
+"#;
+
+/// BT.709 limited range, matching the colorimetry advertised for YUV formats
+const BT709_Y: [f32; 4] = [0.182_586, 0.614_231, 0.062_007, 0.062_745];
+const BT709_U: [f32; 4] = [-0.100_644, -0.338_572, 0.439_216, 0.501_961];
+const BT709_V: [f32; 4] = [0.439_216, -0.398_942, -0.040_274, 0.501_961];
+
+/// Full screen quad as triangle strip
+const QUAD: [f32; 8] = [-1.0, -1.0, 1.0, -1.0, -1.0, 1.0, 1.0, 1.0];
+
+/// How a plane of the output is rendered
+#[derive(Debug, Clone, Copy)]
+enum Pass {
+    /// Sample the RGB input as is
+    Copy,
+    /// Convert to one or two YUV components
+    Yuv([f32; 4], [f32; 4]),
+}
+
+/// Render passes and the DRM fourcc of the render target for every plane of `format`
+fn output_passes(format: VideoFormat) -> Option<Vec<(Pass, u32)>> {
+    let r8 = drm_fourcc::DrmFourcc::R8 as u32;
+    let gr88 = drm_fourcc::DrmFourcc::Gr88 as u32;
+    let passes = match format {
+        VideoFormat::Nv12 => vec![
+            (Pass::Yuv(BT709_Y, [0.0; 4]), r8),
+            (Pass::Yuv(BT709_U, BT709_V), gr88),
+        ],
+        VideoFormat::I420 => vec![
+            (Pass::Yuv(BT709_Y, [0.0; 4]), r8),
+            (Pass::Yuv(BT709_U, [0.0; 4]), r8),
+            (Pass::Yuv(BT709_V, [0.0; 4]), r8),
+        ],
+        format if is_packed_rgb(format) => {
+            vec![(Pass::Copy, gst_video_format_to_drm_fourcc_code(format)?)]
+        }
+        _ => return None,
+    };
+    Some(passes)
+}
+
+/// 32 bit RGB with 8 bit components, the formats the shaders sample and render
+fn is_packed_rgb(format: VideoFormat) -> bool {
+    let info = gstreamer_video::VideoFormatInfo::from_format(format);
+    info.is_rgb() && info.n_planes() == 1 && info.bits() == 8 && info.pixel_stride()[0] == 4
+}
+
+#[derive(Debug)]
+struct Renderer {
+    egl: EglContext,
+    copy_program: glow::Program,
+    yuv_program: glow::Program,
+    quad: glow::Buffer,
+    framebuffer: glow::Framebuffer,
+}
+
+impl Renderer {
+    fn new(egl: EglContext) -> Result<Self, glib::BoolError> {
+        let current = egl.make_current()?;
+        let gl = &egl.gl;
+        let resources = unsafe {
+            compile_program(gl, COPY_FRAGMENT_SHADER).and_then(|copy_program| {
+                let yuv_program = compile_program(gl, YUV_FRAGMENT_SHADER)
+                    .inspect_err(|_| gl.delete_program(copy_program))?;
+                let quad = gl.create_buffer()?;
+                gl.bind_buffer(glow::ARRAY_BUFFER, Some(quad));
+                gl.buffer_data_u8_slice(
+                    glow::ARRAY_BUFFER,
+                    &QUAD
+                        .iter()
+                        .flat_map(|v| v.to_ne_bytes())
+                        .collect::<Vec<_>>(),
+                    glow::STATIC_DRAW,
+                );
+                gl.bind_buffer(glow::ARRAY_BUFFER, None);
+                let framebuffer = gl.create_framebuffer()?;
+                Ok((copy_program, yuv_program, quad, framebuffer))
+            })
+        };
+        drop(current);
+
+        let (copy_program, yuv_program, quad, framebuffer) =
+            resources.map_err(|err| glib::bool_error!("failed to set up renderer: {}", err))?;
+        Ok(Renderer {
+            egl,
+            copy_program,
+            yuv_program,
+            quad,
+            framebuffer,
+        })
+    }
+
+    /// Render `input` into every plane of the output, each scaled to the size of
+    /// its render target.
+    fn render(&self, input: &Dmabuf, outputs: &[(Pass, Dmabuf)]) -> Result<(), glib::BoolError> {
+        let _current = self.egl.make_current()?;
+        let input_image = self.egl.import_dmabuf(input)?;
+        let gl = &self.egl.gl;
+
+        unsafe {
+            let input_texture = gl
+                .create_texture()
+                .map_err(|err| glib::bool_error!("{}", err))?;
+            gl.active_texture(glow::TEXTURE0);
+            gl.bind_texture(TEXTURE_EXTERNAL_OES, Some(input_texture));
+            self.egl.bind_image(TEXTURE_EXTERNAL_OES, &input_image);
+            for (parameter, value) in [
+                (glow::TEXTURE_MIN_FILTER, glow::LINEAR),
+                (glow::TEXTURE_MAG_FILTER, glow::LINEAR),
+                (glow::TEXTURE_WRAP_S, glow::CLAMP_TO_EDGE),
+                (glow::TEXTURE_WRAP_T, glow::CLAMP_TO_EDGE),
+            ] {
+                gl.tex_parameter_i32(TEXTURE_EXTERNAL_OES, parameter, value as i32);
+            }
+            gl.bind_buffer(glow::ARRAY_BUFFER, Some(self.quad));
+            gl.enable_vertex_attrib_array(0);
+            gl.vertex_attrib_pointer_f32(0, 2, glow::FLOAT, false, 0, 0);
+            gl.bind_framebuffer(glow::FRAMEBUFFER, Some(self.framebuffer));
+
+            let result = outputs
+                .iter()
+                .try_for_each(|(pass, output)| self.render_pass(*pass, output));
+            // Downstream accesses the output without any fence
+            gl.finish();
+
+            gl.bind_framebuffer(glow::FRAMEBUFFER, None);
+            gl.disable_vertex_attrib_array(0);
+            gl.bind_buffer(glow::ARRAY_BUFFER, None);
+            gl.bind_texture(TEXTURE_EXTERNAL_OES, None);
+            gl.delete_texture(input_texture);
+            result
+        }
+    }
+
+    /// # Safety
+    ///
+    /// The context has to be current with the input texture, the quad and the
+    /// framebuffer bound.
+    unsafe fn render_pass(&self, pass: Pass, output: &Dmabuf) -> Result<(), glib::BoolError> {
+        let gl = &self.egl.gl;
+        let output_image = self.egl.import_dmabuf(output)?;
+        let output_texture = gl
+            .create_texture()
+            .map_err(|err| glib::bool_error!("{}", err))?;
+        gl.bind_texture(glow::TEXTURE_2D, Some(output_texture));
+        self.egl.bind_image(glow::TEXTURE_2D, &output_image);
+        gl.framebuffer_texture_2d(
+            glow::FRAMEBUFFER,
+            glow::COLOR_ATTACHMENT0,
+            glow::TEXTURE_2D,
+            Some(output_texture),
+            0,
+        );
+
+        let status = gl.check_framebuffer_status(glow::FRAMEBUFFER);
+        let result = if status == glow::FRAMEBUFFER_COMPLETE {
+            let program = match pass {
+                Pass::Copy => self.copy_program,
+                Pass::Yuv(..) => self.yuv_program,
+            };
+            gl.viewport(0, 0, output.width as i32, output.height as i32);
+            gl.use_program(Some(program));
+            gl.uniform_1_i32(gl.get_uniform_location(program, "tex").as_ref(), 0);
+            if let Pass::Yuv(first, second) = pass {
+                gl.uniform_4_f32_slice(gl.get_uniform_location(program, "first").as_ref(), &first);
+                gl.uniform_4_f32_slice(
+                    gl.get_uniform_location(program, "second").as_ref(),
+                    &second,
+                );
+            }
+            gl.draw_arrays(glow::TRIANGLE_STRIP, 0, 4);
+            Ok(())
+        } else {
+            Err(glib::bool_error!("incomplete framebuffer {:#x}", status))
+        };
+
+        gl.framebuffer_texture_2d(
+            glow::FRAMEBUFFER,
+            glow::COLOR_ATTACHMENT0,
+            glow::TEXTURE_2D,
+            None,
+            0,
+        );
+        gl.bind_texture(glow::TEXTURE_2D, None);
+        gl.delete_texture(output_texture);
+        result
+    }
+}
+
+impl Drop for Renderer {
+    fn drop(&mut self) {
+        if let Ok(_current) = self.egl.make_current() {
+            let gl = &self.egl.gl;
+            unsafe {
+                gl.delete_framebuffer(self.framebuffer);
+                gl.delete_buffer(self.quad);
+                gl.delete_program(self.copy_program);
+                gl.delete_program(self.yuv_program);
+            }
+        }
+    }
+}
+
+unsafe fn compile_program(
+    gl: &glow::Context,
+    fragment_shader: &str,
+) -> Result<glow::Program, String> {
+    let program = gl.create_program()?;
+    let mut shaders = Vec::new();
+    for (kind, source) in [
+        (glow::VERTEX_SHADER, VERTEX_SHADER),
+        (glow::FRAGMENT_SHADER, fragment_shader),
+    ] {
+        let shader = gl.create_shader(kind)?;
+        gl.shader_source(shader, source);
+        gl.compile_shader(shader);
+        if !gl.get_shader_compile_status(shader) {
+            let log = gl.get_shader_info_log(shader);
+            gl.delete_shader(shader);
+            for shader in shaders {
+                gl.delete_shader(shader);
+            }
+            gl.delete_program(program);
+            return Err(log);
+        }
+        gl.attach_shader(program, shader);
+        shaders.push(shader);
+    }
+    gl.bind_attrib_location(program, 0, "position");
+    gl.link_program(program);
+    for shader in shaders {
+        gl.detach_shader(program, shader);
+        gl.delete_shader(shader);
+    }
+    if !gl.get_program_link_status(program) {
+        let log = gl.get_program_info_log(program);
+        gl.delete_program(program);
+        return Err(log);
+    }
+    Ok(program)
+}
+
+/// Scales and converts dmabuf frames with GLES into linear buffer objects,
+/// mappable by CPU consumers and importable by hardware encoders.
+#[derive(Debug)]
+pub struct GpuConverter {
+    renderer: Mutex<Renderer>,
+    allocator: GbmMemoryAllocator,
+}
+
+impl GpuConverter {
+    /// Create a converter on the render node of `device`, or the first usable render
+    /// node if the device is unknown.
+    pub fn new(device: Option<u64>) -> Result<Self, glib::BoolError> {
+        let allocator = device
+            .and_then(GbmMemoryAllocator::for_device)
+            .or_else(|| {
+                Some(GbmMemoryAllocator::default()).filter(|allocator| allocator.has_device())
+            })
+            .ok_or_else(|| glib::bool_error!("no render node available"))?;
+        let node = allocator
+            .property::<Option<String>>("device")
+            .ok_or_else(|| glib::bool_error!("allocator without device path"))?;
+        gstreamer::debug!(CAT, "converting on {}", node);
+
+        let renderer = Renderer::new(EglContext::new(node.as_ref())?)?;
+        Ok(GpuConverter {
+            renderer: Mutex::new(renderer),
+            allocator,
+        })
+    }
+
+    /// Whether frames of `input_info` can be converted to `output_info`. Packed RGB
+    /// input is converted to other packed RGB formats, NV12 or I420.
+    pub fn supports(
+        input_info: &gstreamer_video::VideoInfo,
+        output_info: &gstreamer_video::VideoInfo,
+    ) -> bool {
+        is_packed_rgb(input_info.format())
+            && gst_video_format_to_drm_fourcc_code(input_info.format()).is_some()
+            && output_passes(output_info.format()).is_some()
+    }
+
+    /// Convert `buffer`, a linear dmabuf frame of `input_info`, into a new buffer of
+    /// `output_info`.
+    pub fn convert(
+        &self,
+        buffer: &gstreamer::BufferRef,
+        input_info: &gstreamer_video::VideoInfo,
+        output_info: &gstreamer_video::VideoInfo,
+    ) -> Result<gstreamer::Buffer, glib::BoolError> {
+        let format = gst_video_format_to_drm_fourcc_code(input_info.format())
+            .ok_or_else(|| glib::bool_error!("unsupported format {}", input_info.format()))?;
+        let passes = output_passes(output_info.format())
+            .ok_or_else(|| glib::bool_error!("unsupported format {}", output_info.format()))?;
+
+        let (offset, stride) = buffer
+            .meta::<gstreamer_video::VideoMeta>()
+            .map(|meta| (meta.offset()[0], meta.stride()[0]))
+            .unwrap_or((input_info.offset()[0], input_info.stride()[0]));
+        let (memory_index, _, skip) = buffer
+            .find_memory(offset, Some(1))
+            .ok_or_else(|| glib::bool_error!("buffer too small"))?;
+        let memory = buffer
+            .peek_memory(memory_index)
+            .downcast_memory_ref::<gstreamer_allocators::DmaBufMemory>()
+            .ok_or_else(|| glib::bool_error!("not a dmabuf"))?;
+        let input = Dmabuf {
+            width: input_info.width(),
+            height: input_info.height(),
+            format,
+            modifier: DRM_FORMAT_MOD_LINEAR,
+            planes: vec![DmabufPlane {
+                fd: memory.fd(),
+                offset: (memory.offset() + skip) as u32,
+                pitch: stride as u32,
+            }],
+        };
+
+        // Linear, so the output works for CPU consumers as well
+        let allocation = self
+            .allocator
+            .alloc(output_info, &[gbm::Modifier::Linear])?;
+        let output_fd = allocation
+            .memory
+            .downcast_memory_ref::<gstreamer_allocators::DmaBufMemory>()
+            .map(|memory| memory.fd())
+            .ok_or_else(|| glib::bool_error!("gbm allocation is not a dmabuf"))?;
+        // Every plane is rendered separately into a view of the buffer object
+        let format_info = output_info.format_info();
+        let outputs = passes
+            .into_iter()
+            .enumerate()
+            .map(|(plane, (pass, format))| {
+                let plane_output = Dmabuf {
+                    width: format_info.scale_width(plane as u8, output_info.width()),
+                    height: format_info.scale_height(plane as u8, output_info.height()),
+                    format,
+                    modifier: allocation.modifier.into(),
+                    planes: vec![DmabufPlane {
+                        fd: output_fd,
+                        offset: allocation.offsets[plane] as u32,
+                        pitch: allocation.strides[plane] as u32,
+                    }],
+                };
+                (pass, plane_output)
+            })
+            .collect::<Vec<_>>();
+
+        self.renderer.lock().unwrap().render(&input, &outputs)?;
+
+        let mut output_buffer = gstreamer::Buffer::new();
+        let output_buffer_mut = output_buffer.get_mut().unwrap();
+        output_buffer_mut.append_memory(allocation.memory);
+        gstreamer_video::VideoMeta::add_full(
+            output_buffer_mut,
+            gstreamer_video::VideoFrameFlags::empty(),
+            output_info.format(),
+            output_info.width(),
+            output_info.height(),
+            &allocation.offsets,
+            &allocation.strides,
+        )?;
+        Ok(output_buffer)
+    }
+}
@@ -0,0 +1,277 @@
+//! Surfaceless EGL context on a GBM device with dmabuf import.
+
+use std::ffi::c_void;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+
+use gbm::AsRaw;
+use gstreamer::glib;
+use khronos_egl as egl;
+
+use crate::utils::DRM_FORMAT_MOD_INVALID;
+
+type Egl = egl::DynamicInstance<egl::EGL1_5>;
+
+/// `glEGLImageTargetTexture2DOES` from `GL_OES_EGL_image`
+type ImageTargetTexture2DOes = unsafe extern "system" fn(target: u32, image: *const c_void);
+
+const PLATFORM_GBM_KHR: egl::Enum = 0x31d7;
+const LINUX_DMA_BUF_EXT: egl::Enum = 0x3270;
+const LINUX_DRM_FOURCC_EXT: egl::Attrib = 0x3271;
+
+/// Fd, offset, pitch, modifier lo and modifier hi attributes for every plane
+const DMA_BUF_PLANE_ATTRIBS: [[egl::Attrib; 5]; 4] = [
+    [0x3272, 0x3273, 0x3274, 0x3443, 0x3444],
+    [0x3275, 0x3276, 0x3277, 0x3445, 0x3446],
+    [0x3278, 0x3279, 0x327a, 0x3447, 0x3448],
+    [0x3440, 0x3441, 0x3442, 0x3449, 0x344a],
+];
+
+const REQUIRED_EXTENSIONS: &[&str] = &[
+    "EGL_KHR_surfaceless_context",
+    "EGL_EXT_image_dma_buf_import",
+    "EGL_EXT_image_dma_buf_import_modifiers",
+];
+
+/// A plane of a dmabuf to import
+#[derive(Debug, Clone, Copy)]
+pub(super) struct DmabufPlane {
+    pub fd: RawFd,
+    pub offset: u32,
+    pub pitch: u32,
+}
+
+/// A dmabuf to import as [`EglImage`]
+#[derive(Debug)]
+pub(super) struct Dmabuf {
+    pub width: u32,
+    pub height: u32,
+    /// DRM fourcc code
+    pub format: u32,
+    pub modifier: u64,
+    pub planes: Vec<DmabufPlane>,
+}
+
+pub(super) struct EglContext {
+    egl: Egl,
+    display: egl::Display,
+    context: egl::Context,
+    pub gl: glow::Context,
+    image_target_texture: ImageTargetTexture2DOes,
+    // The display is created on this device and must not outlive it
+    _device: gbm::Device<std::fs::File>,
+}
+
+// The context is only made current by the thread holding the lock around it
+unsafe impl Send for EglContext {}
+
+impl std::fmt::Debug for EglContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EglContext")
+            .field("display", &self.display.as_ptr())
+            .field("context", &self.context.as_ptr())
+            .finish()
+    }
+}
+
+impl EglContext {
+    /// Create a GLES 2 context on the render node `node`.
+    pub fn new(node: &Path) -> Result<Self, glib::BoolError> {
+        let egl = unsafe { Egl::load_required() }
+            .map_err(|err| glib::bool_error!("failed to load libEGL: {}", err))?;
+
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(node)
+            .map_err(|err| glib::bool_error!("failed to open {}: {}", node.display(), err))?;
+        let device = gbm::Device::new(file)
+            .map_err(|err| glib::bool_error!("failed to create gbm device: {}", err))?;
+
+        let display = unsafe {
+            egl.get_platform_display(
+                PLATFORM_GBM_KHR,
+                device.as_raw() as *mut c_void,
+                &[egl::ATTRIB_NONE],
+            )
+        }
+        .map_err(|err| glib::bool_error!("failed to get display: {}", err))?;
+        egl.initialize(display)
+            .map_err(|err| glib::bool_error!("failed to initialize display: {}", err))?;
+
+        let extensions = egl
+            .query_string(Some(display), egl::EXTENSIONS)
+            .map(|extensions| extensions.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        if let Some(missing) = REQUIRED_EXTENSIONS
+            .iter()
+            .find(|extension| !extensions.split(' ').any(|name| name == **extension))
+        {
+            let _ = egl.terminate(display);
+            return Err(glib::bool_error!("display does not support {}", missing));
+        }
+
+        let context = egl
+            .bind_api(egl::OPENGL_ES_API)
+            .and_then(|_| {
+                egl.choose_first_config(
+                    display,
+                    &[egl::RENDERABLE_TYPE, egl::OPENGL_ES2_BIT, egl::NONE],
+                )
+            })
+            .and_then(|config| {
+                let config = config.ok_or(egl::Error::BadConfig)?;
+                egl.create_context(
+                    display,
+                    config,
+                    None,
+                    &[egl::CONTEXT_CLIENT_VERSION, 2, egl::NONE],
+                )
+            });
+        let context = match context {
+            Ok(context) => context,
+            Err(err) => {
+                let _ = egl.terminate(display);
+                return Err(glib::bool_error!("failed to create context: {}", err));
+            }
+        };
+
+        if let Err(err) = egl.make_current(display, None, None, Some(context)) {
+            let _ = egl.destroy_context(display, context);
+            let _ = egl.terminate(display);
+            return Err(glib::bool_error!("failed to make context current: {}", err));
+        }
+        let gl = unsafe {
+            glow::Context::from_loader_function(|name| {
+                egl.get_proc_address(name)
+                    .map_or(std::ptr::null(), |f| f as *const c_void)
+            })
+        };
+        let image_target_texture = egl.get_proc_address("glEGLImageTargetTexture2DOES");
+        let _ = egl.make_current(display, None, None, None);
+
+        let Some(image_target_texture) = image_target_texture else {
+            let _ = egl.destroy_context(display, context);
+            let _ = egl.terminate(display);
+            return Err(glib::bool_error!("GL_OES_EGL_image is not supported"));
+        };
+
+        Ok(EglContext {
+            egl,
+            display,
+            context,
+            gl,
+            image_target_texture: unsafe {
+                std::mem::transmute::<extern "system" fn(), ImageTargetTexture2DOes>(
+                    image_target_texture,
+                )
+            },
+            _device: device,
+        })
+    }
+
+    /// Make the context current on the calling thread until the guard is dropped.
+    pub fn make_current(&self) -> Result<CurrentGuard<'_>, glib::BoolError> {
+        self.egl
+            .make_current(self.display, None, None, Some(self.context))
+            .map_err(|err| glib::bool_error!("failed to make context current: {}", err))?;
+        Ok(CurrentGuard { context: self })
+    }
+
+    pub fn import_dmabuf(&self, dmabuf: &Dmabuf) -> Result<EglImage<'_>, glib::BoolError> {
+        if dmabuf.planes.is_empty() || dmabuf.planes.len() > DMA_BUF_PLANE_ATTRIBS.len() {
+            return Err(glib::bool_error!(
+                "unsupported number of planes {}",
+                dmabuf.planes.len()
+            ));
+        }
+
+        let mut attribs = vec![
+            egl::WIDTH as egl::Attrib,
+            dmabuf.width as egl::Attrib,
+            egl::HEIGHT as egl::Attrib,
+            dmabuf.height as egl::Attrib,
+            LINUX_DRM_FOURCC_EXT,
+            dmabuf.format as egl::Attrib,
+        ];
+        for (plane, names) in dmabuf.planes.iter().zip(DMA_BUF_PLANE_ATTRIBS.iter()) {
+            attribs.extend([
+                names[0],
+                plane.fd as egl::Attrib,
+                names[1],
+                plane.offset as egl::Attrib,
+                names[2],
+                plane.pitch as egl::Attrib,
+            ]);
+            // Without a modifier the driver picks the layout of the import
+            if dmabuf.modifier != DRM_FORMAT_MOD_INVALID {
+                attribs.extend([
+                    names[3],
+                    (dmabuf.modifier & 0xffff_ffff) as egl::Attrib,
+                    names[4],
+                    (dmabuf.modifier >> 32) as egl::Attrib,
+                ]);
+            }
+        }
+        attribs.push(egl::ATTRIB_NONE);
+
+        // dmabuf imports are not bound to a context
+        let image = unsafe {
+            self.egl.create_image(
+                self.display,
+                egl::Context::from_ptr(egl::NO_CONTEXT),
+                LINUX_DMA_BUF_EXT,
+                egl::ClientBuffer::from_ptr(std::ptr::null_mut()),
+                &attribs,
+            )
+        }
+        .map_err(|err| glib::bool_error!("failed to import dmabuf: {}", err))?;
+
+        Ok(EglImage {
+            context: self,
+            image,
+        })
+    }
+
+    /// Use `image` as storage of the texture bound to `target`.
+    ///
+    /// # Safety
+    ///
+    /// The context has to be current and a texture has to be bound to `target`.
+    pub unsafe fn bind_image(&self, target: u32, image: &EglImage<'_>) {
+        (self.image_target_texture)(target, image.image.as_ptr());
+    }
+}
+
+impl Drop for EglContext {
+    fn drop(&mut self) {
+        let _ = self.egl.make_current(self.display, None, None, None);
+        let _ = self.egl.destroy_context(self.display, self.context);
+        let _ = self.egl.terminate(self.display);
+    }
+}
+
+/// Keeps an [`EglContext`] current
+pub(super) struct CurrentGuard<'a> {
+    context: &'a EglContext,
+}
+
+impl Drop for CurrentGuard<'_> {
+    fn drop(&mut self) {
+        let context = self.context;
+        let _ = context.egl.make_current(context.display, None, None, None);
+    }
+}
+
+/// An imported dmabuf, destroyed on drop
+pub(super) struct EglImage<'a> {
+    context: &'a EglContext,
+    image: egl::Image,
+}
+
+impl Drop for EglImage<'_> {
+    fn drop(&mut self) {
+        let context = self.context;
+        let _ = context.egl.destroy_image(context.display, self.image);
+    }
+}
@@ -0,0 +1,222 @@
+//! `memory:GLMemory` output, a `GstGLUpload` imports the captured dmabufs as
+//! EGLImages into textures of a GL context shared with downstream.
+
+use std::ptr;
+
+use gstreamer::glib::{self, translate::*};
+use gstreamer::prelude::*;
+use gstreamer_gl::ffi;
+
+glib::wrapper! {
+    struct GLUpload(Object<ffi::GstGLUpload, ffi::GstGLUploadClass>) @extends gstreamer::Object;
+
+    match fn {
+        type_ => || ffi::gst_gl_upload_get_type(),
+    }
+}
+
+unsafe impl Send for GLUpload {}
+unsafe impl Sync for GLUpload {}
+
+/// `caps` with the GLMemory feature, for the structures of `caps`
+pub(crate) fn gl_memory_caps(caps: &gstreamer::CapsRef) -> gstreamer::Caps {
+    let mut gl_caps = gstreamer::Caps::new_empty();
+    {
+        let gl_caps = gl_caps.get_mut().unwrap();
+        for structure in caps.iter() {
+            let mut structure = structure.to_owned();
+            structure.set("texture-target", "2D");
+            gl_caps.append_structure_full(
+                structure,
+                Some(gstreamer::CapsFeatures::new([
+                    gstreamer_gl::CAPS_FEATURE_MEMORY_GL_MEMORY,
+                ])),
+            );
+        }
+    }
+    gl_caps
+}
+
+/// Whether `caps` were negotiated with the GLMemory feature
+pub(crate) fn is_gl_memory_caps(caps: &gstreamer::CapsRef) -> bool {
+    caps.features(0)
+        .map(|features| features.contains(gstreamer_gl::CAPS_FEATURE_MEMORY_GL_MEMORY))
+        .unwrap_or(false)
+}
+
+/// GL display and context of an element and the upload of its frames.
+#[derive(Debug, Default)]
+pub(crate) struct GlMemoryUpload {
+    display: Option<gstreamer_gl::GLDisplay>,
+    /// Context of the application textures are shared with
+    other_context: Option<gstreamer_gl::GLContext>,
+    context: Option<gstreamer_gl::GLContext>,
+    upload: Option<GLUpload>,
+}
+
+impl GlMemoryUpload {
+    /// Take the display or application context set on `element`.
+    pub(crate) fn set_context(
+        &mut self,
+        element: &gstreamer::Element,
+        context: &gstreamer::Context,
+    ) {
+        unsafe {
+            let mut display = self.display.take().to_glib_full();
+            let mut other_context = self.other_context.take().to_glib_full();
+            ffi::gst_gl_handle_set_context(
+                element.to_glib_none().0,
+                context.to_glib_none().0,
+                &mut display,
+                &mut other_context,
+            );
+            self.display = from_glib_full(display);
+            self.other_context = from_glib_full(other_context);
+        }
+    }
+
+    /// Answer a context query for the display and context in use, returns `false`
+    /// if the query is not for them.
+    pub(crate) fn handle_query(
+        &self,
+        element: &gstreamer::Element,
+        query: &mut gstreamer::QueryRef,
+    ) -> bool {
+        unsafe {
+            from_glib(ffi::gst_gl_handle_context_query(
+                element.to_glib_none().0,
+                query.as_mut_ptr(),
+                self.display.to_glib_none().0,
+                self.context.to_glib_none().0,
+                self.other_context.to_glib_none().0,
+            ))
+        }
+    }
+
+    /// The context of downstream, a new one on the same display otherwise.
+    fn ensure_context(
+        &mut self,
+        element: &gstreamer::Element,
+    ) -> Result<gstreamer_gl::GLContext, glib::BoolError> {
+        unsafe {
+            let mut display = self.display.take().to_glib_full();
+            let mut other_context = self.other_context.take().to_glib_full();
+            let found: bool = from_glib(ffi::gst_gl_ensure_element_data(
+                element.as_ptr() as *mut _,
+                &mut display,
+                &mut other_context,
+            ));
+            self.display = from_glib_full(display);
+            self.other_context = from_glib_full(other_context);
+            if !found {
+                return Err(glib::bool_error!("no GL display"));
+            }
+
+            if self.context.is_none() {
+                let mut context = ptr::null_mut();
+                let found: bool = from_glib(ffi::gst_gl_query_local_gl_context(
+                    element.as_ptr() as *mut _,
+                    gstreamer::ffi::GST_PAD_SRC,
+                    &mut context,
+                ));
+                if found {
+                    self.context = from_glib_full(context);
+                }
+            }
+
+            if self.context.is_none() {
+                let display = self.display.as_ref().unwrap();
+                let _lock = display.object_lock();
+                let mut context = ptr::null_mut();
+                let mut error = ptr::null_mut();
+                let created: bool = from_glib(ffi::gst_gl_display_create_context(
+                    display.to_glib_none().0,
+                    self.other_context.to_glib_none().0,
+                    &mut context,
+                    &mut error,
+                ));
+                if !created {
+                    let error: glib::Error = from_glib_full(error);
+                    return Err(glib::bool_error!("failed to create GL context: {}", error));
+                }
+                ffi::gst_gl_display_add_context(display.to_glib_none().0, context);
+                self.context = from_glib_full(context);
+            }
+        }
+        Ok(self.context.clone().unwrap())
+    }
+
+    /// Upload frames of `in_caps` as `out_caps`, the GLMemory caps downstream negotiated.
+    pub(crate) fn configure(
+        &mut self,
+        element: &gstreamer::Element,
+        in_caps: &gstreamer::Caps,
+        out_caps: &gstreamer::Caps,
+    ) -> Result<(), glib::BoolError> {
+        let context = self.ensure_context(element)?;
+        let upload: GLUpload =
+            unsafe { from_glib_full(ffi::gst_gl_upload_new(context.to_glib_none().0)) };
+        let configured: bool = unsafe {
+            from_glib(ffi::gst_gl_upload_set_caps(
+                upload.to_glib_none().0,
+                in_caps.as_ptr() as *mut _,
+                out_caps.as_ptr() as *mut _,
+            ))
+        };
+        if !configured {
+            self.upload = None;
+            return Err(glib::bool_error!(
+                "can not upload {} as {}",
+                in_caps,
+                out_caps
+            ));
+        }
+        self.upload = Some(upload);
+        Ok(())
+    }
+
+    /// Whether frames are uploaded, see [`configure`](Self::configure)
+    pub(crate) fn is_configured(&self) -> bool {
+        self.upload.is_some()
+    }
+
+    /// A buffer of GL textures with the content of `buffer`.
+    pub(crate) fn upload(
+        &self,
+        buffer: gstreamer::Buffer,
+    ) -> Result<gstreamer::Buffer, glib::BoolError> {
+        let upload = self
+            .upload
+            .as_ref()
+            .ok_or_else(|| glib::bool_error!("GL upload not configured"))?;
+        let mut output = ptr::null_mut();
+        let ret = unsafe {
+            ffi::gst_gl_upload_perform_with_buffer(
+                upload.to_glib_none().0,
+                buffer.as_ptr() as *mut _,
+                &mut output,
+            )
+        };
+        if ret != ffi::GST_GL_UPLOAD_DONE || output.is_null() {
+            return Err(glib::bool_error!("GL upload failed with {}", ret));
+        }
+        let mut output: gstreamer::Buffer = unsafe { from_glib_full(output) };
+        if output.as_ptr() != buffer.as_ptr() {
+            // Imported textures sample the captured buffer, it must not be
+            // captured into again before downstream is done with them
+            gstreamer::ParentBufferMeta::add(output.make_mut(), &buffer);
+        }
+        Ok(output)
+    }
+
+    /// Drop the upload, the display and context are kept for the next negotiation
+    pub(crate) fn reset_upload(&mut self) {
+        self.upload = None;
+    }
+
+    /// Drop the upload and the GL context
+    pub(crate) fn reset(&mut self) {
+        self.upload = None;
+        self.context = None;
+    }
+}
@@ -0,0 +1,14 @@
+//! GPU processing of captured dmabuf frames with EGL and GLES, and their upload
+//! to GL memory.
+
+#[cfg(feature = "gles")]
+mod converter;
+#[cfg(feature = "gles")]
+mod egl;
+#[cfg(feature = "gl")]
+mod gl_memory;
+
+#[cfg(feature = "gles")]
+pub(crate) use self::converter::GpuConverter;
+#[cfg(feature = "gl")]
+pub(crate) use self::gl_memory::{gl_memory_caps, is_gl_memory_caps, GlMemoryUpload};
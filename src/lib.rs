@@ -4,11 +4,46 @@ use gstreamer::glib;
 
 mod allocators;
 mod buffer_pool;
-mod wlrscreencopysrc;
+mod deviceprovider;
+mod gpu;
+mod session;
 mod utils;
+mod wlrscreencopysrc;
+
+pub use allocators::{dma_heaps, DmaHeap};
+#[cfg(feature = "dma-heap")]
+pub use allocators::{DmaHeapKind, DmaHeapMemoryAllocator};
+pub use buffer_pool::{
+    DmabufLayoutMeta, DmabufLayoutPlane, WaylandBufferMeta, WaylandBufferPool,
+    WaylandBufferPoolConfig, WaylandMemoryType, BUFFER_POOL_CONFIG_ACQUIRE_TIMEOUT,
+    BUFFER_POOL_CONFIG_DMABUF_MODIFIERS, BUFFER_POOL_CONFIG_MEMORY_PER_PLANE,
+    BUFFER_POOL_CONFIG_MEMORY_POOL, BUFFER_POOL_CONFIG_MEMORY_TYPE, BUFFER_POOL_CONFIG_SHM_STRIDE,
+    BUFFER_POOL_CONFIG_TRIM_TIMEOUT, DMABUF_LAYOUT_META_API_NAME, WAYLAND_BUFFER_META_API_NAME,
+};
+pub use deviceprovider::{WlrScreencopyDevice, WlrScreencopyDeviceProvider};
+pub use session::{
+    BufferFormats, ConnectCancel, CopiedFrame, DmabufFormat, FrameState, Mode, OutputInfo, Rect,
+    ScreencopySession, SessionError, ShmFormat, VirtualInput,
+};
+pub use utils::{
+    drm_format_code_from_string, drm_format_code_to_string, drm_fourcc_requires_little_endian,
+    gst_video_format_from_drm_fourcc_code, gst_video_format_from_wl_shm,
+    gst_video_format_to_drm_fourcc_code, gst_video_format_to_wl_shm,
+    wl_shm_format_from_drm_fourcc_code, wl_shm_format_to_drm_fourcc_code, VideoInfoDmaDrm,
+    DMA_DRM_FORMAT, DRM_FORMAT_BIG_ENDIAN, DRM_FORMAT_MOD_INVALID, DRM_FORMAT_MOD_LINEAR, FORMATS,
+};
+#[cfg(feature = "dmabuf")]
+pub use utils::{drm_format_from_string, drm_format_to_string};
+pub use wlrscreencopysrc::{
+    CopyTimeoutPolicy, DamageReport, PowerOffMode, Presentation, PrivacyFill, ScreencopyDamageMeta,
+    ScreencopyFrameMeta, WlrScreencopySrc, WlrScreencopySrcBuilder, OUTPUT_GEOMETRY_MESSAGE_NAME,
+    STATS_MESSAGE_NAME,
+};
 
 fn plugin_init(plugin: &gstreamer::Plugin) -> Result<(), glib::BoolError> {
-    wlrscreencopysrc::register(plugin)
+    allocators::register()?;
+    wlrscreencopysrc::register(plugin)?;
+    deviceprovider::register(plugin)
 }
 
 gstreamer::plugin_define!(
@@ -0,0 +1,234 @@
+//! Wayland connections shared between all sessions of the process.
+//!
+//! Every session still binds its own globals on its own event queue, but the
+//! socket and the dispatch thread reading from it are shared per display.
+
+use std::collections::HashMap;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, Weak};
+
+use once_cell::sync::Lazy;
+use wayland_client::backend::WaylandError;
+use wayland_client::{Connection, DispatchError};
+
+use super::dispatch::DispatchThread;
+use super::SessionError;
+
+type DispatchFn = Box<dyn FnMut() -> Result<(), DispatchError> + Send>;
+type ErrorFn = Box<dyn FnMut(DispatchError) + Send>;
+
+struct Listener {
+    id: usize,
+    dispatch: DispatchFn,
+    on_error: ErrorFn,
+}
+
+#[derive(Default)]
+struct Listeners {
+    next_id: usize,
+    entries: Vec<Listener>,
+    /// Listeners removed while the dispatch thread was running them
+    removed: Vec<usize>,
+    /// Set once reading from the connection failed, it can not be shared anymore
+    error: Option<String>,
+}
+
+/// Connections by display name, `None` is the display from the environment
+static CONNECTIONS: Lazy<Mutex<HashMap<Option<String>, Weak<SharedConnection>>>> =
+    Lazy::new(Default::default);
+
+/// A connection and the thread dispatching it for all registered listeners.
+pub(super) struct SharedConnection {
+    connection: Connection,
+    listeners: Arc<Mutex<Listeners>>,
+    _dispatch_thread: DispatchThread,
+}
+
+impl std::fmt::Debug for SharedConnection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SharedConnection")
+            .field("connection", &self.connection)
+            .finish()
+    }
+}
+
+impl SharedConnection {
+    pub(super) fn connection(&self) -> &Connection {
+        &self.connection
+    }
+
+    /// Call `dispatch` from the dispatch thread whenever events were read, and
+    /// `on_error` if the connection failed.
+    ///
+    /// The listener is removed when the returned handle is dropped.
+    pub(super) fn add_listener<D, E>(self: &Arc<Self>, dispatch: D, on_error: E) -> ListenerHandle
+    where
+        D: FnMut() -> Result<(), DispatchError> + Send + 'static,
+        E: FnMut(DispatchError) + Send + 'static,
+    {
+        let mut listeners = self.listeners.lock().unwrap();
+        let id = listeners.next_id;
+        listeners.next_id += 1;
+        listeners.entries.push(Listener {
+            id,
+            dispatch: Box::new(dispatch),
+            on_error: Box::new(on_error),
+        });
+
+        ListenerHandle {
+            shared: self.clone(),
+            id,
+        }
+    }
+}
+
+/// Registration of a listener on a [`SharedConnection`].
+#[derive(Debug)]
+pub(super) struct ListenerHandle {
+    shared: Arc<SharedConnection>,
+    id: usize,
+}
+
+impl Drop for ListenerHandle {
+    fn drop(&mut self) {
+        let mut listeners = self.shared.listeners.lock().unwrap();
+        let len = listeners.entries.len();
+        listeners.entries.retain(|listener| listener.id != self.id);
+        if listeners.entries.len() == len {
+            // Currently run by the dispatch thread
+            listeners.removed.push(self.id);
+        }
+    }
+}
+
+/// The error the connection failed with, for every listener
+fn connection_error(err: &str) -> DispatchError {
+    WaylandError::Io(std::io::Error::new(
+        std::io::ErrorKind::BrokenPipe,
+        err.to_owned(),
+    ))
+    .into()
+}
+
+fn run_listeners(listeners: &Mutex<Listeners>) {
+    // Run without holding the lock, a listener may drop the last reference to its
+    // session and thereby remove itself
+    let mut running = std::mem::take(&mut listeners.lock().unwrap().entries);
+    for listener in running.iter_mut() {
+        if let Err(err) = (listener.dispatch)() {
+            (listener.on_error)(err);
+        }
+    }
+
+    let mut listeners = listeners.lock().unwrap();
+    let removed = std::mem::take(&mut listeners.removed);
+    running.retain(|listener| !removed.contains(&listener.id));
+    running.append(&mut listeners.entries);
+    listeners.entries = running;
+}
+
+fn fail_listeners(listeners: &Mutex<Listeners>, err: DispatchError) {
+    let err = err.to_string();
+    let mut running = {
+        let mut listeners = listeners.lock().unwrap();
+        listeners.error = Some(err.clone());
+        std::mem::take(&mut listeners.entries)
+    };
+    for listener in running.iter_mut() {
+        (listener.on_error)(connection_error(&err));
+    }
+
+    let mut listeners = listeners.lock().unwrap();
+    let removed = std::mem::take(&mut listeners.removed);
+    running.retain(|listener| !removed.contains(&listener.id));
+    running.append(&mut listeners.entries);
+    listeners.entries = running;
+}
+
+/// Get the connection to `wayland_display`, connecting if no session in the
+/// process is connected to it yet.
+pub(super) fn shared(wayland_display: Option<&str>) -> Result<Arc<SharedConnection>, SessionError> {
+    let key = wayland_display.map(String::from);
+    let mut connections = CONNECTIONS.lock().unwrap();
+    if let Some(shared) = connections.get(&key).and_then(Weak::upgrade) {
+        if shared.listeners.lock().unwrap().error.is_none() {
+            return Ok(shared);
+        }
+    }
+
+    let connection = open(wayland_display)?;
+    let listeners = Arc::new(Mutex::new(Listeners::default()));
+    let dispatch_listeners = listeners.clone();
+    let error_listeners = listeners.clone();
+    let dispatch_thread = DispatchThread::spawn(
+        connection.clone(),
+        move || {
+            run_listeners(&dispatch_listeners);
+            Ok(())
+        },
+        move |err| fail_listeners(&error_listeners, err),
+    )
+    .map_err(|err| {
+        SessionError::Connect(format!("Failed to spawn wayland dispatch thread: {}", err))
+    })?;
+
+    let shared = Arc::new(SharedConnection {
+        connection,
+        listeners,
+        _dispatch_thread: dispatch_thread,
+    });
+    connections.retain(|_, shared| shared.strong_count() > 0);
+    connections.insert(key, Arc::downgrade(&shared));
+    Ok(shared)
+}
+
+fn open(wayland_display: Option<&str>) -> Result<Connection, SessionError> {
+    let conn = if let Some(wayland_display) = wayland_display {
+        let wayland_display = PathBuf::from_str(wayland_display).map_err(|err| {
+            SessionError::InvalidDisplay(format!(
+                "Invalid wayland display {}: {}",
+                wayland_display, err
+            ))
+        })?;
+
+        let socket_path = if wayland_display.is_absolute() {
+            wayland_display
+        } else {
+            let mut socket_path = std::env::var_os("XDG_RUNTIME_DIR")
+                .map(Into::<PathBuf>::into)
+                .ok_or_else(|| {
+                    SessionError::InvalidDisplay(format!(
+                        "XDG_RUNTIME_DIR is not set, can not locate wayland display {}",
+                        wayland_display.display()
+                    ))
+                })?;
+            if !socket_path.is_absolute() {
+                return Err(SessionError::InvalidDisplay(format!(
+                    "XDG_RUNTIME_DIR is not an absolute path: {}",
+                    socket_path.display()
+                )));
+            }
+            socket_path.push(wayland_display);
+            socket_path
+        };
+
+        let stream = UnixStream::connect(&socket_path).map_err(|err| {
+            SessionError::Connect(format!(
+                "Failed to connect to wayland display {}: {}",
+                socket_path.display(),
+                err
+            ))
+        })?;
+        Connection::from_socket(stream).map_err(|err| {
+            SessionError::Connect(format!("Failed to create wayland connection: {}", err))
+        })?
+    } else {
+        Connection::connect_to_env().map_err(|err| {
+            SessionError::Connect(format!("Failed to connect to wayland display: {}", err))
+        })?
+    };
+
+    Ok(conn)
+}
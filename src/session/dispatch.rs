@@ -0,0 +1,209 @@
+//! Reading and dispatching of the Wayland connection on a dedicated thread.
+
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Instant;
+
+use wayland_client::backend::{ReadEventsGuard, WaylandError};
+use wayland_client::{Connection, DispatchError, EventQueue};
+
+/// Poll timeout, events read from the socket by other threads (like the buffer
+/// pool waiting for releases) are dispatched at least this often
+const DISPATCH_INTERVAL_MS: i32 = 50;
+
+fn is_would_block(err: &WaylandError) -> bool {
+    matches!(err, WaylandError::Io(err) if err.kind() == std::io::ErrorKind::WouldBlock)
+}
+
+/// Self-pipe waking up a thread polling the connection, stays woken until reset.
+#[derive(Debug)]
+pub(super) struct Wakeup {
+    read: OwnedFd,
+    write: OwnedFd,
+    woken: AtomicBool,
+}
+
+impl Wakeup {
+    pub(super) fn new() -> std::io::Result<Self> {
+        let (read, write) =
+            nix::unistd::pipe2(nix::fcntl::OFlag::O_CLOEXEC | nix::fcntl::OFlag::O_NONBLOCK)?;
+        let (read, write) = unsafe { (OwnedFd::from_raw_fd(read), OwnedFd::from_raw_fd(write)) };
+        Ok(Wakeup {
+            read,
+            write,
+            woken: AtomicBool::new(false),
+        })
+    }
+
+    pub(super) fn wake(&self) {
+        self.woken.store(true, Ordering::SeqCst);
+        let _ = nix::unistd::write(self.write.as_raw_fd(), &[0]);
+    }
+
+    pub(super) fn reset(&self) {
+        self.woken.store(false, Ordering::SeqCst);
+        let mut buf = [0u8; 16];
+        while matches!(nix::unistd::read(self.read.as_raw_fd(), &mut buf), Ok(len) if len > 0) {}
+    }
+
+    pub(super) fn is_woken(&self) -> bool {
+        self.woken.load(Ordering::SeqCst)
+    }
+
+    fn fd(&self) -> RawFd {
+        self.read.as_raw_fd()
+    }
+}
+
+/// Wait up to `timeout_ms` for events on the connection of `guard` and read them
+/// into their queues, returns early if `wakeup` is woken.
+fn poll_read(
+    guard: ReadEventsGuard,
+    wakeup: Option<&Wakeup>,
+    timeout_ms: i32,
+) -> Result<(), WaylandError> {
+    let mut fds = vec![nix::poll::PollFd::new(
+        guard.connection_fd().as_raw_fd(),
+        nix::poll::PollFlags::POLLIN,
+    )];
+    if let Some(wakeup) = wakeup {
+        fds.push(nix::poll::PollFd::new(
+            wakeup.fd(),
+            nix::poll::PollFlags::POLLIN,
+        ));
+    }
+    match nix::poll::poll(&mut fds, timeout_ms) {
+        Ok(_) => (),
+        Err(nix::errno::Errno::EINTR) => return Ok(()),
+        Err(err) => return Err(WaylandError::Io(err.into())),
+    }
+
+    let is_readable = fds[0]
+        .revents()
+        .map(|revents| !revents.is_empty())
+        .unwrap_or(false);
+    if is_readable {
+        match guard.read() {
+            Err(err) if !is_would_block(&err) => return Err(err),
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+/// Outcome of [`dispatch_until`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Dispatched {
+    Done,
+    Timeout,
+    Woken,
+}
+
+/// Dispatch `event_queue` until `done` is true, `deadline` passed or `wakeup` is
+/// woken.
+///
+/// Other threads read from the same connection and queue the events without
+/// waking the poll, so it times out every [`DISPATCH_INTERVAL_MS`] to dispatch them.
+pub(super) fn dispatch_until<S>(
+    event_queue: &mut EventQueue<S>,
+    state: &mut S,
+    deadline: Option<Instant>,
+    wakeup: Option<&Wakeup>,
+    done: impl Fn(&S) -> bool,
+) -> Result<Dispatched, DispatchError> {
+    loop {
+        event_queue.dispatch_pending(state)?;
+        if done(state) {
+            return Ok(Dispatched::Done);
+        }
+        if wakeup.is_some_and(Wakeup::is_woken) {
+            return Ok(Dispatched::Woken);
+        }
+        let timeout_ms = match deadline {
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    return Ok(Dispatched::Timeout);
+                }
+                std::cmp::min(remaining.as_millis(), DISPATCH_INTERVAL_MS as u128) as i32
+            }
+            None => DISPATCH_INTERVAL_MS,
+        };
+
+        match event_queue.flush() {
+            Err(err) if !is_would_block(&err) => return Err(err.into()),
+            _ => (),
+        }
+        let guard = event_queue.prepare_read()?;
+        poll_read(guard, wakeup, timeout_ms)?;
+    }
+}
+
+/// Thread reading events from a connection, stopped when dropped.
+#[derive(Debug)]
+pub(super) struct DispatchThread {
+    wakeup: Arc<Wakeup>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl DispatchThread {
+    /// Spawn a thread calling `dispatch` whenever events were read from `connection`.
+    ///
+    /// `on_error` is called once if reading or dispatching fails, the thread exits
+    /// afterwards.
+    pub(super) fn spawn<D, E>(
+        connection: Connection,
+        mut dispatch: D,
+        on_error: E,
+    ) -> std::io::Result<Self>
+    where
+        D: FnMut() -> Result<(), DispatchError> + Send + 'static,
+        E: FnOnce(DispatchError) + Send + 'static,
+    {
+        let wakeup = Arc::new(Wakeup::new()?);
+        let thread_wakeup = wakeup.clone();
+
+        let handle = std::thread::Builder::new()
+            .name("wlr-screencopy-dispatch".into())
+            .spawn(move || {
+                let result = (|| loop {
+                    dispatch()?;
+
+                    match connection.flush() {
+                        Err(err) if !is_would_block(&err) => return Err(err.into()),
+                        _ => (),
+                    }
+
+                    let guard = connection.prepare_read()?;
+                    poll_read(guard, Some(&thread_wakeup), DISPATCH_INTERVAL_MS)?;
+                    if thread_wakeup.is_woken() {
+                        return Ok(());
+                    }
+                })();
+
+                if let Err(err) = result {
+                    on_error(err);
+                }
+            })?;
+
+        Ok(DispatchThread {
+            wakeup,
+            handle: Some(handle),
+        })
+    }
+}
+
+impl Drop for DispatchThread {
+    fn drop(&mut self) {
+        self.wakeup.wake();
+
+        if let Some(handle) = self.handle.take() {
+            // The thread can drop the last reference to the session itself
+            if handle.thread().id() != std::thread::current().id() {
+                let _ = handle.join();
+            }
+        }
+    }
+}
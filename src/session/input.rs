@@ -0,0 +1,443 @@
+//! Input injection through the virtual pointer and keyboard protocols.
+
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+use std::time::Instant;
+
+use wayland_client::protocol::{wl_keyboard, wl_pointer, wl_seat::WlSeat};
+use wayland_client::{Connection, Dispatch, Proxy, QueueHandle};
+use wayland_protocols_misc::zwp_virtual_keyboard_v1::client::{
+    zwp_virtual_keyboard_manager_v1::ZwpVirtualKeyboardManagerV1,
+    zwp_virtual_keyboard_v1::ZwpVirtualKeyboardV1,
+};
+use wayland_protocols_wlr::virtual_pointer::v1::client::{
+    zwlr_virtual_pointer_manager_v1::ZwlrVirtualPointerManagerV1,
+    zwlr_virtual_pointer_v1::ZwlrVirtualPointerV1,
+};
+
+use super::state::WaylandState;
+use super::{protocol_log, ScreencopySession, SessionError, CAT};
+
+/// Keymap uploaded for the virtual keyboard, key names are translated for it
+const KEYMAP: &str = "xkb_keymap {
+    xkb_keycodes { include \"evdev+aliases(qwerty)\" };
+    xkb_types { include \"complete\" };
+    xkb_compat { include \"complete\" };
+    xkb_symbols { include \"pc+us+inet(evdev)\" };
+};
+";
+
+const KEY_LEFTSHIFT: u32 = 42;
+const KEY_RIGHTSHIFT: u32 = 54;
+
+/// Linux input codes of the mouse buttons
+const BTN_LEFT: u32 = 0x110;
+const BTN_RIGHT: u32 = 0x111;
+const BTN_MIDDLE: u32 = 0x112;
+const BTN_SIDE: u32 = 0x113;
+const BTN_EXTRA: u32 = 0x114;
+
+/// Scroll distance of one wheel step in surface coordinates
+const SCROLL_STEP: f64 = 15.0;
+
+/// X keysym name, character, evdev keycode and whether shift is needed on a us layout
+#[rustfmt::skip]
+const KEYS: &[(&str, Option<char>, u32, bool)] = &[
+    ("Escape", None, 1, false),
+    ("1", Some('1'), 2, false), ("exclam", Some('!'), 2, true),
+    ("2", Some('2'), 3, false), ("at", Some('@'), 3, true),
+    ("3", Some('3'), 4, false), ("numbersign", Some('#'), 4, true),
+    ("4", Some('4'), 5, false), ("dollar", Some('$'), 5, true),
+    ("5", Some('5'), 6, false), ("percent", Some('%'), 6, true),
+    ("6", Some('6'), 7, false), ("asciicircum", Some('^'), 7, true),
+    ("7", Some('7'), 8, false), ("ampersand", Some('&'), 8, true),
+    ("8", Some('8'), 9, false), ("asterisk", Some('*'), 9, true),
+    ("9", Some('9'), 10, false), ("parenleft", Some('('), 10, true),
+    ("0", Some('0'), 11, false), ("parenright", Some(')'), 11, true),
+    ("minus", Some('-'), 12, false), ("underscore", Some('_'), 12, true),
+    ("equal", Some('='), 13, false), ("plus", Some('+'), 13, true),
+    ("BackSpace", None, 14, false),
+    ("Tab", Some('\t'), 15, false),
+    ("bracketleft", Some('['), 26, false), ("braceleft", Some('{'), 26, true),
+    ("bracketright", Some(']'), 27, false), ("braceright", Some('}'), 27, true),
+    ("Return", Some('\n'), 28, false),
+    ("Control_L", None, 29, false),
+    ("semicolon", Some(';'), 39, false), ("colon", Some(':'), 39, true),
+    ("apostrophe", Some('\''), 40, false), ("quotedbl", Some('"'), 40, true),
+    ("grave", Some('`'), 41, false), ("asciitilde", Some('~'), 41, true),
+    ("Shift_L", None, KEY_LEFTSHIFT, false),
+    ("backslash", Some('\\'), 43, false), ("bar", Some('|'), 43, true),
+    ("comma", Some(','), 51, false), ("less", Some('<'), 51, true),
+    ("period", Some('.'), 52, false), ("greater", Some('>'), 52, true),
+    ("slash", Some('/'), 53, false), ("question", Some('?'), 53, true),
+    ("Shift_R", None, KEY_RIGHTSHIFT, false),
+    ("Alt_L", None, 56, false),
+    ("space", Some(' '), 57, false),
+    ("Caps_Lock", None, 58, false),
+    ("F1", None, 59, false), ("F2", None, 60, false), ("F3", None, 61, false),
+    ("F4", None, 62, false), ("F5", None, 63, false), ("F6", None, 64, false),
+    ("F7", None, 65, false), ("F8", None, 66, false), ("F9", None, 67, false),
+    ("F10", None, 68, false), ("F11", None, 87, false), ("F12", None, 88, false),
+    ("Control_R", None, 97, false),
+    ("Alt_R", None, 100, false), ("ISO_Level3_Shift", None, 100, false),
+    ("Home", None, 102, false),
+    ("Up", None, 103, false),
+    ("Page_Up", None, 104, false), ("Prior", None, 104, false),
+    ("Left", None, 105, false),
+    ("Right", None, 106, false),
+    ("End", None, 107, false),
+    ("Down", None, 108, false),
+    ("Page_Down", None, 109, false), ("Next", None, 109, false),
+    ("Insert", None, 110, false),
+    ("Delete", None, 111, false),
+    ("Super_L", None, 125, false),
+    ("Super_R", None, 126, false),
+    ("Menu", None, 127, false),
+];
+
+/// Evdev keycodes of the letters a to z
+const LETTERS: [u32; 26] = [
+    30, 48, 46, 32, 18, 33, 34, 35, 23, 36, 37, 38, 50, 49, 24, 25, 16, 19, 31, 20, 22, 47, 17, 45,
+    21, 44,
+];
+
+/// The evdev keycode for the X keysym or character `key` and whether it needs shift.
+fn keycode(key: &str) -> Option<(u32, bool)> {
+    let mut chars = key.chars();
+    let single_char = chars.next().filter(|_| chars.next().is_none());
+
+    if let Some(c) = single_char.filter(char::is_ascii_alphabetic) {
+        let index = (c.to_ascii_lowercase() as u8 - b'a') as usize;
+        return Some((LETTERS[index], c.is_ascii_uppercase()));
+    }
+
+    KEYS.iter()
+        .find(|(name, c, _, _)| *name == key || (single_char.is_some() && *c == single_char))
+        .map(|(_, _, keycode, shift)| (*keycode, *shift))
+}
+
+/// The Linux input code of the GStreamer navigation `button`.
+fn button_code(button: i32) -> Option<u32> {
+    match button {
+        1 => Some(BTN_LEFT),
+        2 => Some(BTN_MIDDLE),
+        3 => Some(BTN_RIGHT),
+        8 => Some(BTN_SIDE),
+        9 => Some(BTN_EXTRA),
+        _ => None,
+    }
+}
+
+/// A virtual pointer and keyboard injecting input into the compositor of a
+/// [`ScreencopySession`].
+///
+/// The pointer is mapped to the captured output if the compositor supports it.
+/// Pressed keys and buttons are released when the devices are dropped.
+#[derive(Debug)]
+pub struct VirtualInput {
+    session: std::sync::Arc<ScreencopySession>,
+    pointer: Option<ZwlrVirtualPointerV1>,
+    keyboard: Option<ZwpVirtualKeyboardV1>,
+    start: Instant,
+    pressed_buttons: Vec<u32>,
+    /// Pressed keys and whether shift was pressed for them
+    pressed_keys: Vec<(u32, bool)>,
+}
+
+impl VirtualInput {
+    /// Create the virtual devices on the seat of `session`, fails if the compositor
+    /// supports neither virtual pointers nor virtual keyboards.
+    pub fn new(session: &std::sync::Arc<ScreencopySession>) -> Result<Self, SessionError> {
+        let state = session.state.lock().unwrap();
+        let qhandle = &state.qhandle;
+        let output = state
+            .output(session.output_name.as_deref())
+            .map(|(output, _)| output.clone());
+
+        let pointer = state.virtual_pointer_manager.as_ref().map(|manager| {
+            if manager.version() >= 2 {
+                manager.create_virtual_pointer_with_output(
+                    state.wl_seat.as_ref(),
+                    output.as_ref(),
+                    qhandle,
+                    (),
+                )
+            } else {
+                gstreamer::warning!(
+                    CAT,
+                    "virtual pointer can not be mapped to the output, coordinates span all outputs"
+                );
+                manager.create_virtual_pointer(state.wl_seat.as_ref(), qhandle, ())
+            }
+        });
+
+        let keyboard = match (
+            state.virtual_keyboard_manager.as_ref(),
+            state.wl_seat.as_ref(),
+        ) {
+            (Some(manager), Some(wl_seat)) => {
+                let keyboard = manager.create_virtual_keyboard(wl_seat, qhandle, ());
+                match upload_keymap(&keyboard) {
+                    Ok(()) => Some(keyboard),
+                    Err(err) => {
+                        gstreamer::warning!(CAT, "failed to upload keymap: {}", err);
+                        keyboard.destroy();
+                        None
+                    }
+                }
+            }
+            _ => None,
+        };
+        drop(state);
+
+        if pointer.is_none() && keyboard.is_none() {
+            return Err(SessionError::MissingGlobal {
+                interface: "zwlr_virtual_pointer_manager_v1",
+                reason: "neither virtual pointers nor virtual keyboards are supported".into(),
+            });
+        }
+        if keyboard.is_none() {
+            gstreamer::warning!(
+                CAT,
+                "virtual keyboards are not supported, ignoring key events"
+            );
+        }
+        if pointer.is_none() {
+            gstreamer::warning!(
+                CAT,
+                "virtual pointers are not supported, ignoring pointer events"
+            );
+        }
+        session.flush();
+
+        Ok(VirtualInput {
+            session: session.clone(),
+            pointer,
+            keyboard,
+            start: Instant::now(),
+            pressed_buttons: Vec::new(),
+            pressed_keys: Vec::new(),
+        })
+    }
+
+    fn time(&self) -> u32 {
+        self.start.elapsed().as_millis() as u32
+    }
+
+    /// Move the pointer to `x`, `y` in a frame of `width` x `height`.
+    pub fn pointer_motion(&mut self, x: f64, y: f64, width: u32, height: u32) {
+        let Some(pointer) = self.pointer.as_ref() else {
+            return;
+        };
+        if width == 0 || height == 0 {
+            return;
+        }
+        let x = x.clamp(0.0, (width - 1) as f64) as u32;
+        let y = y.clamp(0.0, (height - 1) as f64) as u32;
+        pointer.motion_absolute(self.time(), x, y, width, height);
+        pointer.frame();
+        self.session.flush();
+    }
+
+    /// Press or release the GStreamer navigation `button`, scroll buttons are ignored.
+    pub fn pointer_button(&mut self, button: i32, pressed: bool) {
+        let Some(pointer) = self.pointer.as_ref() else {
+            return;
+        };
+        let Some(code) = button_code(button) else {
+            gstreamer::trace!(CAT, "ignoring button {}", button);
+            return;
+        };
+
+        let is_pressed = self.pressed_buttons.contains(&code);
+        if pressed == is_pressed {
+            return;
+        }
+        let state = if pressed {
+            self.pressed_buttons.push(code);
+            wl_pointer::ButtonState::Pressed
+        } else {
+            self.pressed_buttons.retain(|pressed| *pressed != code);
+            wl_pointer::ButtonState::Released
+        };
+        pointer.button(self.time(), code, state);
+        pointer.frame();
+        self.session.flush();
+    }
+
+    /// Scroll by `delta_x`, `delta_y` wheel steps.
+    pub fn pointer_scroll(&mut self, delta_x: f64, delta_y: f64) {
+        let Some(pointer) = self.pointer.as_ref() else {
+            return;
+        };
+        let time = self.time();
+        pointer.axis_source(wl_pointer::AxisSource::Wheel);
+        for (axis, delta) in [
+            (wl_pointer::Axis::HorizontalScroll, delta_x),
+            (wl_pointer::Axis::VerticalScroll, delta_y),
+        ] {
+            if delta == 0.0 {
+                continue;
+            }
+            pointer.axis_discrete(time, axis, delta * SCROLL_STEP, delta.round() as i32);
+        }
+        pointer.frame();
+        self.session.flush();
+    }
+
+    /// Press or release `key`, an X keysym name like `Return` or a single character.
+    pub fn key(&mut self, key: &str, pressed: bool) {
+        let Some(keyboard) = self.keyboard.as_ref() else {
+            return;
+        };
+        let Some((code, shift)) = keycode(key) else {
+            gstreamer::debug!(CAT, "ignoring unknown key {:?}", key);
+            return;
+        };
+
+        let time = self.time();
+        let shift_held = self
+            .pressed_keys
+            .iter()
+            .any(|(code, _)| *code == KEY_LEFTSHIFT || *code == KEY_RIGHTSHIFT);
+        if pressed {
+            if self
+                .pressed_keys
+                .iter()
+                .any(|(pressed, _)| *pressed == code)
+            {
+                return;
+            }
+            let synthetic_shift = shift && !shift_held;
+            if synthetic_shift {
+                keyboard.key(time, KEY_LEFTSHIFT, wl_keyboard::KeyState::Pressed as u32);
+            }
+            keyboard.key(time, code, wl_keyboard::KeyState::Pressed as u32);
+            self.pressed_keys.push((code, synthetic_shift));
+        } else {
+            let Some(index) = self
+                .pressed_keys
+                .iter()
+                .position(|(pressed, _)| *pressed == code)
+            else {
+                return;
+            };
+            let (_, synthetic_shift) = self.pressed_keys.remove(index);
+            keyboard.key(time, code, wl_keyboard::KeyState::Released as u32);
+            if synthetic_shift {
+                keyboard.key(time, KEY_LEFTSHIFT, wl_keyboard::KeyState::Released as u32);
+            }
+        }
+        self.session.flush();
+    }
+}
+
+impl Drop for VirtualInput {
+    fn drop(&mut self) {
+        let time = self.time();
+        if let Some(pointer) = self.pointer.take() {
+            for code in self.pressed_buttons.drain(..) {
+                pointer.button(time, code, wl_pointer::ButtonState::Released);
+            }
+            pointer.frame();
+            pointer.destroy();
+        }
+        if let Some(keyboard) = self.keyboard.take() {
+            for (code, synthetic_shift) in self.pressed_keys.drain(..).rev() {
+                keyboard.key(time, code, wl_keyboard::KeyState::Released as u32);
+                if synthetic_shift {
+                    keyboard.key(time, KEY_LEFTSHIFT, wl_keyboard::KeyState::Released as u32);
+                }
+            }
+            keyboard.destroy();
+        }
+        self.session.flush();
+    }
+}
+
+fn upload_keymap(keyboard: &ZwpVirtualKeyboardV1) -> std::io::Result<()> {
+    let memfd = memfd::MemfdOptions::default()
+        .close_on_exec(true)
+        .create("wlrscreencopysrc-keymap")
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?;
+    let mut file = memfd.into_file();
+    // The keymap is passed as a NUL terminated string
+    file.write_all(KEYMAP.as_bytes())?;
+    file.write_all(&[0])?;
+    keyboard.keymap(
+        wl_keyboard::KeymapFormat::XkbV1 as u32,
+        file.as_raw_fd(),
+        KEYMAP.len() as u32 + 1,
+    );
+    Ok(())
+}
+
+impl Dispatch<WlSeat, ()> for WaylandState {
+    fn event(
+        _state: &mut Self,
+        proxy: &WlSeat,
+        event: <WlSeat as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        protocol_log::event(proxy, &event);
+        // The seat is only used to create the virtual devices
+    }
+}
+
+impl Dispatch<ZwlrVirtualPointerManagerV1, ()> for WaylandState {
+    fn event(
+        _state: &mut Self,
+        proxy: &ZwlrVirtualPointerManagerV1,
+        event: <ZwlrVirtualPointerManagerV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        protocol_log::event(proxy, &event);
+        // No events to handle
+    }
+}
+
+impl Dispatch<ZwlrVirtualPointerV1, ()> for WaylandState {
+    fn event(
+        _state: &mut Self,
+        proxy: &ZwlrVirtualPointerV1,
+        event: <ZwlrVirtualPointerV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        protocol_log::event(proxy, &event);
+        // No events to handle
+    }
+}
+
+impl Dispatch<ZwpVirtualKeyboardManagerV1, ()> for WaylandState {
+    fn event(
+        _state: &mut Self,
+        proxy: &ZwpVirtualKeyboardManagerV1,
+        event: <ZwpVirtualKeyboardManagerV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        protocol_log::event(proxy, &event);
+        // No events to handle
+    }
+}
+
+impl Dispatch<ZwpVirtualKeyboardV1, ()> for WaylandState {
+    fn event(
+        _state: &mut Self,
+        proxy: &ZwpVirtualKeyboardV1,
+        event: <ZwpVirtualKeyboardV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        protocol_log::event(proxy, &event);
+        // No events to handle
+    }
+}
@@ -0,0 +1,945 @@
+//! Capturing a Wayland output through wlr-screencopy, independent of GStreamer.
+//!
+//! A [`ScreencopySession`] binds the globals of a compositor, discovers its
+//! outputs and drives the frame capture of one output. The caller provides the
+//! `wl_buffer`s the frames are copied into.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+use wayland_client::{Connection, DispatchError, EventQueue, Proxy};
+
+mod connection;
+mod dispatch;
+mod input;
+mod output_power;
+pub(crate) mod protocol_log;
+mod registry;
+mod state;
+mod toplevel;
+
+pub use input::VirtualInput;
+
+use connection::ListenerHandle;
+use dispatch::{Dispatched, Wakeup};
+use registry::Globals;
+use state::WaylandState;
+
+static CAT: Lazy<gstreamer::DebugCategory> = Lazy::new(|| {
+    gstreamer::DebugCategory::new(
+        "wlrscreencopysession",
+        gstreamer::DebugColorFlags::empty(),
+        Some("wlr-screencopy session"),
+    )
+});
+
+/// How long the compositor has to announce its globals, describe its outputs and
+/// the first frame before connecting fails, and the buffer formats of every
+/// further frame
+const SETUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Errors of a [`ScreencopySession`]
+#[derive(Debug)]
+pub enum SessionError {
+    /// The display name or `XDG_RUNTIME_DIR` can not be used to locate the socket
+    InvalidDisplay(String),
+    /// Connecting to the compositor failed
+    Connect(String),
+    /// The compositor does not implement a required protocol
+    MissingGlobal {
+        interface: &'static str,
+        reason: String,
+    },
+    /// The output to capture does not exist (anymore)
+    OutputNotFound {
+        name: Option<String>,
+        available: Vec<String>,
+    },
+    /// Reading or dispatching events failed, the session is unusable afterwards
+    Dispatch(DispatchError),
+    /// Waiting was interrupted by [`ScreencopySession::interrupt`] or connecting
+    /// by a [`ConnectCancel`]
+    Interrupted,
+    /// The compositor did not send the named event of a frame in time
+    Timeout(&'static str),
+    /// No frame is scheduled that could be copied
+    NotCapturing,
+    /// The captured output was powered off while waiting for a copy
+    PoweredOff,
+}
+
+impl std::fmt::Display for SessionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SessionError::InvalidDisplay(reason) | SessionError::Connect(reason) => {
+                f.write_str(reason)
+            }
+            SessionError::MissingGlobal { interface, reason } => {
+                write!(f, "Compositor does not support {}: {}", interface, reason)
+            }
+            SessionError::OutputNotFound {
+                name: Some(name),
+                available,
+            } => write!(
+                f,
+                "Output {} not found, available outputs: {}",
+                name,
+                available.join(" ")
+            ),
+            SessionError::OutputNotFound { name: None, .. } => {
+                f.write_str("Compositor did not advertise any outputs")
+            }
+            SessionError::Dispatch(err) => write!(f, "Failed to dispatch wayland events: {}", err),
+            SessionError::Interrupted => f.write_str("Interrupted"),
+            SessionError::Timeout(event) => write!(
+                f,
+                "Compositor did not send {} within {:?}",
+                event, SETUP_TIMEOUT
+            ),
+            SessionError::NotCapturing => f.write_str("No frame scheduled"),
+            SessionError::PoweredOff => f.write_str("Output was powered off"),
+        }
+    }
+}
+
+impl std::error::Error for SessionError {}
+
+/// Mode of an output
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Mode {
+    pub width: i32,
+    pub height: i32,
+    /// Refresh rate in mHz, 0 if unknown
+    pub refresh: i32,
+}
+
+/// Properties of an output as announced by wl_output and xdg_output
+#[derive(Debug, Default, Clone)]
+pub struct OutputInfo {
+    pub name: String,
+    pub description: String,
+    /// The current mode
+    pub mode: Mode,
+    pub scale: i32,
+    /// Position in the global logical coordinate space
+    pub logical_position: Option<(i32, i32)>,
+    pub logical_size: Option<(i32, i32)>,
+    /// Physical size in millimeters, `None` if unknown
+    pub physical_size: Option<(i32, i32)>,
+    pub transform: Option<wayland_client::protocol::wl_output::Transform>,
+    done: bool,
+    mode_changed: bool,
+    /// Name of the wl_output global, to notice its removal
+    global: u32,
+}
+
+/// Whether the output `name` matches the requested output name `pattern`, a
+/// trailing `*` matches every output name starting with the part before it
+pub(crate) fn output_name_matches(pattern: &str, name: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => name == pattern,
+    }
+}
+
+/// Whether `pattern` can match several outputs, see [`output_name_matches`]
+pub(crate) fn is_output_pattern(pattern: &str) -> bool {
+    pattern.ends_with('*')
+}
+
+/// A wl_shm buffer layout the compositor can copy a frame into
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShmFormat {
+    pub format: wayland_client::protocol::wl_shm::Format,
+    pub width: u32,
+    pub height: u32,
+    pub stride: u32,
+}
+
+/// A dmabuf format the compositor can copy a frame into
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DmabufFormat {
+    /// DRM fourcc code
+    pub format: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// The buffer formats announced for the next frame
+#[derive(Debug, Default, Clone)]
+pub struct BufferFormats {
+    pub shm: Vec<ShmFormat>,
+    pub dmabuf: Vec<DmabufFormat>,
+}
+
+/// A rectangle, damage is reported in buffer coordinates
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    /// The smallest rectangle containing both rectangles
+    pub fn union(&self, other: &Rect) -> Rect {
+        let x = std::cmp::min(self.x, other.x);
+        let y = std::cmp::min(self.y, other.y);
+        let right = std::cmp::max(self.x + self.width, other.x + other.width);
+        let bottom = std::cmp::max(self.y + self.height, other.y + other.height);
+        Rect {
+            x,
+            y,
+            width: right - x,
+            height: bottom - y,
+        }
+    }
+}
+
+/// Outcome of a copy
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameState {
+    /// The copy succeeded, with the `CLOCK_MONOTONIC` time of the frame
+    Ready(std::time::Duration),
+    /// The copy failed, for example because the compositor could not import the buffer
+    Failed,
+}
+
+/// A completed copy
+#[derive(Debug, Clone)]
+pub struct CopiedFrame {
+    pub state: FrameState,
+    /// Whether the frame is stored upside down
+    pub y_invert: bool,
+    /// Damage since the previous frame, only reported for copies with damage
+    pub damage: Vec<Rect>,
+}
+
+/// Capture of one output of a wlroots based compositor.
+///
+/// Events are dispatched on a thread shared by all sessions of the display, so
+/// all methods can be called from any thread. The session has a frame scheduled
+/// unless capturing is stopped, its [`BufferFormats`] tell which buffers
+/// [`copy`](Self::copy) accepts.
+pub struct ScreencopySession {
+    output_name: Option<String>,
+    overlay_cursor: bool,
+    /// Captured part of the output in output-local logical coordinates
+    region: Option<Rect>,
+    connection: Connection,
+    event_queue: Mutex<EventQueue<WaylandState>>,
+    state: Mutex<WaylandState>,
+    /// Signalled by the dispatch thread after every dispatch of the event queue
+    state_cond: Condvar,
+    interrupted: AtomicBool,
+    /// Registration on the shared connection dispatching `event_queue`
+    dispatch_listener: Mutex<Option<ListenerHandle>>,
+}
+
+impl std::fmt::Debug for ScreencopySession {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ScreencopySession")
+            .field("output_name", &self.output_name)
+            .field("region", &self.region)
+            .field("connection", &self.connection)
+            .finish()
+    }
+}
+
+/// Aborts connecting a session from another thread, see
+/// [`ScreencopySession::connect_cancellable`].
+#[derive(Debug, Clone)]
+pub struct ConnectCancel(Arc<Wakeup>);
+
+impl ConnectCancel {
+    pub fn new() -> std::io::Result<Self> {
+        Wakeup::new().map(|wakeup| ConnectCancel(Arc::new(wakeup)))
+    }
+
+    /// Let the current and future connects fail with [`SessionError::Interrupted`]
+    /// until [`reset`](Self::reset) is called.
+    pub fn cancel(&self) {
+        self.0.wake();
+    }
+
+    pub fn reset(&self) {
+        self.0.reset();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.is_woken()
+    }
+}
+
+/// Dispatch the private queue of a session being set up until `done`, failing
+/// after `deadline` or once `cancel` is cancelled.
+fn dispatch_setup<S>(
+    event_queue: &mut EventQueue<S>,
+    state: &mut S,
+    deadline: Instant,
+    cancel: Option<&ConnectCancel>,
+    what: &str,
+    done: impl Fn(&S) -> bool,
+) -> Result<(), SessionError> {
+    let wakeup = cancel.map(|cancel| &*cancel.0);
+    match dispatch::dispatch_until(event_queue, state, Some(deadline), wakeup, done)
+        .map_err(SessionError::Dispatch)?
+    {
+        Dispatched::Done => Ok(()),
+        Dispatched::Woken => Err(SessionError::Interrupted),
+        Dispatched::Timeout => Err(SessionError::Connect(format!(
+            "Compositor did not send {} within {:?}",
+            what, SETUP_TIMEOUT
+        ))),
+    }
+}
+
+/// Outcome of [`ScreencopySession::wait_for`]
+enum Wait {
+    Done,
+    Timeout,
+}
+
+impl ScreencopySession {
+    /// Connect to `wayland_display` and schedule the capture of the output named
+    /// `output_name`, or of the first output if no name is given. A trailing `*`
+    /// selects the first output starting with the part before it. With
+    /// `overlay_cursor` the frames include the pointer.
+    ///
+    /// `wayland_display` is a socket name relative to `XDG_RUNTIME_DIR` or an
+    /// absolute path, `None` uses the display from the environment.
+    pub fn connect(
+        wayland_display: Option<&str>,
+        output_name: Option<&str>,
+        overlay_cursor: bool,
+    ) -> Result<Arc<Self>, SessionError> {
+        Self::connect_region(wayland_display, output_name, overlay_cursor, None)
+    }
+
+    /// Like [`connect`](Self::connect), but only capture `region` of the output
+    /// given in output-local logical coordinates.
+    pub fn connect_region(
+        wayland_display: Option<&str>,
+        output_name: Option<&str>,
+        overlay_cursor: bool,
+        region: Option<Rect>,
+    ) -> Result<Arc<Self>, SessionError> {
+        Self::setup(wayland_display, output_name, overlay_cursor, region, None)
+    }
+
+    /// Like [`connect_region`](Self::connect_region), but fail with
+    /// [`SessionError::Interrupted`] as soon as `cancel` is cancelled.
+    pub fn connect_cancellable(
+        wayland_display: Option<&str>,
+        output_name: Option<&str>,
+        overlay_cursor: bool,
+        region: Option<Rect>,
+        cancel: &ConnectCancel,
+    ) -> Result<Arc<Self>, SessionError> {
+        Self::setup(
+            wayland_display,
+            output_name,
+            overlay_cursor,
+            region,
+            Some(cancel),
+        )
+    }
+
+    fn setup(
+        wayland_display: Option<&str>,
+        output_name: Option<&str>,
+        overlay_cursor: bool,
+        region: Option<Rect>,
+        cancel: Option<&ConnectCancel>,
+    ) -> Result<Arc<Self>, SessionError> {
+        if cancel.is_some_and(ConnectCancel::is_cancelled) {
+            return Err(SessionError::Interrupted);
+        }
+        let shared_connection = connection::shared(wayland_display)?;
+        let conn = shared_connection.connection().clone();
+        let deadline = Instant::now() + SETUP_TIMEOUT;
+        let event_queue = conn.new_event_queue::<WaylandState>();
+        let qhandle = event_queue.handle();
+        // Only reports removed globals to the session, created first so no removal
+        // after collecting the globals is missed
+        conn.display().get_registry(&qhandle, ());
+
+        let mut globals_queue = conn.new_event_queue::<Globals>();
+        let registry = conn.display().get_registry(&globals_queue.handle(), ());
+        conn.display().sync(&globals_queue.handle(), ());
+        let mut globals = Globals::default();
+        dispatch_setup(
+            &mut globals_queue,
+            &mut globals,
+            deadline,
+            cancel,
+            "its globals",
+            |globals| globals.done,
+        )?;
+        let wl_shm = globals
+            .bind::<wayland_client::protocol::wl_shm::WlShm, _>(&registry, &qhandle, 1..=1, ())
+            .map_err(|err| SessionError::MissingGlobal {
+                interface: "wl_shm",
+                reason: err.to_string(),
+            })?;
+        let zwp_linux_dmabuf = globals.bind::<wayland_protocols::wp::linux_dmabuf::zv1::client::zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1, _>(&registry, &qhandle, 2..=4, ()).ok();
+        // Version 4 announces formats and the main device through feedback only
+        let dmabuf_feedback = zwp_linux_dmabuf
+            .as_ref()
+            .filter(|dmabuf| dmabuf.version() >= 4)
+            .map(|dmabuf| {
+                (
+                    dmabuf.get_default_feedback(&qhandle, ()),
+                    Default::default(),
+                )
+            });
+        let wlr_screencopy_manager = globals.bind::<wayland_protocols_wlr::screencopy::v1::client::zwlr_screencopy_manager_v1::ZwlrScreencopyManagerV1, _>(&registry, &qhandle, 1..=3, ()).map_err(|err| SessionError::MissingGlobal {
+            interface: "zwlr_screencopy_manager_v1",
+            reason: err.to_string(),
+        })?;
+        let xdg_output_manager = globals.bind::<wayland_protocols::xdg::xdg_output::zv1::client::zxdg_output_manager_v1::ZxdgOutputManagerV1, _>(&registry, &qhandle, 2..=3, ()).ok();
+        // Only used to inject input through a VirtualInput
+        let wl_seat = globals
+            .bind::<wayland_client::protocol::wl_seat::WlSeat, _>(&registry, &qhandle, 1..=1, ())
+            .ok();
+        let virtual_pointer_manager = globals.bind::<wayland_protocols_wlr::virtual_pointer::v1::client::zwlr_virtual_pointer_manager_v1::ZwlrVirtualPointerManagerV1, _>(&registry, &qhandle, 1..=2, ()).ok();
+        let virtual_keyboard_manager = globals.bind::<wayland_protocols_misc::zwp_virtual_keyboard_v1::client::zwp_virtual_keyboard_manager_v1::ZwpVirtualKeyboardManagerV1, _>(&registry, &qhandle, 1..=1, ()).ok();
+        // Tells which applications are focused on the captured output
+        let foreign_toplevel_manager = globals.bind::<wayland_protocols_wlr::foreign_toplevel::v1::client::zwlr_foreign_toplevel_manager_v1::ZwlrForeignToplevelManagerV1, _>(&registry, &qhandle, 1..=3, ()).ok();
+        // Tells when the captured output is powered off
+        let output_power_manager = globals.bind::<wayland_protocols_wlr::output_power_management::v1::client::zwlr_output_power_manager_v1::ZwlrOutputPowerManagerV1, _>(&registry, &qhandle, 1..=1, ()).ok();
+
+        let mut wayland_state = WaylandState {
+            current_frame: None,
+            stopped_formats: None,
+            outputs: Vec::new(),
+            wlr_screencopy_manager,
+            wl_shm,
+            dmabuf: zwp_linux_dmabuf,
+            dmabuf_rejected: false,
+            dmabuf_modifiers: HashMap::new(),
+            dmabuf_feedback,
+            wl_seat,
+            virtual_pointer_manager,
+            virtual_keyboard_manager,
+            foreign_toplevel_manager,
+            toplevels: Vec::new(),
+            output_power_manager,
+            output_powers: Vec::new(),
+            dispatch_error: None,
+            qhandle: qhandle.clone(),
+        };
+
+        for global in globals
+            .list
+            .iter()
+            .filter(|global| global.interface == "wl_output")
+        {
+            if global.version < 2 {
+                gstreamer::warning!(
+                    CAT,
+                    "ignoring wl_output {}, at least version 2 is required",
+                    global.name
+                );
+                continue;
+            }
+
+            let version = std::cmp::min(global.version, 4);
+
+            let output = registry.bind::<wayland_client::protocol::wl_output::WlOutput, _, _>(
+                global.name,
+                version,
+                &qhandle,
+                (),
+            );
+
+            let zxdg_output = if version < 4 {
+                xdg_output_manager.as_ref().map(|xdg_output_manager| {
+                    xdg_output_manager.get_xdg_output(&output, &qhandle, output.downgrade())
+                })
+            } else {
+                None
+            };
+
+            if let Some(output_power_manager) = wayland_state.output_power_manager.as_ref() {
+                wayland_state.output_powers.push(output_power::OutputPower {
+                    power: output_power_manager.get_output_power(&output, &qhandle, ()),
+                    output: output.clone(),
+                    on: true,
+                });
+            }
+
+            wayland_state.outputs.push((
+                output,
+                zxdg_output,
+                OutputInfo {
+                    global: global.name,
+                    ..Default::default()
+                },
+            ));
+        }
+
+        // Existing xdg outputs stay valid, the manager is not needed anymore
+        if let Some(xdg_output_manager) = xdg_output_manager {
+            xdg_output_manager.destroy();
+        }
+
+        // The session owns the proxies from here on, they are destroyed when it is
+        // dropped even if the setup fails below
+        let session = Arc::new(ScreencopySession {
+            output_name: output_name.map(String::from),
+            overlay_cursor,
+            region,
+            connection: conn,
+            event_queue: Mutex::new(event_queue),
+            state: Mutex::new(wayland_state),
+            state_cond: Condvar::new(),
+            interrupted: AtomicBool::new(false),
+            dispatch_listener: Mutex::new(None),
+        });
+
+        {
+            let mut event_queue = session.event_queue.lock().unwrap();
+            let mut wayland_state = session.state.lock().unwrap();
+
+            // roundtrip to get data for our output info
+            dispatch_setup(
+                &mut event_queue,
+                &mut wayland_state,
+                deadline,
+                cancel,
+                "the outputs",
+                |state| {
+                    !state.outputs.iter().any(|(_, _, info)| !info.done)
+                        && !state
+                            .dmabuf_feedback
+                            .as_ref()
+                            .map(|(_, feedback)| !feedback.done)
+                            .unwrap_or(false)
+                },
+            )?;
+
+            session.capture_output(&mut wayland_state)?;
+
+            // third roundtrip to get frame info
+            dispatch_setup(
+                &mut event_queue,
+                &mut wayland_state,
+                deadline,
+                cancel,
+                "the frame formats",
+                |state| {
+                    state
+                        .current_frame
+                        .as_ref()
+                        .map(|(_, info)| info.done)
+                        .unwrap_or(false)
+                },
+            )?;
+        }
+
+        let dispatch_session = Arc::downgrade(&session);
+        let error_session = Arc::downgrade(&session);
+        let dispatch_listener = shared_connection.add_listener(
+            move || {
+                let Some(session) = dispatch_session.upgrade() else {
+                    return Ok(());
+                };
+                let mut event_queue = session.event_queue.lock().unwrap();
+                let mut state = session.state.lock().unwrap();
+                event_queue.dispatch_pending(&mut *state)?;
+                session.state_cond.notify_all();
+                Ok(())
+            },
+            move |err| {
+                let Some(session) = error_session.upgrade() else {
+                    return;
+                };
+                gstreamer::debug!(CAT, "dispatch failed: {}", err);
+                session.state.lock().unwrap().dispatch_error = Some(err);
+                session.state_cond.notify_all();
+            },
+        );
+        *session.dispatch_listener.lock().unwrap() = Some(dispatch_listener);
+
+        Ok(session)
+    }
+
+    pub fn connection(&self) -> &Connection {
+        &self.connection
+    }
+
+    /// The name of the captured output as requested, `None` for the first output
+    pub fn output_name(&self) -> Option<&str> {
+        self.output_name.as_deref()
+    }
+
+    pub fn wl_shm(&self) -> wayland_client::protocol::wl_shm::WlShm {
+        self.state.lock().unwrap().wl_shm.clone()
+    }
+
+    /// The dmabuf global, `None` if the compositor does not support zwp_linux_dmabuf_v1
+    pub fn linux_dmabuf(
+        &self,
+    ) -> Option<
+        wayland_protocols::wp::linux_dmabuf::zv1::client::zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1,
+    > {
+        self.state.lock().unwrap().dmabuf.clone()
+    }
+
+    /// Modifiers the compositor accepts for the DRM fourcc `format`
+    pub fn dmabuf_modifiers(&self, format: u32) -> Vec<u64> {
+        self.state
+            .lock()
+            .unwrap()
+            .dmabuf_modifiers
+            .get(&format)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// `dev_t` of the DRM device the compositor renders with, buffers allocated on
+    /// other devices might not be importable. Only known with zwp_linux_dmabuf_v1
+    /// version 4.
+    pub fn dmabuf_main_device(&self) -> Option<u64> {
+        self.state
+            .lock()
+            .unwrap()
+            .dmabuf_feedback
+            .as_ref()
+            .and_then(|(_, feedback)| feedback.main_device)
+    }
+
+    /// The captured output, `None` if it went away
+    pub fn output_info(&self) -> Option<OutputInfo> {
+        let state = self.state.lock().unwrap();
+        state
+            .output(self.output_name.as_deref())
+            .map(|(_, info)| info.clone())
+    }
+
+    /// All outputs of the compositor
+    pub fn outputs(&self) -> Vec<OutputInfo> {
+        let state = self.state.lock().unwrap();
+        state
+            .outputs
+            .iter()
+            .map(|(_, _, info)| info.clone())
+            .collect()
+    }
+
+    /// Whether the mode of the captured output changed since the last call
+    pub fn take_mode_changed(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state
+            .output_info_mut(self.output_name.as_deref())
+            .map(|info| std::mem::take(&mut info.mode_changed))
+            .unwrap_or(false)
+    }
+
+    /// The buffer formats of the scheduled frame, dmabuf formats are left out
+    /// after [`reject_dmabuf`](Self::reject_dmabuf)
+    pub fn buffer_formats(&self) -> BufferFormats {
+        let state = self.state.lock().unwrap();
+        let mut formats = state.frame_formats().cloned().unwrap_or_default();
+        if state.dmabuf_rejected {
+            formats.dmabuf.clear();
+        }
+        formats
+    }
+
+    pub fn dmabuf_rejected(&self) -> bool {
+        self.state.lock().unwrap().dmabuf_rejected
+    }
+
+    /// Stop offering dmabuf formats after the compositor failed to copy into a
+    /// dmabuf, returns `true` if shm formats are available as a fallback.
+    pub fn reject_dmabuf(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let has_shm_formats = state
+            .frame_formats()
+            .map(|formats| !formats.shm.is_empty())
+            .unwrap_or(false);
+        if state.dmabuf_rejected || !has_shm_formats {
+            return false;
+        }
+        state.dmabuf_rejected = true;
+        true
+    }
+
+    /// Ask the compositor to copy the scheduled frame into `buffer`.
+    ///
+    /// With `with_damage` the copy only completes once the output has been
+    /// damaged, if the compositor supports it. Fails with
+    /// [`SessionError::NotCapturing`] if no frame is scheduled.
+    pub fn copy(
+        &self,
+        buffer: &wayland_client::protocol::wl_buffer::WlBuffer,
+        with_damage: bool,
+    ) -> Result<(), SessionError> {
+        let state = self.state.lock().unwrap();
+        let Some((frame, _)) = state.current_frame.as_ref() else {
+            return Err(SessionError::NotCapturing);
+        };
+        if with_damage && frame.version() >= 2 {
+            protocol_log::request(frame, format_args!("copy_with_damage({})", buffer.id()));
+            frame.copy_with_damage(buffer);
+        } else {
+            protocol_log::request(frame, format_args!("copy({})", buffer.id()));
+            frame.copy(buffer);
+        }
+        drop(state);
+        self.flush();
+        Ok(())
+    }
+
+    /// Wait for the copy requested by [`copy`](Self::copy) and schedule the next frame.
+    ///
+    /// Returns `Ok(None)` if `deadline` passed first, the copy stays pending and
+    /// can be waited for again. Fails with [`SessionError::Timeout`] if the next
+    /// frame is not set up in time, and with [`SessionError::PoweredOff`] if the
+    /// output is powered off before the copy completed, it most likely never will.
+    pub fn wait_copied(
+        &self,
+        deadline: Option<Instant>,
+    ) -> Result<Option<CopiedFrame>, SessionError> {
+        let state = self.state.lock().unwrap();
+        let powered_off = |state: &WaylandState| {
+            state
+                .output(self.output_name.as_deref())
+                .map(|(output, _)| state.is_powered_off(output))
+                .unwrap_or(false)
+        };
+        let (mut state, wait) = self.wait_for(state, deadline, |state| {
+            state
+                .current_frame
+                .as_ref()
+                .map(|(_, info)| info.state.is_some())
+                .unwrap_or(false)
+                || powered_off(state)
+        })?;
+        if let Wait::Timeout = wait {
+            return Ok(None);
+        }
+        if state
+            .current_frame
+            .as_ref()
+            .map(|(_, info)| info.state.is_none())
+            .unwrap_or(true)
+        {
+            return Err(SessionError::PoweredOff);
+        }
+
+        let (frame, frame_info) = state.current_frame.take().unwrap();
+        protocol_log::request(&frame, format_args!("destroy()"));
+        frame.destroy();
+        let copied_frame = CopiedFrame {
+            state: frame_info.state.unwrap(),
+            y_invert: frame_info
+                .flags
+                .map(|flags| flags.contains(wayland_protocols_wlr::screencopy::v1::client::zwlr_screencopy_frame_v1::Flags::YInvert))
+                .unwrap_or(false),
+            damage: frame_info.damage,
+        };
+
+        // then shedule the next frame
+        self.capture_output(&mut state)?;
+        drop(state);
+        self.flush();
+
+        self.wait_buffer_done()?;
+
+        Ok(Some(copied_frame))
+    }
+
+    /// Wait until the compositor announced the buffer formats of the scheduled frame.
+    fn wait_buffer_done(&self) -> Result<(), SessionError> {
+        let deadline = Instant::now() + SETUP_TIMEOUT;
+        let state = self.state.lock().unwrap();
+        let (_state, wait) = self.wait_for(state, Some(deadline), |state| {
+            state
+                .current_frame
+                .as_ref()
+                .map(|(_, info)| info.done)
+                .unwrap_or(false)
+        })?;
+        match wait {
+            Wait::Done => Ok(()),
+            Wait::Timeout => Err(SessionError::Timeout("buffer_done")),
+        }
+    }
+
+    /// Destroy the scheduled frame so the compositor does not prepare frames while
+    /// nothing is copied, the buffer formats stay available. A pending copy has
+    /// to be waited for first.
+    pub fn stop_capture(&self) {
+        let mut state = self.state.lock().unwrap();
+        let Some((frame, frame_info)) = state.current_frame.take() else {
+            return;
+        };
+        protocol_log::request(&frame, format_args!("destroy()"));
+        frame.destroy();
+        state.stopped_formats = Some(frame_info.formats);
+        drop(state);
+        self.flush();
+    }
+
+    /// Schedule a frame again after [`stop_capture`](Self::stop_capture), does
+    /// nothing if a frame is scheduled. Fails with [`SessionError::Timeout`] if
+    /// the compositor does not announce the buffer formats in time.
+    pub fn start_capture(&self) -> Result<(), SessionError> {
+        let mut state = self.state.lock().unwrap();
+        if state.current_frame.is_some() {
+            return Ok(());
+        }
+        self.capture_output(&mut state)?;
+        drop(state);
+        self.flush();
+
+        self.wait_buffer_done()?;
+        self.state.lock().unwrap().stopped_formats = None;
+        Ok(())
+    }
+
+    /// Let all current and future waits fail with [`SessionError::Interrupted`]
+    /// until [`resume`](Self::resume) is called.
+    pub fn interrupt(&self) {
+        self.interrupted.store(true, Ordering::SeqCst);
+        // Take the lock so a waiter can not miss the notification
+        let _state = self.state.lock().unwrap();
+        self.state_cond.notify_all();
+    }
+
+    pub fn resume(&self) {
+        self.interrupted.store(false, Ordering::SeqCst);
+    }
+
+    /// Send requests made outside of the dispatch thread to the compositor.
+    pub fn flush(&self) {
+        if let Err(err) = self.connection.flush() {
+            gstreamer::debug!(CAT, "failed to flush connection: {}", err);
+        }
+    }
+
+    fn capture_output(&self, state: &mut WaylandState) -> Result<(), SessionError> {
+        let Some((output, _)) = state.output(self.output_name.as_deref()) else {
+            return Err(state.output_not_found(self.output_name.as_deref()));
+        };
+
+        let frame = match self.region {
+            Some(region) => state.wlr_screencopy_manager.capture_output_region(
+                self.overlay_cursor as i32,
+                output,
+                region.x as i32,
+                region.y as i32,
+                region.width as i32,
+                region.height as i32,
+                &state.qhandle,
+                (),
+            ),
+            None => state.wlr_screencopy_manager.capture_output(
+                self.overlay_cursor as i32,
+                output,
+                &state.qhandle,
+                (),
+            ),
+        };
+        protocol_log::request(
+            &state.wlr_screencopy_manager,
+            format_args!(
+                "capture_output({}, {}, {:?}) -> {}",
+                self.overlay_cursor,
+                output.id(),
+                self.region,
+                frame.id()
+            ),
+        );
+        state.current_frame = Some((frame, Default::default()));
+        Ok(())
+    }
+
+    /// Wait until the dispatch thread made `done` true, `deadline` passed or the
+    /// connection failed.
+    fn wait_for<'a>(
+        &self,
+        mut state: MutexGuard<'a, WaylandState>,
+        deadline: Option<Instant>,
+        done: impl Fn(&WaylandState) -> bool,
+    ) -> Result<(MutexGuard<'a, WaylandState>, Wait), SessionError> {
+        loop {
+            if self.interrupted.load(Ordering::SeqCst) {
+                return Err(SessionError::Interrupted);
+            }
+            if let Some(err) = state.dispatch_error.take() {
+                return Err(SessionError::Dispatch(err));
+            }
+            if done(&state) {
+                return Ok((state, Wait::Done));
+            }
+
+            state = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Ok((state, Wait::Timeout));
+                    }
+                    self.state_cond
+                        .wait_timeout(state, deadline - now)
+                        .unwrap()
+                        .0
+                }
+                None => self.state_cond.wait(state).unwrap(),
+            };
+        }
+    }
+}
+
+impl Drop for ScreencopySession {
+    fn drop(&mut self) {
+        // Stop dispatching before the proxies go away
+        self.dispatch_listener.get_mut().unwrap().take();
+
+        let state = self.state.get_mut().unwrap();
+        if let Some((frame, _)) = state.current_frame.take() {
+            frame.destroy();
+        }
+        for output_power in state.output_powers.drain(..) {
+            output_power.power.destroy();
+        }
+        if let Some(output_power_manager) = state.output_power_manager.take() {
+            output_power_manager.destroy();
+        }
+        for (output, zxdg_output, _) in state.outputs.drain(..) {
+            if let Some(zxdg_output) = zxdg_output {
+                zxdg_output.destroy();
+            }
+            if output.version() >= 3 {
+                output.release();
+            }
+        }
+        if let Some((dmabuf_feedback, _)) = state.dmabuf_feedback.take() {
+            dmabuf_feedback.destroy();
+        }
+        if let Some(dmabuf) = state.dmabuf.take() {
+            dmabuf.destroy();
+        }
+        state.wlr_screencopy_manager.destroy();
+        if let Some(virtual_pointer_manager) = state.virtual_pointer_manager.take() {
+            virtual_pointer_manager.destroy();
+        }
+        for (toplevel, _) in state.toplevels.drain(..) {
+            toplevel.destroy();
+        }
+        if let Some(foreign_toplevel_manager) = state.foreign_toplevel_manager.take() {
+            // The compositor destroys the manager after sending finished
+            foreign_toplevel_manager.stop();
+        }
+
+        // Make sure the destructors reach the compositor before the connection is released
+        self.flush();
+    }
+}
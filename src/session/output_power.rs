@@ -0,0 +1,112 @@
+//! Tracking the power mode of outputs through wlr-output-power-management.
+//!
+//! Compositors stop rendering powered off outputs, frames of them fail or are
+//! never copied until the output is powered on again.
+
+use std::time::Instant;
+
+use wayland_client::protocol::wl_output::WlOutput;
+use wayland_client::{Connection, Dispatch, Proxy, QueueHandle, WEnum};
+use wayland_protocols_wlr::output_power_management::v1::client::{
+    zwlr_output_power_manager_v1::ZwlrOutputPowerManagerV1,
+    zwlr_output_power_v1::{self, ZwlrOutputPowerV1},
+};
+
+use super::state::WaylandState;
+use super::{protocol_log, ScreencopySession, SessionError, Wait};
+
+#[derive(Debug)]
+pub(super) struct OutputPower {
+    pub(super) power: ZwlrOutputPowerV1,
+    pub(super) output: WlOutput,
+    /// `false` once the compositor reported the output as powered off
+    pub(super) on: bool,
+}
+
+impl WaylandState {
+    /// Whether the power mode of `output` is known to be off
+    pub(super) fn is_powered_off(&self, output: &WlOutput) -> bool {
+        self.output_powers
+            .iter()
+            .any(|output_power| &output_power.output == output && !output_power.on)
+    }
+}
+
+impl ScreencopySession {
+    /// Whether the compositor reports the power mode of its outputs, without it
+    /// [`is_powered_off`](Self::is_powered_off) is always `false`
+    pub fn supports_output_power(&self) -> bool {
+        self.state.lock().unwrap().output_power_manager.is_some()
+    }
+
+    /// Whether the captured output is powered off, the compositor does not render
+    /// it and copies fail or never complete
+    pub fn is_powered_off(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state
+            .output(self.output_name.as_deref())
+            .map(|(output, _)| state.is_powered_off(output))
+            .unwrap_or(false)
+    }
+
+    /// Wait until the captured output is powered on again, returns `false` if
+    /// `deadline` passed first.
+    pub fn wait_powered_on(&self, deadline: Option<Instant>) -> Result<bool, SessionError> {
+        let state = self.state.lock().unwrap();
+        let (_state, wait) = self.wait_for(state, deadline, |state| {
+            state
+                .output(self.output_name.as_deref())
+                .map(|(output, _)| !state.is_powered_off(output))
+                .unwrap_or(true)
+        })?;
+        Ok(matches!(wait, Wait::Done))
+    }
+}
+
+impl Dispatch<ZwlrOutputPowerManagerV1, ()> for WaylandState {
+    fn event(
+        _state: &mut Self,
+        proxy: &ZwlrOutputPowerManagerV1,
+        event: <ZwlrOutputPowerManagerV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        protocol_log::event(proxy, &event);
+    }
+}
+
+impl Dispatch<ZwlrOutputPowerV1, ()> for WaylandState {
+    fn event(
+        state: &mut Self,
+        proxy: &ZwlrOutputPowerV1,
+        event: <ZwlrOutputPowerV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        protocol_log::event(proxy, &event);
+        let Some(index) = state
+            .output_powers
+            .iter()
+            .position(|output_power| &output_power.power == proxy)
+        else {
+            return;
+        };
+
+        match event {
+            zwlr_output_power_v1::Event::Mode { mode } => {
+                state.output_powers[index].on =
+                    mode != WEnum::Value(zwlr_output_power_v1::Mode::Off);
+            }
+            zwlr_output_power_v1::Event::Failed => {
+                // The output does not support power management or went away,
+                // its frames are handled like the ones of any other output
+                let output_power = state.output_powers.remove(index);
+                protocol_log::request(&output_power.power, format_args!("destroy()"));
+                output_power.power.destroy();
+            }
+            _ => (),
+        }
+    }
+}
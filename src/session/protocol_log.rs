@@ -0,0 +1,44 @@
+//! Logging of the Wayland messages of a session, similar to `WAYLAND_DEBUG`.
+//!
+//! Messages are logged to the `wlrscreencopyprotocol` debug category at level
+//! `LOG`, so they can be enabled with `GST_DEBUG=wlrscreencopyprotocol:6` or at
+//! runtime without affecting other Wayland clients of the process.
+
+use std::fmt::Debug;
+
+use once_cell::sync::Lazy;
+use wayland_client::backend::{io_lifetimes::OwnedFd, protocol::Message, ObjectId};
+use wayland_client::Proxy;
+
+pub(crate) static PROTOCOL_CAT: Lazy<gstreamer::DebugCategory> = Lazy::new(|| {
+    gstreamer::DebugCategory::new(
+        "wlrscreencopyprotocol",
+        gstreamer::DebugColorFlags::empty(),
+        Some("wlr-screencopy Wayland protocol messages"),
+    )
+});
+
+/// Log an event received by `proxy`.
+pub(crate) fn event<I>(proxy: &I, event: &I::Event)
+where
+    I: Proxy,
+    I::Event: Debug,
+{
+    gstreamer::log!(PROTOCOL_CAT, "<- {} {:?}", proxy.id(), event);
+}
+
+/// Log an event received by an object with raw object data.
+pub(crate) fn message(msg: &Message<ObjectId, OwnedFd>) {
+    gstreamer::log!(
+        PROTOCOL_CAT,
+        "<- {} opcode {} {:?}",
+        msg.sender_id,
+        msg.opcode,
+        msg.args
+    );
+}
+
+/// Log a request sent to `proxy`, `request` is the name and arguments of it.
+pub(crate) fn request<I: Proxy>(proxy: &I, request: std::fmt::Arguments<'_>) {
+    gstreamer::log!(PROTOCOL_CAT, "-> {}.{}", proxy.id(), request);
+}
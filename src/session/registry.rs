@@ -0,0 +1,98 @@
+//! Collecting the globals of the compositor while a session is set up.
+//!
+//! The session state can only be created once the globals it needs are bound, so
+//! the initial globals are announced to a `wl_registry` on an event queue of its
+//! own. A `wl_display.sync` tells when all of them have been announced.
+
+use std::ops::RangeInclusive;
+
+use wayland_client::globals::{BindError, Global};
+use wayland_client::protocol::{wl_callback, wl_registry};
+use wayland_client::{Connection, Dispatch, Proxy, QueueHandle};
+
+use super::protocol_log;
+use super::state::WaylandState;
+
+/// Globals announced by the compositor until it answered the sync
+#[derive(Debug, Default)]
+pub(super) struct Globals {
+    pub(super) list: Vec<Global>,
+    /// Set once the compositor answered the sync, every global has been announced
+    pub(super) done: bool,
+}
+
+impl Globals {
+    /// Bind the first global implementing `I` with the highest version in `version`
+    /// the compositor supports.
+    pub(super) fn bind<I, U>(
+        &self,
+        registry: &wl_registry::WlRegistry,
+        qhandle: &QueueHandle<WaylandState>,
+        version: RangeInclusive<u32>,
+        udata: U,
+    ) -> Result<I, BindError>
+    where
+        I: Proxy + 'static,
+        WaylandState: Dispatch<I, U>,
+        U: Send + Sync + 'static,
+    {
+        let global = self
+            .list
+            .iter()
+            .find(|global| global.interface == I::interface().name)
+            .ok_or(BindError::NotPresent)?;
+        if global.version < *version.start() {
+            return Err(BindError::UnsupportedVersion);
+        }
+        Ok(registry.bind(
+            global.name,
+            std::cmp::min(global.version, *version.end()),
+            qhandle,
+            udata,
+        ))
+    }
+}
+
+impl Dispatch<wl_registry::WlRegistry, ()> for Globals {
+    fn event(
+        state: &mut Self,
+        proxy: &wl_registry::WlRegistry,
+        event: wl_registry::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        protocol_log::event(proxy, &event);
+        match event {
+            wl_registry::Event::Global {
+                name,
+                interface,
+                version,
+            } => state.list.push(Global {
+                name,
+                interface,
+                version,
+            }),
+            wl_registry::Event::GlobalRemove { name } => {
+                state.list.retain(|global| global.name != name)
+            }
+            _ => (),
+        }
+    }
+}
+
+impl Dispatch<wl_callback::WlCallback, ()> for Globals {
+    fn event(
+        state: &mut Self,
+        proxy: &wl_callback::WlCallback,
+        event: wl_callback::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        protocol_log::event(proxy, &event);
+        if let wl_callback::Event::Done { .. } = event {
+            state.done = true;
+        }
+    }
+}
@@ -0,0 +1,479 @@
+//! Wayland objects of a session and the handling of their events.
+
+use std::collections::HashMap;
+
+use wayland_client::{protocol::wl_registry, Connection, Dispatch, Proxy};
+use wayland_client::{QueueHandle, Weak};
+
+use super::output_power::OutputPower;
+use super::toplevel::Toplevel;
+use super::{
+    output_name_matches, protocol_log, BufferFormats, DmabufFormat, FrameState, OutputInfo, Rect,
+    SessionError, ShmFormat, CAT,
+};
+
+#[derive(Debug, Default)]
+pub(super) struct FrameInfo {
+    pub(super) formats: BufferFormats,
+    /// Set once all buffer formats have been announced
+    pub(super) done: bool,
+    pub(super) state: Option<FrameState>,
+    pub(super) flags:
+        Option<wayland_protocols_wlr::screencopy::v1::client::zwlr_screencopy_frame_v1::Flags>,
+    /// Damage since the previous copy, only reported for copy_with_damage
+    pub(super) damage: Vec<Rect>,
+}
+
+/// Default feedback of zwp_linux_dmabuf_v1 version 4
+#[derive(Debug, Default)]
+pub(super) struct DmabufFeedback {
+    /// `(format, modifier)` pairs the tranches refer to by index
+    pub(super) format_table: Vec<(u32, u64)>,
+    /// `dev_t` of the device the compositor composites on
+    pub(super) main_device: Option<u64>,
+    /// Set once the first batch of feedback has been received
+    pub(super) done: bool,
+}
+
+impl DmabufFeedback {
+    /// Read the format table, it is shared with other clients so the file offset
+    /// must not be touched.
+    fn read_format_table(fd: std::os::fd::OwnedFd, size: u32) -> std::io::Result<Vec<(u32, u64)>> {
+        use std::os::unix::fs::FileExt;
+
+        let file = std::fs::File::from(fd);
+        let mut table = vec![0u8; size as usize];
+        file.read_exact_at(&mut table, 0)?;
+        // Every entry is a u32 format, 4 bytes padding and a u64 modifier
+        Ok(table
+            .chunks_exact(16)
+            .map(|entry| {
+                (
+                    u32::from_ne_bytes(entry[0..4].try_into().unwrap()),
+                    u64::from_ne_bytes(entry[8..16].try_into().unwrap()),
+                )
+            })
+            .collect())
+    }
+}
+
+/// Parse a `dev_t` sent as array by the dmabuf feedback
+fn parse_dev_t(device: &[u8]) -> Option<u64> {
+    Some(u64::from_ne_bytes(device.try_into().ok()?))
+}
+
+#[derive(Debug)]
+pub(super) struct WaylandState {
+    pub(super) wl_shm: wayland_client::protocol::wl_shm::WlShm,
+    pub(super) dmabuf: Option<wayland_protocols::wp::linux_dmabuf::zv1::client::zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1>,
+    pub(super) wlr_screencopy_manager: wayland_protocols_wlr::screencopy::v1::client::zwlr_screencopy_manager_v1::ZwlrScreencopyManagerV1,
+    pub(super) outputs: Vec<(wayland_client::protocol::wl_output::WlOutput, Option<wayland_protocols::xdg::xdg_output::zv1::client::zxdg_output_v1::ZxdgOutputV1>, OutputInfo)>,
+    pub(super) current_frame: Option<(wayland_protocols_wlr::screencopy::v1::client::zwlr_screencopy_frame_v1::ZwlrScreencopyFrameV1, FrameInfo)>,
+    /// Buffer formats of the last frame while capturing is stopped
+    pub(super) stopped_formats: Option<BufferFormats>,
+    /// Set after the compositor failed to copy into a dmabuf, only shm is used afterwards
+    pub(super) dmabuf_rejected: bool,
+    /// Modifiers advertised by zwp_linux_dmabuf_v1 per DRM fourcc
+    pub(super) dmabuf_modifiers: HashMap<u32, Vec<u64>>,
+    /// Replaces the format and modifier events starting with version 4
+    pub(super) dmabuf_feedback: Option<(wayland_protocols::wp::linux_dmabuf::zv1::client::zwp_linux_dmabuf_feedback_v1::ZwpLinuxDmabufFeedbackV1, DmabufFeedback)>,
+    pub(super) wl_seat: Option<wayland_client::protocol::wl_seat::WlSeat>,
+    pub(super) virtual_pointer_manager: Option<wayland_protocols_wlr::virtual_pointer::v1::client::zwlr_virtual_pointer_manager_v1::ZwlrVirtualPointerManagerV1>,
+    pub(super) virtual_keyboard_manager: Option<wayland_protocols_misc::zwp_virtual_keyboard_v1::client::zwp_virtual_keyboard_manager_v1::ZwpVirtualKeyboardManagerV1>,
+    pub(super) foreign_toplevel_manager: Option<wayland_protocols_wlr::foreign_toplevel::v1::client::zwlr_foreign_toplevel_manager_v1::ZwlrForeignToplevelManagerV1>,
+    pub(super) toplevels: Vec<(wayland_protocols_wlr::foreign_toplevel::v1::client::zwlr_foreign_toplevel_handle_v1::ZwlrForeignToplevelHandleV1, Toplevel)>,
+    pub(super) output_power_manager: Option<wayland_protocols_wlr::output_power_management::v1::client::zwlr_output_power_manager_v1::ZwlrOutputPowerManagerV1>,
+    /// Power mode of the outputs, only while the manager is bound
+    pub(super) output_powers: Vec<OutputPower>,
+    /// Set by the dispatch thread when the connection failed
+    pub(super) dispatch_error: Option<wayland_client::DispatchError>,
+
+    pub(super) qhandle: QueueHandle<WaylandState>,
+}
+
+impl WaylandState {
+    pub(super) fn output_info_mut(&mut self, output_name: Option<&str>) -> Option<&mut OutputInfo> {
+        let output = if let Some(output_name) = output_name {
+            self.outputs
+                .iter_mut()
+                .find(|(_, _, info)| output_name_matches(output_name, &info.name))
+        } else {
+            self.outputs.first_mut()
+        };
+        output.map(|(_, _, info)| info)
+    }
+
+    /// The first output matching `output_name`, or the first output if no name is given
+    pub(super) fn output(
+        &self,
+        output_name: Option<&str>,
+    ) -> Option<(&wayland_client::protocol::wl_output::WlOutput, &OutputInfo)> {
+        let output = if let Some(output_name) = output_name {
+            self.outputs
+                .iter()
+                .find(|(_, _, info)| output_name_matches(output_name, &info.name))
+        } else {
+            self.outputs.first()
+        };
+        output.map(|(output, _, info)| (output, info))
+    }
+
+    /// Buffer formats of the scheduled frame, or of the last frame if capturing is stopped
+    pub(super) fn frame_formats(&self) -> Option<&BufferFormats> {
+        self.current_frame
+            .as_ref()
+            .filter(|(_, info)| info.done)
+            .map(|(_, info)| &info.formats)
+            .or(self.stopped_formats.as_ref())
+    }
+
+    /// Error for a missing output, listing the available outputs
+    pub(super) fn output_not_found(&self, output_name: Option<&str>) -> SessionError {
+        SessionError::OutputNotFound {
+            name: output_name.map(String::from),
+            available: self
+                .outputs
+                .iter()
+                .map(|(_, _, info)| info.name.clone())
+                .collect(),
+        }
+    }
+}
+
+impl Dispatch<wayland_client::protocol::wl_output::WlOutput, ()> for WaylandState {
+    fn event(
+        state: &mut Self,
+        proxy: &wayland_client::protocol::wl_output::WlOutput,
+        event: <wayland_client::protocol::wl_output::WlOutput as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &wayland_client::QueueHandle<Self>,
+    ) {
+        protocol_log::event(proxy, &event);
+        // Events can still be queued for an output whose global was removed
+        let Some((_, zxdg_output, output_info)) = state
+            .outputs
+            .iter_mut()
+            .find(|(output, _, _)| output == proxy)
+        else {
+            return;
+        };
+
+        match event {
+            wayland_client::protocol::wl_output::Event::Geometry {
+                x,
+                y,
+                physical_width,
+                physical_height,
+                transform,
+                ..
+            } => {
+                // xdg_output knows better if available
+                if zxdg_output.is_none() {
+                    output_info.logical_position = Some((x, y));
+                }
+                output_info.physical_size = (physical_width > 0 && physical_height > 0)
+                    .then_some((physical_width, physical_height));
+                output_info.transform = transform.into_result().ok();
+            }
+            wayland_client::protocol::wl_output::Event::Mode {
+                flags,
+                width,
+                height,
+                refresh,
+            } => {
+                if let Ok(flags) = flags.into_result() {
+                    if flags.contains(wayland_client::protocol::wl_output::Mode::Current) {
+                        // A new current mode after the initial burst of events means the
+                        // user switched resolution or refresh rate while we are streaming
+                        if output_info.done
+                            && (output_info.mode.width != width
+                                || output_info.mode.height != height
+                                || output_info.mode.refresh != refresh)
+                        {
+                            output_info.mode_changed = true;
+                        }
+                        output_info.mode.width = width;
+                        output_info.mode.height = height;
+                        output_info.mode.refresh = refresh;
+                    }
+                }
+            }
+            wayland_client::protocol::wl_output::Event::Done => {
+                output_info.done = true;
+            }
+            wayland_client::protocol::wl_output::Event::Scale { factor } => {
+                output_info.scale = factor
+            }
+            wayland_client::protocol::wl_output::Event::Name { name } => output_info.name = name,
+            wayland_client::protocol::wl_output::Event::Description { description } => {
+                output_info.description = description
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl Dispatch<wayland_protocols_wlr::screencopy::v1::client::zwlr_screencopy_manager_v1::ZwlrScreencopyManagerV1, ()> for WaylandState {
+    fn event(
+        _state: &mut Self,
+        proxy: &wayland_protocols_wlr::screencopy::v1::client::zwlr_screencopy_manager_v1::ZwlrScreencopyManagerV1,
+        event: <wayland_protocols_wlr::screencopy::v1::client::zwlr_screencopy_manager_v1::ZwlrScreencopyManagerV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &wayland_client::QueueHandle<Self>,
+    ) {
+        protocol_log::event(proxy, &event);
+        // No events to handle
+    }
+}
+
+impl Dispatch<wayland_protocols_wlr::screencopy::v1::client::zwlr_screencopy_frame_v1::ZwlrScreencopyFrameV1, ()> for WaylandState {
+    fn event(
+        state: &mut Self,
+        proxy: &wayland_protocols_wlr::screencopy::v1::client::zwlr_screencopy_frame_v1::ZwlrScreencopyFrameV1,
+        event: <wayland_protocols_wlr::screencopy::v1::client::zwlr_screencopy_frame_v1::ZwlrScreencopyFrameV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &wayland_client::QueueHandle<Self>,
+    ) {
+        protocol_log::event(proxy, &event);
+        let Some((frame, frame_info)) = state.current_frame.as_mut() else {
+            gstreamer::trace!(CAT, "ignoring event of {} without a pending frame", proxy.id());
+            return;
+        };
+        if frame != proxy {
+            // Only one frame is requested at a time, the compositor mixed them up
+            gstreamer::warning!(CAT, "event of {} while capturing {}", proxy.id(), frame.id());
+            frame_info.state = Some(FrameState::Failed);
+            return;
+        }
+
+        match event {
+            wayland_protocols_wlr::screencopy::v1::client::zwlr_screencopy_frame_v1::Event::Buffer { format, width, height, stride } => {
+                if let Ok(format) = format.into_result() {
+                    frame_info.formats.shm.push(ShmFormat { format, width, height, stride });
+                }
+            },
+            wayland_protocols_wlr::screencopy::v1::client::zwlr_screencopy_frame_v1::Event::Flags { flags } => {
+                match flags.into_result() {
+                    Ok(flags) => frame_info.flags = Some(flags),
+                    Err(err) => {
+                        gstreamer::warning!(CAT, "invalid frame flags: {:?}", err);
+                        frame_info.state = Some(FrameState::Failed);
+                    }
+                }
+            },
+            wayland_protocols_wlr::screencopy::v1::client::zwlr_screencopy_frame_v1::Event::Ready { tv_sec_hi, tv_sec_lo, tv_nsec } => {
+                let secs = (tv_sec_hi as u64) << 32 | tv_sec_lo as u64;
+                // Keeps a frame failed by invalid events failed
+                frame_info.state.get_or_insert(FrameState::Ready(std::time::Duration::new(secs, tv_nsec)));
+            },
+            wayland_protocols_wlr::screencopy::v1::client::zwlr_screencopy_frame_v1::Event::Failed => {
+                frame_info.state = Some(FrameState::Failed);
+            },
+            wayland_protocols_wlr::screencopy::v1::client::zwlr_screencopy_frame_v1::Event::Damage { x, y, width, height } => {
+                frame_info.damage.push(Rect { x, y, width, height });
+            },
+            wayland_protocols_wlr::screencopy::v1::client::zwlr_screencopy_frame_v1::Event::LinuxDmabuf { format, width, height } => {
+                frame_info.formats.dmabuf.push(DmabufFormat { format, width, height });
+            },
+            wayland_protocols_wlr::screencopy::v1::client::zwlr_screencopy_frame_v1::Event::BufferDone =>  frame_info.done = true,
+            _ => gstreamer::trace!(CAT, "ignoring unknown frame event"),
+        }
+    }
+}
+
+impl wayland_client::Dispatch<wl_registry::WlRegistry, ()> for WaylandState {
+    fn event(
+        state: &mut WaylandState,
+        proxy: &wl_registry::WlRegistry,
+        event: wl_registry::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<WaylandState>,
+    ) {
+        protocol_log::event(proxy, &event);
+        // New outputs are only picked up by a new session, a removed one must not be
+        // captured anymore
+        let wl_registry::Event::GlobalRemove { name } = event else {
+            return;
+        };
+        let Some(index) = state
+            .outputs
+            .iter()
+            .position(|(_, _, info)| info.global == name)
+        else {
+            return;
+        };
+        let (output, zxdg_output, info) = state.outputs.remove(index);
+        gstreamer::debug!(CAT, "output {} removed", info.name);
+        state.output_powers.retain(|output_power| {
+            if output_power.output != output {
+                return true;
+            }
+            protocol_log::request(&output_power.power, format_args!("destroy()"));
+            output_power.power.destroy();
+            false
+        });
+        if let Some(zxdg_output) = zxdg_output {
+            zxdg_output.destroy();
+        }
+        if output.version() >= 3 {
+            output.release();
+        }
+    }
+}
+
+impl wayland_client::Dispatch<wayland_client::protocol::wl_shm::WlShm, ()> for WaylandState {
+    fn event(
+        _state: &mut Self,
+        proxy: &wayland_client::protocol::wl_shm::WlShm,
+        event: <wayland_client::protocol::wl_shm::WlShm as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        protocol_log::event(proxy, &event);
+        // We completely ignore the formats and rely on the compositor to only send
+        // shm frame formats it supports
+    }
+}
+
+impl
+    wayland_client::Dispatch<
+        wayland_protocols::wp::linux_dmabuf::zv1::client::zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1,
+        (),
+    > for WaylandState
+{
+    fn event(
+        state: &mut Self,
+        proxy: &wayland_protocols::wp::linux_dmabuf::zv1::client::zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1,
+        event: <wayland_protocols::wp::linux_dmabuf::zv1::client::zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        protocol_log::event(proxy, &event);
+        // We rely on the compositor to only send dmabuf frame formats it supports,
+        // but remember the modifiers so we can allocate buffers it can import
+        if let wayland_protocols::wp::linux_dmabuf::zv1::client::zwp_linux_dmabuf_v1::Event::Modifier { format, modifier_hi, modifier_lo } = event {
+            let modifier = (modifier_hi as u64) << 32 | modifier_lo as u64;
+            state.dmabuf_modifiers.entry(format).or_default().push(modifier);
+        }
+    }
+}
+
+impl
+    wayland_client::Dispatch<
+        wayland_protocols::wp::linux_dmabuf::zv1::client::zwp_linux_dmabuf_feedback_v1::ZwpLinuxDmabufFeedbackV1,
+        (),
+    > for WaylandState
+{
+    fn event(
+        state: &mut Self,
+        proxy: &wayland_protocols::wp::linux_dmabuf::zv1::client::zwp_linux_dmabuf_feedback_v1::ZwpLinuxDmabufFeedbackV1,
+        event: <wayland_protocols::wp::linux_dmabuf::zv1::client::zwp_linux_dmabuf_feedback_v1::ZwpLinuxDmabufFeedbackV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        protocol_log::event(proxy, &event);
+        use wayland_protocols::wp::linux_dmabuf::zv1::client::zwp_linux_dmabuf_feedback_v1::Event;
+
+        let Some((_, feedback)) = state.dmabuf_feedback.as_mut() else {
+            return;
+        };
+
+        match event {
+            Event::FormatTable { fd, size } => {
+                match DmabufFeedback::read_format_table(fd, size) {
+                    Ok(format_table) => feedback.format_table = format_table,
+                    Err(err) => {
+                        gstreamer::warning!(CAT, "failed to read dmabuf format table: {}", err);
+                        feedback.format_table.clear();
+                    }
+                }
+            }
+            Event::MainDevice { device } => {
+                feedback.main_device = parse_dev_t(&device);
+                gstreamer::debug!(CAT, "dmabuf main device {:?}", feedback.main_device);
+            }
+            Event::TrancheFormats { indices } => {
+                // Same as the modifier events of older versions, all tranches are
+                // importable by the compositor
+                for index in indices.chunks_exact(2) {
+                    let index = u16::from_ne_bytes([index[0], index[1]]) as usize;
+                    if let Some((format, modifier)) = feedback.format_table.get(index).copied() {
+                        let modifiers = state.dmabuf_modifiers.entry(format).or_default();
+                        if !modifiers.contains(&modifier) {
+                            modifiers.push(modifier);
+                        }
+                    }
+                }
+            }
+            Event::Done => feedback.done = true,
+            _ => (),
+        }
+    }
+}
+
+impl wayland_client::Dispatch<wayland_protocols::xdg::xdg_output::zv1::client::zxdg_output_manager_v1::ZxdgOutputManagerV1, ()> for WaylandState {
+    fn event(
+        _state: &mut Self,
+        proxy: &wayland_protocols::xdg::xdg_output::zv1::client::zxdg_output_manager_v1::ZxdgOutputManagerV1,
+        event: <wayland_protocols::xdg::xdg_output::zv1::client::zxdg_output_manager_v1::ZxdgOutputManagerV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        protocol_log::event(proxy, &event);
+       // No events 
+    }
+}
+
+impl
+    wayland_client::Dispatch<
+        wayland_protocols::xdg::xdg_output::zv1::client::zxdg_output_v1::ZxdgOutputV1,
+        Weak<wayland_client::protocol::wl_output::WlOutput>,
+    > for WaylandState
+{
+    fn event(
+        state: &mut Self,
+        proxy: &wayland_protocols::xdg::xdg_output::zv1::client::zxdg_output_v1::ZxdgOutputV1,
+        event: <wayland_protocols::xdg::xdg_output::zv1::client::zxdg_output_v1::ZxdgOutputV1 as Proxy>::Event,
+        data: &Weak<wayland_client::protocol::wl_output::WlOutput>,
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        protocol_log::event(proxy, &event);
+        let Some((_, _, output_info)) = state
+            .outputs
+            .iter_mut()
+            .find(|(output, _, _)| output == data)
+        else {
+            // The output was removed while the compositor still described it
+            gstreamer::trace!(CAT, "ignoring event of {} for a removed output", proxy.id());
+            return;
+        };
+
+        match event {
+            wayland_protocols::xdg::xdg_output::zv1::client::zxdg_output_v1::Event::LogicalPosition { x, y } => {
+                output_info.logical_position = Some((x, y));
+            },
+            wayland_protocols::xdg::xdg_output::zv1::client::zxdg_output_v1::Event::LogicalSize { width, height } => {
+                output_info.logical_size = Some((width, height));
+            },
+            wayland_protocols::xdg::xdg_output::zv1::client::zxdg_output_v1::Event::Done => {
+                output_info.done = true;
+            },
+            wayland_protocols::xdg::xdg_output::zv1::client::zxdg_output_v1::Event::Name { name } => {
+                output_info.name = name;
+            },
+            wayland_protocols::xdg::xdg_output::zv1::client::zxdg_output_v1::Event::Description { description } => {
+                output_info.description = description;
+            },
+            _ => unreachable!(),
+        }
+    }
+}
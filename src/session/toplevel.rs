@@ -0,0 +1,132 @@
+//! Tracking of the toplevels of the compositor through wlr-foreign-toplevel-management.
+
+use wayland_client::protocol::wl_output::WlOutput;
+use wayland_client::{Connection, Dispatch, Proxy, QueueHandle};
+use wayland_protocols_wlr::foreign_toplevel::v1::client::{
+    zwlr_foreign_toplevel_handle_v1::{self, ZwlrForeignToplevelHandleV1},
+    zwlr_foreign_toplevel_manager_v1::{self, ZwlrForeignToplevelManagerV1},
+};
+
+use super::state::WaylandState;
+use super::{protocol_log, ScreencopySession};
+
+/// State of a toplevel as of the last `done` event
+#[derive(Debug, Default, Clone)]
+pub(super) struct ToplevelInfo {
+    pub(super) app_id: String,
+    pub(super) outputs: Vec<WlOutput>,
+    pub(super) activated: bool,
+}
+
+#[derive(Debug, Default)]
+pub(super) struct Toplevel {
+    pub(super) current: ToplevelInfo,
+    /// Changes applied with the next `done` event
+    pending: ToplevelInfo,
+}
+
+impl ScreencopySession {
+    /// Whether the compositor announces its toplevels, without it
+    /// [`activated_app_ids`](Self::activated_app_ids) is always empty
+    pub fn supports_toplevels(&self) -> bool {
+        self.state
+            .lock()
+            .unwrap()
+            .foreign_toplevel_manager
+            .is_some()
+    }
+
+    /// App ids of the activated toplevels on the captured output
+    pub fn activated_app_ids(&self) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        let Some((output, _)) = state.output(self.output_name.as_deref()) else {
+            return Vec::new();
+        };
+        state
+            .toplevels
+            .iter()
+            .map(|(_, toplevel)| &toplevel.current)
+            .filter(|info| info.activated && info.outputs.contains(output))
+            .map(|info| info.app_id.clone())
+            .collect()
+    }
+}
+
+impl Dispatch<ZwlrForeignToplevelManagerV1, ()> for WaylandState {
+    fn event(
+        state: &mut Self,
+        proxy: &ZwlrForeignToplevelManagerV1,
+        event: <ZwlrForeignToplevelManagerV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        protocol_log::event(proxy, &event);
+        match event {
+            zwlr_foreign_toplevel_manager_v1::Event::Toplevel { toplevel } => {
+                state.toplevels.push((toplevel, Default::default()));
+            }
+            zwlr_foreign_toplevel_manager_v1::Event::Finished => {
+                state.foreign_toplevel_manager = None;
+            }
+            _ => (),
+        }
+    }
+
+    wayland_client::event_created_child!(WaylandState, ZwlrForeignToplevelManagerV1, [
+        zwlr_foreign_toplevel_manager_v1::EVT_TOPLEVEL_OPCODE => (ZwlrForeignToplevelHandleV1, ()),
+    ]);
+}
+
+impl Dispatch<ZwlrForeignToplevelHandleV1, ()> for WaylandState {
+    fn event(
+        state: &mut Self,
+        proxy: &ZwlrForeignToplevelHandleV1,
+        event: <ZwlrForeignToplevelHandleV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        protocol_log::event(proxy, &event);
+        let Some(index) = state
+            .toplevels
+            .iter()
+            .position(|(handle, _)| handle == proxy)
+        else {
+            return;
+        };
+        let toplevel = &mut state.toplevels[index].1;
+
+        match event {
+            zwlr_foreign_toplevel_handle_v1::Event::AppId { app_id } => {
+                toplevel.pending.app_id = app_id;
+            }
+            zwlr_foreign_toplevel_handle_v1::Event::OutputEnter { output }
+                if !toplevel.pending.outputs.contains(&output) =>
+            {
+                toplevel.pending.outputs.push(output);
+            }
+            zwlr_foreign_toplevel_handle_v1::Event::OutputLeave { output } => {
+                toplevel
+                    .pending
+                    .outputs
+                    .retain(|entered| entered != &output);
+            }
+            zwlr_foreign_toplevel_handle_v1::Event::State { state: states } => {
+                toplevel.pending.activated = states
+                    .chunks_exact(4)
+                    .map(|value| u32::from_ne_bytes([value[0], value[1], value[2], value[3]]))
+                    .any(|value| value == zwlr_foreign_toplevel_handle_v1::State::Activated as u32);
+            }
+            zwlr_foreign_toplevel_handle_v1::Event::Done => {
+                toplevel.current = toplevel.pending.clone();
+            }
+            zwlr_foreign_toplevel_handle_v1::Event::Closed => {
+                let (handle, _) = state.toplevels.remove(index);
+                protocol_log::request(&handle, format_args!("destroy()"));
+                handle.destroy();
+            }
+            _ => (),
+        }
+    }
+}
@@ -1,63 +1,316 @@
-use gstreamer_video::VideoFormat;
+use gstreamer::glib;
+use gstreamer_video::{
+    VideoColorMatrix, VideoColorPrimaries, VideoColorRange, VideoColorimetry, VideoFormat,
+    VideoFormatInfo, VideoInfo, VideoTransferFunction,
+};
 
 use wayland_client::protocol::wl_shm;
 
+const fn fourcc(code: &[u8; 4]) -> u32 {
+    u32::from_le_bytes(*code)
+}
+
+const DRM_FORMAT_ARGB8888: u32 = fourcc(b"AR24");
+const DRM_FORMAT_XRGB8888: u32 = fourcc(b"XR24");
+
+/// `DRM_FORMAT_BIG_ENDIAN`, flags a fourcc as packed into a big-endian word
+pub const DRM_FORMAT_BIG_ENDIAN: u32 = 1 << 31;
+
+/// `code` packed into a native-endian word, the layout of the native-endian
+/// formats of GStreamer
+const fn native_endian(code: u32) -> u32 {
+    if cfg!(target_endian = "big") {
+        code | DRM_FORMAT_BIG_ENDIAN
+    } else {
+        code
+    }
+}
+
+/// `DRM_FORMAT_MOD_INVALID`, announced by compositors using implicit modifiers
+pub const DRM_FORMAT_MOD_INVALID: u64 = 0x00ff_ffff_ffff_ffff;
+/// `DRM_FORMAT_MOD_LINEAR`
+pub const DRM_FORMAT_MOD_LINEAR: u64 = 0;
+
+/// Format field of `DMA_DRM` caps, the layout is given by `drm-format`
+pub const DMA_DRM_FORMAT: &str = "DMA_DRM";
+
+/// Formats GStreamer and the compositor can both express, as video format and
+/// DRM fourcc. Every format is listed once, a format missing here can neither be
+/// captured through wl_shm nor through dmabufs.
+///
+/// DRM formats give the layout of a pixel packed into a little-endian word,
+/// GStreamer names 8 bit components in memory order, so `ARGB8888` is stored as
+/// B, G, R, A which is `BGRA`. Components wider than a byte are little-endian on
+/// both sides, the 16 bit RGB formats of GStreamer are native-endian though and
+/// map to the [`DRM_FORMAT_BIG_ENDIAN`] variants on big-endian hosts, which
+/// neither wl_shm nor compositors offer. The `_BE` formats of GStreamer have no
+/// mapping at all.
+pub const FORMATS: &[(VideoFormat, u32)] = &[
+    // 32 bit RGB
+    (VideoFormat::Bgra, DRM_FORMAT_ARGB8888),
+    (VideoFormat::Bgrx, DRM_FORMAT_XRGB8888),
+    (VideoFormat::Rgba, fourcc(b"AB24")),
+    (VideoFormat::Rgbx, fourcc(b"XB24")),
+    (VideoFormat::Abgr, fourcc(b"RA24")),
+    (VideoFormat::Xbgr, fourcc(b"RX24")),
+    (VideoFormat::Argb, fourcc(b"BA24")),
+    (VideoFormat::Xrgb, fourcc(b"BX24")),
+    (VideoFormat::Bgr10a2Le, fourcc(b"AR30")),
+    (VideoFormat::Rgb10a2Le, fourcc(b"AB30")),
+    // 24 and 16 bit RGB
+    (VideoFormat::Bgr, fourcc(b"RG24")),
+    (VideoFormat::Rgb, fourcc(b"BG24")),
+    (VideoFormat::Rgb16, native_endian(fourcc(b"RG16"))),
+    (VideoFormat::Bgr16, native_endian(fourcc(b"BG16"))),
+    (VideoFormat::Rgb15, native_endian(fourcc(b"XR15"))),
+    (VideoFormat::Bgr15, native_endian(fourcc(b"XB15"))),
+    // Gray
+    (VideoFormat::Gray8, fourcc(b"R8  ")),
+    (VideoFormat::Gray16Le, fourcc(b"R16 ")),
+    // Packed YUV
+    (VideoFormat::Vuya, fourcc(b"AYUV")),
+    (VideoFormat::Yuy2, fourcc(b"YUYV")),
+    (VideoFormat::Yvyu, fourcc(b"YVYU")),
+    (VideoFormat::Uyvy, fourcc(b"UYVY")),
+    (VideoFormat::Vyuy, fourcc(b"VYUY")),
+    // Semi-planar and planar YUV
+    (VideoFormat::Nv12, fourcc(b"NV12")),
+    (VideoFormat::Nv21, fourcc(b"NV21")),
+    (VideoFormat::Nv16, fourcc(b"NV16")),
+    (VideoFormat::Nv61, fourcc(b"NV61")),
+    (VideoFormat::Nv24, fourcc(b"NV24")),
+    (VideoFormat::P01010le, fourcc(b"P010")),
+    (VideoFormat::P016Le, fourcc(b"P016")),
+    (VideoFormat::I420, fourcc(b"YU12")),
+    (VideoFormat::Yv12, fourcc(b"YV12")),
+    (VideoFormat::Y42b, fourcc(b"YU16")),
+    (VideoFormat::Y444, fourcc(b"YU24")),
+];
+
+/// The wl_shm format of a DRM fourcc, they are the same except for the two
+/// formats every compositor supports
+pub fn wl_shm_format_from_drm_fourcc_code(fourcc: u32) -> Option<wl_shm::Format> {
+    match fourcc {
+        DRM_FORMAT_ARGB8888 => Some(wl_shm::Format::Argb8888),
+        DRM_FORMAT_XRGB8888 => Some(wl_shm::Format::Xrgb8888),
+        // wl_shm reuses 0 and 1 for the above, they are no valid fourccs
+        0 | 1 => None,
+        fourcc => wl_shm::Format::try_from(fourcc).ok(),
+    }
+}
+
+/// The DRM fourcc of a wl_shm format, see [`wl_shm_format_from_drm_fourcc_code`]
+pub fn wl_shm_format_to_drm_fourcc_code(format: wl_shm::Format) -> u32 {
+    match format {
+        wl_shm::Format::Argb8888 => DRM_FORMAT_ARGB8888,
+        wl_shm::Format::Xrgb8888 => DRM_FORMAT_XRGB8888,
+        format => format as u32,
+    }
+}
+
+/// The video format of a wl_shm format, `None` if GStreamer can not express it
 pub fn gst_video_format_from_wl_shm(format: wl_shm::Format) -> Option<VideoFormat> {
-    let format = match format {
-        wl_shm::Format::Abgr8888 => VideoFormat::Rgba,
-        wl_shm::Format::Argb8888 => VideoFormat::Bgra,
-        wl_shm::Format::Bgra8888 => VideoFormat::Argb,
-        wl_shm::Format::Bgrx8888 => VideoFormat::Xrgb,
-        wl_shm::Format::Rgba8888 => VideoFormat::Abgr,
-        wl_shm::Format::Rgbx8888 => VideoFormat::Xbgr,
-        wl_shm::Format::Xbgr8888 => VideoFormat::Rgbx,
-        wl_shm::Format::Xrgb8888 => VideoFormat::Bgrx,
-        _ => return None,
-    };
-    Some(format)
+    gst_video_format_from_drm_fourcc_code(wl_shm_format_to_drm_fourcc_code(format))
 }
 
+/// The wl_shm format of a video format, `None` if wl_shm can not express it
 pub fn gst_video_format_to_wl_shm(format: VideoFormat) -> Option<wl_shm::Format> {
-    let format = match format {
-        VideoFormat::Abgr => wl_shm::Format::Rgba8888,
-        VideoFormat::Argb => wl_shm::Format::Bgra8888,
-        VideoFormat::Bgra => wl_shm::Format::Argb8888,
-        VideoFormat::Bgrx => wl_shm::Format::Xrgb8888,
-        VideoFormat::Rgba => wl_shm::Format::Abgr8888,
-        VideoFormat::Rgbx => wl_shm::Format::Xbgr8888,
-        VideoFormat::Xbgr => wl_shm::Format::Rgbx8888,
-        VideoFormat::Xrgb => wl_shm::Format::Bgrx8888,
-        _ => return None,
-    };
-    Some(format)
-}
-
-pub fn gst_video_format_from_drm_fourcc(format: drm_fourcc::DrmFourcc) -> Option<VideoFormat> {
-    let format = match format {
-        drm_fourcc::DrmFourcc::Abgr8888 => VideoFormat::Rgba,
-        drm_fourcc::DrmFourcc::Argb8888 => VideoFormat::Bgra,
-        drm_fourcc::DrmFourcc::Bgra8888 => VideoFormat::Argb,
-        drm_fourcc::DrmFourcc::Bgrx8888 => VideoFormat::Xrgb,
-        drm_fourcc::DrmFourcc::Rgba8888 => VideoFormat::Abgr,
-        drm_fourcc::DrmFourcc::Rgbx8888 => VideoFormat::Xbgr,
-        drm_fourcc::DrmFourcc::Xbgr8888 => VideoFormat::Rgbx,
-        drm_fourcc::DrmFourcc::Xrgb8888 => VideoFormat::Bgrx,
-        _ => return None,
-    };
-    Some(format)
+    gst_video_format_to_drm_fourcc_code(format).and_then(wl_shm_format_from_drm_fourcc_code)
 }
 
+/// The DRM fourcc of a video format, `None` if there is none
+#[cfg(feature = "gbm")]
 pub fn gst_video_format_to_drm_fourcc(format: VideoFormat) -> Option<drm_fourcc::DrmFourcc> {
-    let format = match format {
-        gstreamer_video::VideoFormat::Abgr => drm_fourcc::DrmFourcc::Rgba8888,
-        gstreamer_video::VideoFormat::Argb => drm_fourcc::DrmFourcc::Bgra8888,
-        gstreamer_video::VideoFormat::Bgra => drm_fourcc::DrmFourcc::Argb8888,
-        gstreamer_video::VideoFormat::Bgrx => drm_fourcc::DrmFourcc::Xrgb8888,
-        gstreamer_video::VideoFormat::Rgba => drm_fourcc::DrmFourcc::Abgr8888,
-        gstreamer_video::VideoFormat::Rgbx => drm_fourcc::DrmFourcc::Xbgr8888,
-        gstreamer_video::VideoFormat::Xbgr => drm_fourcc::DrmFourcc::Rgbx8888,
-        gstreamer_video::VideoFormat::Xrgb => drm_fourcc::DrmFourcc::Bgrx8888,
-        _ => return None,
+    gst_video_format_to_drm_fourcc_code(format)
+        .and_then(|fourcc| drm_fourcc::DrmFourcc::try_from(fourcc).ok())
+}
+
+/// The video format of a raw DRM fourcc code, `None` if GStreamer can not express it
+pub fn gst_video_format_from_drm_fourcc_code(fourcc: u32) -> Option<VideoFormat> {
+    FORMATS
+        .iter()
+        .find(|(_, drm_fourcc)| *drm_fourcc == fourcc)
+        .map(|(format, _)| *format)
+}
+
+/// Whether `fourcc` is known but only has a video format on little-endian hosts
+/// and this is a big-endian one
+pub fn drm_fourcc_requires_little_endian(fourcc: u32) -> bool {
+    fourcc & DRM_FORMAT_BIG_ENDIAN == 0
+        && gst_video_format_from_drm_fourcc_code(fourcc | DRM_FORMAT_BIG_ENDIAN).is_some()
+}
+
+/// Like [`gst_video_format_to_drm_fourcc`] returning the raw fourcc code.
+pub fn gst_video_format_to_drm_fourcc_code(format: VideoFormat) -> Option<u32> {
+    FORMATS
+        .iter()
+        .find(|(video_format, _)| *video_format == format)
+        .map(|(_, fourcc)| *fourcc)
+}
+
+/// `drm-format` string of `fourcc` with `modifier`, like `NV12:0x0100000000000002`.
+/// Linear buffers leave out the modifier.
+pub fn drm_format_code_to_string(fourcc: u32, modifier: u64) -> String {
+    let fourcc = String::from_utf8_lossy(&fourcc.to_le_bytes())
+        .trim_end()
+        .to_owned();
+    if modifier == DRM_FORMAT_MOD_LINEAR {
+        fourcc
+    } else {
+        format!("{}:{:#018x}", fourcc, modifier)
+    }
+}
+
+/// Fourcc and modifier of a `drm-format` string, see [`drm_format_code_to_string`]
+pub fn drm_format_code_from_string(drm_format: &str) -> Option<(u32, u64)> {
+    let (fourcc, modifier) = match drm_format.split_once(':') {
+        Some((fourcc, modifier)) => {
+            let modifier = modifier.strip_prefix("0x").unwrap_or(modifier);
+            (fourcc, u64::from_str_radix(modifier, 16).ok()?)
+        }
+        None => (drm_format, DRM_FORMAT_MOD_LINEAR),
     };
-    Some(format)
+    if fourcc.is_empty() || fourcc.len() > 4 {
+        return None;
+    }
+    // Fourccs shorter than 4 characters are padded with spaces
+    let mut code = [b' '; 4];
+    code[..fourcc.len()].copy_from_slice(fourcc.as_bytes());
+    Some((u32::from_le_bytes(code), modifier))
+}
+
+/// Like [`drm_format_code_to_string`] for a typed fourcc and modifier.
+#[cfg(feature = "dmabuf")]
+pub fn drm_format_to_string(
+    fourcc: drm_fourcc::DrmFourcc,
+    modifier: drm_fourcc::DrmModifier,
+) -> String {
+    drm_format_code_to_string(fourcc as u32, modifier.into())
+}
+
+/// Like [`drm_format_code_from_string`], `None` for fourccs unknown to drm-fourcc.
+#[cfg(feature = "dmabuf")]
+pub fn drm_format_from_string(
+    drm_format: &str,
+) -> Option<(drm_fourcc::DrmFourcc, drm_fourcc::DrmModifier)> {
+    let (fourcc, modifier) = drm_format_code_from_string(drm_format)?;
+    let fourcc = drm_fourcc::DrmFourcc::try_from(fourcc).ok()?;
+    Some((fourcc, drm_fourcc::DrmModifier::from(modifier)))
+}
+
+/// Video info of `DMA_DRM` caps, like `GstVideoInfoDmaDrm` of GStreamer 1.24.
+///
+/// The bindings in use predate it, `vinfo` has the video format matching the
+/// fourcc and the default layout of it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VideoInfoDmaDrm {
+    pub vinfo: VideoInfo,
+    pub drm_fourcc: u32,
+    pub drm_modifier: u64,
+}
+
+impl VideoInfoDmaDrm {
+    pub fn new(vinfo: VideoInfo, drm_fourcc: u32, drm_modifier: u64) -> Self {
+        Self {
+            vinfo,
+            drm_fourcc,
+            drm_modifier,
+        }
+    }
+
+    /// `vinfo` with the fourcc of its format, `None` if it has none
+    pub fn from_video_info(vinfo: VideoInfo, drm_modifier: u64) -> Option<Self> {
+        let drm_fourcc = gst_video_format_to_drm_fourcc_code(vinfo.format())?;
+        Some(Self::new(vinfo, drm_fourcc, drm_modifier))
+    }
+
+    /// Whether `caps` have the `DMA_DRM` format
+    pub fn is_dma_drm_caps(caps: &gstreamer::CapsRef) -> bool {
+        caps.structure(0)
+            .and_then(|structure| structure.get::<&str>("format").ok())
+            .is_some_and(|format| format == DMA_DRM_FORMAT)
+    }
+
+    /// Parse fixed `DMA_DRM` caps
+    pub fn from_caps(caps: &gstreamer::CapsRef) -> Result<Self, glib::BoolError> {
+        if !Self::is_dma_drm_caps(caps) {
+            return Err(glib::bool_error!("no DMA_DRM caps: {}", caps));
+        }
+        let structure = caps.structure(0).unwrap();
+        let drm_format = structure
+            .get::<&str>("drm-format")
+            .map_err(|_| glib::bool_error!("no fixed drm-format in {}", caps))?;
+        let (drm_fourcc, drm_modifier) = drm_format_code_from_string(drm_format)
+            .ok_or_else(|| glib::bool_error!("invalid drm-format {}", drm_format))?;
+        let format = gst_video_format_from_drm_fourcc_code(drm_fourcc)
+            .ok_or_else(|| glib::bool_error!("unsupported drm-format {}", drm_format))?;
+
+        let mut caps = caps.to_owned();
+        {
+            let structure = caps.make_mut().structure_mut(0).unwrap();
+            structure.set("format", format.to_str());
+            structure.remove_field("drm-format");
+        }
+        Ok(Self::new(
+            VideoInfo::from_caps(&caps)?,
+            drm_fourcc,
+            drm_modifier,
+        ))
+    }
+
+    /// `DMA_DRM` caps with the `memory:DMABuf` feature
+    pub fn to_caps(&self) -> Result<gstreamer::Caps, glib::BoolError> {
+        let mut caps = self.vinfo.to_caps()?;
+        {
+            let caps = caps.get_mut().unwrap();
+            let structure = caps.structure_mut(0).unwrap();
+            structure.set("format", DMA_DRM_FORMAT);
+            structure.set(
+                "drm-format",
+                drm_format_code_to_string(self.drm_fourcc, self.drm_modifier),
+            );
+            // Colorimetry and chroma site are implied by the fourcc
+            structure.remove_fields(["colorimetry", "chroma-site"]);
+            caps.set_features(
+                0,
+                Some(gstreamer::CapsFeatures::new([
+                    gstreamer_allocators::CAPS_FEATURE_MEMORY_DMABUF,
+                ])),
+            );
+        }
+        Ok(caps)
+    }
+}
+
+/// Colorimetry to advertise for frames captured from an output.
+///
+/// Compositors scan out in sRGB, so RGB formats are tagged as full range sRGB.
+/// YUV formats are assumed to be produced by a BT.709 conversion of that content.
+pub fn gst_video_colorimetry_for_format(format: VideoFormat) -> VideoColorimetry {
+    let info = VideoFormatInfo::from_format(format);
+    if info.is_yuv() {
+        VideoColorimetry::new(
+            VideoColorRange::Range16_235,
+            VideoColorMatrix::Bt709,
+            VideoTransferFunction::Bt709,
+            VideoColorPrimaries::Bt709,
+        )
+    } else {
+        VideoColorimetry::new(
+            VideoColorRange::Range0_255,
+            VideoColorMatrix::Rgb,
+            VideoTransferFunction::Srgb,
+            VideoColorPrimaries::Bt709,
+        )
+    }
+}
+
+/// Chroma siting for subsampled formats, `None` for formats without chroma subsampling.
+pub fn gst_video_chroma_site_for_format(format: VideoFormat) -> Option<&'static str> {
+    let info = VideoFormatInfo::from_format(format);
+    if info.is_yuv() && (info.w_sub()[1] > 0 || info.h_sub()[1] > 0) {
+        Some("mpeg2")
+    } else {
+        None
+    }
 }
@@ -0,0 +1,101 @@
+//! `DMA_DRM` caps of GStreamer 1.24, describing dmabufs by DRM fourcc and modifier.
+//!
+//! The bindings in use predate them, so the caps are handled as plain structures
+//! and translated to a regular [`VideoInfo`] for everything else.
+
+use gstreamer::glib;
+use gstreamer_video::VideoInfo;
+
+use crate::utils::{
+    drm_format_code_to_string, gst_video_format_to_drm_fourcc_code, VideoInfoDmaDrm,
+    DMA_DRM_FORMAT, DRM_FORMAT_MOD_INVALID, DRM_FORMAT_MOD_LINEAR,
+};
+
+/// Whether the GStreamer libraries in use understand `DMA_DRM` caps
+pub(super) fn is_supported() -> bool {
+    let (major, minor, _, _) = gstreamer::version();
+    (major, minor) >= (1, 24)
+}
+
+/// Fourcc and modifier of fixed `DMA_DRM` caps, `None` for other caps.
+pub(super) fn drm_format(caps: &gstreamer::CapsRef) -> Option<(u32, u64)> {
+    VideoInfoDmaDrm::from_caps(caps)
+        .ok()
+        .map(|info| (info.drm_fourcc, info.drm_modifier))
+}
+
+/// Video info of `caps`, `DMA_DRM` caps get the video format matching their fourcc
+/// and the default layout.
+pub(super) fn video_info(caps: &gstreamer::CapsRef) -> Result<VideoInfo, glib::BoolError> {
+    if VideoInfoDmaDrm::is_dma_drm_caps(caps) {
+        VideoInfoDmaDrm::from_caps(caps).map(|info| info.vinfo)
+    } else {
+        VideoInfo::from_caps(caps)
+    }
+}
+
+/// Modifiers that can be negotiated out of the modifiers the compositor `offered`.
+///
+/// Tiled layouts can only be allocated with gbm, implicit modifiers equal linear.
+pub(super) fn negotiable_modifiers(offered: &[u64]) -> Vec<u64> {
+    let mut modifiers = offered
+        .iter()
+        .copied()
+        .filter(|modifier| {
+            cfg!(feature = "gbm")
+                && *modifier != DRM_FORMAT_MOD_INVALID
+                && *modifier != DRM_FORMAT_MOD_LINEAR
+        })
+        .collect::<Vec<_>>();
+    let linear = offered.is_empty()
+        || offered.iter().any(|modifier| {
+            *modifier == DRM_FORMAT_MOD_INVALID || *modifier == DRM_FORMAT_MOD_LINEAR
+        });
+    if linear {
+        modifiers.push(DRM_FORMAT_MOD_LINEAR);
+    }
+    modifiers
+}
+
+/// `DMA_DRM` caps for the structures of `caps` with a format the compositor
+/// offers as dmabuf, `modifiers` are the modifiers that can be allocated for it.
+pub(super) fn dma_drm_caps(
+    caps: &gstreamer::CapsRef,
+    dmabuf_formats: &[u32],
+    modifiers: impl Fn(u32) -> Vec<u64>,
+) -> gstreamer::Caps {
+    let mut dma_drm_caps = gstreamer::Caps::new_empty();
+    for structure in caps.iter() {
+        let Some(fourcc) = structure
+            .get::<&str>("format")
+            .ok()
+            .and_then(|format| {
+                gst_video_format_to_drm_fourcc_code(gstreamer_video::VideoFormat::from_string(
+                    format,
+                ))
+            })
+            .filter(|fourcc| dmabuf_formats.contains(fourcc))
+        else {
+            continue;
+        };
+
+        let drm_formats = modifiers(fourcc)
+            .into_iter()
+            .map(|modifier| drm_format_code_to_string(fourcc, modifier))
+            .collect::<Vec<_>>();
+        let mut structure = structure.to_owned();
+        structure.set("format", DMA_DRM_FORMAT);
+        structure.set("drm-format", gstreamer::List::new(drm_formats));
+        // Colorimetry and chroma site are implied by the fourcc
+        structure.remove_fields(["colorimetry", "chroma-site"]);
+
+        let dma_drm_caps = dma_drm_caps.get_mut().unwrap();
+        dma_drm_caps.append_structure_full(
+            structure,
+            Some(gstreamer::CapsFeatures::new([
+                gstreamer_allocators::CAPS_FEATURE_MEMORY_DMABUF,
+            ])),
+        );
+    }
+    dma_drm_caps
+}
@@ -0,0 +1,47 @@
+//! Layout of the captured output posted as element message.
+
+use wayland_client::protocol::wl_output::Transform;
+
+use crate::session::OutputInfo;
+
+/// Name of the element message structure
+pub const OUTPUT_GEOMETRY_MESSAGE_NAME: &str = "wlrscreencopysrc-output-geometry";
+
+fn transform_name(transform: Option<Transform>) -> &'static str {
+    match transform {
+        Some(Transform::Normal) | None => "normal",
+        Some(Transform::_90) => "90",
+        Some(Transform::_180) => "180",
+        Some(Transform::_270) => "270",
+        Some(Transform::Flipped) => "flipped",
+        Some(Transform::Flipped90) => "flipped-90",
+        Some(Transform::Flipped180) => "flipped-180",
+        Some(Transform::Flipped270) => "flipped-270",
+        Some(_) => "unknown",
+    }
+}
+
+/// Position, size, scale and transform of `output_info`, unknown values are left out
+pub(super) fn output_geometry(output_info: &OutputInfo) -> gstreamer::Structure {
+    let mut geometry = gstreamer::Structure::builder(OUTPUT_GEOMETRY_MESSAGE_NAME)
+        .field("name", output_info.name.as_str())
+        .field("description", output_info.description.as_str())
+        .field("width", output_info.mode.width)
+        .field("height", output_info.mode.height)
+        .field("scale", output_info.scale)
+        .field("transform", transform_name(output_info.transform))
+        .build();
+    if let Some((x, y)) = output_info.logical_position {
+        geometry.set("x", x);
+        geometry.set("y", y);
+    }
+    if let Some((width, height)) = output_info.logical_size {
+        geometry.set("logical-width", width);
+        geometry.set("logical-height", height);
+    }
+    if let Some((width, height)) = output_info.physical_size {
+        geometry.set("physical-width", width);
+        geometry.set("physical-height", height);
+    }
+    geometry
+}
@@ -1,26 +1,42 @@
-use std::os::unix::net::UnixStream;
-use std::path::PathBuf;
-use std::str::FromStr;
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Instant;
 
-use gstreamer::prelude::{Cast, ParamSpecBuilderExt, ToValue};
+use gstreamer::prelude::{
+    Cast, ClockExt, ElementExt, ElementExtManual, GstParamSpecBuilderExt, ObjectExt, PadExt,
+    PadExtManual, ParamSpecBuilderExt, StaticType, ToValue,
+};
+use gstreamer_base::prelude::BaseSrcExtManual;
 use gstreamer_base::traits::BaseSrcExt;
 use gstreamer_video::VideoBufferPoolConfig;
 use once_cell::sync::Lazy;
 
 use gstreamer::subclass::prelude::*;
-use gstreamer::{glib, prelude::BufferPoolExtManual};
+use gstreamer::{
+    glib,
+    prelude::{BufferPoolExt, BufferPoolExtManual},
+};
 use gstreamer_base::subclass::prelude::*;
 
-use wayland_client::globals::{registry_queue_init, GlobalListContents};
-use wayland_client::{protocol::wl_registry, Connection, Dispatch, Proxy};
-use wayland_client::{QueueHandle, Weak};
-
-use crate::allocators::{DmaHeapMemoryAllocator, GbmMemoryAllocator, MemfdMemoryAllocator};
-use crate::buffer_pool::{WaylandBufferMeta, WaylandBufferPool};
+use super::geometry::output_geometry;
+use super::region::{self, Region, RegionCapture, REGION_FORMAT};
+use super::stats::Stats;
+use super::{dma_drm, privacy, snapshot};
+use super::{CopyTimeoutPolicy, DamageReport, PowerOffMode, Presentation, PrivacyFill};
+use super::{ScreencopyDamageMeta, ScreencopyFrameMeta};
+use crate::allocators::MemfdMemoryAllocator;
+use crate::buffer_pool::{
+    WaylandBufferMeta, WaylandBufferPool, WaylandBufferPoolConfig, WaylandMemoryType,
+};
+use crate::session::{
+    is_output_pattern, BufferFormats, ConnectCancel, CopiedFrame, FrameState, OutputInfo, Rect,
+    ScreencopySession, SessionError, VirtualInput,
+};
 use crate::utils::{
-    gst_video_format_from_drm_fourcc, gst_video_format_from_wl_shm, gst_video_format_to_drm_fourcc,
-    gst_video_format_to_wl_shm,
+    drm_format_code_to_string, drm_fourcc_requires_little_endian, gst_video_chroma_site_for_format,
+    gst_video_colorimetry_for_format, gst_video_format_from_drm_fourcc_code,
+    gst_video_format_from_wl_shm, gst_video_format_to_drm_fourcc_code, gst_video_format_to_wl_shm,
+    wl_shm_format_to_drm_fourcc_code, DRM_FORMAT_MOD_INVALID, DRM_FORMAT_MOD_LINEAR,
 };
 
 static CAT: Lazy<gstreamer::DebugCategory> = Lazy::new(|| {
@@ -31,448 +47,3077 @@ static CAT: Lazy<gstreamer::DebugCategory> = Lazy::new(|| {
     )
 });
 
-#[derive(Debug, Default)]
+static REFERENCE_TIMESTAMP_CAPS: Lazy<gstreamer::Caps> =
+    Lazy::new(|| gstreamer::Caps::new_empty_simple(super::REFERENCE_TIMESTAMP_CAPS));
+
+/// Context type of the VA display shared by the va elements
+const VA_DISPLAY_CONTEXT: &str = "gst.va.display.handle";
+
+/// Formats offered in addition to the ones of the compositor with `convert`
+const CONVERT_FORMATS: &[gstreamer_video::VideoFormat] = &[
+    gstreamer_video::VideoFormat::Nv12,
+    gstreamer_video::VideoFormat::I420,
+];
+
+const DEFAULT_MAX_RETRIES: u32 = 3;
+const DEFAULT_RETRY_DELAY: u32 = 20;
+const MAX_RETRIES_LIMIT: u32 = 100;
+const RETRY_DELAY_LIMIT: u32 = 10_000;
+/// Upper bound of the retry delay doubled for every further failure
+const RETRY_BACKOFF_MAX: std::time::Duration = std::time::Duration::from_secs(10);
+/// Upper bound of `stats-interval` in seconds, one hour
+const STATS_INTERVAL_LIMIT: u32 = 3600;
+/// Upper bound of `trim-timeout` in milliseconds, one hour
+const TRIM_TIMEOUT_LIMIT: u32 = 3_600_000;
+/// Upper bound of `scale-width` and `scale-height`, the largest texture size
+/// common GPUs support
+const SCALE_SIZE_LIMIT: u32 = 16384;
+/// Upper bound of `min-buffers` and `max-buffers`, every buffer holds a full frame
+const BUFFERS_LIMIT: u32 = 64;
+/// Upper bound of `copy-timeout` in milliseconds, one minute
+const COPY_TIMEOUT_LIMIT: u32 = 60_000;
+/// Upper bound of `acquire-timeout` in milliseconds, one minute
+const ACQUIRE_TIMEOUT_LIMIT: u32 = 60_000;
+
+/// Delay before the first reconnection attempt, doubled after every failed attempt
+const RECONNECT_BACKOFF_MIN: std::time::Duration = std::time::Duration::from_millis(100);
+const RECONNECT_BACKOFF_MAX: std::time::Duration = std::time::Duration::from_secs(5);
+/// Upper bound of the buffers kept in flight for the pipeline latency
+const MAX_LATENCY_BUFFERS: u32 = 16;
+/// How long to wait for the compositor's GPU copy into a dmabuf to finish
+const FENCE_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(100);
+/// Interval of the retries and gaps while capturing is paused after persistent failures
+const PAUSED_RETRY_INTERVAL: std::time::Duration = std::time::Duration::from_millis(500);
+/// Interval of gaps or repeated frames while the output is powered off, if neither
+/// the caps nor the output have a framerate
+const POWERED_OFF_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
+
+/// What the allocation settled on, exposed through read-only properties
+#[derive(Debug, Clone)]
+struct AllocationInfo {
+    /// Caps feature of the negotiated caps, like `memory:DMABuf`
+    negotiated_memory: String,
+    /// Memory the compositor copies into, `dmabuf` or `shm`
+    capture_memory: &'static str,
+    /// Type name of the allocator of the capture pool
+    allocator: String,
+}
+
+/// Caps feature of the first structure of `caps`
+fn caps_memory(caps: &gstreamer::CapsRef) -> String {
+    caps.features(0)
+        .filter(|features| !features.is_any())
+        .and_then(|features| features.nth(0).map(|feature| feature.to_string()))
+        .unwrap_or_else(|| gstreamer::CAPS_FEATURE_MEMORY_SYSTEM_MEMORY.to_string())
+}
+
+fn make_raw_caps(
+    format: gstreamer_video::VideoFormat,
+    width: u32,
+    height: u32,
+    max_framerate: gstreamer::Fraction,
+    pixel_aspect_ratio: gstreamer::Fraction,
+) -> gstreamer::Caps {
+    let mut builder = gstreamer_video::video_make_raw_caps(&[format])
+        .width(width as i32)
+        .height(height as i32)
+        .framerate_range(..max_framerate)
+        .pixel_aspect_ratio(pixel_aspect_ratio)
+        .field(
+            "colorimetry",
+            gst_video_colorimetry_for_format(format).to_string(),
+        );
+    if let Some(chroma_site) = gst_video_chroma_site_for_format(format) {
+        builder = builder.field("chroma-site", chroma_site);
+    }
+    builder.build()
+}
+
+#[derive(Debug)]
 struct Settings {
     wayland_display: Option<String>,
     output_name: Option<String>,
+    region: Option<Region>,
+    show_pointer: bool,
+    defer_capture: bool,
+    navigation: bool,
+    presentation: Presentation,
+    damage_aware: bool,
+    damage_report: DamageReport,
+    drop_duplicates: bool,
+    leaky: bool,
+    qos: bool,
+    reconnect: bool,
+    max_retries: u32,
+    retry_delay: u32,
+    push_corrupted: bool,
+    pause_on_failure: bool,
+    copy_timeout: u32,
+    copy_timeout_policy: CopyTimeoutPolicy,
+    power_off_mode: PowerOffMode,
+    stats_interval: u32,
+    trim_timeout: u32,
+    acquire_timeout: u32,
+    min_buffers: u32,
+    max_buffers: u32,
+    time_code: bool,
+    scale_width: u32,
+    scale_height: u32,
+    convert: bool,
+    gl_memory: bool,
+    privacy_app_ids: Vec<String>,
+    privacy_fill: PrivacyFill,
+    privacy_color: u32,
 }
 
-#[derive(Debug, Default)]
-struct Mode {
-    width: i32,
-    height: i32,
-    refresh: i32,
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            wayland_display: None,
+            output_name: None,
+            region: None,
+            show_pointer: false,
+            defer_capture: false,
+            navigation: false,
+            presentation: Presentation::default(),
+            damage_aware: false,
+            damage_report: DamageReport::default(),
+            drop_duplicates: false,
+            leaky: false,
+            qos: true,
+            reconnect: false,
+            max_retries: DEFAULT_MAX_RETRIES,
+            retry_delay: DEFAULT_RETRY_DELAY,
+            push_corrupted: false,
+            pause_on_failure: true,
+            copy_timeout: 0,
+            copy_timeout_policy: CopyTimeoutPolicy::default(),
+            power_off_mode: PowerOffMode::default(),
+            stats_interval: 0,
+            trim_timeout: 0,
+            acquire_timeout: 0,
+            min_buffers: 0,
+            max_buffers: 0,
+            time_code: false,
+            scale_width: 0,
+            scale_height: 0,
+            convert: false,
+            gl_memory: false,
+            privacy_app_ids: Vec::new(),
+            privacy_fill: PrivacyFill::default(),
+            privacy_color: privacy::BLACK,
+        }
+    }
 }
 
-#[derive(Debug, Default)]
-struct OutputInfo {
-    name: String,
-    description: String,
-    mode: Mode,
-    done: bool,
+/// Refresh rate in mHz as framerate, `None` if unknown.
+fn refresh_rate(refresh: i32) -> Option<gstreamer::Fraction> {
+    (refresh > 0)
+        .then(|| gstreamer::Fraction::approximate_f64(refresh as f64 / 1_000f64))
+        .flatten()
 }
 
-#[derive(Debug)]
-struct FrameShmFormat {
-    format: wayland_client::protocol::wl_shm::Format,
-    width: u32,
-    height: u32,
-    stride: u32,
+/// Caps for capturing an output with the given buffer formats.
+pub(crate) fn output_caps(
+    formats: &BufferFormats,
+    output_info: &OutputInfo,
+    presentation: Presentation,
+) -> gstreamer::Caps {
+    let output_refresh = refresh_rate(output_info.mode.refresh)
+        .unwrap_or_else(|| gstreamer::Fraction::new(i32::MAX, 1));
+
+    let mut caps = gstreamer::Caps::new_empty();
+
+    for dmabuf_format in formats.dmabuf.iter() {
+        let Some(format) = gst_video_format_from_drm_fourcc_code(dmabuf_format.format) else {
+            log_unsupported_format(dmabuf_format.format);
+            continue;
+        };
+        let dmabuf_format_caps = make_raw_caps(
+            format,
+            dmabuf_format.width,
+            dmabuf_format.height,
+            output_refresh,
+            pixel_aspect_ratio(
+                output_info,
+                presentation,
+                dmabuf_format.width,
+                dmabuf_format.height,
+            ),
+        );
+        caps.merge(dmabuf_format_caps);
+    }
+
+    for shm_format in formats.shm.iter() {
+        let Some(format) = gst_video_format_from_wl_shm(shm_format.format) else {
+            log_unsupported_format(wl_shm_format_to_drm_fourcc_code(shm_format.format));
+            continue;
+        };
+        let shm_format_caps = make_raw_caps(
+            format,
+            shm_format.width,
+            shm_format.height,
+            output_refresh,
+            pixel_aspect_ratio(
+                output_info,
+                presentation,
+                shm_format.width,
+                shm_format.height,
+            ),
+        );
+        caps.merge(shm_format_caps);
+    }
+
+    caps
 }
 
-#[derive(Debug)]
-struct FrameDmabufFormat {
-    format: drm_fourcc::DrmFourcc,
+/// Log why a format offered by the compositor is not part of the caps.
+fn log_unsupported_format(fourcc: u32) {
+    let drm_format = drm_format_code_to_string(fourcc, DRM_FORMAT_MOD_LINEAR);
+    if drm_fourcc_requires_little_endian(fourcc) {
+        gstreamer::debug!(
+            CAT,
+            "skipping {}, only supported on little-endian hosts",
+            drm_format
+        );
+    } else {
+        gstreamer::trace!(CAT, "skipping {}, no matching video format", drm_format);
+    }
+}
+
+/// Pixel aspect ratio of a frame with the given size when presented at the logical
+/// size of the output.
+fn pixel_aspect_ratio(
+    output_info: &OutputInfo,
+    presentation: Presentation,
     width: u32,
     height: u32,
+) -> gstreamer::Fraction {
+    let logical_size = output_info.logical_size.or_else(|| {
+        (output_info.scale > 0).then(|| {
+            (
+                output_info.mode.width / output_info.scale,
+                output_info.mode.height / output_info.scale,
+            )
+        })
+    });
+
+    match (presentation, logical_size) {
+        (Presentation::Logical, Some((logical_width, logical_height)))
+            if logical_width > 0 && logical_height > 0 && width > 0 && height > 0 =>
+        {
+            let numer = logical_width as i64 * height as i64;
+            let denom = logical_height as i64 * width as i64;
+            gstreamer::Fraction::approximate_f64(numer as f64 / denom as f64)
+                .unwrap_or_else(|| gstreamer::Fraction::new(1, 1))
+        }
+        _ => gstreamer::Fraction::new(1, 1),
+    }
 }
 
-#[derive(Debug)]
-enum FrameState {
-    Ready(std::time::Duration),
-    Failed,
+/// A single rectangle covering all of `damage`, `None` without damage.
+fn damage_union(damage: &[Rect]) -> Option<Rect> {
+    damage
+        .iter()
+        .copied()
+        .reduce(|union, rect| union.union(&rect))
+}
+
+/// Give up on a copy into `buffer`, the pool frees it instead of reusing it.
+fn discard_buffer(pool: &gstreamer::BufferPool, buffer: gstreamer::Buffer) {
+    if let Some(pool) = pool.downcast_ref::<WaylandBufferPool>() {
+        pool.discard(&buffer);
+    }
+    drop(buffer);
+}
+
+/// Cheap checksum of all memory of `buffer` to detect unchanged frames, `None`
+/// if it can not be mapped.
+fn frame_checksum(buffer: &gstreamer::BufferRef) -> Option<u64> {
+    let map = buffer.map_readable().ok()?;
+    let mut chunks = map.chunks_exact(8);
+    let mut checksum = map.len() as u64;
+    for chunk in &mut chunks {
+        let value = u64::from_ne_bytes(chunk.try_into().unwrap());
+        checksum = (checksum.rotate_left(5) ^ value).wrapping_mul(0x517c_c1b7_2722_0a95);
+    }
+    for byte in chunks.remainder() {
+        checksum = (checksum.rotate_left(5) ^ *byte as u64).wrapping_mul(0x517c_c1b7_2722_0a95);
+    }
+    Some(checksum)
 }
 
+/// Numbering of the copied frames for [`ScreencopyFrameMeta`]
 #[derive(Debug, Default)]
-struct FrameInfo {
-    shm_formats: Vec<FrameShmFormat>,
-    dmabuf_formats: Vec<FrameDmabufFormat>,
-    done: bool,
-    state: Option<FrameState>,
-    flags: Option<wayland_protocols_wlr::screencopy::v1::client::zwlr_screencopy_frame_v1::Flags>,
+struct FrameCounter {
+    /// Number of frames copied since start
+    copied: u64,
+    /// Sequence number of the last frame pushed downstream
+    pushed: Option<u64>,
+}
+
+impl FrameCounter {
+    /// Count a frame that is not pushed downstream.
+    fn drop_frame(&mut self) {
+        self.copied += 1;
+    }
+
+    /// Count a frame that is pushed downstream, returns its sequence number and the
+    /// number of frames dropped since the previous one.
+    fn push_frame(&mut self) -> (u64, u64) {
+        let sequence = self.copied;
+        self.copied += 1;
+        let dropped = sequence - self.pushed.map(|pushed| pushed + 1).unwrap_or(0);
+        self.pushed = Some(sequence);
+        (sequence, dropped)
+    }
 }
 
+/// Outcome of [`WlrScreencopySrc::capture`]
 #[derive(Debug)]
-struct WaylandState {
-    wl_shm: wayland_client::protocol::wl_shm::WlShm,
-    dmabuf: Option<wayland_protocols::wp::linux_dmabuf::zv1::client::zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1>,
-    wlr_screencopy_manager: wayland_protocols_wlr::screencopy::v1::client::zwlr_screencopy_manager_v1::ZwlrScreencopyManagerV1,
-    outputs: Vec<(wayland_client::protocol::wl_output::WlOutput, Option<wayland_protocols::xdg::xdg_output::zv1::client::zxdg_output_v1::ZxdgOutputV1>, OutputInfo)>,
-    current_frame: Option<(wayland_protocols_wlr::screencopy::v1::client::zwlr_screencopy_frame_v1::ZwlrScreencopyFrameV1, FrameInfo)>,
-
-    qhandle: QueueHandle<WaylandState>,
-}
-
-impl Dispatch<wayland_client::protocol::wl_output::WlOutput, ()> for WaylandState {
-    fn event(
-        state: &mut Self,
-        proxy: &wayland_client::protocol::wl_output::WlOutput,
-        event: <wayland_client::protocol::wl_output::WlOutput as Proxy>::Event,
-        _data: &(),
-        _conn: &Connection,
-        _qhandle: &wayland_client::QueueHandle<Self>,
-    ) {
-        let (_, _, output_info) = state
-            .outputs
-            .iter_mut()
-            .find(|(output, _, _)| output == proxy)
-            .expect("non existing output");
-
-        match event {
-            wayland_client::protocol::wl_output::Event::Geometry { .. } => {}
-            wayland_client::protocol::wl_output::Event::Mode {
-                flags,
-                width,
-                height,
-                refresh,
-            } => {
-                if let Ok(flags) = flags.into_result() {
-                    if flags.contains(wayland_client::protocol::wl_output::Mode::Current) {
-                        output_info.mode.width = width;
-                        output_info.mode.height = height;
-                        output_info.mode.refresh = refresh;
-                    }
-                }
-            }
-            wayland_client::protocol::wl_output::Event::Done => {
-                output_info.done = true;
-            }
-            wayland_client::protocol::wl_output::Event::Scale { .. } => {}
-            wayland_client::protocol::wl_output::Event::Name { name } => output_info.name = name,
-            wayland_client::protocol::wl_output::Event::Description { description } => {
-                output_info.description = description
-            }
-            _ => unreachable!(),
-        }
+enum Capture {
+    Frame(gstreamer::Buffer, CopiedFrame),
+    /// The output was not damaged within one frame interval, the copy is
+    /// still pending
+    NoDamage(std::time::Duration),
+    /// The connection to the compositor failed
+    Disconnected(wayland_client::DispatchError),
+    /// The copy was made for a previously negotiated pool
+    Discarded,
+    /// The compositor did not copy the frame within copy-timeout, the copy was
+    /// given up
+    TimedOut(std::time::Duration),
+    /// The captured output is powered off, nothing was copied
+    PoweredOff,
+    /// The captured output went away
+    OutputLost,
+    /// Downstream did not return a buffer within acquire-timeout, the frame is skipped
+    NoBuffer(std::time::Duration),
+}
+
+/// Whether downstream only accesses the frames with the CPU. Hardware elements
+/// negotiate memory:DMABuf, propose a pool or a dmabuf allocator, mapping dmabufs
+/// is slower than memfd for everything else.
+fn is_software_downstream(caps: &gstreamer::CapsRef, query: &gstreamer::query::Allocation) -> bool {
+    let dmabuf_caps = caps
+        .features(0)
+        .map(|features| features.contains(gstreamer_allocators::CAPS_FEATURE_MEMORY_DMABUF))
+        .unwrap_or(false);
+    let proposed_pool = query
+        .allocation_pools()
+        .iter()
+        .any(|(pool, _, _, _)| pool.is_some());
+    let proposed_dmabuf_allocator = query
+        .allocation_params()
+        .iter()
+        .filter_map(|(allocator, _)| allocator.as_ref())
+        .any(|allocator| allocator.is::<gstreamer_allocators::DmaBufAllocator>());
+
+    !dmabuf_caps && !proposed_pool && !proposed_dmabuf_allocator
+}
+
+/// The allocator and params proposed by downstream, the allocator of a proposed
+/// pool is used if no allocator has been proposed directly.
+fn downstream_allocation(
+    query: &gstreamer::query::Allocation,
+) -> (
+    Option<gstreamer::Allocator>,
+    Option<gstreamer::AllocationParams>,
+) {
+    let (allocator, params) = query
+        .allocation_params()
+        .into_iter()
+        .next()
+        .map(|(allocator, params)| (allocator, Some(params)))
+        .unwrap_or((None, None));
+    let allocator = allocator.or_else(|| {
+        query
+            .allocation_pools()
+            .into_iter()
+            .next()
+            .and_then(|(pool, _, _, _)| pool)
+            .and_then(|pool| pool.config().allocator())
+            .and_then(|(allocator, _)| allocator)
+    });
+    (allocator, params)
+}
+
+/// Whether `allocator` allocates memory the compositor can import. The base fd
+/// allocators can only wrap existing fds, so only subclasses qualify.
+fn is_importable_allocator(allocator: &gstreamer::Allocator, dmabuf: bool) -> bool {
+    use gstreamer_allocators::{DmaBufAllocator, FdAllocator};
+
+    if dmabuf {
+        allocator.is::<DmaBufAllocator>() && allocator.type_() != DmaBufAllocator::static_type()
+    } else {
+        allocator.is::<FdAllocator>()
+            && !allocator.is::<DmaBufAllocator>()
+            && allocator.type_() != FdAllocator::static_type()
     }
 }
 
-impl Dispatch<wayland_protocols_wlr::screencopy::v1::client::zwlr_screencopy_manager_v1::ZwlrScreencopyManagerV1, ()> for WaylandState {
-    fn event(
-        _state: &mut Self,
-        _proxy: &wayland_protocols_wlr::screencopy::v1::client::zwlr_screencopy_manager_v1::ZwlrScreencopyManagerV1,
-        _event: <wayland_protocols_wlr::screencopy::v1::client::zwlr_screencopy_manager_v1::ZwlrScreencopyManagerV1 as Proxy>::Event,
-        _data: &(),
-        _conn: &Connection,
-        _qhandle: &wayland_client::QueueHandle<Self>,
-    ) {
-        // No events to handle
+/// Combine our allocation params with the ones proposed by downstream, using
+/// the stricter alignment, prefix and padding.
+fn merge_allocation_params(
+    params: Option<gstreamer::AllocationParams>,
+    downstream_params: Option<&gstreamer::AllocationParams>,
+) -> Option<gstreamer::AllocationParams> {
+    match (params, downstream_params) {
+        (Some(params), Some(downstream_params)) => Some(gstreamer::AllocationParams::new(
+            params.flags() | downstream_params.flags(),
+            params.align() | downstream_params.align(),
+            std::cmp::max(params.prefix(), downstream_params.prefix()),
+            std::cmp::max(params.padding(), downstream_params.padding()),
+        )),
+        (params, None) => params,
+        (None, Some(downstream_params)) => Some(downstream_params.clone()),
     }
 }
 
-impl Dispatch<wayland_protocols_wlr::screencopy::v1::client::zwlr_screencopy_frame_v1::ZwlrScreencopyFrameV1, ()> for WaylandState {
-    fn event(
-        state: &mut Self,
-        proxy: &wayland_protocols_wlr::screencopy::v1::client::zwlr_screencopy_frame_v1::ZwlrScreencopyFrameV1,
-        event: <wayland_protocols_wlr::screencopy::v1::client::zwlr_screencopy_frame_v1::ZwlrScreencopyFrameV1 as Proxy>::Event,
-        _data: &(),
-        _conn: &Connection,
-        _qhandle: &wayland_client::QueueHandle<Self>,
-    ) {
-        let (frame, frame_info) = state.current_frame.as_mut().expect("no frame");
+/// The video alignment configured on the pool proposed by downstream, encoders use
+/// it to request the padding and stride alignment they can import.
+fn downstream_video_alignment(
+    query: &gstreamer::query::Allocation,
+) -> Option<gstreamer_video::VideoAlignment> {
+    let (pool, _, _, _) = query.allocation_pools().into_iter().next()?;
+    let config = pool?.config();
+    if !config.has_option(gstreamer_video::BUFFER_POOL_OPTION_VIDEO_ALIGNMENT.as_ref()) {
+        return None;
+    }
+    config.video_alignment()
+}
 
-        if frame != proxy {
-            panic!("wrong frame");
-        }
+/// Combine two video alignments so buffers satisfy both, the larger padding and
+/// all stride alignment bits are used.
+fn merge_video_alignment(
+    align: gstreamer_video::VideoAlignment,
+    downstream_align: Option<&gstreamer_video::VideoAlignment>,
+) -> gstreamer_video::VideoAlignment {
+    let Some(downstream_align) = downstream_align else {
+        return align;
+    };
 
-        match event {
-            wayland_protocols_wlr::screencopy::v1::client::zwlr_screencopy_frame_v1::Event::Buffer { format, width, height, stride } => {
-                // TODO: Figure out how to handle the stride, currently we use the stride as defined by gstreamer for a format
-                if let Ok(format) = format.into_result() {
-                    frame_info.shm_formats.push(FrameShmFormat { format, width, height, stride });
-                }
-            },
-            wayland_protocols_wlr::screencopy::v1::client::zwlr_screencopy_frame_v1::Event::Flags { flags } => {
-                let flags = flags.into_result().unwrap();
-                frame_info.flags = Some(flags);
-            },
-            wayland_protocols_wlr::screencopy::v1::client::zwlr_screencopy_frame_v1::Event::Ready { tv_sec_hi, tv_sec_lo, tv_nsec } => {
-                let secs = (tv_sec_hi as u64) << 32 | tv_sec_lo as u64;
-                frame_info.state = Some(FrameState::Ready(std::time::Duration::new(secs, tv_nsec)));
-            },
-            wayland_protocols_wlr::screencopy::v1::client::zwlr_screencopy_frame_v1::Event::Failed => {
-                frame_info.state = Some(FrameState::Failed);
-            },
-            wayland_protocols_wlr::screencopy::v1::client::zwlr_screencopy_frame_v1::Event::Damage { .. } => {},
-            wayland_protocols_wlr::screencopy::v1::client::zwlr_screencopy_frame_v1::Event::LinuxDmabuf { format, width, height } => {
-                if let Ok(format) = drm_fourcc::DrmFourcc::try_from(format) {
-                    frame_info.dmabuf_formats.push(FrameDmabufFormat { format, width, height });
-                }
-            },
-            wayland_protocols_wlr::screencopy::v1::client::zwlr_screencopy_frame_v1::Event::BufferDone =>  frame_info.done = true,
-            _ => todo!(),
+    let mut stride_align = [0u32; gstreamer_video::ffi::GST_VIDEO_MAX_PLANES as usize];
+    for (plane, stride_align) in stride_align.iter_mut().enumerate() {
+        *stride_align = align.stride_align()[plane] | downstream_align.stride_align()[plane];
+    }
+    gstreamer_video::VideoAlignment::new(
+        std::cmp::max(align.padding_top(), downstream_align.padding_top()),
+        std::cmp::max(align.padding_bottom(), downstream_align.padding_bottom()),
+        std::cmp::max(align.padding_left(), downstream_align.padding_left()),
+        std::cmp::max(align.padding_right(), downstream_align.padding_right()),
+        &stride_align,
+    )
+}
+
+/// Whether both alignments pad and align every plane the same
+fn same_video_alignment(
+    a: Option<&gstreamer_video::VideoAlignment>,
+    b: Option<&gstreamer_video::VideoAlignment>,
+) -> bool {
+    match (a, b) {
+        (None, None) => true,
+        (Some(a), Some(b)) => {
+            a.padding_top() == b.padding_top()
+                && a.padding_bottom() == b.padding_bottom()
+                && a.padding_left() == b.padding_left()
+                && a.padding_right() == b.padding_right()
+                && a.stride_align() == b.stride_align()
         }
+        _ => false,
     }
 }
 
+/// Whether `pool` produces the same buffers for `caps`, `allocator` and
+/// `video_align` as a newly configured pool would and keeps `min` of them.
+fn is_pool_compatible(
+    pool: &WaylandBufferPool,
+    caps: &gstreamer::Caps,
+    allocator: &gstreamer::Allocator,
+    video_align: Option<&gstreamer_video::VideoAlignment>,
+    min: u32,
+) -> bool {
+    // The framerate does not affect the buffers
+    let without_framerate = |caps: &gstreamer::Caps| {
+        let mut caps = caps.copy();
+        for structure in caps.make_mut().iter_mut() {
+            structure.remove_field("framerate");
+        }
+        caps
+    };
+
+    let config = pool.config();
+    let same_caps = config
+        .params()
+        .and_then(|(pool_caps, _, _, _)| pool_caps)
+        .map(|pool_caps| without_framerate(&pool_caps) == without_framerate(caps))
+        .unwrap_or(false);
+    let same_allocator = config
+        .allocator()
+        .and_then(|(pool_allocator, _)| pool_allocator)
+        .map(|pool_allocator| pool_allocator.type_() == allocator.type_())
+        .unwrap_or(false);
+    // Downstream asks for a different alignment after restarting, like an encoder
+    let pool_align = config
+        .has_option(gstreamer_video::BUFFER_POOL_OPTION_VIDEO_ALIGNMENT.as_ref())
+        .then(|| config.video_alignment())
+        .flatten();
+    let same_align = same_video_alignment(pool_align.as_ref(), video_align);
+    // The pipeline latency changed the buffers needed in flight
+    let same_min = config
+        .params()
+        .map(|(_, _, pool_min, _)| pool_min == min)
+        .unwrap_or(false);
+
+    same_caps && same_allocator && same_align && same_min
+}
+
+/// Size the compositor announced for frames of `format`, `None` if it is not offered.
+fn buffer_format_size(
+    formats: &BufferFormats,
+    format: gstreamer_video::VideoFormat,
+) -> Option<(u32, u32)> {
+    let dmabuf_size = gst_video_format_to_drm_fourcc_code(format).and_then(|format| {
+        formats
+            .dmabuf
+            .iter()
+            .find(|dmabuf_format| dmabuf_format.format == format)
+            .map(|dmabuf_format| (dmabuf_format.width, dmabuf_format.height))
+    });
+    dmabuf_size.or_else(|| {
+        let format = gst_video_format_to_wl_shm(format)?;
+        formats
+            .shm
+            .iter()
+            .find(|shm_format| shm_format.format == format)
+            .map(|shm_format| (shm_format.width, shm_format.height))
+    })
+}
+
+/// `value` scaled by `numer / denom`, rounded to an even size.
+fn scale_dimension(value: u32, numer: u32, denom: u32) -> u32 {
+    let scaled = value as u64 * numer as u64 / std::cmp::max(denom, 1) as u64;
+    std::cmp::max((scaled as u32 + 1) & !1, 2)
+}
+
+/// Whether the buffer parameters announced for a frame are still compatible with
+/// the negotiated video info.
+fn frame_matches_video_info(
+    formats: &BufferFormats,
+    video_info: &gstreamer_video::VideoInfo,
+) -> bool {
+    let dmabuf_match = gst_video_format_to_drm_fourcc_code(video_info.format())
+        .map(|format| {
+            formats.dmabuf.iter().any(|dmabuf_format| {
+                dmabuf_format.format == format
+                    && dmabuf_format.width == video_info.width()
+                    && dmabuf_format.height == video_info.height()
+            })
+        })
+        .unwrap_or(false);
+    let shm_match = gst_video_format_to_wl_shm(video_info.format())
+        .map(|format| {
+            formats.shm.iter().any(|shm_format| {
+                shm_format.format == format
+                    && shm_format.width == video_info.width()
+                    && shm_format.height == video_info.height()
+            })
+        })
+        .unwrap_or(false);
+    dmabuf_match || shm_match
+}
+
 #[derive(Debug, Default)]
 pub struct WlrScreencopySrc {
     settings: Mutex<Settings>,
-    wayland_state: Mutex<Option<WaylandState>>,
-    _connection: Mutex<Option<wayland_client::Connection>>,
-    event_queue: Mutex<Option<wayland_client::EventQueue<WaylandState>>>,
-}
-
-impl wayland_client::Dispatch<wl_registry::WlRegistry, GlobalListContents> for WaylandState {
-    fn event(
-        _state: &mut WaylandState,
-        _proxy: &wl_registry::WlRegistry,
-        _event: wl_registry::Event,
-        _data: &GlobalListContents,
-        _conn: &Connection,
-        _qhandle: &QueueHandle<WaylandState>,
-    ) {
-    }
+    session: Mutex<Option<Arc<ScreencopySession>>>,
+    /// Capture of a region spanning several outputs, replaces the session
+    region_capture: Mutex<Option<Arc<RegionCapture>>>,
+    /// Devices navigation events are injected through, if enabled
+    input: Mutex<Option<VirtualInput>>,
+    /// Set between `unlock` and `unlock_stop`, interrupts waiting for the compositor
+    unlocked: AtomicBool,
+    /// Signalled by `unlock` to interrupt the delays between attempts
+    unlock_cond: (Mutex<()>, Condvar),
+    /// Cancels connecting on `unlock`, created by the first connect
+    connect_cancel: Mutex<Option<ConnectCancel>>,
+    /// Whether the last copy timed out, so a frozen compositor is only warned about once
+    copy_timed_out: AtomicBool,
+    /// Set while capturing is paused because the compositor keeps failing frames
+    capture_paused: AtomicBool,
+    /// Set while the captured output is powered off and capturing is paused
+    powered_off: AtomicBool,
+    /// Last pushed frame, repeated while the output is powered off
+    last_frame: Mutex<Option<gstreamer::Buffer>>,
+    /// Result of the last allocation query, `None` before negotiation
+    allocation_info: Mutex<Option<AllocationInfo>>,
+    /// `CLOCK_MONOTONIC` time of the first frame, timestamps start there when not live
+    first_timestamp: Mutex<Option<std::time::Duration>>,
+    /// Pool the frames are captured into when they have to be repacked or scaled
+    /// for downstream
+    repack_pool: Mutex<Option<WaylandBufferPool>>,
+    /// Video info of the captured frames if they are scaled to the negotiated size
+    convert_input: Mutex<Option<gstreamer_video::VideoInfo>>,
+    /// Scales dmabuf frames, `None` if frames are scaled on the CPU
+    #[cfg(feature = "gles")]
+    gpu_converter: Mutex<Option<Arc<crate::gpu::GpuConverter>>>,
+    /// Uploads frames to textures if memory:GLMemory was negotiated
+    #[cfg(feature = "gl")]
+    gl_memory: Mutex<crate::gpu::GlMemoryUpload>,
+    cpu_converter: Mutex<Option<gstreamer_video::VideoConverter>>,
+    /// Buffer of a submitted copy that has not completed yet, the pool it is from and
+    /// when the copy was submitted
+    pending_copy: Mutex<Option<(gstreamer::Buffer, gstreamer::BufferPool, Instant)>>,
+    /// Running time up to which the stream has been covered by buffers or gaps
+    gap_position: Mutex<Option<gstreamer::ClockTime>>,
+    frame_counter: Mutex<FrameCounter>,
+    /// Reason of the last warning about using shm instead of dmabuf
+    shm_fallback_reason: Mutex<Option<String>>,
+    stats: Mutex<Stats>,
+    /// Earliest running time downstream can still process in time, from QoS events
+    qos_earliest_time: Mutex<Option<gstreamer::ClockTime>>,
+    /// Latency the pipeline configured, from LATENCY events
+    pipeline_latency: Mutex<Option<gstreamer::ClockTime>>,
+    /// Wall clock time of the first time code, `Some(None)` if it is unknown
+    time_code_jam: Mutex<Option<Option<glib::DateTime>>>,
+    /// Last frame without a private application for `PrivacyFill::LastFrame`
+    last_safe_buffer: Mutex<Option<gstreamer::Buffer>>,
+    /// Checksum of the last pushed frame for drop-duplicates
+    last_checksum: Mutex<Option<u64>>,
 }
 
-impl wayland_client::Dispatch<wayland_client::protocol::wl_shm::WlShm, ()> for WaylandState {
-    fn event(
-        _state: &mut Self,
-        _proxy: &wayland_client::protocol::wl_shm::WlShm,
-        _event: <wayland_client::protocol::wl_shm::WlShm as Proxy>::Event,
-        _data: &(),
-        _conn: &Connection,
-        _qhandle: &QueueHandle<Self>,
-    ) {
-        // We completely ignore the formats and rely on the compositor to only send
-        // shm frame formats it supports
-    }
-}
-
-impl
-    wayland_client::Dispatch<
-        wayland_protocols::wp::linux_dmabuf::zv1::client::zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1,
-        (),
-    > for WaylandState
-{
-    fn event(
-        _state: &mut Self,
-        _proxy: &wayland_protocols::wp::linux_dmabuf::zv1::client::zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1,
-        _event: <wayland_protocols::wp::linux_dmabuf::zv1::client::zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1 as Proxy>::Event,
-        _data: &(),
-        _conn: &Connection,
-        _qhandle: &QueueHandle<Self>,
-    ) {
-        // We completely ignore the formats and rely on the compositor to only send
-        // dmabuf frame formats it supports
+/// Error message for a failed session, keeping the hints for common setup problems
+pub(super) fn session_error_msg(err: SessionError) -> gstreamer::ErrorMessage {
+    match err {
+        SessionError::InvalidDisplay(_) => {
+            gstreamer::error_msg!(gstreamer::ResourceError::Settings, ["{}", err])
+        }
+        SessionError::Connect(_) => {
+            gstreamer::error_msg!(gstreamer::ResourceError::OpenRead, ["{}", err])
+        }
+        SessionError::MissingGlobal {
+            interface: "zwlr_screencopy_manager_v1",
+            reason,
+        } => gstreamer::error_msg!(
+            gstreamer::ResourceError::NotFound,
+            ("Compositor does not support screen capture via zwlr_screencopy_manager_v1"),
+            [
+                "{}. The wlr-screencopy-unstable-v1 protocol is required, it is implemented by wlroots based compositors like sway, but not by GNOME or KDE",
+                reason
+            ]
+        ),
+        SessionError::OutputNotFound {
+            name: Some(ref name),
+            ref available,
+        } => gstreamer::error_msg!(
+            gstreamer::ResourceError::NotFound,
+            ("Output {} not found", name),
+            ["available outputs: {}", available.join(" ")]
+        ),
+        SessionError::MissingGlobal { .. } | SessionError::OutputNotFound { .. } => {
+            gstreamer::error_msg!(gstreamer::ResourceError::NotFound, ["{}", err])
+        }
+        SessionError::Dispatch(_)
+        | SessionError::Interrupted
+        | SessionError::Timeout(_)
+        | SessionError::NotCapturing
+        | SessionError::PoweredOff => {
+            gstreamer::error_msg!(gstreamer::ResourceError::Read, ["{}", err])
+        }
     }
 }
 
-impl wayland_client::Dispatch<wayland_protocols::xdg::xdg_output::zv1::client::zxdg_output_manager_v1::ZxdgOutputManagerV1, ()> for WaylandState {
-    fn event(
-        _state: &mut Self,
-        _proxy: &wayland_protocols::xdg::xdg_output::zv1::client::zxdg_output_manager_v1::ZxdgOutputManagerV1,
-        _event: <wayland_protocols::xdg::xdg_output::zv1::client::zxdg_output_manager_v1::ZxdgOutputManagerV1 as Proxy>::Event,
-        _data: &(),
-        _conn: &Connection,
-        _qhandle: &QueueHandle<Self>,
-    ) {
-       // No events 
-    }
-}
-
-impl
-    wayland_client::Dispatch<
-        wayland_protocols::xdg::xdg_output::zv1::client::zxdg_output_v1::ZxdgOutputV1,
-        Weak<wayland_client::protocol::wl_output::WlOutput>,
-    > for WaylandState
-{
-    fn event(
-        state: &mut Self,
-        _proxy: &wayland_protocols::xdg::xdg_output::zv1::client::zxdg_output_v1::ZxdgOutputV1,
-        event: <wayland_protocols::xdg::xdg_output::zv1::client::zxdg_output_v1::ZxdgOutputV1 as Proxy>::Event,
-        data: &Weak<wayland_client::protocol::wl_output::WlOutput>,
-        _conn: &Connection,
-        _qhandle: &QueueHandle<Self>,
-    ) {
-        let (_, _, output_info) = state
-            .outputs
-            .iter_mut()
-            .find(|(output, _, _)| output == data)
-            .expect("non existing output");
-
-        match event {
-            wayland_protocols::xdg::xdg_output::zv1::client::zxdg_output_v1::Event::LogicalPosition {.. } => {},
-            wayland_protocols::xdg::xdg_output::zv1::client::zxdg_output_v1::Event::LogicalSize { .. } => {},
-            wayland_protocols::xdg::xdg_output::zv1::client::zxdg_output_v1::Event::Done => {
-                output_info.done = true;
-            },
-            wayland_protocols::xdg::xdg_output::zv1::client::zxdg_output_v1::Event::Name { name } => {
-                output_info.name = name;
-            },
-            wayland_protocols::xdg::xdg_output::zv1::client::zxdg_output_v1::Event::Description { description } => {
-                output_info.description = description;
-            },
-            _ => unreachable!(),
+impl WlrScreencopySrc {
+    /// Open a session for the configured display and output.
+    fn connect_to_wl_display(&self) -> Result<(), gstreamer::ErrorMessage> {
+        // Never keep the proxies of a previous connection alive
+        if self.session.lock().unwrap().is_some() || self.region_capture.lock().unwrap().is_some() {
+            self.disconnect_from_wl_display();
+        }
+
+        let (wayland_display, output_name, region, show_pointer, defer_capture, navigation) = {
+            let settings = self.settings.lock().unwrap();
+            (
+                settings.wayland_display.clone(),
+                settings.output_name.clone(),
+                settings.region,
+                settings.show_pointer,
+                settings.defer_capture,
+                settings.navigation,
+            )
+        };
+        let cancel = self.connect_cancel()?;
+        let session = match region {
+            Some(region) => {
+                let Some(session) = self.connect_region(
+                    wayland_display.as_deref(),
+                    &region,
+                    show_pointer,
+                    &cancel,
+                )?
+                else {
+                    return Ok(());
+                };
+                session
+            }
+            None => ScreencopySession::connect_cancellable(
+                wayland_display.as_deref(),
+                output_name.as_deref(),
+                show_pointer,
+                None,
+                &cancel,
+            )
+            .map_err(session_error_msg)?,
+        };
+        if defer_capture {
+            // The probed frame is enough for negotiation, capturing starts with
+            // the first frame requested in PLAYING
+            session.stop_capture();
+        }
+        if navigation {
+            // Capturing works without input injection, only warn
+            match VirtualInput::new(&session) {
+                Ok(input) => *self.input.lock().unwrap() = Some(input),
+                Err(err) => gstreamer::element_imp_warning!(
+                    self,
+                    gstreamer::ResourceError::Settings,
+                    ("Navigation events can not be injected"),
+                    ["{}", err]
+                ),
+            }
+        }
+        *self.session.lock().unwrap() = Some(session.clone());
+        // `unlock` may have run while connecting
+        if self.unlocked.load(Ordering::SeqCst) {
+            session.interrupt();
         }
+        self.post_output_geometry(&session);
+
+        Ok(())
     }
-}
 
-impl WlrScreencopySrc {
-    fn connect_to_wl_display(&self, wayland_display: Option<&str>, output_name: Option<&str>) {
-        let conn = if let Some(wayland_display) = wayland_display {
-            let wayland_display = PathBuf::from_str(wayland_display).unwrap();
+    /// The token cancelling connects on `unlock`, already cancelled while unlocked.
+    fn connect_cancel(&self) -> Result<ConnectCancel, gstreamer::ErrorMessage> {
+        let mut connect_cancel = self.connect_cancel.lock().unwrap();
+        let cancel = match connect_cancel.as_ref() {
+            Some(cancel) => cancel.clone(),
+            None => {
+                let cancel = ConnectCancel::new().map_err(|err| {
+                    gstreamer::error_msg!(
+                        gstreamer::ResourceError::Failed,
+                        ["failed to create wakeup pipe: {}", err]
+                    )
+                })?;
+                *connect_cancel = Some(cancel.clone());
+                cancel
+            }
+        };
+        drop(connect_cancel);
+        // `unlock` may have run before the token existed
+        if self.unlocked.load(Ordering::SeqCst) {
+            cancel.cancel();
+        }
+        Ok(cancel)
+    }
 
-            let socket_path = if wayland_display.is_absolute() {
-                wayland_display
-            } else {
-                let mut socket_path = std::env::var_os("XDG_RUNTIME_DIR")
-                    .map(Into::<PathBuf>::into)
-                    .unwrap();
-                if !socket_path.is_absolute() {
-                    panic!("oh no");
+    /// Connect for the capture of `region`, returns the session if the region lies
+    /// on a single output. Otherwise a [`RegionCapture`] is set up.
+    fn connect_region(
+        &self,
+        wayland_display: Option<&str>,
+        region: &Region,
+        show_pointer: bool,
+        cancel: &ConnectCancel,
+    ) -> Result<Option<Arc<ScreencopySession>>, gstreamer::ErrorMessage> {
+        // Only used to find the outputs and their positions
+        let outputs =
+            ScreencopySession::connect_cancellable(wayland_display, None, false, None, cancel)
+                .map_err(session_error_msg)?
+                .outputs();
+        let intersecting = outputs
+            .iter()
+            .filter_map(|output_info| {
+                region::intersect(region, output_info).map(|(local, _)| (output_info, local))
+            })
+            .collect::<Vec<_>>();
+
+        match intersecting[..] {
+            [] => Err(gstreamer::error_msg!(
+                gstreamer::ResourceError::NotFound,
+                ("Region {} does not intersect any output", region),
+                [
+                    "outputs: {}",
+                    outputs
+                        .iter()
+                        .map(|info| format!("{} at {:?}", info.name, info.logical_position))
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                ]
+            )),
+            [(output_info, local)] => {
+                gstreamer::debug!(CAT, imp: self, "capturing {:?} of {}", local, output_info.name);
+                ScreencopySession::connect_cancellable(
+                    wayland_display,
+                    Some(&output_info.name),
+                    show_pointer,
+                    Some(local),
+                    cancel,
+                )
+                .map(Some)
+                .map_err(session_error_msg)
+            }
+            _ => {
+                let region_capture = Arc::new(RegionCapture::connect(
+                    wayland_display,
+                    region,
+                    show_pointer,
+                    &outputs,
+                    cancel,
+                )?);
+                gstreamer::debug!(CAT, imp: self, "composing {:?}", region_capture);
+                *self.region_capture.lock().unwrap() = Some(region_capture.clone());
+                // `unlock` may have run while connecting
+                if self.unlocked.load(Ordering::SeqCst) {
+                    region_capture
+                        .sessions()
+                        .for_each(|session| session.interrupt());
                 }
-                socket_path.push(wayland_display);
-                socket_path
-            };
+                Ok(None)
+            }
+        }
+    }
 
-            let stream = UnixStream::connect(socket_path).expect("oh no");
-            Connection::from_socket(stream).unwrap()
-        } else {
-            Connection::connect_to_env().unwrap()
-        };
-        let (globals, mut event_queue) = registry_queue_init::<WaylandState>(&conn).unwrap();
-        let qhandle = event_queue.handle();
-        let wl_shm = globals
-            .bind::<wayland_client::protocol::wl_shm::WlShm, _, _>(&qhandle, 1..=1, ())
-            .expect("wl_shm missing");
-        let zwp_linux_dmabuf = globals.bind::<wayland_protocols::wp::linux_dmabuf::zv1::client::zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1, _, _>(&qhandle, 2..=3, ()).ok();
-        let wlr_screencopy_manager = globals.bind::<wayland_protocols_wlr::screencopy::v1::client::zwlr_screencopy_manager_v1::ZwlrScreencopyManagerV1, _, _>(&qhandle, 1..=3, ()).expect("not wlr screencopy");
-        let xdg_output_manager = globals.bind::<wayland_protocols::xdg::xdg_output::zv1::client::zxdg_output_manager_v1::ZxdgOutputManagerV1, _, _>(&qhandle, 2..=3, ()).ok();
-
-        let mut wayland_state = WaylandState {
-            current_frame: None,
-            outputs: Vec::new(),
-            wlr_screencopy_manager,
-            wl_shm,
-            dmabuf: zwp_linux_dmabuf,
-            qhandle: qhandle.clone(),
-        };
-
-        globals.contents().with_list(|global_list| {
-            for global in global_list
-                .iter()
-                .filter(|global| global.interface == "wl_output")
-            {
-                if global.version < 2 {
-                    panic!("at least version 2 is required");
+    /// Release the session opened by `connect_to_wl_display`.
+    fn disconnect_from_wl_display(&self) {
+        // The copy targets a buffer of this connection
+        self.pending_copy.lock().unwrap().take();
+        self.input.lock().unwrap().take();
+        self.session.lock().unwrap().take();
+        self.region_capture.lock().unwrap().take();
+    }
+
+    /// Capture and compose the next frame of a region spanning several outputs,
+    /// returns `None` after reconnecting as the outputs may have changed.
+    fn create_region_frame(
+        &self,
+        region_capture: &RegionCapture,
+    ) -> Result<Option<gstreamer::Buffer>, gstreamer::FlowError> {
+        let mut failures = 0;
+        loop {
+            let pool = self
+                .obj()
+                .buffer_pool()
+                .expect("buffer_pool set in decide_allocation");
+            let buffer = pool.acquire_buffer(None)?;
+            let mut frame = gstreamer_video::VideoFrame::from_buffer_writable(
+                buffer,
+                region_capture.video_info(),
+            )
+            .map_err(|_| {
+                gstreamer::warning!(CAT, imp: self, "failed to map output frame");
+                gstreamer::FlowError::Error
+            })?;
+
+            let timestamp = match region_capture.capture(&mut frame) {
+                Ok(Some(timestamp)) => timestamp,
+                Ok(None) => {
+                    drop(frame);
+                    self.retry_failed_frame(&mut failures)?;
+                    continue;
+                }
+                Err(SessionError::Dispatch(err)) => {
+                    drop(frame);
+                    drop(pool);
+                    self.handle_disconnect(err)?;
+                    return Ok(None);
                 }
+                Err(SessionError::Interrupted) => return Err(gstreamer::FlowError::Flushing),
+                Err(err) => {
+                    self.post_error_message(session_error_msg(err));
+                    return Err(gstreamer::FlowError::Error);
+                }
+            };
 
-                let version = std::cmp::min(global.version, 4);
+            let (mut buffer, _) =
+                self.apply_privacy(frame.into_buffer(), region_capture.video_info())?;
+            let pts = self.running_time_from_monotonic(timestamp);
+            let buffer_mut = buffer.make_mut();
+            buffer_mut.set_pts(pts);
+            gstreamer::ReferenceTimestampMeta::add(
+                buffer_mut,
+                &REFERENCE_TIMESTAMP_CAPS,
+                gstreamer::ClockTime::from_nseconds(timestamp.as_nanos() as u64),
+                gstreamer::ClockTime::NONE,
+            );
+            let (sequence, dropped) = self.frame_counter.lock().unwrap().push_frame();
+            ScreencopyFrameMeta::add(buffer_mut, sequence, dropped);
+            self.record_stats(timestamp, dropped, "shm");
+            return Ok(Some(buffer));
+        }
+    }
 
-                let output = globals
-                    .registry()
-                    .bind::<wayland_client::protocol::wl_output::WlOutput, _, _>(
-                        global.name,
-                        version,
-                        &qhandle,
-                        (),
-                    );
+    /// Inject a navigation event into the compositor, returns `false` if it is not
+    /// a navigation event or navigation is disabled.
+    fn handle_navigation(&self, event: &gstreamer::Event) -> bool {
+        if event.type_() != gstreamer::EventType::Navigation {
+            return false;
+        }
+        let mut input = self.input.lock().unwrap();
+        let Some(input) = input.as_mut() else {
+            return false;
+        };
 
-                let zxdg_output = if version < 4 {
-                    xdg_output_manager.as_ref().map(|xdg_output_manager| {
-                        xdg_output_manager.get_xdg_output(&output, &qhandle, output.downgrade())
-                    })
-                } else {
-                    None
-                };
+        let navigation_event = match gstreamer_video::NavigationEvent::parse(event) {
+            Ok(navigation_event) => navigation_event,
+            Err(err) => {
+                gstreamer::debug!(CAT, imp: self, "invalid navigation event: {}", err);
+                return false;
+            }
+        };
 
-                wayland_state
-                    .outputs
-                    .push((output, zxdg_output, Default::default()));
+        // Coordinates are relative to the negotiated frame
+        let frame_size = || {
+            self.obj()
+                .src_pad()
+                .current_caps()
+                .and_then(|caps| dma_drm::video_info(&caps).ok())
+                .map(|video_info| (video_info.width(), video_info.height()))
+        };
+
+        gstreamer::trace!(CAT, imp: self, "injecting {:?}", navigation_event);
+        match navigation_event {
+            gstreamer_video::NavigationEvent::KeyPress { key, .. } => input.key(&key, true),
+            gstreamer_video::NavigationEvent::KeyRelease { key, .. } => input.key(&key, false),
+            gstreamer_video::NavigationEvent::MouseMove { x, y, .. } => {
+                let Some((width, height)) = frame_size() else {
+                    return false;
+                };
+                input.pointer_motion(x, y, width, height);
             }
-        });
+            gstreamer_video::NavigationEvent::MouseButtonPress { button, x, y, .. }
+            | gstreamer_video::NavigationEvent::MouseButtonRelease { button, x, y, .. } => {
+                let pressed = matches!(
+                    navigation_event,
+                    gstreamer_video::NavigationEvent::MouseButtonPress { .. }
+                );
+                if let Some((width, height)) = frame_size() {
+                    input.pointer_motion(x, y, width, height);
+                }
+                input.pointer_button(button, pressed);
+            }
+            gstreamer_video::NavigationEvent::MouseScroll {
+                x,
+                y,
+                delta_x,
+                delta_y,
+                ..
+            } => {
+                if let Some((width, height)) = frame_size() {
+                    input.pointer_motion(x, y, width, height);
+                }
+                input.pointer_scroll(delta_x, delta_y);
+            }
+            _ => return false,
+        }
+        true
+    }
 
-        // roundtrip to get data for our output info
-        while wayland_state.outputs.iter().any(|(_, _, info)| !info.done) {
-            event_queue
-                .blocking_dispatch(&mut wayland_state)
-                .expect("failed to dispatch");
+    /// Apply copy-timeout-policy after the compositor did not copy a frame within `timeout`.
+    fn handle_copy_timeout(
+        &self,
+        timeout: std::time::Duration,
+    ) -> Result<(), gstreamer::FlowError> {
+        let policy = self.settings.lock().unwrap().copy_timeout_policy;
+        if policy == CopyTimeoutPolicy::Error {
+            self.post_error_message(gstreamer::error_msg!(
+                gstreamer::ResourceError::Read,
+                ("Compositor did not copy a frame within {:?}", timeout)
+            ));
+            return Err(gstreamer::FlowError::Error);
         }
 
-        let (output, _, _) = if let Some(output_name) = output_name {
-            wayland_state
-                .outputs
-                .iter()
-                .find(|(_, _, info)| info.name == output_name)
-                .unwrap_or_else(|| {
-                    panic!(
-                        "output {} not found, available outputs: {}",
-                        output_name,
-                        wayland_state
-                            .outputs
-                            .iter()
-                            .map(|(_, _, info)| &info.name)
-                            .fold("".to_owned(), |acc, item| { format!("{} {}", acc, item) })
-                            .trim()
-                    )
-                })
+        if !self.copy_timed_out.swap(true, Ordering::SeqCst) {
+            gstreamer::element_imp_warning!(
+                self,
+                gstreamer::ResourceError::Read,
+                ("Compositor did not copy a frame within {:?}", timeout),
+                ["retrying with policy {:?}", policy]
+            );
         } else {
-            wayland_state.outputs.first().expect("no outputs")
-        };
+            gstreamer::debug!(CAT, imp: self, "copy timed out again");
+        }
+        self.frame_counter.lock().unwrap().drop_frame();
+        if policy == CopyTimeoutPolicy::Gap {
+            self.push_gap(timeout)?;
+        }
+        Ok(())
+    }
 
-        let frame = wayland_state
-            .wlr_screencopy_manager
-            .capture_output(0, output, &qhandle, ());
-        wayland_state.current_frame = Some((frame, Default::default()));
+    /// Pause capturing while the captured output is powered off, waits up to one
+    /// frame interval for it to be powered on again.
+    ///
+    /// Returns the last frame to push again with `PowerOffMode::LastFrame`, a gap
+    /// is pushed otherwise.
+    fn handle_powered_off(&self) -> Result<Option<gstreamer::Buffer>, gstreamer::FlowError> {
+        let session = self.session()?;
+        if !self.powered_off.swap(true, Ordering::SeqCst) {
+            gstreamer::info!(CAT, imp: self, "output powered off, pausing capture");
+            // The compositor does not render the output, a pending copy never completes
+            if let Some((buffer, pool, _)) = self.pending_copy.lock().unwrap().take() {
+                discard_buffer(&pool, buffer);
+            }
+            session.stop_capture();
+        }
 
-        // third roundtrip to get frame info
-        while !wayland_state
-            .current_frame
-            .as_ref()
-            .map(|(_, info)| info.done)
-            .unwrap_or(false)
-        {
-            event_queue
-                .blocking_dispatch(&mut wayland_state)
-                .expect("failed to dispatch");
+        let interval = self
+            .frame_interval(&session)
+            .unwrap_or(POWERED_OFF_INTERVAL);
+        match session.wait_powered_on(Some(Instant::now() + interval)) {
+            Ok(true) => {
+                gstreamer::info!(CAT, imp: self, "output powered on, resuming capture");
+                self.powered_off.store(false, Ordering::SeqCst);
+                return Ok(None);
+            }
+            Ok(false) => (),
+            Err(SessionError::Dispatch(err)) => {
+                self.handle_disconnect(err)?;
+                return Ok(None);
+            }
+            Err(SessionError::Interrupted) => return Err(gstreamer::FlowError::Flushing),
+            Err(err) => {
+                self.post_error_message(session_error_msg(err));
+                return Err(gstreamer::FlowError::Error);
+            }
         }
 
-        *self.wayland_state.lock().unwrap() = Some(wayland_state);
-        *self._connection.lock().unwrap() = Some(conn);
-        *self.event_queue.lock().unwrap() = Some(event_queue);
+        let last_frame = (self.settings.lock().unwrap().power_off_mode == PowerOffMode::LastFrame)
+            .then(|| self.last_frame.lock().unwrap().clone())
+            .flatten();
+        let Some(mut buffer) = last_frame else {
+            self.push_gap(interval)?;
+            return Ok(None);
+        };
+        let pts = self.running_time_now();
+        *self.gap_position.lock().unwrap() = pts;
+        buffer.make_mut().set_pts(pts);
+        Ok(Some(buffer))
     }
-}
 
-impl ObjectImpl for WlrScreencopySrc {
-    fn properties() -> &'static [glib::ParamSpec] {
-        static PROPERTIES: Lazy<Vec<glib::ParamSpec>> = Lazy::new(|| {
-            vec![
-                glib::ParamSpecString::builder("display")
-                    .nick("Wayland Display")
-                    .blurb("Wayland Display to use")
-                    .construct()
-                    .build(),
-                glib::ParamSpecString::builder("output-name")
-                    .nick("Wayland output name")
-                    .blurb("Name of the output to capture")
-                    .construct()
-                    .build(),
-            ]
-        });
+    /// Capture a single frame while stopped, see
+    /// [`capture_snapshot`](super::WlrScreencopySrc::capture_snapshot).
+    pub(super) fn capture_snapshot(
+        &self,
+        timeout: Option<std::time::Duration>,
+    ) -> Result<gstreamer::Sample, gstreamer::ErrorMessage> {
+        if self.obj().current_state() != gstreamer::State::Null {
+            return Err(gstreamer::error_msg!(
+                gstreamer::CoreError::StateChange,
+                ["snapshots can only be captured in the NULL state"]
+            ));
+        }
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
 
-        PROPERTIES.as_ref()
+        self.connect_to_wl_display()?;
+        let result = self.snapshot(deadline);
+        self.disconnect_from_wl_display();
+        *self.last_safe_buffer.lock().unwrap() = None;
+        result
     }
 
-    fn set_property(&self, _id: usize, value: &glib::Value, pspec: &glib::ParamSpec) {
-        match pspec.name() {
-            "display" => {
-                let mut settings = self.settings.lock().unwrap();
-                let wayland_display = value
-                    .get::<Option<String>>()
-                    .expect("type checked upstream");
-                settings.wayland_display = wayland_display;
+    fn snapshot(
+        &self,
+        deadline: Option<Instant>,
+    ) -> Result<gstreamer::Sample, gstreamer::ErrorMessage> {
+        let region_capture = self.region_capture.lock().unwrap().clone();
+        let (buffer, video_info) = match region_capture {
+            Some(region_capture) => snapshot::capture_region(&region_capture)?,
+            None => {
+                let session = self.session.lock().unwrap().clone().unwrap();
+                let Some(output_info) = session.output_info() else {
+                    return Err(gstreamer::error_msg!(
+                        gstreamer::ResourceError::NotFound,
+                        ["output {:?} not found", session.output_name()]
+                    ));
+                };
+                let presentation = self.settings.lock().unwrap().presentation;
+                let formats = session.buffer_formats();
+                let (width, height) = formats
+                    .shm
+                    .first()
+                    .map(|shm_format| (shm_format.width, shm_format.height))
+                    .unwrap_or((
+                        output_info.mode.width as u32,
+                        output_info.mode.height as u32,
+                    ));
+                let par = pixel_aspect_ratio(&output_info, presentation, width, height);
+                snapshot::capture_output(&session, par, deadline)?
+            }
+        };
+
+        // Snapshots hide private applications like streamed frames
+        let (buffer, _) = self.apply_privacy(buffer, &video_info).map_err(|_| {
+            gstreamer::error_msg!(
+                gstreamer::ResourceError::Write,
+                ["failed to hide private application"]
+            )
+        })?;
+        let caps = video_info
+            .to_caps()
+            .map_err(|err| gstreamer::error_msg!(gstreamer::CoreError::Negotiation, ["{}", err]))?;
+        Ok(gstreamer::Sample::builder()
+            .buffer(&buffer)
+            .caps(&caps)
+            .build())
+    }
+
+    /// The current session, fails with flushing while reconnecting.
+    fn session(&self) -> Result<Arc<ScreencopySession>, gstreamer::FlowError> {
+        self.session
+            .lock()
+            .unwrap()
+            .clone()
+            .ok_or(gstreamer::FlowError::Flushing)
+    }
+}
+
+impl WlrScreencopySrc {
+    /// Copy the current frame into a buffer from `pool` and schedule the next frame.
+    ///
+    /// In damage-aware mode this returns [`Capture::NoDamage`] if the output has not
+    /// been damaged within one frame interval, the next call continues waiting.
+    fn capture(&self, pool: &gstreamer::BufferPool) -> Result<Capture, gstreamer::FlowError> {
+        let session = self.session()?;
+        if session.is_powered_off() {
+            return Ok(Capture::PoweredOff);
+        }
+
+        // Capturing is stopped after connecting with defer-capture
+        match session.start_capture() {
+            Ok(()) => (),
+            Err(SessionError::Dispatch(err)) => return Ok(Capture::Disconnected(err)),
+            Err(SessionError::Interrupted) => return Err(gstreamer::FlowError::Flushing),
+            Err(SessionError::OutputNotFound { .. }) => return Ok(Capture::OutputLost),
+            Err(err) => {
+                self.post_error_message(session_error_msg(err));
+                return Err(gstreamer::FlowError::Error);
+            }
+        }
+
+        let pending_copy = self.pending_copy.lock().unwrap().take();
+        // The pool changed since the copy was submitted, the buffer does not match the
+        // negotiated caps anymore
+        let mut stale = false;
+        let (new_buffer, pool, submitted) = match pending_copy {
+            Some((new_buffer, pending_pool, submitted)) => {
+                stale = pending_pool != *pool;
+                (new_buffer, pending_pool, Some(submitted))
+            }
+            None => {
+                let buffer_pool_aquire_params = gstreamer::BufferPoolAcquireParams::with_flags(
+                    gstreamer::BufferPoolAcquireFlags::empty(),
+                );
+                let acquire_timeout = self.settings.lock().unwrap().acquire_timeout;
+                let new_buffer = match pool.acquire_buffer(Some(&buffer_pool_aquire_params)) {
+                    // Only a pool with acquire-timeout gives up without DONTWAIT
+                    Err(gstreamer::FlowError::Eos) if acquire_timeout > 0 => {
+                        return Ok(Capture::NoBuffer(std::time::Duration::from_millis(
+                            acquire_timeout as u64,
+                        )))
+                    }
+                    result => result?,
+                };
+                self.start_copy(&session, pool, &new_buffer)?;
+                (new_buffer, pool.clone(), None)
+            }
+        };
+
+        let (damage_aware, leaky, copy_timeout) = {
+            let settings = self.settings.lock().unwrap();
+            (settings.damage_aware, settings.leaky, settings.copy_timeout)
+        };
+        let frame_interval = if damage_aware {
+            self.frame_interval(&session)
+        } else {
+            None
+        };
+        // Waiting for damage is no sign of a frozen compositor
+        let copy_timeout = (!damage_aware && copy_timeout > 0)
+            .then(|| std::time::Duration::from_millis(copy_timeout as u64));
+        // A copy submitted ahead more than one frame interval ago is from before
+        // downstream blocked, in damage-aware mode a new copy would wait for the
+        // next damage so the frame is kept
+        let overdue = leaky
+            && !damage_aware
+            && submitted
+                .zip(self.frame_interval(&session))
+                .map(|(submitted, frame_interval)| submitted.elapsed() > frame_interval)
+                .unwrap_or(false);
+        let deadline = frame_interval
+            .map(|frame_interval| Instant::now() + frame_interval)
+            .or_else(|| {
+                copy_timeout
+                    .map(|copy_timeout| submitted.unwrap_or_else(Instant::now) + copy_timeout)
+            });
+        let mut copied_frame = match session.wait_copied(deadline) {
+            Ok(Some(copied_frame)) => copied_frame,
+            Ok(None) if copy_timeout.is_some() => {
+                // The compositor may still copy into the buffer after the frame is
+                // destroyed, a new frame is scheduled with the next capture
+                discard_buffer(&pool, new_buffer);
+                session.stop_capture();
+                return Ok(Capture::TimedOut(copy_timeout.unwrap()));
+            }
+            Ok(None) => {
+                *self.pending_copy.lock().unwrap() =
+                    Some((new_buffer, pool, submitted.unwrap_or_else(Instant::now)));
+                return Ok(Capture::NoDamage(frame_interval.unwrap()));
+            }
+            Err(SessionError::Dispatch(err)) => return Ok(Capture::Disconnected(err)),
+            Err(SessionError::Interrupted) => return Err(gstreamer::FlowError::Flushing),
+            Err(SessionError::PoweredOff) => {
+                discard_buffer(&pool, new_buffer);
+                session.stop_capture();
+                return Ok(Capture::PoweredOff);
+            }
+            // The output went away while streaming
+            Err(SessionError::OutputNotFound { .. }) => return Ok(Capture::OutputLost),
+            Err(err) => {
+                self.post_error_message(session_error_msg(err));
+                return Err(gstreamer::FlowError::Error);
+            }
+        };
+        // The compositor fails the frames of removed outputs
+        if copied_frame.state == FrameState::Failed && session.output_info().is_none() {
+            return Ok(Capture::OutputLost);
+        }
+
+        // Check if the output changed in a way that requires new caps, the new frame
+        // will then be copied into a buffer from the renegotiated pool
+        let mode_changed = session.take_mode_changed();
+        let capture_video_info = self.convert_input.lock().unwrap().clone().or_else(|| {
+            self.obj()
+                .src_pad()
+                .current_caps()
+                .and_then(|caps| dma_drm::video_info(&caps).ok())
+        });
+        let frame_changed = capture_video_info
+            .map(|video_info| !frame_matches_video_info(&session.buffer_formats(), &video_info))
+            .unwrap_or(false);
+        if mode_changed || frame_changed {
+            gstreamer::info!(CAT, imp: self, "output changed, renegotiating");
+            self.obj().src_pad().mark_reconfigure();
+            self.post_output_geometry(&session);
+        } else if !stale {
+            // Let the compositor copy the next frame while this one is pushed downstream
+            self.submit_next_copy(&session, &pool)?;
+        }
+
+        if stale {
+            gstreamer::debug!(CAT, imp: self, "discarding frame copied into a stale buffer");
+            return Ok(Capture::Discarded);
+        }
+
+        if overdue {
+            gstreamer::debug!(CAT, imp: self, "dropping frame copied before downstream blocked");
+            return Ok(Capture::Discarded);
+        }
+
+        if self.settings.lock().unwrap().damage_report == DamageReport::Union {
+            copied_frame.damage = damage_union(&copied_frame.damage).into_iter().collect();
+        }
+
+        Ok(Capture::Frame(new_buffer, copied_frame))
+    }
+
+    /// Submit the copy of the next frame into a free buffer of `pool`, picked up by
+    /// the next call to `capture`.
+    fn submit_next_copy(
+        &self,
+        session: &ScreencopySession,
+        pool: &gstreamer::BufferPool,
+    ) -> Result<(), gstreamer::FlowError> {
+        // Never block on downstream holding all buffers, the copy is submitted
+        // with the next capture then
+        let buffer_pool_aquire_params = gstreamer::BufferPoolAcquireParams::with_flags(
+            gstreamer::BufferPoolAcquireFlags::DONTWAIT,
+        );
+        let Ok(next_buffer) = pool.acquire_buffer(Some(&buffer_pool_aquire_params)) else {
+            return Ok(());
+        };
+        self.start_copy(session, pool, &next_buffer)?;
+        *self.pending_copy.lock().unwrap() = Some((next_buffer, pool.clone(), Instant::now()));
+        Ok(())
+    }
+
+    /// Ask the compositor to copy the current frame into `buffer`.
+    fn start_copy(
+        &self,
+        session: &ScreencopySession,
+        pool: &gstreamer::BufferPool,
+        buffer: &gstreamer::Buffer,
+    ) -> Result<(), gstreamer::FlowError> {
+        let wl_buffer_meta = buffer
+            .meta::<WaylandBufferMeta>()
+            .expect("no wayland buffer meta");
+        let damage_aware = self.settings.lock().unwrap().damage_aware;
+        // The buffer has to be busy before the compositor can release it
+        if let Some(pool) = pool.downcast_ref::<WaylandBufferPool>() {
+            pool.mark_busy(buffer);
+        }
+        session
+            .copy(wl_buffer_meta.wl_buffer(), damage_aware)
+            .map_err(|err| {
+                self.post_error_message(session_error_msg(err));
+                gstreamer::FlowError::Error
+            })
+    }
+
+    /// The interval after which a gap is reported if the output was not damaged, the
+    /// negotiated framerate or the refresh rate of the output for variable framerates.
+    fn frame_interval(&self, session: &ScreencopySession) -> Option<std::time::Duration> {
+        if let Some(interval) = self.caps_frame_interval() {
+            return Some(interval);
+        }
+
+        // The refresh rate is in mHz
+        session
+            .output_info()
+            .map(|info| info.mode.refresh)
+            .filter(|refresh| *refresh > 0)
+            .map(|refresh| std::time::Duration::from_nanos(1_000_000_000_000 / refresh as u64))
+    }
+
+    /// Frame interval of the negotiated framerate, `None` for variable framerates
+    fn caps_frame_interval(&self) -> Option<std::time::Duration> {
+        let fps = self
+            .obj()
+            .src_pad()
+            .current_caps()
+            .and_then(|caps| dma_drm::video_info(&caps).ok())
+            .map(|video_info| video_info.fps())
+            .filter(|fps| fps.numer() > 0 && fps.denom() > 0);
+        fps.map(|fps| {
+            std::time::Duration::from_nanos(1_000_000_000 * fps.denom() as u64 / fps.numer() as u64)
+        })
+    }
+
+    /// Buffers in flight while downstream holds every frame for the pipeline latency,
+    /// 1 for low latency pipelines or before the latency is known.
+    fn latency_buffers(&self) -> u32 {
+        let Some(latency) = *self.pipeline_latency.lock().unwrap() else {
+            return 1;
+        };
+        let session = self.session.lock().unwrap().clone();
+        let interval = match session {
+            Some(session) => self.frame_interval(&session),
+            None => self.caps_frame_interval(),
+        };
+        let Some(interval) = interval.filter(|interval| !interval.is_zero()) else {
+            return 1;
+        };
+        let frames = latency.nseconds() as u128 / interval.as_nanos();
+        std::cmp::min(frames, MAX_LATENCY_BUFFERS as u128 - 1) as u32 + 1
+    }
+
+    /// Remember the pipeline latency and resize the pool with the next negotiation
+    /// if it needs a different number of buffers in flight.
+    fn handle_latency(&self, latency: gstreamer::ClockTime) {
+        let previous = self.latency_buffers();
+        *self.pipeline_latency.lock().unwrap() = Some(latency);
+        let buffers = self.latency_buffers();
+        if buffers != previous {
+            gstreamer::debug!(CAT, imp: self, "latency {} needs {} buffers in flight", latency, buffers);
+            self.obj().src_pad().mark_reconfigure();
+        }
+    }
+
+    /// Whether drop-duplicates is enabled and `buffer` equals the previous frame.
+    ///
+    /// Damaged frames always changed, the checksum is only computed when the
+    /// compositor did not report damage.
+    fn is_duplicate(&self, buffer: &gstreamer::BufferRef, damaged: bool) -> bool {
+        let mut last_checksum = self.last_checksum.lock().unwrap();
+        if !self.settings.lock().unwrap().drop_duplicates || damaged {
+            *last_checksum = None;
+            return false;
+        }
+        let Some(checksum) = frame_checksum(buffer) else {
+            *last_checksum = None;
+            return false;
+        };
+        last_checksum.replace(checksum) == Some(checksum)
+    }
+
+    /// Hide `buffer` if a private application is focused on the captured outputs,
+    /// returns whether it was hidden.
+    ///
+    /// The buffer must not carry the metas of the element yet, the last safe frame
+    /// is pushed again in place of the hidden one.
+    fn apply_privacy(
+        &self,
+        buffer: gstreamer::Buffer,
+        video_info: &gstreamer_video::VideoInfo,
+    ) -> Result<(gstreamer::Buffer, bool), gstreamer::FlowError> {
+        let (app_ids, fill, color) = {
+            let settings = self.settings.lock().unwrap();
+            if settings.privacy_app_ids.is_empty() {
+                return Ok((buffer, false));
+            }
+            (
+                settings.privacy_app_ids.clone(),
+                settings.privacy_fill,
+                settings.privacy_color,
+            )
+        };
+
+        let sessions: Vec<_> = match self.region_capture.lock().unwrap().as_ref() {
+            Some(region_capture) => region_capture.sessions().cloned().collect(),
+            None => self.session.lock().unwrap().iter().cloned().collect(),
+        };
+        // Without toplevel information nothing is known to be safe
+        let focused = sessions.iter().find_map(|session| {
+            if !session.supports_toplevels() {
+                return Some(String::from("unknown application"));
+            }
+            privacy::private_app_id(&app_ids, &session.activated_app_ids()).map(String::from)
+        });
+        let Some(focused) = focused else {
+            if fill == PrivacyFill::LastFrame {
+                *self.last_safe_buffer.lock().unwrap() = Some(buffer.clone());
+            }
+            return Ok((buffer, false));
+        };
+        gstreamer::trace!(CAT, imp: self, "hiding frame, {} is focused", focused);
+
+        if fill == PrivacyFill::LastFrame {
+            if let Some(last_safe_buffer) = self.last_safe_buffer.lock().unwrap().clone() {
+                return Ok((last_safe_buffer, true));
+            }
+        }
+        let color = if fill == PrivacyFill::Color {
+            color
+        } else {
+            privacy::BLACK
+        };
+        let mut frame = gstreamer_video::VideoFrame::from_buffer_writable(buffer, video_info)
+            .map_err(|_| {
+                gstreamer::warning!(CAT, imp: self, "failed to map frame to hide");
+                gstreamer::FlowError::Error
+            })?;
+        privacy::fill_frame(&mut frame.as_mut_video_frame_ref(), color).map_err(|err| {
+            gstreamer::warning!(CAT, imp: self, "failed to hide frame: {}", err);
+            gstreamer::FlowError::Error
+        })?;
+        Ok((frame.into_buffer(), true))
+    }
+
+    /// Repack a captured frame if downstream needs it and hide it while a private
+    /// application is focused, returns whether it was hidden.
+    fn finish_frame(
+        &self,
+        buffer: gstreamer::Buffer,
+        repack: bool,
+    ) -> Result<(gstreamer::Buffer, bool), gstreamer::FlowError> {
+        let buffer = if repack { self.repack(buffer)? } else { buffer };
+        let video_info = self
+            .obj()
+            .src_pad()
+            .current_caps()
+            .and_then(|caps| dma_drm::video_info(&caps).ok());
+        match video_info {
+            Some(video_info) => self.apply_privacy(buffer, &video_info),
+            None => Ok((buffer, false)),
+        }
+    }
+
+    /// Attach a time code counting frames at the negotiated framerate since running
+    /// time 0, or at the refresh rate of the output for variable framerates.
+    ///
+    /// The time code follows the capture clock, frames skipped by damage-aware
+    /// capture or dropping advance it as well.
+    fn add_time_code(&self, buffer: &mut gstreamer::BufferRef, pts: Option<gstreamer::ClockTime>) {
+        let Some(pts) = pts else {
+            return;
+        };
+        let fps = self
+            .obj()
+            .src_pad()
+            .current_caps()
+            .and_then(|caps| dma_drm::video_info(&caps).ok())
+            .map(|video_info| video_info.fps())
+            .filter(|fps| fps.numer() > 0 && fps.denom() > 0)
+            .or_else(|| {
+                self.session
+                    .lock()
+                    .unwrap()
+                    .as_ref()
+                    .and_then(|session| session.output_info())
+                    .and_then(|info| refresh_rate(info.mode.refresh))
+            });
+        let Some(fps) = fps else {
+            return;
+        };
+
+        let daily_jam = self
+            .time_code_jam
+            .lock()
+            .unwrap()
+            .get_or_insert_with(|| glib::DateTime::now_local().ok())
+            .clone();
+        let flags = if fps.denom() == 1001 && (fps.numer() == 30_000 || fps.numer() == 60_000) {
+            gstreamer_video::VideoTimeCodeFlags::DROP_FRAME
+        } else {
+            gstreamer_video::VideoTimeCodeFlags::empty()
+        };
+        let frames = pts.nseconds() as u128 * fps.numer() as u128
+            / (fps.denom() as u128 * gstreamer::ClockTime::SECOND.nseconds() as u128);
+
+        let time_code =
+            gstreamer_video::VideoTimeCode::new(fps, daily_jam.as_ref(), flags, 0, 0, 0, 0, 0);
+        match gstreamer_video::ValidVideoTimeCode::try_from(time_code) {
+            Ok(mut time_code) => {
+                time_code.add_frames(frames as i64);
+                gstreamer_video::VideoTimeCodeMeta::add(buffer, &time_code);
+            }
+            Err(_) => {
+                gstreamer::debug!(CAT, imp: self, "no valid time code for {} at {}", pts, fps)
+            }
+        }
+    }
+
+    /// Wait before retrying a failed frame, fails once the retries are exhausted.
+    ///
+    /// Output reconfiguration and VT switches let single frames fail, the next
+    /// frame usually succeeds.
+    fn retry_failed_frame(&self, failures: &mut u32) -> Result<(), gstreamer::FlowError> {
+        let (max_retries, retry_delay, pause_on_failure) = {
+            let settings = self.settings.lock().unwrap();
+            (
+                settings.max_retries,
+                settings.retry_delay,
+                settings.pause_on_failure,
+            )
+        };
+
+        *failures += 1;
+        if *failures > max_retries && pause_on_failure {
+            // Frames fail while the session is inactive, for example after switching
+            // to another VT, and are copied again once it resumes
+            if !self.capture_paused.swap(true, Ordering::SeqCst) {
+                gstreamer::element_imp_warning!(
+                    self,
+                    gstreamer::ResourceError::Read,
+                    ("Failed to copy frames, pausing capture"),
+                    [
+                        "compositor failed {} consecutive frames, retrying every {:?}",
+                        *failures,
+                        PAUSED_RETRY_INTERVAL
+                    ]
+                );
+            }
+            self.push_gap(PAUSED_RETRY_INTERVAL)?;
+            return self.wait_unlocked(PAUSED_RETRY_INTERVAL);
+        }
+        if *failures > max_retries {
+            gstreamer::element_imp_error!(
+                self,
+                gstreamer::ResourceError::Read,
+                ("Failed to copy frame"),
+                ["compositor failed {} consecutive frames", *failures]
+            );
+            return Err(gstreamer::FlowError::Error);
+        }
+
+        let delay = std::cmp::min(
+            std::time::Duration::from_millis(retry_delay as u64)
+                .saturating_mul(1 << (*failures - 1).min(16)),
+            RETRY_BACKOFF_MAX,
+        );
+        gstreamer::element_imp_warning!(
+            self,
+            gstreamer::ResourceError::Read,
+            ("Failed to copy frame, retrying"),
+            [
+                "attempt {} of {}, retrying in {:?}",
+                *failures,
+                max_retries,
+                delay
+            ]
+        );
+        self.wait_unlocked(delay)
+    }
+
+    /// Handle a lost compositor connection, reconnecting if enabled.
+    ///
+    /// Returns once a new connection has been established and the caps have been
+    /// renegotiated for it.
+    fn handle_disconnect(
+        &self,
+        err: wayland_client::DispatchError,
+    ) -> Result<(), gstreamer::FlowError> {
+        if !self.settings.lock().unwrap().reconnect {
+            self.post_error_message(gstreamer::error_msg!(
+                gstreamer::ResourceError::Read,
+                ("Lost connection to the compositor"),
+                ["{}", err]
+            ));
+            return Err(gstreamer::FlowError::Error);
+        }
+
+        gstreamer::element_imp_warning!(
+            self,
+            gstreamer::ResourceError::Read,
+            ("Lost connection to the compositor, reconnecting"),
+            ["{}", err]
+        );
+        self.reconnect()?;
+        gstreamer::info!(CAT, imp: self, "reconnected to the compositor");
+        Ok(())
+    }
+
+    /// Handle the captured output going away. With an output-name pattern this waits
+    /// for a matching output to appear and captures it instead.
+    fn handle_output_lost(&self) -> Result<(), gstreamer::FlowError> {
+        let output_name = self.settings.lock().unwrap().output_name.clone();
+        let Some(pattern) = output_name.filter(|output_name| is_output_pattern(output_name)) else {
+            let session = self.session()?;
+            self.post_error_message(gstreamer::error_msg!(
+                gstreamer::ResourceError::NotFound,
+                (
+                    "Output {} went away",
+                    session.output_name().unwrap_or("of the compositor")
+                )
+            ));
+            return Err(gstreamer::FlowError::Error);
+        };
+
+        gstreamer::element_imp_warning!(
+            self,
+            gstreamer::ResourceError::NotFound,
+            (
+                "Output went away, waiting for an output matching {}",
+                pattern
+            )
+        );
+        // The session only knows the outputs present when it connected
+        self.reconnect()?;
+        let name = self.session()?.output_info().map(|info| info.name);
+        gstreamer::info!(CAT, imp: self, "capturing output {:?}", name);
+        Ok(())
+    }
+
+    /// Connect again with backoff, returns once a new connection has been established
+    /// and the caps have been renegotiated for it.
+    fn reconnect(&self) -> Result<(), gstreamer::FlowError> {
+        // Buffers and pools are bound to the old connection
+        self.disconnect_from_wl_display();
+        if let Some(repack_pool) = self.repack_pool.lock().unwrap().take() {
+            let _ = repack_pool.set_active(false);
+        }
+        if let Some(pool) = self.obj().buffer_pool() {
+            let _ = pool.set_active(false);
+        }
+
+        let mut backoff = RECONNECT_BACKOFF_MIN;
+        loop {
+            self.wait_unlocked(backoff)?;
+
+            match self.connect_to_wl_display() {
+                Ok(()) => break,
+                // The error message does not tell whether `unlock` interrupted connecting
+                Err(_) if self.unlocked.load(Ordering::SeqCst) => {
+                    self.disconnect_from_wl_display();
+                    return Err(gstreamer::FlowError::Flushing);
+                }
+                Err(err) => {
+                    gstreamer::debug!(CAT, imp: self, "reconnect failed: {:?}", err);
+                    self.disconnect_from_wl_display();
+                    backoff = std::cmp::min(backoff * 2, RECONNECT_BACKOFF_MAX);
+                }
+            }
+        }
+
+        if !self.obj().negotiate() {
+            return Err(gstreamer::FlowError::NotNegotiated);
+        }
+        Ok(())
+    }
+
+    /// Sleep for `timeout`, returns `Flushing` early if `unlock` interrupts it.
+    fn wait_unlocked(&self, timeout: std::time::Duration) -> Result<(), gstreamer::FlowError> {
+        let (lock, cond) = &self.unlock_cond;
+        let _guard = cond
+            .wait_timeout_while(lock.lock().unwrap(), timeout, |_| {
+                !self.unlocked.load(Ordering::SeqCst)
+            })
+            .unwrap();
+        if self.unlocked.load(Ordering::SeqCst)
+            || self
+                .obj()
+                .src_pad()
+                .pad_flags()
+                .contains(gstreamer::PadFlags::FLUSHING)
+        {
+            return Err(gstreamer::FlowError::Flushing);
+        }
+        Ok(())
+    }
+
+    /// Renegotiate caps and allocation if downstream sent a RECONFIGURE event, like
+    /// an encoder restarting with a different alignment. The base class only checks
+    /// before `create`, which can keep pushing gaps for a long time.
+    fn renegotiate_if_needed(&self) -> Result<(), gstreamer::FlowError> {
+        let obj = self.obj();
+        let src_pad = obj.src_pad();
+        if !src_pad.check_reconfigure() {
+            return Ok(());
+        }
+
+        gstreamer::debug!(CAT, imp: self, "reconfiguration requested, renegotiating");
+        if !obj.negotiate() {
+            // Try again with the next buffer like the base class does
+            src_pad.mark_reconfigure();
+            if src_pad.pad_flags().contains(gstreamer::PadFlags::FLUSHING) {
+                return Err(gstreamer::FlowError::Flushing);
+            }
+            return Err(gstreamer::FlowError::NotNegotiated);
+        }
+        Ok(())
+    }
+
+    /// Account a pushed frame and post the statistics if the interval passed.
+    fn record_stats(
+        &self,
+        timestamp: std::time::Duration,
+        dropped: u64,
+        memory_type: &'static str,
+    ) {
+        let stats_interval = self.settings.lock().unwrap().stats_interval;
+        if stats_interval == 0 {
+            return;
+        }
+
+        let latency = nix::time::clock_gettime(nix::time::ClockId::CLOCK_MONOTONIC)
+            .ok()
+            .map(|now| std::time::Duration::from(now).saturating_sub(timestamp));
+        let report = {
+            let mut stats = self.stats.lock().unwrap();
+            stats.record_frame(latency, dropped, memory_type);
+            stats.take_report(std::time::Duration::from_secs(stats_interval as u64))
+        };
+
+        if let Some(report) = report {
+            gstreamer::debug!(CAT, imp: self, "posting stats {}", report);
+            let obj = self.obj();
+            let _ = obj.post_message(
+                gstreamer::message::Element::builder(report)
+                    .src(&*obj)
+                    .build(),
+            );
+        }
+    }
+
+    /// Tell the application where the captured output lies in the compositor layout
+    fn post_output_geometry(&self, session: &ScreencopySession) {
+        let Some(output_info) = session.output_info() else {
+            return;
+        };
+        let geometry = output_geometry(&output_info);
+        gstreamer::debug!(CAT, imp: self, "posting output geometry {}", geometry);
+        let obj = self.obj();
+        let _ = obj.post_message(
+            gstreamer::message::Element::builder(geometry)
+                .src(&*obj)
+                .build(),
+        );
+    }
+
+    /// Cover `interval` after the last buffer or gap with a gap event.
+    fn push_gap(&self, interval: std::time::Duration) -> Result<(), gstreamer::FlowError> {
+        let duration = gstreamer::ClockTime::from_nseconds(interval.as_nanos() as u64);
+        let mut gap_position = self.gap_position.lock().unwrap();
+        let Some(timestamp) = gap_position.or_else(|| {
+            self.running_time_now()
+                .map(|now| now.saturating_sub(duration))
+        }) else {
+            return Ok(());
+        };
+        *gap_position = Some(timestamp + duration);
+        drop(gap_position);
+
+        gstreamer::trace!(CAT, imp: self, "no damage, pushing gap at {}", timestamp);
+        let pushed = self.obj().src_pad().push_event(
+            gstreamer::event::Gap::builder(timestamp)
+                .duration(duration)
+                .build(),
+        );
+        if !pushed
+            && self
+                .obj()
+                .src_pad()
+                .pad_flags()
+                .contains(gstreamer::PadFlags::FLUSHING)
+        {
+            return Err(gstreamer::FlowError::Flushing);
+        }
+        Ok(())
+    }
+
+    /// Remember up to when downstream is late, a frame captured before that would
+    /// only be dropped by the sink after occupying the encoder.
+    fn handle_qos(&self, qos: &gstreamer::event::Qos) {
+        if !self.settings.lock().unwrap().qos {
+            return;
+        }
+
+        let (_, proportion, diff, timestamp) = qos.get();
+        let earliest_time = timestamp.filter(|_| diff > 0).map(|timestamp| {
+            // Skip twice the lateness so downstream can catch up
+            timestamp + gstreamer::ClockTime::from_nseconds(2 * diff as u64)
+        });
+        gstreamer::trace!(
+            CAT,
+            imp: self,
+            "qos proportion {} diff {} earliest time {:?}",
+            proportion,
+            diff,
+            earliest_time
+        );
+        *self.qos_earliest_time.lock().unwrap() = earliest_time;
+    }
+
+    /// Whether a frame with `pts` would arrive too late downstream
+    fn is_late(&self, pts: Option<gstreamer::ClockTime>) -> bool {
+        let earliest_time = *self.qos_earliest_time.lock().unwrap();
+        pts.zip(earliest_time)
+            .map(|(pts, earliest_time)| pts < earliest_time)
+            .unwrap_or(false)
+    }
+
+    fn running_time_now(&self) -> Option<gstreamer::ClockTime> {
+        let obj = self.obj();
+        if !obj.is_live() {
+            let monotonic_now =
+                nix::time::clock_gettime(nix::time::ClockId::CLOCK_MONOTONIC).ok()?;
+            return Some(self.stream_time_from_monotonic(monotonic_now.into()));
+        }
+        let now = obj.clock()?.time()?;
+        Some(now.saturating_sub(obj.base_time()?))
+    }
+
+    /// Convert a `CLOCK_MONOTONIC` timestamp from the compositor to running time.
+    ///
+    /// The pipeline clock is calibrated against `CLOCK_MONOTONIC` on every call, so
+    /// this also works for clocks other than the monotonic system clock.
+    fn running_time_from_monotonic(
+        &self,
+        timestamp: std::time::Duration,
+    ) -> Option<gstreamer::ClockTime> {
+        let obj = self.obj();
+        if !obj.is_live() {
+            return Some(self.stream_time_from_monotonic(timestamp));
+        }
+        let clock = obj.clock()?;
+        let base_time = obj.base_time()?;
+
+        let clock_now = clock.time()?;
+        let monotonic_now = nix::time::clock_gettime(nix::time::ClockId::CLOCK_MONOTONIC)
+            .map(std::time::Duration::from)
+            .ok()?;
+
+        // Frames are captured in the past, a capture time in the future can
+        // only come from a broken compositor
+        let age = monotonic_now.saturating_sub(timestamp);
+        let capture_time =
+            clock_now.saturating_sub(gstreamer::ClockTime::from_nseconds(age.as_nanos() as u64));
+
+        // Frames captured before the pipeline started count as captured at its start
+        Some(capture_time.saturating_sub(base_time))
+    }
+
+    /// Time since the first frame for a `CLOCK_MONOTONIC` timestamp, used instead of
+    /// the running time when not live as there is no clock while prerolling.
+    fn stream_time_from_monotonic(&self, timestamp: std::time::Duration) -> gstreamer::ClockTime {
+        let mut first_timestamp = self.first_timestamp.lock().unwrap();
+        let first_timestamp = *first_timestamp.get_or_insert(timestamp);
+        gstreamer::ClockTime::from_nseconds(
+            timestamp.saturating_sub(first_timestamp).as_nanos() as u64
+        )
+    }
+
+    /// Post a warning that frames are captured to shm instead of dmabuf.
+    fn warn_shm_fallback(&self, reason: String) {
+        let mut shm_fallback_reason = self.shm_fallback_reason.lock().unwrap();
+        // Only warn again if the reason changed, renegotiation happens a lot
+        if shm_fallback_reason.as_deref() == Some(reason.as_str()) {
+            return;
+        }
+
+        gstreamer::element_imp_warning!(
+            self,
+            gstreamer::CoreError::Negotiation,
+            ("Falling back to shm, capturing will use more CPU"),
+            ["{}", reason]
+        );
+        *shm_fallback_reason = Some(reason);
+    }
+
+    /// The pool proposed by downstream configured for `caps` if its buffers are
+    /// dmabufs, `None` otherwise.
+    fn downstream_memory_pool(
+        &self,
+        query: &gstreamer::query::Allocation,
+        caps: &gstreamer::Caps,
+        size: u32,
+        min: u32,
+        max: u32,
+    ) -> Option<gstreamer::BufferPool> {
+        let (pool, _, _, _) = query.allocation_pools().into_iter().next()?;
+        let pool = pool.filter(|pool| !pool.is::<WaylandBufferPool>())?;
+
+        let mut config = pool.config();
+        config.set_params(Some(caps), size, min, max);
+        if pool.has_option(gstreamer_video::BUFFER_POOL_OPTION_VIDEO_META.as_ref()) {
+            config.add_option(gstreamer_video::BUFFER_POOL_OPTION_VIDEO_META.as_ref());
+        }
+        if let Err(err) = pool.set_config(config) {
+            gstreamer::debug!(CAT, imp: self, "failed to configure downstream pool: {}", err);
+            return None;
+        }
+        if pool.set_active(true).is_err() {
+            return None;
+        }
+        let is_dmabuf = pool
+            .acquire_buffer(None)
+            .map(|buffer| {
+                buffer.iter_memories().all(|memory| {
+                    memory
+                        .downcast_memory_ref::<gstreamer_allocators::DmaBufMemory>()
+                        .is_some()
+                })
+            })
+            .unwrap_or(false);
+        // The wayland pool activates it again together with itself
+        let _ = pool.set_active(false);
+        if !is_dmabuf {
+            gstreamer::debug!(CAT, imp: self, "downstream pool {} does not provide dmabufs", pool.type_().name());
+            return None;
+        }
+        Some(pool)
+    }
+
+    /// DRM device of the VA display downstream shares through a `GstContext`, so
+    /// dmabufs are allocated on the GPU that encodes them.
+    fn va_device(&self) -> Option<u64> {
+        let mut query = gstreamer::query::Context::new(VA_DISPLAY_CONTEXT);
+        if !self.obj().src_pad().peer_query(&mut query) {
+            return None;
+        }
+        let display = query
+            .context()?
+            .structure()
+            .get::<glib::Object>("gst-display")
+            .ok()?;
+        // Only DRM displays know their device
+        display.find_property("path")?;
+        let path = display.property::<Option<String>>("path")?;
+        let device = nix::sys::stat::stat(path.as_str()).ok()?.st_rdev;
+        gstreamer::debug!(CAT, imp: self, "downstream VA display uses {}", path);
+        Some(device)
+    }
+
+    /// Warn if VA drivers can not import the buffers of `pool` without a copy,
+    /// they expect one dmabuf per plane with pitches aligned to 128 bytes.
+    fn check_va_layout(&self, pool: &WaylandBufferPool) {
+        if pool.set_active(true).is_err() {
+            return;
+        }
+        let Ok(buffer) = pool.acquire_buffer(None) else {
+            return;
+        };
+        let Some(meta) = buffer.meta::<gstreamer_video::VideoMeta>() else {
+            gstreamer::warning!(CAT, imp: self, "dmabuf without video meta, VA import assumes the default layout");
+            return;
+        };
+        let n_planes = meta.n_planes() as usize;
+        if buffer.n_memory() as usize != n_planes {
+            gstreamer::warning!(
+                CAT,
+                imp: self,
+                "VA import expects one dmabuf per plane, got {} for {} planes",
+                buffer.n_memory(),
+                n_planes
+            );
+        }
+        let strides = &meta.stride()[..n_planes];
+        if strides.iter().any(|stride| stride % 128 != 0) {
+            gstreamer::warning!(
+                CAT,
+                imp: self,
+                "plane strides {:?} are not aligned to 128 bytes, VA drivers may copy frames",
+                strides
+            );
+        }
+    }
+
+    /// Activate `pool` and import one buffer, returns `false` if the compositor
+    /// rejected the dmabuf with every modifier.
+    fn probe_dmabuf_import(&self, pool: &WaylandBufferPool) -> bool {
+        if pool.set_active(true).is_err() {
+            // Preallocating the minimum number of buffers already failed
+            return !pool.dmabuf_rejected();
+        }
+        match pool.acquire_buffer(None) {
+            Ok(_) => true,
+            Err(err) => {
+                gstreamer::debug!(CAT, imp: self, "failed to import dmabuf: {:?}", err);
+                !pool.dmabuf_rejected()
+            }
+        }
+    }
+
+    /// Mark dmabuf as unusable if the failed copy used a dmabuf backed buffer.
+    ///
+    /// Returns `true` if the next negotiation will select a different memory type.
+    fn reject_dmabuf(&self, buffer: &gstreamer::Buffer) -> bool {
+        let is_dmabuf = buffer
+            .peek_memory(0)
+            .downcast_memory_ref::<gstreamer_allocators::DmaBufMemory>()
+            .is_some();
+        if !is_dmabuf {
+            return false;
+        }
+
+        self.session
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|session| session.reject_dmabuf())
+            .unwrap_or(false)
+    }
+
+    /// Copy a captured frame into a tightly packed buffer from the downstream pool.
+    fn repack(&self, buffer: gstreamer::Buffer) -> Result<gstreamer::Buffer, gstreamer::FlowError> {
+        let obj = self.obj();
+        let caps = obj
+            .src_pad()
+            .current_caps()
+            .ok_or(gstreamer::FlowError::NotNegotiated)?;
+        let video_info =
+            dma_drm::video_info(&caps).map_err(|_| gstreamer::FlowError::NotNegotiated)?;
+        if let Some(convert_input) = self.convert_input.lock().unwrap().clone() {
+            return self.convert(buffer, &convert_input, &video_info);
+        }
+        let pool = obj
+            .buffer_pool()
+            .expect("buffer_pool set in decide_allocation");
+        let output_buffer = pool.acquire_buffer(None)?;
+
+        let input_frame = gstreamer_video::VideoFrame::from_buffer_readable(buffer, &video_info)
+            .map_err(|_| {
+                gstreamer::warning!(CAT, imp: self, "failed to map captured frame");
+                gstreamer::FlowError::Error
+            })?;
+        let mut output_frame =
+            gstreamer_video::VideoFrame::from_buffer_writable(output_buffer, &video_info).map_err(
+                |_| {
+                    gstreamer::warning!(CAT, imp: self, "failed to map output frame");
+                    gstreamer::FlowError::Error
+                },
+            )?;
+        input_frame.copy(&mut output_frame).map_err(|err| {
+            gstreamer::warning!(CAT, imp: self, "failed to repack frame: {}", err);
+            gstreamer::FlowError::Error
+        })?;
+
+        Ok(output_frame.into_buffer())
+    }
+
+    /// Scale and convert a captured frame of `input_info` to the negotiated
+    /// `video_info`, on the GPU if the frame is a dmabuf and a converter is available.
+    fn convert(
+        &self,
+        buffer: gstreamer::Buffer,
+        input_info: &gstreamer_video::VideoInfo,
+        video_info: &gstreamer_video::VideoInfo,
+    ) -> Result<gstreamer::Buffer, gstreamer::FlowError> {
+        if let Some(output_buffer) = self.gpu_convert(&buffer, input_info, video_info) {
+            return Ok(output_buffer);
+        }
+
+        // Without GPU conversion downstream may not have been offered a pool
+        let output_buffer = match self.obj().buffer_pool() {
+            Some(pool) => pool.acquire_buffer(None)?,
+            None => gstreamer::Buffer::with_size(video_info.size())
+                .map_err(|_| gstreamer::FlowError::Error)?,
+        };
+
+        let mut cpu_converter = self.cpu_converter.lock().unwrap();
+        if cpu_converter.is_none() {
+            let converter = gstreamer_video::VideoConverter::new(input_info, video_info, None)
+                .map_err(|err| {
+                    gstreamer::warning!(CAT, imp: self, "failed to create converter: {}", err);
+                    gstreamer::FlowError::NotNegotiated
+                })?;
+            *cpu_converter = Some(converter);
+        }
+
+        let input_frame = gstreamer_video::VideoFrame::from_buffer_readable(buffer, input_info)
+            .map_err(|_| {
+                gstreamer::warning!(CAT, imp: self, "failed to map captured frame");
+                gstreamer::FlowError::Error
+            })?;
+        let mut output_frame =
+            gstreamer_video::VideoFrame::from_buffer_writable(output_buffer, video_info).map_err(
+                |_| {
+                    gstreamer::warning!(CAT, imp: self, "failed to map output frame");
+                    gstreamer::FlowError::Error
+                },
+            )?;
+        cpu_converter
+            .as_ref()
+            .unwrap()
+            .frame(&input_frame, &mut output_frame);
+
+        Ok(output_frame.into_buffer())
+    }
+
+    #[cfg(feature = "gles")]
+    fn gpu_convert(
+        &self,
+        buffer: &gstreamer::BufferRef,
+        input_info: &gstreamer_video::VideoInfo,
+        video_info: &gstreamer_video::VideoInfo,
+    ) -> Option<gstreamer::Buffer> {
+        let gpu_converter = self.gpu_converter.lock().unwrap().clone()?;
+        match gpu_converter.convert(buffer, input_info, video_info) {
+            Ok(output_buffer) => Some(output_buffer),
+            Err(err) => {
+                gstreamer::warning!(CAT, imp: self, "GPU conversion failed, converting on the CPU: {}", err);
+                *self.gpu_converter.lock().unwrap() = None;
+                None
+            }
+        }
+    }
+
+    #[cfg(not(feature = "gles"))]
+    fn gpu_convert(
+        &self,
+        _buffer: &gstreamer::BufferRef,
+        _input_info: &gstreamer_video::VideoInfo,
+        _video_info: &gstreamer_video::VideoInfo,
+    ) -> Option<gstreamer::Buffer> {
+        None
+    }
+
+    /// Set up GPU conversion of dmabuf frames of `input_info` to `video_info`, `None`
+    /// disables it. Returns whether frames are converted on the GPU.
+    #[cfg(feature = "gles")]
+    fn prepare_gpu_converter(
+        &self,
+        session: &ScreencopySession,
+        input_info: Option<&gstreamer_video::VideoInfo>,
+        video_info: &gstreamer_video::VideoInfo,
+    ) -> bool {
+        let mut gpu_converter = self.gpu_converter.lock().unwrap();
+        let Some(input_info) = input_info else {
+            *gpu_converter = None;
+            return false;
+        };
+        if !crate::gpu::GpuConverter::supports(input_info, video_info) {
+            gstreamer::debug!(
+                CAT,
+                imp: self,
+                "no GPU conversion from {} to {}",
+                input_info.format(),
+                video_info.format()
+            );
+            *gpu_converter = None;
+            return false;
+        }
+        if gpu_converter.is_some() {
+            return true;
+        }
+
+        match crate::gpu::GpuConverter::new(session.dmabuf_main_device()) {
+            Ok(converter) => {
+                *gpu_converter = Some(Arc::new(converter));
+                true
+            }
+            Err(err) => {
+                gstreamer::warning!(CAT, imp: self, "GPU conversion not available, converting on the CPU: {}", err);
+                false
+            }
+        }
+    }
+
+    #[cfg(not(feature = "gles"))]
+    fn prepare_gpu_converter(
+        &self,
+        _session: &ScreencopySession,
+        _input_info: Option<&gstreamer_video::VideoInfo>,
+        _video_info: &gstreamer_video::VideoInfo,
+    ) -> bool {
+        false
+    }
+
+    /// Import a captured frame as GL textures if memory:GLMemory was negotiated.
+    #[cfg(feature = "gl")]
+    fn upload_gl(
+        &self,
+        buffer: gstreamer::Buffer,
+    ) -> Result<gstreamer::Buffer, gstreamer::FlowError> {
+        let gl_memory = self.gl_memory.lock().unwrap();
+        if !gl_memory.is_configured() {
+            return Ok(buffer);
+        }
+        gl_memory.upload(buffer).map_err(|err| {
+            gstreamer::warning!(CAT, imp: self, "failed to upload frame: {}", err);
+            gstreamer::FlowError::Error
+        })
+    }
+
+    #[cfg(not(feature = "gl"))]
+    fn upload_gl(
+        &self,
+        buffer: gstreamer::Buffer,
+    ) -> Result<gstreamer::Buffer, gstreamer::FlowError> {
+        Ok(buffer)
+    }
+
+    /// Size frames of `width`x`height` are scaled to, `None` without scaling. If only
+    /// one dimension is configured the other keeps the aspect ratio.
+    fn scaled_size(&self, width: u32, height: u32) -> Option<(u32, u32)> {
+        let (scale_width, scale_height) = {
+            let settings = self.settings.lock().unwrap();
+            (settings.scale_width, settings.scale_height)
+        };
+        let size = match (scale_width, scale_height) {
+            (0, 0) => return None,
+            (0, scale_height) => (scale_dimension(width, scale_height, height), scale_height),
+            (scale_width, 0) => (scale_width, scale_dimension(height, scale_width, width)),
+            size => size,
+        };
+        (size != (width, height)).then_some(size)
+    }
+
+    /// `caps` with the frame size replaced by the scaled size, the pixel aspect ratio
+    /// keeps the display aspect ratio of the output.
+    fn scale_caps(&self, mut caps: gstreamer::Caps) -> gstreamer::Caps {
+        for structure in caps.make_mut().iter_mut() {
+            let (Ok(width), Ok(height)) = (
+                structure.get::<i32>("width"),
+                structure.get::<i32>("height"),
+            ) else {
+                continue;
+            };
+            let Some((scaled_width, scaled_height)) = self.scaled_size(width as u32, height as u32)
+            else {
+                continue;
+            };
+            let par = structure
+                .get::<gstreamer::Fraction>("pixel-aspect-ratio")
+                .unwrap_or_else(|_| gstreamer::Fraction::new(1, 1));
+            let numer = par.numer() as i64 * width as i64 * scaled_height as i64;
+            let denom = par.denom() as i64 * height as i64 * scaled_width as i64;
+            let par = gstreamer::Fraction::approximate_f64(numer as f64 / denom as f64)
+                .unwrap_or_else(|| gstreamer::Fraction::new(1, 1));
+            structure.set("width", scaled_width as i32);
+            structure.set("height", scaled_height as i32);
+            structure.set("pixel-aspect-ratio", par);
+        }
+        caps
+    }
+
+    /// `caps` extended by the formats frames can be converted to, at the size and
+    /// framerate of the first structure.
+    fn convert_caps(&self, mut caps: gstreamer::Caps) -> gstreamer::Caps {
+        let Some(structure) = caps.structure(0) else {
+            return caps;
+        };
+        let (Ok(width), Ok(height), Ok(framerate), Ok(par)) = (
+            structure.get::<i32>("width"),
+            structure.get::<i32>("height"),
+            structure.get::<gstreamer::FractionRange>("framerate"),
+            structure.get::<gstreamer::Fraction>("pixel-aspect-ratio"),
+        ) else {
+            return caps;
+        };
+
+        for format in CONVERT_FORMATS {
+            let offered = caps
+                .iter()
+                .any(|structure| structure.get::<&str>("format").ok() == Some(format.to_str()));
+            if !offered {
+                caps.merge(make_raw_caps(
+                    *format,
+                    width as u32,
+                    height as u32,
+                    framerate.max(),
+                    par,
+                ));
+            }
+        }
+        caps
+    }
+
+    /// Video info of the frames captured for the negotiated `video_info`, if they
+    /// have to be scaled or converted to it.
+    fn convert_input_info(
+        &self,
+        formats: &BufferFormats,
+        video_info: &gstreamer_video::VideoInfo,
+    ) -> Option<gstreamer_video::VideoInfo> {
+        let format = if buffer_format_size(formats, video_info.format()).is_some() {
+            video_info.format()
+        } else if self.settings.lock().unwrap().convert
+            && CONVERT_FORMATS.contains(&video_info.format())
+        {
+            // Capture in an RGB format, preferably as dmabuf for GPU conversion
+            formats
+                .dmabuf
+                .iter()
+                .filter_map(|dmabuf_format| {
+                    gst_video_format_from_drm_fourcc_code(dmabuf_format.format)
+                })
+                .chain(
+                    formats
+                        .shm
+                        .iter()
+                        .filter_map(|shm_format| gst_video_format_from_wl_shm(shm_format.format)),
+                )
+                .find(|format| gstreamer_video::VideoFormatInfo::from_format(*format).is_rgb())?
+        } else {
+            return None;
+        };
+
+        let (width, height) = buffer_format_size(formats, format)?;
+        let size = self.scaled_size(width, height).unwrap_or((width, height));
+        if size != (video_info.width(), video_info.height())
+            || (format == video_info.format() && size == (width, height))
+        {
+            return None;
+        }
+        gstreamer_video::VideoInfo::builder(format, width, height)
+            .fps(video_info.fps())
+            .colorimetry(&gst_video_colorimetry_for_format(format))
+            .build()
+            .ok()
+    }
+
+    /// Apply `min-buffers` and `max-buffers` to the buffer counts downstream asked
+    /// for, the minimum downstream needs always wins.
+    fn buffer_counts(&self, min: u32, max: u32) -> (u32, u32) {
+        let (min_buffers, max_buffers) = {
+            let settings = self.settings.lock().unwrap();
+            (settings.min_buffers, settings.max_buffers)
+        };
+        let min_buffers = if max_buffers != 0 && min_buffers > max_buffers {
+            gstreamer::warning!(
+                CAT,
+                imp: self,
+                "min-buffers {} exceeds max-buffers {}, using max-buffers",
+                min_buffers,
+                max_buffers
+            );
+            max_buffers
+        } else {
+            min_buffers
+        };
+        let max = match (max, max_buffers) {
+            (max, 0) => max,
+            (0, max_buffers) => max_buffers,
+            (max, max_buffers) => std::cmp::min(max, max_buffers),
+        };
+        // Only as many as the maximum allows, downstream stalls instead
+        let latency_buffers = match max {
+            0 => self.latency_buffers(),
+            max => std::cmp::min(self.latency_buffers(), max),
+        };
+        let min = std::cmp::max(std::cmp::max(min, min_buffers), latency_buffers);
+        if max != 0 && max < min {
+            gstreamer::warning!(
+                CAT,
+                imp: self,
+                "max-buffers {} is below the minimum of {} buffers",
+                max,
+                min
+            );
+            return (min, min);
+        }
+        (min, max)
+    }
+
+    /// Composed region frames are written by the CPU, any system memory pool works.
+    fn decide_region_allocation(
+        &self,
+        query: &mut gstreamer::query::Allocation,
+    ) -> Result<(), gstreamer::LoggableError> {
+        let (caps, _) = query.get_owned();
+        let caps = caps.expect("query without caps");
+        let video_info = dma_drm::video_info(&caps)
+            .map_err(|err| gstreamer::loggable_error!(CAT, "invalid caps: {}", err))?;
+        let size = video_info.size() as u32;
+
+        let (has_pool, min, max) = query
+            .allocation_pools()
+            .get(0)
+            .map(|(_, _, min, max)| (true, *min, *max))
+            .unwrap_or((false, 0, 0));
+        let (min, max) = self.buffer_counts(std::cmp::max(min, 2), max);
+
+        let pool = gstreamer_video::VideoBufferPool::new();
+        let mut config = pool.config();
+        config.set_params(Some(&caps), size, min, max);
+        pool.set_config(config)
+            .map_err(|err| gstreamer::loggable_error!(CAT, "failed to configure pool: {}", err))?;
+
+        if has_pool {
+            query.set_nth_allocation_pool(0, Some(&pool), size, min, max);
+        } else {
+            query.add_allocation_pool(Some(&pool), size, min, max);
+        }
+        // The outputs are copied into shm and composed on the CPU
+        self.set_allocation_info(AllocationInfo {
+            negotiated_memory: caps_memory(&caps),
+            capture_memory: "shm",
+            allocator: gstreamer::Allocator::find(None)
+                .map(|allocator| allocator.type_().name().to_owned())
+                .unwrap_or_default(),
+        });
+        Ok(())
+    }
+
+    /// Remember what the allocation settled on and notify the read-only properties
+    fn set_allocation_info(&self, allocation_info: AllocationInfo) {
+        *self.allocation_info.lock().unwrap() = Some(allocation_info);
+        for property in [
+            "negotiated-memory",
+            "capture-memory",
+            "active-allocator",
+            "dmabuf-modifier",
+        ] {
+            self.obj().notify(property);
+        }
+    }
+
+    /// Modifier of the dmabufs frames are captured into, `None` for shm
+    fn dmabuf_modifier(&self) -> Option<u64> {
+        let pool = self.repack_pool.lock().unwrap().clone().or_else(|| {
+            self.obj()
+                .buffer_pool()
+                .and_then(|pool| pool.downcast::<WaylandBufferPool>().ok())
+        })?;
+        pool.dmabuf_modifier()
+    }
+}
+
+impl ObjectImpl for WlrScreencopySrc {
+    fn signals() -> &'static [glib::subclass::Signal] {
+        static SIGNALS: Lazy<Vec<glib::subclass::Signal>> = Lazy::new(|| {
+            vec![glib::subclass::Signal::builder("capture-snapshot")
+                .param_types([u64::static_type()])
+                .return_type::<gstreamer::Sample>()
+                .action()
+                .class_handler(|_, args| {
+                    let element = args[0]
+                        .get::<super::WlrScreencopySrc>()
+                        .expect("signal arg");
+                    // Timeout in milliseconds, 0 waits until the frame is copied
+                    let timeout = args[1].get::<u64>().expect("signal arg");
+                    let timeout = (timeout > 0).then(|| std::time::Duration::from_millis(timeout));
+                    let sample = element
+                        .imp()
+                        .capture_snapshot(timeout)
+                        .map_err(|err| {
+                            gstreamer::warning!(CAT, obj: &element, "failed to capture snapshot: {}", err)
+                        })
+                        .ok();
+                    Some(sample.to_value())
+                })
+                .build()]
+        });
+
+        SIGNALS.as_ref()
+    }
+
+    fn properties() -> &'static [glib::ParamSpec] {
+        static PROPERTIES: Lazy<Vec<glib::ParamSpec>> = Lazy::new(|| {
+            vec![
+                glib::ParamSpecString::builder("display")
+                    .nick("Wayland Display")
+                    .blurb("Wayland Display to use")
+                    .construct()
+                    .build(),
+                glib::ParamSpecString::builder("output-name")
+                    .nick("Wayland output name")
+                    .blurb("Name of the output to capture, a trailing * captures the first output starting with the part before it and switches to the next matching output when it goes away")
+                    .construct()
+                    .build(),
+                glib::ParamSpecString::builder("region")
+                    .nick("Region")
+                    .blurb("Area to capture in global logical coordinates as x,y,width,height, may span several outputs, overrides output-name")
+                    .mutable_ready()
+                    .build(),
+                glib::ParamSpecBoolean::builder("show-pointer")
+                    .nick("Show pointer")
+                    .blurb("Include the pointer in the captured frames")
+                    .default_value(false)
+                    .mutable_ready()
+                    .build(),
+                glib::ParamSpecBoolean::builder("defer-capture")
+                    .nick("Defer capture")
+                    .blurb("Only probe the output when starting and begin capturing with the first frame in PLAYING")
+                    .default_value(false)
+                    .mutable_ready()
+                    .build(),
+                glib::ParamSpecBoolean::builder("navigation")
+                    .nick("Navigation")
+                    .blurb("Inject upstream navigation events as pointer and keyboard input into the compositor")
+                    .default_value(false)
+                    .mutable_ready()
+                    .build(),
+                glib::ParamSpecEnum::builder_with_default("presentation", Presentation::default())
+                    .nick("Presentation")
+                    .blurb("Whether to present frames in physical pixels or with a pixel aspect ratio matching the logical output size")
+                    .mutable_ready()
+                    .build(),
+                glib::ParamSpecBoolean::builder("damage-aware")
+                    .nick("Damage aware")
+                    .blurb("Only copy frames once the output has been damaged and push gap events while nothing changes")
+                    .default_value(false)
+                    .mutable_ready()
+                    .build(),
+                glib::ParamSpecBoolean::builder("drop-duplicates")
+                    .nick("Drop duplicates")
+                    .blurb("Push gap events instead of frames identical to the previous one, detected by a checksum for frames without damage")
+                    .default_value(false)
+                    .mutable_playing()
+                    .build(),
+                glib::ParamSpecEnum::builder_with_default("damage-report", DamageReport::default())
+                    .nick("Damage report")
+                    .blurb("Whether damage is reported as every damaged rectangle or as a single rectangle covering all of them")
+                    .mutable_playing()
+                    .build(),
+                glib::ParamSpecBoolean::builder("leaky")
+                    .nick("Leaky")
+                    .blurb("Drop the frame copied ahead if downstream blocked for longer than one frame interval instead of pushing it late, ignored in damage-aware mode")
+                    .default_value(false)
+                    .mutable_playing()
+                    .build(),
+                glib::ParamSpecBoolean::builder("qos")
+                    .nick("QoS")
+                    .blurb("Skip frames that would arrive too late according to QoS events from downstream")
+                    .default_value(true)
+                    .mutable_playing()
+                    .build(),
+                glib::ParamSpecUInt::builder("max-retries")
+                    .nick("Max retries")
+                    .blurb("Number of consecutive failed frames to retry before pausing or giving up, see pause-on-failure")
+                    .maximum(MAX_RETRIES_LIMIT)
+                    .default_value(DEFAULT_MAX_RETRIES)
+                    .mutable_playing()
+                    .build(),
+                glib::ParamSpecUInt::builder("retry-delay")
+                    .nick("Retry delay")
+                    .blurb("Delay in milliseconds before retrying a failed frame, doubled for every further failure")
+                    .maximum(RETRY_DELAY_LIMIT)
+                    .default_value(DEFAULT_RETRY_DELAY)
+                    .mutable_playing()
+                    .build(),
+                glib::ParamSpecBoolean::builder("push-corrupted")
+                    .nick("Push corrupted")
+                    .blurb("Push frames that still failed after all retries flagged as corrupted instead of failing the stream")
+                    .default_value(false)
+                    .mutable_playing()
+                    .build(),
+                glib::ParamSpecBoolean::builder("pause-on-failure")
+                    .nick("Pause on failure")
+                    .blurb("Pause capturing with gaps until the compositor copies frames again instead of failing the stream after all retries, for example while another VT is active")
+                    .default_value(true)
+                    .mutable_playing()
+                    .build(),
+                glib::ParamSpecUInt::builder("copy-timeout")
+                    .nick("Copy timeout")
+                    .blurb("Time in milliseconds the compositor has to copy a frame before copy-timeout-policy applies, 0 to wait forever. Not used in damage-aware mode")
+                    .maximum(COPY_TIMEOUT_LIMIT)
+                    .default_value(0)
+                    .mutable_playing()
+                    .build(),
+                glib::ParamSpecEnum::builder_with_default("copy-timeout-policy", CopyTimeoutPolicy::default())
+                    .nick("Copy timeout policy")
+                    .blurb("What happens when the compositor did not copy a frame within copy-timeout")
+                    .mutable_playing()
+                    .build(),
+                glib::ParamSpecEnum::builder_with_default("power-off-mode", PowerOffMode::default())
+                    .nick("Power off mode")
+                    .blurb("What is pushed while the captured output is powered off, requires wlr-output-power-management")
+                    .mutable_playing()
+                    .build(),
+                glib::ParamSpecBoolean::builder("is-live")
+                    .nick("Is live")
+                    .blurb("Act as a live source, otherwise frames are pushed as fast as the compositor copies them with timestamps starting at zero, for benchmarks and offline pipelines")
+                    .default_value(true)
+                    .mutable_ready()
+                    .build(),
+                glib::ParamSpecString::builder("negotiated-memory")
+                    .nick("Negotiated memory")
+                    .blurb("Caps feature negotiated with downstream, like memory:DMABuf, unset before negotiation")
+                    .read_only()
+                    .build(),
+                glib::ParamSpecString::builder("capture-memory")
+                    .nick("Capture memory")
+                    .blurb("Memory the compositor copies the frames into, dmabuf or shm, unset before negotiation")
+                    .read_only()
+                    .build(),
+                glib::ParamSpecString::builder("active-allocator")
+                    .nick("Active allocator")
+                    .blurb("Type name of the allocator of the buffers frames are captured into, unset before negotiation")
+                    .read_only()
+                    .build(),
+                glib::ParamSpecUInt64::builder("dmabuf-modifier")
+                    .nick("Dmabuf modifier")
+                    .blurb("DRM modifier of the dmabufs frames are captured into, DRM_FORMAT_MOD_INVALID for shm or before the first frame")
+                    .default_value(DRM_FORMAT_MOD_INVALID)
+                    .read_only()
+                    .build(),
+                gstreamer::ParamSpecArray::builder("dma-heaps")
+                    .nick("DMA heaps")
+                    .blurb("Names of the dma-buf heaps in /dev/dma_heap that can be allocated from, empty without dma-heap support")
+                    .element_spec(&glib::ParamSpecString::builder("heap").build())
+                    .read_only()
+                    .build(),
+                glib::ParamSpecUInt::builder("stats-interval")
+                    .nick("Statistics interval")
+                    .blurb("Interval in seconds for posting capture statistics as element messages, 0 to disable")
+                    .maximum(STATS_INTERVAL_LIMIT)
+                    .default_value(0)
+                    .mutable_playing()
+                    .build(),
+                glib::ParamSpecBoolean::builder("time-code")
+                    .nick("Time code")
+                    .blurb("Attach a video time code derived from the running time to every frame")
+                    .default_value(false)
+                    .mutable_ready()
+                    .build(),
+                glib::ParamSpecUInt::builder("scale-width")
+                    .nick("Scale width")
+                    .blurb("Width to scale frames to, on the GPU for dmabuf frames, 0 keeps the width or the aspect ratio if scale-height is set")
+                    .maximum(SCALE_SIZE_LIMIT)
+                    .default_value(0)
+                    .mutable_ready()
+                    .build(),
+                glib::ParamSpecUInt::builder("scale-height")
+                    .nick("Scale height")
+                    .blurb("Height to scale frames to, on the GPU for dmabuf frames, 0 keeps the height or the aspect ratio if scale-width is set")
+                    .maximum(SCALE_SIZE_LIMIT)
+                    .default_value(0)
+                    .mutable_ready()
+                    .build(),
+                glib::ParamSpecBoolean::builder("convert")
+                    .nick("Convert")
+                    .blurb("Offer NV12 and I420 in addition to the formats of the compositor, converted on the GPU for dmabuf frames")
+                    .default_value(false)
+                    .mutable_ready()
+                    .build(),
+                glib::ParamSpecBoolean::builder("gl-memory")
+                    .nick("GL memory")
+                    .blurb("Offer memory:GLMemory caps and import frames as GL textures, requires the gl feature")
+                    .default_value(false)
+                    .mutable_ready()
+                    .build(),
+                gstreamer::ParamSpecArray::builder("privacy-app-ids")
+                    .nick("Privacy app ids")
+                    .blurb("App ids of applications whose frames are hidden while they are focused on the captured output")
+                    .element_spec(&glib::ParamSpecString::builder("app-id").build())
+                    .mutable_playing()
+                    .build(),
+                glib::ParamSpecEnum::builder_with_default("privacy-fill", PrivacyFill::default())
+                    .nick("Privacy fill")
+                    .blurb("What replaces the frames while a private application is focused")
+                    .mutable_playing()
+                    .build(),
+                glib::ParamSpecUInt::builder("privacy-color")
+                    .nick("Privacy color")
+                    .blurb("ARGB color frames are filled with while a private application is focused and privacy-fill is color")
+                    .default_value(privacy::BLACK)
+                    .mutable_playing()
+                    .build(),
+                glib::ParamSpecUInt::builder("trim-timeout")
+                    .nick("Trim timeout")
+                    .blurb("Idle time in milliseconds after which buffers beyond the recent peak usage are freed, 0 to keep all buffers")
+                    .maximum(TRIM_TIMEOUT_LIMIT)
+                    .default_value(0)
+                    .mutable_ready()
+                    .build(),
+                glib::ParamSpecUInt::builder("acquire-timeout")
+                    .nick("Acquire timeout")
+                    .blurb("Time in milliseconds to wait for downstream to return a buffer once all are in use, frames are skipped afterwards, 0 to wait forever")
+                    .maximum(ACQUIRE_TIMEOUT_LIMIT)
+                    .default_value(0)
+                    .mutable_ready()
+                    .build(),
+                glib::ParamSpecUInt::builder("min-buffers")
+                    .nick("Min buffers")
+                    .blurb("Minimum number of buffers in the pool, downstream may require more, 0 for the downstream minimum")
+                    .maximum(BUFFERS_LIMIT)
+                    .default_value(0)
+                    .mutable_ready()
+                    .build(),
+                glib::ParamSpecUInt::builder("max-buffers")
+                    .nick("Max buffers")
+                    .blurb("Maximum number of buffers in the pool, 0 for the downstream maximum or the pool default")
+                    .maximum(BUFFERS_LIMIT)
+                    .default_value(0)
+                    .mutable_ready()
+                    .build(),
+                glib::ParamSpecBoolean::builder("reconnect")
+                    .nick("Reconnect")
+                    .blurb("Try to reconnect to the compositor if the connection is lost instead of failing")
+                    .default_value(false)
+                    .mutable_playing()
+                    .build(),
+            ]
+        });
+
+        PROPERTIES.as_ref()
+    }
+
+    fn set_property(&self, _id: usize, value: &glib::Value, pspec: &glib::ParamSpec) {
+        match pspec.name() {
+            "display" => {
+                let mut settings = self.settings.lock().unwrap();
+                let wayland_display = value
+                    .get::<Option<String>>()
+                    .expect("type checked upstream");
+                settings.wayland_display = wayland_display;
+            }
+            "output-name" => {
+                let mut settings = self.settings.lock().unwrap();
+                let output_name = value
+                    .get::<Option<String>>()
+                    .expect("type checked upstream");
+                settings.output_name = output_name;
+            }
+            "region" => {
+                let mut settings = self.settings.lock().unwrap();
+                let region = value.get::<Option<&str>>().expect("type checked upstream");
+                settings.region = match region.map(str::parse::<Region>).transpose() {
+                    Ok(region) => region,
+                    Err(err) => {
+                        gstreamer::warning!(CAT, imp: self, "ignoring region: {}", err);
+                        settings.region
+                    }
+                };
+            }
+            "show-pointer" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.show_pointer = value.get::<bool>().expect("type checked upstream");
+            }
+            "defer-capture" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.defer_capture = value.get::<bool>().expect("type checked upstream");
+            }
+            "navigation" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.navigation = value.get::<bool>().expect("type checked upstream");
+            }
+            "presentation" => {
+                let mut settings = self.settings.lock().unwrap();
+                let presentation = value.get::<Presentation>().expect("type checked upstream");
+                settings.presentation = presentation;
+            }
+            "leaky" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.leaky = value.get::<bool>().expect("type checked upstream");
+            }
+            "qos" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.qos = value.get::<bool>().expect("type checked upstream");
+                if !settings.qos {
+                    *self.qos_earliest_time.lock().unwrap() = None;
+                }
+            }
+            "damage-aware" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.damage_aware = value.get::<bool>().expect("type checked upstream");
+            }
+            "drop-duplicates" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.drop_duplicates = value.get::<bool>().expect("type checked upstream");
+            }
+            "damage-report" => {
+                let mut settings = self.settings.lock().unwrap();
+                let damage_report = value.get::<DamageReport>().expect("type checked upstream");
+                settings.damage_report = damage_report;
+            }
+            "reconnect" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.reconnect = value.get::<bool>().expect("type checked upstream");
+            }
+            "time-code" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.time_code = value.get::<bool>().expect("type checked upstream");
+            }
+            "trim-timeout" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.trim_timeout = value.get::<u32>().expect("type checked upstream");
+            }
+            "acquire-timeout" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.acquire_timeout = value.get::<u32>().expect("type checked upstream");
+            }
+            "min-buffers" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.min_buffers = value.get::<u32>().expect("type checked upstream");
+            }
+            "max-buffers" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.max_buffers = value.get::<u32>().expect("type checked upstream");
+            }
+            "scale-width" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.scale_width = value.get::<u32>().expect("type checked upstream");
+            }
+            "scale-height" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.scale_height = value.get::<u32>().expect("type checked upstream");
+            }
+            "convert" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.convert = value.get::<bool>().expect("type checked upstream");
+            }
+            "gl-memory" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.gl_memory = value.get::<bool>().expect("type checked upstream");
+            }
+            "privacy-app-ids" => {
+                let mut settings = self.settings.lock().unwrap();
+                let app_ids = value
+                    .get::<gstreamer::Array>()
+                    .expect("type checked upstream");
+                settings.privacy_app_ids = app_ids
+                    .iter()
+                    .filter_map(|app_id| app_id.get::<String>().ok())
+                    .collect();
+            }
+            "privacy-fill" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.privacy_fill = value.get::<PrivacyFill>().expect("type checked upstream");
+            }
+            "privacy-color" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.privacy_color = value.get::<u32>().expect("type checked upstream");
+            }
+            "stats-interval" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.stats_interval = value.get::<u32>().expect("type checked upstream");
+            }
+            "push-corrupted" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.push_corrupted = value.get::<bool>().expect("type checked upstream");
+            }
+            "pause-on-failure" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.pause_on_failure = value.get::<bool>().expect("type checked upstream");
+            }
+            "copy-timeout" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.copy_timeout = value.get::<u32>().expect("type checked upstream");
+            }
+            "copy-timeout-policy" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.copy_timeout_policy = value
+                    .get::<CopyTimeoutPolicy>()
+                    .expect("type checked upstream");
+            }
+            "power-off-mode" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.power_off_mode =
+                    value.get::<PowerOffMode>().expect("type checked upstream");
+            }
+            "is-live" => {
+                self.obj()
+                    .set_live(value.get::<bool>().expect("type checked upstream"));
+            }
+            "max-retries" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.max_retries = value.get::<u32>().expect("type checked upstream");
+            }
+            "retry-delay" => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.retry_delay = value.get::<u32>().expect("type checked upstream");
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn property(&self, _id: usize, pspec: &glib::ParamSpec) -> glib::Value {
+        match pspec.name() {
+            "display" => {
+                let settings = self.settings.lock().unwrap();
+                settings.wayland_display.to_value()
+            }
+            "output-name" => {
+                let settings = self.settings.lock().unwrap();
+                settings.output_name.to_value()
+            }
+            "region" => {
+                let settings = self.settings.lock().unwrap();
+                settings.region.map(|region| region.to_string()).to_value()
+            }
+            "show-pointer" => {
+                let settings = self.settings.lock().unwrap();
+                settings.show_pointer.to_value()
+            }
+            "defer-capture" => {
+                let settings = self.settings.lock().unwrap();
+                settings.defer_capture.to_value()
+            }
+            "navigation" => {
+                let settings = self.settings.lock().unwrap();
+                settings.navigation.to_value()
+            }
+            "presentation" => {
+                let settings = self.settings.lock().unwrap();
+                settings.presentation.to_value()
+            }
+            "leaky" => {
+                let settings = self.settings.lock().unwrap();
+                settings.leaky.to_value()
+            }
+            "qos" => {
+                let settings = self.settings.lock().unwrap();
+                settings.qos.to_value()
+            }
+            "damage-aware" => {
+                let settings = self.settings.lock().unwrap();
+                settings.damage_aware.to_value()
+            }
+            "drop-duplicates" => {
+                let settings = self.settings.lock().unwrap();
+                settings.drop_duplicates.to_value()
+            }
+            "damage-report" => {
+                let settings = self.settings.lock().unwrap();
+                settings.damage_report.to_value()
+            }
+            "reconnect" => {
+                let settings = self.settings.lock().unwrap();
+                settings.reconnect.to_value()
+            }
+            "stats-interval" => {
+                let settings = self.settings.lock().unwrap();
+                settings.stats_interval.to_value()
+            }
+            "trim-timeout" => {
+                let settings = self.settings.lock().unwrap();
+                settings.trim_timeout.to_value()
+            }
+            "acquire-timeout" => {
+                let settings = self.settings.lock().unwrap();
+                settings.acquire_timeout.to_value()
+            }
+            "min-buffers" => {
+                let settings = self.settings.lock().unwrap();
+                settings.min_buffers.to_value()
+            }
+            "max-buffers" => {
+                let settings = self.settings.lock().unwrap();
+                settings.max_buffers.to_value()
+            }
+            "scale-width" => {
+                let settings = self.settings.lock().unwrap();
+                settings.scale_width.to_value()
+            }
+            "scale-height" => {
+                let settings = self.settings.lock().unwrap();
+                settings.scale_height.to_value()
+            }
+            "convert" => {
+                let settings = self.settings.lock().unwrap();
+                settings.convert.to_value()
+            }
+            "gl-memory" => {
+                let settings = self.settings.lock().unwrap();
+                settings.gl_memory.to_value()
+            }
+            "privacy-app-ids" => {
+                let settings = self.settings.lock().unwrap();
+                gstreamer::Array::new(settings.privacy_app_ids.clone()).to_value()
+            }
+            "privacy-fill" => {
+                let settings = self.settings.lock().unwrap();
+                settings.privacy_fill.to_value()
+            }
+            "privacy-color" => {
+                let settings = self.settings.lock().unwrap();
+                settings.privacy_color.to_value()
+            }
+            "time-code" => {
+                let settings = self.settings.lock().unwrap();
+                settings.time_code.to_value()
+            }
+            "push-corrupted" => {
+                let settings = self.settings.lock().unwrap();
+                settings.push_corrupted.to_value()
+            }
+            "pause-on-failure" => {
+                let settings = self.settings.lock().unwrap();
+                settings.pause_on_failure.to_value()
+            }
+            "copy-timeout" => {
+                let settings = self.settings.lock().unwrap();
+                settings.copy_timeout.to_value()
+            }
+            "copy-timeout-policy" => {
+                let settings = self.settings.lock().unwrap();
+                settings.copy_timeout_policy.to_value()
+            }
+            "power-off-mode" => {
+                let settings = self.settings.lock().unwrap();
+                settings.power_off_mode.to_value()
+            }
+            "is-live" => self.obj().is_live().to_value(),
+            "negotiated-memory" => {
+                let allocation_info = self.allocation_info.lock().unwrap();
+                allocation_info
+                    .as_ref()
+                    .map(|info| info.negotiated_memory.clone())
+                    .to_value()
             }
-            "output-name" => {
-                let mut settings = self.settings.lock().unwrap();
-                let output_name = value
-                    .get::<Option<String>>()
-                    .expect("type checked upstream");
-                settings.output_name = output_name;
+            "capture-memory" => {
+                let allocation_info = self.allocation_info.lock().unwrap();
+                allocation_info
+                    .as_ref()
+                    .map(|info| info.capture_memory)
+                    .to_value()
             }
-            _ => unreachable!(),
-        }
-    }
-
-    fn property(&self, _id: usize, pspec: &glib::ParamSpec) -> glib::Value {
-        match pspec.name() {
-            "display" => {
+            "active-allocator" => {
+                let allocation_info = self.allocation_info.lock().unwrap();
+                allocation_info
+                    .as_ref()
+                    .map(|info| info.allocator.clone())
+                    .to_value()
+            }
+            "dmabuf-modifier" => self
+                .dmabuf_modifier()
+                .unwrap_or(DRM_FORMAT_MOD_INVALID)
+                .to_value(),
+            "dma-heaps" => gstreamer::Array::new(
+                crate::allocators::dma_heaps()
+                    .into_iter()
+                    .filter(|heap| heap.usable)
+                    .map(|heap| heap.name),
+            )
+            .to_value(),
+            "max-retries" => {
                 let settings = self.settings.lock().unwrap();
-                settings.wayland_display.to_value()
+                settings.max_retries.to_value()
             }
-            "output-name" => {
+            "retry-delay" => {
                 let settings = self.settings.lock().unwrap();
-                settings.output_name.to_value()
+                settings.retry_delay.to_value()
             }
             _ => unreachable!(),
         }
@@ -485,8 +3130,8 @@ impl ObjectImpl for WlrScreencopySrc {
         obj.set_live(true);
         obj.set_format(gstreamer::Format::Time);
         obj.set_automatic_eos(false);
-        // Replace this with frame finish timestamp
-        obj.set_do_timestamp(true);
+        // Buffers are timestamped with the capture time reported by the compositor
+        obj.set_do_timestamp(false);
     }
 }
 
@@ -511,16 +3156,36 @@ impl ElementImpl for WlrScreencopySrc {
             let caps = gstreamer_video::VideoCapsBuilder::new()
                 .format_list(gstreamer_video::VIDEO_FORMATS_ALL.iter().copied())
                 .build();
-            let mut dmabuf_caps = gstreamer_video::VideoCapsBuilder::new()
-                .features([gstreamer_allocators::CAPS_FEATURE_MEMORY_DMABUF])
-                .format_list(gstreamer_video::VIDEO_FORMATS_ALL.iter().copied())
-                .build();
-            dmabuf_caps.merge(caps);
+            #[cfg(feature = "dmabuf")]
+            let caps = {
+                let mut dmabuf_caps = gstreamer_video::VideoCapsBuilder::new()
+                    .features([gstreamer_allocators::CAPS_FEATURE_MEMORY_DMABUF])
+                    .format_list(gstreamer_video::VIDEO_FORMATS_ALL.iter().copied())
+                    .build();
+                dmabuf_caps.merge(
+                    gstreamer::Caps::builder("video/x-raw")
+                        .features([gstreamer_allocators::CAPS_FEATURE_MEMORY_DMABUF])
+                        .field("format", crate::utils::DMA_DRM_FORMAT)
+                        .build(),
+                );
+                dmabuf_caps.merge(caps);
+                dmabuf_caps
+            };
+            #[cfg(feature = "gl")]
+            let caps = {
+                let mut gl_caps = gstreamer_video::VideoCapsBuilder::new()
+                    .features([gstreamer_gl::CAPS_FEATURE_MEMORY_GL_MEMORY])
+                    .format_list(gstreamer_video::VIDEO_FORMATS_ALL.iter().copied())
+                    .field("texture-target", "2D")
+                    .build();
+                gl_caps.merge(caps);
+                gl_caps
+            };
             let src_pad_template = gstreamer::PadTemplate::new(
                 "src",
                 gstreamer::PadDirection::Src,
                 gstreamer::PadPresence::Always,
-                &dmabuf_caps,
+                &caps,
             )
             .unwrap();
 
@@ -534,103 +3199,277 @@ impl ElementImpl for WlrScreencopySrc {
         &self,
         transition: gstreamer::StateChange,
     ) -> Result<gstreamer::StateChangeSuccess, gstreamer::StateChangeError> {
-        if transition == gstreamer::StateChange::NullToReady {
-            let settings = self.settings.lock().unwrap();
-            self.connect_to_wl_display(
-                settings.wayland_display.as_deref(),
-                settings.output_name.as_deref(),
-            );
-            return Ok(gstreamer::StateChangeSuccess::Async);
-        }
+        let success = self.parent_change_state(transition)?;
 
-        self.parent_change_state(transition)
+        match transition {
+            // A live source can not produce data in PAUSED, so there is nothing to preroll
+            gstreamer::StateChange::ReadyToPaused | gstreamer::StateChange::PlayingToPaused
+                if self.obj().is_live() =>
+            {
+                Ok(gstreamer::StateChangeSuccess::NoPreroll)
+            }
+            gstreamer::StateChange::ReadyToNull => {
+                // Normally already done in stop(), unless starting failed halfway
+                self.disconnect_from_wl_display();
+                Ok(success)
+            }
+            _ => Ok(success),
+        }
     }
 
     fn query(&self, query: &mut gstreamer::QueryRef) -> bool {
         ElementImplExt::parent_query(self, query)
     }
+
+    fn set_context(&self, context: &gstreamer::Context) {
+        #[cfg(feature = "gl")]
+        self.gl_memory
+            .lock()
+            .unwrap()
+            .set_context(self.obj().upcast_ref(), context);
+        self.parent_set_context(context)
+    }
 }
 
 impl BaseSrcImpl for WlrScreencopySrc {
     fn query(&self, query: &mut gstreamer::QueryRef) -> bool {
-        BaseSrcImplExt::parent_query(self, query)
+        // Share the GL display and context with neighbours
+        #[cfg(feature = "gl")]
+        if matches!(query.view(), gstreamer::QueryView::Context(_))
+            && self
+                .gl_memory
+                .lock()
+                .unwrap()
+                .handle_query(self.obj().upcast_ref(), query)
+        {
+            return true;
+        }
+        match query.view_mut() {
+            // Frames are pushed as they are captured, pulling is not possible
+            gstreamer::QueryViewMut::Scheduling(q) => {
+                q.set(gstreamer::SchedulingFlags::SEQUENTIAL, 1, -1, 0);
+                q.add_scheduling_modes(&[gstreamer::PadMode::Push]);
+                true
+            }
+            gstreamer::QueryViewMut::Seeking(q) => {
+                let format = q.format();
+                q.set(
+                    false,
+                    gstreamer::GenericFormattedValue::new(format, -1),
+                    gstreamer::GenericFormattedValue::new(format, -1),
+                );
+                true
+            }
+            // The stream lasts until it is stopped
+            gstreamer::QueryViewMut::Duration(q) => {
+                let format = q.format();
+                q.set(gstreamer::GenericFormattedValue::new(format, -1));
+                true
+            }
+            _ => BaseSrcImplExt::parent_query(self, query),
+        }
+    }
+
+    fn event(&self, event: &gstreamer::Event) -> bool {
+        if self.handle_navigation(event) {
+            return true;
+        }
+        if let gstreamer::EventView::Qos(qos) = event.view() {
+            self.handle_qos(qos);
+            return true;
+        }
+        if let gstreamer::EventView::Latency(latency) = event.view() {
+            self.handle_latency(latency.latency());
+        }
+        self.parent_event(event)
+    }
+
+    fn start(&self) -> Result<(), gstreamer::ErrorMessage> {
+        self.connect_to_wl_display()?;
+        let privacy = !self.settings.lock().unwrap().privacy_app_ids.is_empty();
+        let supports_toplevels = self
+            .session
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|session| session.supports_toplevels())
+            .unwrap_or(true);
+        if privacy && !supports_toplevels {
+            gstreamer::element_imp_warning!(
+                self,
+                gstreamer::ResourceError::Settings,
+                ["Compositor does not support zwlr_foreign_toplevel_manager_v1, all frames are hidden"]
+            );
+        }
+        if !cfg!(feature = "gl") && self.settings.lock().unwrap().gl_memory {
+            gstreamer::element_imp_warning!(
+                self,
+                gstreamer::ResourceError::Settings,
+                ["Built without the gl feature, memory:GLMemory is not offered"]
+            );
+        }
+        gstreamer::debug!(CAT, imp: self, "started");
+        Ok(())
+    }
+
+    fn unlock(&self) -> Result<(), gstreamer::ErrorMessage> {
+        {
+            let (lock, cond) = &self.unlock_cond;
+            let _guard = lock.lock().unwrap();
+            self.unlocked.store(true, Ordering::SeqCst);
+            cond.notify_all();
+        }
+        if let Some(cancel) = self.connect_cancel.lock().unwrap().as_ref() {
+            cancel.cancel();
+        }
+        if let Some(session) = self.session.lock().unwrap().as_ref() {
+            session.interrupt();
+        }
+        if let Some(region_capture) = self.region_capture.lock().unwrap().as_ref() {
+            region_capture
+                .sessions()
+                .for_each(|session| session.interrupt());
+        }
+        Ok(())
+    }
+
+    fn unlock_stop(&self) -> Result<(), gstreamer::ErrorMessage> {
+        self.unlocked.store(false, Ordering::SeqCst);
+        if let Some(cancel) = self.connect_cancel.lock().unwrap().as_ref() {
+            cancel.reset();
+        }
+        if let Some(session) = self.session.lock().unwrap().as_ref() {
+            session.resume();
+        }
+        if let Some(region_capture) = self.region_capture.lock().unwrap().as_ref() {
+            region_capture
+                .sessions()
+                .for_each(|session| session.resume());
+        }
+        Ok(())
+    }
+
+    fn stop(&self) -> Result<(), gstreamer::ErrorMessage> {
+        if let Some(repack_pool) = self.repack_pool.lock().unwrap().take() {
+            let _ = repack_pool.set_active(false);
+        }
+        self.disconnect_from_wl_display();
+        // Running time restarts with the next segment
+        *self.gap_position.lock().unwrap() = None;
+        *self.frame_counter.lock().unwrap() = FrameCounter::default();
+        *self.shm_fallback_reason.lock().unwrap() = None;
+        *self.stats.lock().unwrap() = Stats::default();
+        *self.qos_earliest_time.lock().unwrap() = None;
+        *self.pipeline_latency.lock().unwrap() = None;
+        *self.time_code_jam.lock().unwrap() = None;
+        *self.last_safe_buffer.lock().unwrap() = None;
+        *self.last_frame.lock().unwrap() = None;
+        *self.allocation_info.lock().unwrap() = None;
+        *self.first_timestamp.lock().unwrap() = None;
+        self.powered_off.store(false, Ordering::SeqCst);
+        self.capture_paused.store(false, Ordering::SeqCst);
+        *self.last_checksum.lock().unwrap() = None;
+        *self.convert_input.lock().unwrap() = None;
+        *self.cpu_converter.lock().unwrap() = None;
+        #[cfg(feature = "gles")]
+        {
+            *self.gpu_converter.lock().unwrap() = None;
+        }
+        #[cfg(feature = "gl")]
+        self.gl_memory.lock().unwrap().reset();
+        self.unlocked.store(false, Ordering::SeqCst);
+        gstreamer::debug!(CAT, imp: self, "stopped");
+        Ok(())
     }
 
     fn caps(&self, filter: Option<&gstreamer::Caps>) -> Option<gstreamer::Caps> {
-        let wayland_state = self.wayland_state.lock().unwrap();
+        if let Some(region_capture) = self.region_capture.lock().unwrap().clone() {
+            let video_info = region_capture.video_info();
+            let max_framerate = refresh_rate(region_capture.refresh())
+                .unwrap_or_else(|| gstreamer::Fraction::new(i32::MAX, 1));
+            return Some(make_raw_caps(
+                REGION_FORMAT,
+                video_info.width(),
+                video_info.height(),
+                max_framerate,
+                gstreamer::Fraction::new(1, 1),
+            ));
+        }
 
-        if let Some(state) = wayland_state.as_ref() {
-            if let Some((_, frame_info)) = state.current_frame.as_ref() {
-                let settings = self.settings.lock().unwrap();
+        let Some(session) = self.session.lock().unwrap().clone() else {
+            return self.parent_caps(filter);
+        };
+        let presentation = self.settings.lock().unwrap().presentation;
 
-                let (_, _, output_info) = if let Some(output_name) = settings.output_name.as_deref()
-                {
-                    state
-                        .outputs
-                        .iter()
-                        .find(|(_, _, info)| info.name == output_name)
-                        .unwrap_or_else(|| {
-                            panic!(
-                                "output {} not found, available outputs: {}",
-                                output_name,
-                                state
-                                    .outputs
-                                    .iter()
-                                    .map(|(_, _, info)| &info.name)
-                                    .fold("".to_owned(), |acc, item| {
-                                        format!("{} {}", acc, item)
-                                    })
-                                    .trim()
-                            )
-                        })
-                } else {
-                    state.outputs.first().expect("no outputs")
-                };
+        let Some(output_info) = session.output_info() else {
+            let available = session
+                .outputs()
+                .into_iter()
+                .map(|info| info.name)
+                .collect::<Vec<_>>();
+            gstreamer::warning!(
+                CAT,
+                imp: self,
+                "output {:?} not found, available outputs: {}",
+                session.output_name(),
+                available.join(" ")
+            );
+            return Some(gstreamer::Caps::new_empty());
+        };
 
-                let output_refresh = if output_info.mode.refresh > 0 {
-                    gstreamer::Fraction::approximate_f64(
-                        output_info.mode.refresh as f64 / 1_000_000f64,
-                    )
-                    .unwrap()
-                } else {
-                    gstreamer::Fraction::new(i32::MAX, 1)
-                };
+        let formats = session.buffer_formats();
+        let unscaled_caps = output_caps(&formats, &output_info, presentation);
+        let mut caps = self.scale_caps(unscaled_caps.clone());
+        let convert = self.settings.lock().unwrap().convert;
+        if convert {
+            caps = self.convert_caps(caps);
+        }
 
-                let mut caps = gstreamer::Caps::new_empty();
+        // Encoders like vah264enc import DMA_DRM dmabufs without a copy, prefer them
+        // unless frames go through the GPU converter anyway
+        let unconverted = !convert && caps == unscaled_caps;
+        if cfg!(feature = "dmabuf")
+            && unconverted
+            && dma_drm::is_supported()
+            && session.linux_dmabuf().is_some()
+            && !session.dmabuf_rejected()
+        {
+            let dmabuf_formats = formats
+                .dmabuf
+                .iter()
+                .map(|dmabuf_format| dmabuf_format.format)
+                .collect::<Vec<_>>();
+            let mut dma_drm_caps = dma_drm::dma_drm_caps(&caps, &dmabuf_formats, |fourcc| {
+                dma_drm::negotiable_modifiers(&session.dmabuf_modifiers(fourcc))
+            });
+            dma_drm_caps.merge(caps);
+            caps = dma_drm_caps;
+        }
 
-                for dmabuf_format in frame_info.dmabuf_formats.iter() {
-                    let Some(format) = gst_video_format_from_drm_fourcc(dmabuf_format.format) else {
-                        continue;
-                    };
-                    let dmabuf_format_caps = gstreamer_video::video_make_raw_caps(&[format])
-                        .width(dmabuf_format.width as i32)
-                        .height(dmabuf_format.height as i32)
-                        .framerate_range(..output_refresh)
-                        .build();
-                    caps.merge(dmabuf_format_caps);
-                }
+        // GL pipelines get textures without a separate glupload
+        #[cfg(feature = "gl")]
+        if self.settings.lock().unwrap().gl_memory && unconverted {
+            let mut gl_caps = crate::gpu::gl_memory_caps(&unscaled_caps);
+            gl_caps.merge(caps);
+            caps = gl_caps;
+        }
 
-                for shm_format in frame_info.shm_formats.iter() {
-                    let Some(format) = gst_video_format_from_wl_shm(shm_format.format) else {
-                        continue;
-                    };
-                    let shm_format_caps = gstreamer_video::video_make_raw_caps(&[format])
-                        .width(shm_format.width as i32)
-                        .height(shm_format.height as i32)
-                        .framerate_range(..output_refresh)
-                        .build();
-                    caps.merge(shm_format_caps);
-                }
+        // TODO: Apply the filter
 
-                // TODO: Apply the filter
+        Some(caps)
+    }
 
-                Some(caps)
-            } else {
-                self.parent_caps(filter)
+    fn fixate(&self, mut caps: gstreamer::Caps) -> gstreamer::Caps {
+        // Capture at the refresh rate of the mode, the upper bound of the framerate range
+        caps.truncate();
+        if let Some(s) = caps.make_mut().structure_mut(0) {
+            if let Ok(range) = s.get::<gstreamer::FractionRange>("framerate") {
+                if range.max().numer() != i32::MAX {
+                    s.fixate_field_nearest_fraction("framerate", range.max());
+                }
             }
-        } else {
-            self.parent_caps(filter)
         }
+        self.parent_fixate(caps)
     }
 
     fn set_caps(&self, caps: &gstreamer::Caps) -> Result<(), gstreamer::LoggableError> {
@@ -641,101 +3480,437 @@ impl BaseSrcImpl for WlrScreencopySrc {
         &self,
         query: &mut gstreamer::query::Allocation,
     ) -> Result<(), gstreamer::LoggableError> {
-        let guard = self.wayland_state.lock().unwrap();
-        let state = guard.as_ref().unwrap();
+        if self.region_capture.lock().unwrap().is_some() {
+            return self.decide_region_allocation(query);
+        }
 
-        let (caps, _) = query.get_owned();
-        let caps = caps.expect("query without caps");
-        let video_info =
-            gstreamer_video::VideoInfo::from_caps(&caps).expect("failed to get video info");
+        let session = self
+            .session
+            .lock()
+            .unwrap()
+            .clone()
+            .ok_or_else(|| gstreamer::loggable_error!(CAT, "not connected"))?;
+        let formats = session.buffer_formats();
+        let linux_dmabuf = session.linux_dmabuf();
+        let dmabuf_rejected = session.dmabuf_rejected();
 
-        let is_dmabuf_format = state
-            .current_frame
-            .as_ref()
-            .map(|(_, frame_info)| {
-                let Some(format) = gst_video_format_to_drm_fourcc(video_info.format()) else {
-                    return false
-                };
-                frame_info
-                    .dmabuf_formats
+        let (downstream_caps, _) = query.get_owned();
+        let downstream_caps = downstream_caps.expect("query without caps");
+        let downstream_info =
+            dma_drm::video_info(&downstream_caps).expect("failed to get video info");
+        // DMA_DRM caps fix the modifier, frames have to be dmabufs with exactly it
+        let drm_format = dma_drm::drm_format(&downstream_caps);
+        let va_device = self.va_device();
+        #[cfg(feature = "gl")]
+        let gl_output = crate::gpu::is_gl_memory_caps(&downstream_caps);
+        #[cfg(not(feature = "gl"))]
+        let gl_output = false;
+
+        // Scaled or converted frames are captured at the size of the output in a
+        // format of the compositor into a separate pool
+        let convert_input = self.convert_input_info(&formats, &downstream_info);
+        let (caps, video_info) = match convert_input.as_ref() {
+            Some(convert_input) => {
+                gstreamer::debug!(
+                    CAT,
+                    imp: self,
+                    "converting frames from {} {}x{} to {} {}x{}",
+                    convert_input.format(),
+                    convert_input.width(),
+                    convert_input.height(),
+                    downstream_info.format(),
+                    downstream_info.width(),
+                    downstream_info.height()
+                );
+                let caps = convert_input.to_caps().map_err(|err| {
+                    gstreamer::loggable_error!(CAT, "invalid capture caps: {}", err)
+                })?;
+                (caps, convert_input.clone())
+            }
+            None if drm_format.is_some() || gl_output => {
+                // The pool only understands caps with a video format
+                let caps = downstream_info.to_caps().map_err(|err| {
+                    gstreamer::loggable_error!(CAT, "invalid capture caps: {}", err)
+                })?;
+                (caps, downstream_info.clone())
+            }
+            None => (downstream_caps.clone(), downstream_info.clone()),
+        };
+
+        let is_dmabuf_format = gst_video_format_to_drm_fourcc_code(video_info.format())
+            .map(|format| {
+                formats
+                    .dmabuf
                     .iter()
                     .any(|dmabuf_format| dmabuf_format.format == format)
             })
             .unwrap_or(false);
 
-        let buffer_pool = WaylandBufferPool::new(&state.wl_shm, state.dmabuf.as_ref());
-        let use_dmabuf_allocator = is_dmabuf_format && state.dmabuf.is_some();
-        let (allocator, allocation_params, video_align) = if use_dmabuf_allocator {
-            gstreamer::debug!(CAT, imp: self, "using dmabuf format");
-
-            let allocator = if DmaHeapMemoryAllocator::is_available() {
-                gstreamer::debug!(CAT, imp: self, "using dma-buf heap allocator");
-                DmaHeapMemoryAllocator::default().upcast()
+        let (downstream_allocator, downstream_params) = downstream_allocation(query);
+        let downstream_align = downstream_video_alignment(query);
+        let software_downstream = is_software_downstream(&downstream_caps, query);
+        // Prefer a downstream allocator, then dma-buf heaps, gbm needs a render
+        // node of the right device, the one of a VA encoder or the one the
+        // compositor renders with if known
+        let gbm_allocator = || {
+            va_device
+                .and_then(crate::allocators::gbm_allocator_for_device)
+                .or_else(|| {
+                    session
+                        .dmabuf_main_device()
+                        .and_then(crate::allocators::gbm_allocator_for_device)
+                })
+                .or_else(crate::allocators::gbm_allocator)
+        };
+        let tiled = drm_format.is_some_and(|(_, modifier)| modifier != DRM_FORMAT_MOD_LINEAR);
+        let dmabuf_allocator = if is_dmabuf_format
+            && linux_dmabuf.is_some()
+            && !dmabuf_rejected
+            && !software_downstream
+        {
+            if tiled {
+                // Only gbm allocates tiled layouts
+                gbm_allocator()
             } else {
-                gstreamer::debug!(CAT, imp: self, "using gbm allocator");
-                GbmMemoryAllocator::default().upcast()
-            };
+                downstream_allocator
+                    .clone()
+                    .filter(|allocator| is_importable_allocator(allocator, true))
+                    .or_else(crate::allocators::dma_heap_allocator)
+                    .or_else(gbm_allocator)
+            }
+        } else {
+            None
+        };
+        let use_dmabuf_allocator = dmabuf_allocator.is_some();
+        if let (Some((fourcc, modifier)), false) = (drm_format, use_dmabuf_allocator) {
+            return Err(gstreamer::loggable_error!(
+                CAT,
+                "no dmabuf allocator for negotiated drm-format {}",
+                drm_format_code_to_string(fourcc, modifier)
+            ));
+        }
+        let (allocator, allocation_params, video_align, shm_stride) = if let Some(allocator) =
+            dmabuf_allocator
+        {
+            gstreamer::debug!(
+                CAT,
+                imp: self,
+                "using dmabuf format with {} allocator",
+                allocator.type_().name()
+            );
+
             // If we use dmabuf memory with a hardware encoder we need to align the memory
-            // An alignment of 32bytes should work for most encoders
+            // An alignment of 32bytes should work for most encoders, VA drivers import
+            // pitches aligned to 128 bytes, on top of that honor whatever alignment
+            // downstream requests on its proposed pool
             let allocation_params =
                 gstreamer::AllocationParams::new(gstreamer::MemoryFlags::empty(), 127, 0, 0);
-            let video_align = gstreamer_video::VideoAlignment::new(0, 0, 0, 0, &[31, 0, 0, 0]);
-            (allocator, Some(allocation_params), Some(video_align))
+            let stride_align = if va_device.is_some() {
+                [127, 127, 127, 127]
+            } else {
+                [31, 0, 0, 0]
+            };
+            let video_align = merge_video_alignment(
+                gstreamer_video::VideoAlignment::new(0, 0, 0, 0, &stride_align),
+                downstream_align.as_ref(),
+            );
+            gstreamer::debug!(CAT, imp: self, "using video alignment {:?}", video_align);
+            (allocator, Some(allocation_params), Some(video_align), None)
         } else {
             gstreamer::debug!(CAT, imp: self, "using shm format");
 
-            let shm_format = state
-                .current_frame
-                .as_ref()
-                .map(|(_, frame_info)| {
-                    let format = gst_video_format_to_wl_shm(video_info.format()).unwrap();
-                    frame_info
-                        .shm_formats
-                        .iter()
-                        .find(|shm_format| shm_format.format == format)
-                        .unwrap()
-                })
+            let reason = if linux_dmabuf.is_none() {
+                Some("compositor does not support zwp_linux_dmabuf_v1".to_owned())
+            } else if dmabuf_rejected {
+                Some("compositor failed to import a dmabuf".to_owned())
+            } else if !is_dmabuf_format {
+                Some(format!(
+                    "format {} is not offered as dmabuf by the compositor",
+                    video_info.format()
+                ))
+            } else if software_downstream {
+                // Not a fallback, shm is the faster choice here
+                gstreamer::debug!(CAT, imp: self, "downstream only accesses frames with the CPU");
+                None
+            } else {
+                Some("no dmabuf allocator available".to_owned())
+            };
+            if let Some(reason) = reason {
+                self.warn_shm_fallback(reason);
+            }
+
+            let format = gst_video_format_to_wl_shm(video_info.format()).unwrap();
+            let shm_format = formats
+                .shm
+                .iter()
+                .find(|shm_format| shm_format.format == format)
                 .unwrap();
 
-            if video_info.stride()[0] != shm_format.stride as i32 {
-                unimplemented!()
-            }
+            // The compositor dictates the stride for shm buffers, let the pool
+            // override the default stride if it differs
+            let shm_stride = if video_info.stride()[0] != shm_format.stride as i32 {
+                gstreamer::debug!(
+                    CAT,
+                    imp: self,
+                    "using compositor stride {} instead of {}",
+                    shm_format.stride,
+                    video_info.stride()[0]
+                );
+                Some(shm_format.stride)
+            } else {
+                None
+            };
 
-            gstreamer::debug!(CAT, imp: self, "using memfd allocator");
-            (MemfdMemoryAllocator::default().upcast(), None, None)
+            let allocator = downstream_allocator
+                .filter(|allocator| is_importable_allocator(allocator, false))
+                .unwrap_or_else(|| MemfdMemoryAllocator::default().upcast());
+            gstreamer::debug!(CAT, imp: self, "using {} allocator", allocator.type_().name());
+            (allocator, None, None, shm_stride)
         };
+        let allocation_params =
+            merge_allocation_params(allocation_params, downstream_params.as_ref());
 
-        if let Some((_, _, min, max)) = query.allocation_pools().get(0) {
-            let mut config = buffer_pool.config();
-            config.set_allocator(Some(&allocator), allocation_params.as_ref());
-            config.add_option(gstreamer_video::BUFFER_POOL_OPTION_VIDEO_META.as_ref());
-            if let Some(video_align) = video_align.as_ref() {
-                config.add_option(gstreamer_video::BUFFER_POOL_OPTION_VIDEO_ALIGNMENT.as_ref());
-                config.set_video_alignment(video_align);
-            }
-            let size = video_info.size() as u32;
-            config.set_params(Some(&caps), size, *min, *max);
+        // Only pass on explicit modifiers, DRM_FORMAT_MOD_INVALID means the
+        // compositor uses implicit modifiers which equals linear for us
+        let mut dmabuf_modifiers = gst_video_format_to_drm_fourcc_code(video_info.format())
+            .map(|format| {
+                session
+                    .dmabuf_modifiers(format)
+                    .into_iter()
+                    .filter(|modifier| *modifier != DRM_FORMAT_MOD_INVALID)
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        if convert_input.is_some() {
+            // The converter imports the captured frames as linear
+            dmabuf_modifiers.retain(|modifier| *modifier == DRM_FORMAT_MOD_LINEAR);
+        }
+        if let Some((_, modifier)) = drm_format {
+            dmabuf_modifiers = vec![modifier];
+        }
+
+        let size = shm_stride
+            .map(|stride| stride as usize * video_info.height() as usize)
+            .unwrap_or_else(|| video_info.size()) as u32;
+
+        let (has_pool, min, max) = query
+            .allocation_pools()
+            .get(0)
+            .map(|(_, _, min, max)| (true, *min, *max))
+            .unwrap_or((false, 0, 0));
+        let (min, max) = self.buffer_counts(min, max);
+
+        // Renegotiation often only changes fields that do not affect the buffers,
+        // like the framerate, keep the buffers of the current pool in that case
+        let current_pool = self.repack_pool.lock().unwrap().clone().or_else(|| {
+            self.obj()
+                .buffer_pool()
+                .and_then(|pool| pool.downcast::<WaylandBufferPool>().ok())
+        });
+        let reusable_pool = current_pool.filter(|pool| {
+            pool.is_active()
+                && is_pool_compatible(pool, &caps, &allocator, video_align.as_ref(), min)
+        });
+        let buffer_pool = if let Some(buffer_pool) = reusable_pool {
+            gstreamer::debug!(CAT, imp: self, "reusing current buffer pool");
             buffer_pool
-                .set_config(config)
-                .expect("failed to set config");
-            query.set_nth_allocation_pool(0, Some(&buffer_pool), size, *min, *max);
         } else {
-            let mut config = buffer_pool.config();
-            config.set_allocator(Some(&allocator), allocation_params.as_ref());
-            config.add_option(gstreamer_video::BUFFER_POOL_OPTION_VIDEO_META.as_ref());
-            if let Some(video_align) = video_align.as_ref() {
-                config.add_option(gstreamer_video::BUFFER_POOL_OPTION_VIDEO_ALIGNMENT.as_ref());
-                config.set_video_alignment(video_align);
+            // Let the compositor write directly into the buffers of a downstream
+            // dmabuf pool, converted frames are captured in another format
+            let memory_pool = if use_dmabuf_allocator && convert_input.is_none() && !gl_output {
+                self.downstream_memory_pool(query, &caps, size, min, max)
+            } else {
+                None
+            };
+            let configure_pool = |memory_pool: Option<&gstreamer::BufferPool>| {
+                let buffer_pool = WaylandBufferPool::new(&session.wl_shm(), linux_dmabuf.as_ref());
+                let mut config = buffer_pool.config();
+                config.set_allocator(Some(&allocator), allocation_params.as_ref());
+                config.set_memory_pool(memory_pool);
+                config.add_option(gstreamer_video::BUFFER_POOL_OPTION_VIDEO_META.as_ref());
+                if let Some(video_align) = video_align.as_ref() {
+                    config.add_option(gstreamer_video::BUFFER_POOL_OPTION_VIDEO_ALIGNMENT.as_ref());
+                    config.set_video_alignment(video_align);
+                }
+                config.set_shm_stride(shm_stride);
+                let (trim_timeout, acquire_timeout) = {
+                    let settings = self.settings.lock().unwrap();
+                    (settings.trim_timeout, settings.acquire_timeout)
+                };
+                config.set_trim_timeout(
+                    (trim_timeout > 0)
+                        .then(|| std::time::Duration::from_millis(trim_timeout as u64)),
+                );
+                config.set_acquire_timeout(
+                    (acquire_timeout > 0)
+                        .then(|| std::time::Duration::from_millis(acquire_timeout as u64)),
+                );
+                if use_dmabuf_allocator {
+                    config.set_memory_type(WaylandMemoryType::Dmabuf);
+                    // Hardware encoders usually expect one fd per plane
+                    config.set_memory_per_plane(true);
+                    config.set_dmabuf_modifiers(&dmabuf_modifiers);
+                } else {
+                    config.set_memory_type(WaylandMemoryType::Shm);
+                }
+                config.set_params(Some(&caps), size, min, max);
+                buffer_pool
+                    .set_config(config)
+                    .expect("failed to set config");
+                buffer_pool
+            };
+
+            let mut buffer_pool = configure_pool(memory_pool.as_ref());
+            let mut imported = false;
+            if memory_pool.is_some() {
+                imported = self.probe_dmabuf_import(&buffer_pool) && !buffer_pool.dmabuf_rejected();
+                if imported {
+                    gstreamer::info!(CAT, imp: self, "capturing into the buffers of the downstream pool");
+                } else {
+                    gstreamer::info!(
+                        CAT,
+                        imp: self,
+                        "compositor can not import the buffers of the downstream pool, allocating our own"
+                    );
+                    let _ = buffer_pool.set_active(false);
+                    buffer_pool = configure_pool(None);
+                }
+            }
+
+            // Import one buffer up front, a rejected dmabuf is only reported
+            // asynchronously and shm can still be negotiated here
+            if use_dmabuf_allocator && !imported && !self.probe_dmabuf_import(&buffer_pool) {
+                let _ = buffer_pool.set_active(false);
+                if session.reject_dmabuf() {
+                    return self.decide_allocation(query);
+                }
+                return Err(gstreamer::loggable_error!(
+                    CAT,
+                    "compositor rejected the dmabuf import and offers no shm formats"
+                ));
             }
-            let video_info =
-                gstreamer_video::VideoInfo::from_caps(&caps).expect("failed to get video info");
-            config.set_params(Some(&caps), video_info.size() as u32, 0, 0);
+
             buffer_pool
-                .set_config(config)
-                .expect("failed to set config");
-            query.add_allocation_pool(Some(&buffer_pool), video_info.size() as u32, 0, 0);
         };
 
+        // The pool limits the number of buffers if downstream did not
+        let (size, min, max) = buffer_pool
+            .config()
+            .params()
+            .map(|(_, size, min, max)| (size, min, max))
+            .unwrap_or((size, min, max));
+
+        // If downstream can not handle video meta it will assume the default layout
+        // for the caps, so padded buffers have to be repacked before pushing them
+        let downstream_video_meta = query
+            .find_allocation_meta::<gstreamer_video::VideoMeta>()
+            .is_some();
+        if drm_format.is_some() && !downstream_video_meta {
+            return Err(gstreamer::loggable_error!(
+                CAT,
+                "downstream negotiated DMA_DRM caps without video meta"
+            ));
+        }
+        if va_device.is_some() && use_dmabuf_allocator {
+            self.check_va_layout(&buffer_pool);
+        }
+        let is_padded = buffer_pool
+            .video_info()
+            .map(|pool_video_info| {
+                pool_video_info.stride() != video_info.stride()
+                    || pool_video_info.offset() != video_info.offset()
+            })
+            .unwrap_or(false);
+        // The GL upload handles the layout of the video meta itself
+        let needs_repack = !gl_output && !downstream_video_meta && is_padded;
+        if needs_repack {
+            gstreamer::debug!(
+                CAT,
+                imp: self,
+                "downstream does not support video meta, repacking padded buffers"
+            );
+        }
+
+        // The GPU renders into buffer objects with their own stride
+        let gpu_conversion = self.prepare_gpu_converter(
+            &session,
+            convert_input
+                .as_ref()
+                .filter(|_| use_dmabuf_allocator && downstream_video_meta),
+            &downstream_info,
+        );
+        *self.cpu_converter.lock().unwrap() = None;
+        *self.convert_input.lock().unwrap() = convert_input.clone();
+
+        let (pool, size): (Option<gstreamer::BufferPool>, u32) =
+            if convert_input.is_some() || needs_repack {
+                buffer_pool.set_active(true).map_err(|err| {
+                    gstreamer::loggable_error!(CAT, "failed to activate pool: {}", err)
+                })?;
+                let old_pool = self
+                    .repack_pool
+                    .lock()
+                    .unwrap()
+                    .replace(buffer_pool.clone());
+                if let Some(old_pool) = old_pool.filter(|old_pool| *old_pool != buffer_pool) {
+                    let _ = old_pool.set_active(false);
+                }
+
+                if gpu_conversion {
+                    // Converted frames are allocated by the converter
+                    (None, downstream_info.size() as u32)
+                } else {
+                    let output_pool = gstreamer_video::VideoBufferPool::new();
+                    let mut config = output_pool.config();
+                    config.set_params(
+                        Some(&downstream_caps),
+                        downstream_info.size() as u32,
+                        min,
+                        max,
+                    );
+                    output_pool
+                        .set_config(config)
+                        .expect("failed to set config");
+                    (Some(output_pool.upcast()), downstream_info.size() as u32)
+                }
+            } else {
+                let old_pool = self.repack_pool.lock().unwrap().take();
+                if let Some(old_pool) = old_pool.filter(|old_pool| *old_pool != buffer_pool) {
+                    let _ = old_pool.set_active(false);
+                }
+                (Some(buffer_pool.upcast()), size)
+            };
+
+        // Frames are captured into our pool and uploaded to downstream's context
+        #[cfg(feature = "gl")]
+        {
+            let mut gl_memory = self.gl_memory.lock().unwrap();
+            if gl_output {
+                gl_memory
+                    .configure(self.obj().upcast_ref(), &caps, &downstream_caps)
+                    .map_err(|err| {
+                        gstreamer::loggable_error!(CAT, "failed to set up GL upload: {}", err)
+                    })?;
+            } else {
+                gl_memory.reset_upload();
+            }
+        }
+
+        if has_pool {
+            query.set_nth_allocation_pool(0, pool.as_ref(), size, min, max);
+        } else {
+            query.add_allocation_pool(pool.as_ref(), size, min, max);
+        }
+
+        self.set_allocation_info(AllocationInfo {
+            negotiated_memory: caps_memory(&downstream_caps),
+            capture_memory: if use_dmabuf_allocator {
+                "dmabuf"
+            } else {
+                "shm"
+            },
+            allocator: allocator.type_().name().to_owned(),
+        });
         Ok(())
     }
 }
@@ -745,95 +3920,225 @@ impl PushSrcImpl for WlrScreencopySrc {
         &self,
         _buffer: Option<&mut gstreamer::BufferRef>,
     ) -> Result<gstreamer_base::subclass::base_src::CreateSuccess, gstreamer::FlowError> {
-        let pool = self
-            .obj()
-            .buffer_pool()
-            .expect("buffer_pool set in decide_allocation");
-        let buffer_pool_aquire_params = gstreamer::BufferPoolAcquireParams::with_flags(
-            gstreamer::BufferPoolAcquireFlags::empty(),
-        );
-        let new_buffer = pool.acquire_buffer(Some(&buffer_pool_aquire_params))?;
-        let wl_buffer_meta = new_buffer
-            .meta::<WaylandBufferMeta>()
-            .expect("no wayland buffer meta");
-        let wl_buffer = wl_buffer_meta.wl_buffer();
-        let mut event_queue_guard = self.event_queue.lock().unwrap();
-        let mut state_guard = self.wayland_state.lock().unwrap();
-        let state = state_guard.as_mut().unwrap();
-        let settings = self.settings.lock().unwrap();
-
-        // first finish the current frame
-        let frame = state
-            .current_frame
-            .as_ref()
-            .map(|(frame, _)| frame)
-            .unwrap();
-        frame.copy(wl_buffer);
+        let mut retried = false;
+        let mut failures = 0;
 
-        while !state
-            .current_frame
-            .as_ref()
-            .map(|(_, info)| info.state.is_some())
-            .unwrap_or(false)
-        {
-            event_queue_guard
-                .as_mut()
-                .unwrap()
-                .blocking_dispatch(state)
-                .expect("failed to dispatch");
-        }
+        loop {
+            self.renegotiate_if_needed()?;
 
-        let (frame, frame_info) = state.current_frame.take().unwrap();
-        frame.destroy();
-        let frame_state = frame_info.state.unwrap();
+            let region_capture = self.region_capture.lock().unwrap().clone();
+            if let Some(region_capture) = region_capture {
+                match self.create_region_frame(&region_capture)? {
+                    Some(buffer) => {
+                        return Ok(
+                            gstreamer_base::subclass::base_src::CreateSuccess::NewBuffer(buffer),
+                        )
+                    }
+                    None => continue,
+                }
+            }
 
-        // then shedule the next frame
-        let (output, _, _) = if let Some(output_name) = settings.output_name.as_deref() {
-            state
-                .outputs
-                .iter()
-                .find(|(_, _, info)| info.name == output_name)
-                .unwrap_or_else(|| {
-                    panic!(
-                        "output {} not found, available outputs: {}",
-                        output_name,
-                        state
-                            .outputs
-                            .iter()
-                            .map(|(_, _, info)| &info.name)
-                            .fold("".to_owned(), |acc, item| { format!("{} {}", acc, item) })
-                            .trim()
-                    )
-                })
-        } else {
-            state.outputs.first().expect("no outputs")
-        };
+            let repack_pool = self.repack_pool.lock().unwrap().clone();
+            let pool = match repack_pool.as_ref() {
+                Some(repack_pool) => repack_pool.clone().upcast(),
+                None => self
+                    .obj()
+                    .buffer_pool()
+                    .expect("buffer_pool set in decide_allocation"),
+            };
 
-        let frame = state
-            .wlr_screencopy_manager
-            .capture_output(0, output, &state.qhandle, ());
-        state.current_frame = Some((frame, Default::default()));
+            let (new_buffer, copied_frame) = match self.capture(&pool)? {
+                Capture::Frame(new_buffer, copied_frame) => (new_buffer, copied_frame),
+                Capture::NoDamage(interval) => {
+                    self.push_gap(interval)?;
+                    continue;
+                }
+                Capture::Disconnected(err) => {
+                    drop(pool);
+                    self.handle_disconnect(err)?;
+                    continue;
+                }
+                Capture::Discarded => {
+                    self.frame_counter.lock().unwrap().drop_frame();
+                    continue;
+                }
+                Capture::TimedOut(timeout) => {
+                    drop(pool);
+                    self.handle_copy_timeout(timeout)?;
+                    continue;
+                }
+                Capture::OutputLost => {
+                    drop(pool);
+                    self.handle_output_lost()?;
+                    continue;
+                }
+                Capture::NoBuffer(timeout) => {
+                    drop(pool);
+                    gstreamer::debug!(CAT, imp: self, "no free buffer within {:?}, skipping frame", timeout);
+                    self.frame_counter.lock().unwrap().drop_frame();
+                    self.push_gap(timeout)?;
+                    continue;
+                }
+                Capture::PoweredOff => {
+                    drop(pool);
+                    match self.handle_powered_off()? {
+                        Some(buffer) => {
+                            return Ok(
+                                gstreamer_base::subclass::base_src::CreateSuccess::NewBuffer(
+                                    buffer,
+                                ),
+                            )
+                        }
+                        None => continue,
+                    }
+                }
+            };
+            self.copy_timed_out.store(false, Ordering::SeqCst);
 
-        while !state
-            .current_frame
-            .as_ref()
-            .map(|(_, info)| info.done)
-            .unwrap_or(false)
-        {
-            event_queue_guard
-                .as_mut()
-                .unwrap()
-                .blocking_dispatch(state)
-                .expect("failed to dispatch");
-        }
+            match copied_frame.state {
+                FrameState::Ready(timestamp) => {
+                    if self.capture_paused.swap(false, Ordering::SeqCst) {
+                        gstreamer::info!(CAT, imp: self, "compositor copies frames again, resuming capture");
+                    }
+                    let time_code = self.settings.lock().unwrap().time_code;
+                    let pts = self.running_time_from_monotonic(timestamp);
+                    if self.is_late(pts) {
+                        gstreamer::debug!(CAT, imp: self, "skipping frame at {:?}, downstream is late", pts);
+                        drop(new_buffer);
+                        self.frame_counter.lock().unwrap().drop_frame();
+                        continue;
+                    }
+                    let memory_type = if new_buffer
+                        .peek_memory(0)
+                        .downcast_memory_ref::<gstreamer_allocators::DmaBufMemory>()
+                        .is_some()
+                    {
+                        "dmabuf"
+                    } else {
+                        "shm"
+                    };
+                    // The frame being ready only means the compositor submitted the copy,
+                    // GPU consumers would sample a partially written dmabuf otherwise.
+                    // Repacking maps the memory which already synchronizes.
+                    if memory_type == "dmabuf"
+                        && repack_pool.is_none()
+                        && !crate::allocators::wait_dmabuf_fences(&new_buffer, FENCE_TIMEOUT)
+                    {
+                        gstreamer::warning!(
+                            CAT,
+                            imp: self,
+                            "dmabuf fences did not signal within {:?}",
+                            FENCE_TIMEOUT
+                        );
+                    }
+                    let (new_buffer, hidden) =
+                        self.finish_frame(new_buffer, repack_pool.is_some())?;
+                    if self.is_duplicate(&new_buffer, !copied_frame.damage.is_empty() && !hidden) {
+                        gstreamer::trace!(CAT, imp: self, "dropping duplicate frame");
+                        drop(new_buffer);
+                        let session = self.session()?;
+                        if let Some(interval) = self.frame_interval(&session) {
+                            self.push_gap(interval)?;
+                        }
+                        continue;
+                    }
+                    let mut new_buffer = self.upload_gl(new_buffer)?;
+                    *self.gap_position.lock().unwrap() = pts;
+                    let buffer_mut = new_buffer.make_mut();
+                    buffer_mut.set_pts(pts);
+                    gstreamer::ReferenceTimestampMeta::add(
+                        buffer_mut,
+                        &REFERENCE_TIMESTAMP_CAPS,
+                        gstreamer::ClockTime::from_nseconds(timestamp.as_nanos() as u64),
+                        gstreamer::ClockTime::NONE,
+                    );
+                    // The damage of a hidden frame would reveal where the private application is
+                    if !hidden && !copied_frame.damage.is_empty() {
+                        let damage = copied_frame
+                            .damage
+                            .iter()
+                            .map(|rect| {
+                                gstreamer_video::VideoRectangle::new(
+                                    rect.x as i32,
+                                    rect.y as i32,
+                                    rect.width as i32,
+                                    rect.height as i32,
+                                )
+                            })
+                            .collect();
+                        ScreencopyDamageMeta::add(buffer_mut, damage);
+                    }
+                    let (sequence, dropped) = self.frame_counter.lock().unwrap().push_frame();
+                    if dropped > 0 {
+                        gstreamer::debug!(CAT, imp: self, "dropped {} frames before {}", dropped, sequence);
+                    }
+                    ScreencopyFrameMeta::add(buffer_mut, sequence, dropped);
+                    if time_code {
+                        self.add_time_code(buffer_mut, pts);
+                    }
+                    self.record_stats(timestamp, dropped, memory_type);
+                    if self.settings.lock().unwrap().power_off_mode == PowerOffMode::LastFrame {
+                        *self.last_frame.lock().unwrap() = Some(new_buffer.clone());
+                    }
+                    return Ok(
+                        gstreamer_base::subclass::base_src::CreateSuccess::NewBuffer(new_buffer),
+                    );
+                }
+                FrameState::Failed if self.session()?.is_powered_off() => {
+                    // Not a failure of the compositor, capturing pauses with the next frame
+                    drop(new_buffer);
+                    drop(pool);
+                }
+                FrameState::Failed if !retried && self.reject_dmabuf(&new_buffer) => {
+                    // The compositor most likely failed to import our dmabuf, fall back
+                    // to shm and retry with a buffer from the renegotiated pool
+                    gstreamer::warning!(
+                        CAT,
+                        imp: self,
+                        "dmabuf copy failed, falling back to shm"
+                    );
+                    drop(new_buffer);
+                    drop(pool);
+                    if !self.obj().negotiate() {
+                        return Err(gstreamer::FlowError::NotNegotiated);
+                    }
+                    retried = true;
+                }
+                FrameState::Failed => {
+                    let (push_corrupted, max_retries) = {
+                        let settings = self.settings.lock().unwrap();
+                        (settings.push_corrupted, settings.max_retries)
+                    };
+                    if push_corrupted && failures >= max_retries {
+                        gstreamer::element_imp_warning!(
+                            self,
+                            gstreamer::ResourceError::Read,
+                            ("Failed to copy frame, pushing corrupted frame"),
+                            ["compositor failed {} consecutive frames", failures + 1]
+                        );
+                        // A corrupted frame still must not show a private application
+                        let (new_buffer, _) =
+                            self.finish_frame(new_buffer, repack_pool.is_some())?;
+                        let mut new_buffer = self.upload_gl(new_buffer)?;
+                        let buffer_mut = new_buffer.make_mut();
+                        buffer_mut.set_pts(self.running_time_now());
+                        buffer_mut.set_flags(
+                            gstreamer::BufferFlags::CORRUPTED | gstreamer::BufferFlags::DROPPABLE,
+                        );
+                        let (sequence, dropped) = self.frame_counter.lock().unwrap().push_frame();
+                        ScreencopyFrameMeta::add(buffer_mut, sequence, dropped);
+                        return Ok(
+                            gstreamer_base::subclass::base_src::CreateSuccess::NewBuffer(
+                                new_buffer,
+                            ),
+                        );
+                    }
 
-        match frame_state {
-            FrameState::Ready(_timestamp) => {
-                // TODO: Set the buffer pts from the duration (and figure out how to transform the time base correctly)
-                // remove base.set_do_timestamp(true) when ready
-                Ok(gstreamer_base::subclass::base_src::CreateSuccess::NewBuffer(new_buffer))
+                    drop(new_buffer);
+                    drop(pool);
+                    self.retry_failed_frame(&mut failures)?;
+                }
             }
-            FrameState::Failed => Err(gstreamer::FlowError::Error),
         }
     }
 }
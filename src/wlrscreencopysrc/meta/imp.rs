@@ -0,0 +1,177 @@
+use std::ptr;
+
+use gstreamer::glib::{
+    self,
+    translate::{from_glib, IntoGlib},
+};
+
+use once_cell::sync::Lazy;
+
+pub(super) struct DamageMetaParams {
+    pub rects: Vec<gstreamer_video::VideoRectangle>,
+}
+
+#[repr(C)]
+pub struct ScreencopyDamageMeta {
+    parent: gstreamer::ffi::GstMeta,
+    pub(super) rects: Vec<gstreamer_video::VideoRectangle>,
+}
+
+pub(super) fn damage_meta_api_get_type() -> glib::Type {
+    static TYPE: Lazy<glib::Type> = Lazy::new(|| unsafe {
+        let t = from_glib(gstreamer::ffi::gst_meta_api_type_register(
+            b"ScreencopyDamageMetaAPI\0".as_ptr() as *const _,
+            [ptr::null::<std::os::raw::c_char>()].as_ptr() as *mut *const _,
+        ));
+
+        assert_ne!(t, glib::Type::INVALID);
+
+        t
+    });
+
+    *TYPE
+}
+
+unsafe extern "C" fn damage_meta_init(
+    meta: *mut gstreamer::ffi::GstMeta,
+    params: glib::ffi::gpointer,
+    _buffer: *mut gstreamer::ffi::GstBuffer,
+) -> glib::ffi::gboolean {
+    assert!(!params.is_null());
+
+    let meta = &mut *(meta as *mut ScreencopyDamageMeta);
+    let params = ptr::read(params as *const DamageMetaParams);
+
+    ptr::write(&mut meta.rects, params.rects);
+
+    true.into_glib()
+}
+
+unsafe extern "C" fn damage_meta_free(
+    meta: *mut gstreamer::ffi::GstMeta,
+    _buffer: *mut gstreamer::ffi::GstBuffer,
+) {
+    let meta = &mut *(meta as *mut ScreencopyDamageMeta);
+
+    ptr::drop_in_place(&mut meta.rects);
+}
+
+// The rectangles stay valid as long as the content is copied unchanged.
+unsafe extern "C" fn damage_meta_transform(
+    dest: *mut gstreamer::ffi::GstBuffer,
+    meta: *mut gstreamer::ffi::GstMeta,
+    _buffer: *mut gstreamer::ffi::GstBuffer,
+    _type_: glib::ffi::GQuark,
+    _data: glib::ffi::gpointer,
+) -> glib::ffi::gboolean {
+    let meta = &*(meta as *mut ScreencopyDamageMeta);
+
+    super::ScreencopyDamageMeta::add(gstreamer::BufferRef::from_mut_ptr(dest), meta.rects.clone());
+
+    true.into_glib()
+}
+
+pub(super) fn damage_meta_get_info() -> *const gstreamer::ffi::GstMetaInfo {
+    struct MetaInfo(ptr::NonNull<gstreamer::ffi::GstMetaInfo>);
+    unsafe impl Send for MetaInfo {}
+    unsafe impl Sync for MetaInfo {}
+
+    static META_INFO: Lazy<MetaInfo> = Lazy::new(|| unsafe {
+        MetaInfo(
+            ptr::NonNull::new(gstreamer::ffi::gst_meta_register(
+                damage_meta_api_get_type().into_glib(),
+                b"ScreencopyDamageMeta\0".as_ptr() as *const _,
+                std::mem::size_of::<ScreencopyDamageMeta>(),
+                Some(damage_meta_init),
+                Some(damage_meta_free),
+                Some(damage_meta_transform),
+            ) as *mut gstreamer::ffi::GstMetaInfo)
+            .expect("Failed to register meta API"),
+        )
+    });
+
+    META_INFO.0.as_ptr()
+}
+
+pub(super) struct FrameMetaParams {
+    pub sequence: u64,
+    pub dropped: u64,
+}
+
+#[repr(C)]
+pub struct ScreencopyFrameMeta {
+    parent: gstreamer::ffi::GstMeta,
+    pub(super) sequence: u64,
+    pub(super) dropped: u64,
+}
+
+pub(super) fn frame_meta_api_get_type() -> glib::Type {
+    static TYPE: Lazy<glib::Type> = Lazy::new(|| unsafe {
+        let t = from_glib(gstreamer::ffi::gst_meta_api_type_register(
+            b"ScreencopyFrameMetaAPI\0".as_ptr() as *const _,
+            [ptr::null::<std::os::raw::c_char>()].as_ptr() as *mut *const _,
+        ));
+
+        assert_ne!(t, glib::Type::INVALID);
+
+        t
+    });
+
+    *TYPE
+}
+
+unsafe extern "C" fn frame_meta_init(
+    meta: *mut gstreamer::ffi::GstMeta,
+    params: glib::ffi::gpointer,
+    _buffer: *mut gstreamer::ffi::GstBuffer,
+) -> glib::ffi::gboolean {
+    assert!(!params.is_null());
+
+    let meta = &mut *(meta as *mut ScreencopyFrameMeta);
+    let params = ptr::read(params as *const FrameMetaParams);
+
+    meta.sequence = params.sequence;
+    meta.dropped = params.dropped;
+
+    true.into_glib()
+}
+
+unsafe extern "C" fn frame_meta_transform(
+    dest: *mut gstreamer::ffi::GstBuffer,
+    meta: *mut gstreamer::ffi::GstMeta,
+    _buffer: *mut gstreamer::ffi::GstBuffer,
+    _type_: glib::ffi::GQuark,
+    _data: glib::ffi::gpointer,
+) -> glib::ffi::gboolean {
+    let meta = &*(meta as *mut ScreencopyFrameMeta);
+
+    super::ScreencopyFrameMeta::add(
+        gstreamer::BufferRef::from_mut_ptr(dest),
+        meta.sequence,
+        meta.dropped,
+    );
+
+    true.into_glib()
+}
+
+pub(super) fn frame_meta_get_info() -> *const gstreamer::ffi::GstMetaInfo {
+    struct MetaInfo(ptr::NonNull<gstreamer::ffi::GstMetaInfo>);
+    unsafe impl Send for MetaInfo {}
+    unsafe impl Sync for MetaInfo {}
+
+    static META_INFO: Lazy<MetaInfo> = Lazy::new(|| unsafe {
+        MetaInfo(
+            ptr::NonNull::new(gstreamer::ffi::gst_meta_register(
+                frame_meta_api_get_type().into_glib(),
+                b"ScreencopyFrameMeta\0".as_ptr() as *const _,
+                std::mem::size_of::<ScreencopyFrameMeta>(),
+                Some(frame_meta_init),
+                None,
+                Some(frame_meta_transform),
+            ) as *mut gstreamer::ffi::GstMetaInfo)
+            .expect("Failed to register meta API"),
+        )
+    });
+
+    META_INFO.0.as_ptr()
+}
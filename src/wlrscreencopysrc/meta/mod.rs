@@ -0,0 +1,119 @@
+use gstreamer::{glib, MetaAPI};
+
+mod imp;
+
+/// Regions of the output that changed since the previous frame, as reported by the
+/// compositor.
+///
+/// Use it for region-of-interest encoding or to only upload changed areas. The meta
+/// API type is registered as `ScreencopyDamageMetaAPI`. With `damage-report=union`
+/// it holds a single rectangle covering all damage.
+#[repr(transparent)]
+pub struct ScreencopyDamageMeta(imp::ScreencopyDamageMeta);
+
+unsafe impl Send for ScreencopyDamageMeta {}
+unsafe impl Sync for ScreencopyDamageMeta {}
+
+impl ScreencopyDamageMeta {
+    // Add a new damage meta to the buffer with the given rectangles.
+    pub fn add(
+        buffer: &mut gstreamer::BufferRef,
+        rects: Vec<gstreamer_video::VideoRectangle>,
+    ) -> gstreamer::MetaRefMut<Self, gstreamer::meta::Standalone> {
+        unsafe {
+            // Manually dropping because gst_buffer_add_meta() takes ownership of the
+            // content of the struct.
+            let mut params = std::mem::ManuallyDrop::new(imp::DamageMetaParams { rects });
+
+            let meta = gstreamer::ffi::gst_buffer_add_meta(
+                buffer.as_mut_ptr(),
+                imp::damage_meta_get_info(),
+                &mut *params as *mut imp::DamageMetaParams as glib::ffi::gpointer,
+            ) as *mut imp::ScreencopyDamageMeta;
+
+            Self::from_mut_ptr(buffer, meta)
+        }
+    }
+
+    // Retrieve the damaged rectangles in buffer coordinates.
+    pub fn rects(&self) -> &[gstreamer_video::VideoRectangle] {
+        &self.0.rects
+    }
+}
+
+// Trait to allow using the gst::Buffer API with this meta.
+unsafe impl MetaAPI for ScreencopyDamageMeta {
+    type GstType = imp::ScreencopyDamageMeta;
+
+    fn meta_api() -> glib::Type {
+        imp::damage_meta_api_get_type()
+    }
+}
+
+impl std::fmt::Debug for ScreencopyDamageMeta {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("ScreencopyDamageMeta")
+            .field("rects", &self.0.rects)
+            .finish()
+    }
+}
+
+/// Position of a frame in the sequence of frames copied from the compositor.
+///
+/// Frames are counted even if they are not pushed downstream, so `dropped` tells
+/// how many copied frames were skipped since the previous buffer. The meta API type
+/// is registered as `ScreencopyFrameMetaAPI`.
+#[repr(transparent)]
+pub struct ScreencopyFrameMeta(imp::ScreencopyFrameMeta);
+
+unsafe impl Send for ScreencopyFrameMeta {}
+unsafe impl Sync for ScreencopyFrameMeta {}
+
+impl ScreencopyFrameMeta {
+    // Add a new frame meta to the buffer.
+    pub fn add(
+        buffer: &mut gstreamer::BufferRef,
+        sequence: u64,
+        dropped: u64,
+    ) -> gstreamer::MetaRefMut<Self, gstreamer::meta::Standalone> {
+        unsafe {
+            let mut params = imp::FrameMetaParams { sequence, dropped };
+
+            let meta = gstreamer::ffi::gst_buffer_add_meta(
+                buffer.as_mut_ptr(),
+                imp::frame_meta_get_info(),
+                &mut params as *mut imp::FrameMetaParams as glib::ffi::gpointer,
+            ) as *mut imp::ScreencopyFrameMeta;
+
+            Self::from_mut_ptr(buffer, meta)
+        }
+    }
+
+    // Monotonically increasing number of the frame, starting at 0 with every start.
+    pub fn sequence(&self) -> u64 {
+        self.0.sequence
+    }
+
+    // Number of frames copied but not pushed since the previous buffer.
+    pub fn dropped(&self) -> u64 {
+        self.0.dropped
+    }
+}
+
+// Trait to allow using the gst::Buffer API with this meta.
+unsafe impl MetaAPI for ScreencopyFrameMeta {
+    type GstType = imp::ScreencopyFrameMeta;
+
+    fn meta_api() -> glib::Type {
+        imp::frame_meta_api_get_type()
+    }
+}
+
+impl std::fmt::Debug for ScreencopyFrameMeta {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("ScreencopyFrameMeta")
+            .field("sequence", &self.0.sequence)
+            .field("dropped", &self.0.dropped)
+            .finish()
+    }
+}
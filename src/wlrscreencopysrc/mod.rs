@@ -1,12 +1,398 @@
 use gstreamer::glib;
 use gstreamer::prelude::*;
+use gstreamer::subclass::prelude::ObjectSubclassIsExt;
 
+mod dma_drm;
+mod geometry;
 mod imp;
+mod meta;
+mod privacy;
+mod region;
+mod snapshot;
+mod stats;
+
+pub use geometry::OUTPUT_GEOMETRY_MESSAGE_NAME;
+pub(crate) use imp::output_caps;
+pub use meta::{ScreencopyDamageMeta, ScreencopyFrameMeta};
+pub use stats::STATS_MESSAGE_NAME;
+
+/// Caps of the [`gstreamer::ReferenceTimestampMeta`] carrying the `CLOCK_MONOTONIC`
+/// capture time reported by the compositor.
+pub const REFERENCE_TIMESTAMP_CAPS: &str = "timestamp/x-clock-monotonic";
+
+/// How the frames are presented, see the `presentation` property
+#[derive(Debug, Default, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, glib::Enum)]
+#[repr(u32)]
+#[enum_type(name = "GstWlrScreencopySrcPresentation")]
+pub enum Presentation {
+    #[default]
+    #[enum_value(
+        name = "Physical: Square pixels in output resolution",
+        nick = "physical"
+    )]
+    Physical = 0,
+    #[enum_value(
+        name = "Logical: Pixel aspect ratio matching the logical output size",
+        nick = "logical"
+    )]
+    Logical = 1,
+}
+
+/// How damage is reported, see the `damage-report` property
+#[derive(Debug, Default, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, glib::Enum)]
+#[repr(u32)]
+#[enum_type(name = "GstWlrScreencopySrcDamageReport")]
+pub enum DamageReport {
+    #[default]
+    #[enum_value(
+        name = "Rects: Every damaged rectangle reported by the compositor",
+        nick = "rects"
+    )]
+    Rects = 0,
+    #[enum_value(name = "Union: A single rectangle covering all damage", nick = "union")]
+    Union = 1,
+}
+
+/// What replaces frames while a private application is focused, see the
+/// `privacy-fill` property
+#[derive(Debug, Default, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, glib::Enum)]
+#[repr(u32)]
+#[enum_type(name = "GstWlrScreencopySrcPrivacyFill")]
+pub enum PrivacyFill {
+    #[default]
+    #[enum_value(name = "Black: Black frames", nick = "black")]
+    Black = 0,
+    #[enum_value(name = "Color: Frames filled with privacy-color", nick = "color")]
+    Color = 1,
+    #[enum_value(
+        name = "Last frame: The last frame without a private application, black if there is none",
+        nick = "last-frame"
+    )]
+    LastFrame = 2,
+}
+
+/// What happens when the compositor did not copy a frame in time, see the
+/// `copy-timeout-policy` property
+#[derive(Debug, Default, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, glib::Enum)]
+#[repr(u32)]
+#[enum_type(name = "GstWlrScreencopySrcCopyTimeoutPolicy")]
+pub enum CopyTimeoutPolicy {
+    #[default]
+    #[enum_value(name = "Retry: Warn and request a new frame", nick = "retry")]
+    Retry = 0,
+    #[enum_value(
+        name = "Gap: Warn, push a gap event and request a new frame",
+        nick = "gap"
+    )]
+    Gap = 1,
+    #[enum_value(name = "Error: Fail the stream", nick = "error")]
+    Error = 2,
+}
+
+/// What is pushed while the captured output is powered off, see the
+/// `power-off-mode` property
+#[derive(Debug, Default, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, glib::Enum)]
+#[repr(u32)]
+#[enum_type(name = "GstWlrScreencopySrcPowerOffMode")]
+pub enum PowerOffMode {
+    #[default]
+    #[enum_value(name = "Gap: Gap events until the output is powered on", nick = "gap")]
+    Gap = 0,
+    #[enum_value(
+        name = "Last frame: The last frame repeated at the framerate, gaps if there is none",
+        nick = "last-frame"
+    )]
+    LastFrame = 1,
+}
 
 glib::wrapper! {
     pub struct WlrScreencopySrc(ObjectSubclass<imp::WlrScreencopySrc>) @extends gstreamer_base::PushSrc, gstreamer_base::BaseSrc, gstreamer::Element, gstreamer::Object;
 }
 
+impl WlrScreencopySrc {
+    /// Create a builder for a `wlrscreencopysrc` with typed property setters.
+    pub fn builder<'a>() -> WlrScreencopySrcBuilder<'a> {
+        WlrScreencopySrcBuilder {
+            builder: glib::Object::builder(),
+        }
+    }
+
+    /// Capture a single frame into a sample with its caps, without a pipeline.
+    ///
+    /// The element has to be in the NULL state, the frame is captured from the
+    /// configured output or region to shm and private applications are hidden.
+    /// `timeout` limits waiting for the copy of an output, `None` waits until it
+    /// is done. The same is available as the `capture-snapshot` action signal
+    /// taking the timeout in milliseconds.
+    pub fn capture_snapshot(
+        &self,
+        timeout: Option<std::time::Duration>,
+    ) -> Result<gstreamer::Sample, gstreamer::ErrorMessage> {
+        self.imp().capture_snapshot(timeout)
+    }
+}
+
+/// Builder for [`WlrScreencopySrc`], created by [`WlrScreencopySrc::builder`].
+#[must_use = "The builder must be built to be used"]
+pub struct WlrScreencopySrcBuilder<'a> {
+    builder: glib::object::ObjectBuilder<'a, WlrScreencopySrc>,
+}
+
+impl<'a> WlrScreencopySrcBuilder<'a> {
+    pub fn name(self, name: &'a str) -> Self {
+        Self {
+            builder: self.builder.property("name", name),
+        }
+    }
+
+    /// Wayland display to connect to, a socket name or an absolute path
+    pub fn display(self, display: &'a str) -> Self {
+        Self {
+            builder: self.builder.property("display", display),
+        }
+    }
+
+    /// Name of the output to capture, the first output is captured by default.
+    /// A trailing `*` matches several outputs, see the `output-name` property.
+    pub fn output_name(self, output_name: &'a str) -> Self {
+        Self {
+            builder: self.builder.property("output-name", output_name),
+        }
+    }
+
+    /// Area to capture in global logical coordinates as `x,y,width,height`, may
+    /// span several outputs
+    pub fn region(self, region: &'a str) -> Self {
+        Self {
+            builder: self.builder.property("region", region),
+        }
+    }
+
+    pub fn show_pointer(self, show_pointer: bool) -> Self {
+        Self {
+            builder: self.builder.property("show-pointer", show_pointer),
+        }
+    }
+
+    /// Only start capturing once PLAYING
+    pub fn defer_capture(self, defer_capture: bool) -> Self {
+        Self {
+            builder: self.builder.property("defer-capture", defer_capture),
+        }
+    }
+
+    /// Inject upstream navigation events as pointer and keyboard input
+    pub fn navigation(self, navigation: bool) -> Self {
+        Self {
+            builder: self.builder.property("navigation", navigation),
+        }
+    }
+
+    pub fn presentation(self, presentation: Presentation) -> Self {
+        Self {
+            builder: self.builder.property("presentation", presentation),
+        }
+    }
+
+    /// Hide frames while an application with one of `app_ids` is focused on the output
+    pub fn privacy_app_ids(self, app_ids: &[&str]) -> Self {
+        Self {
+            builder: self.builder.property(
+                "privacy-app-ids",
+                gstreamer::Array::new(app_ids.iter().copied()),
+            ),
+        }
+    }
+
+    pub fn privacy_fill(self, privacy_fill: PrivacyFill) -> Self {
+        Self {
+            builder: self.builder.property("privacy-fill", privacy_fill),
+        }
+    }
+
+    /// ARGB color of [`PrivacyFill::Color`]
+    pub fn privacy_color(self, privacy_color: u32) -> Self {
+        Self {
+            builder: self.builder.property("privacy-color", privacy_color),
+        }
+    }
+
+    pub fn damage_aware(self, damage_aware: bool) -> Self {
+        Self {
+            builder: self.builder.property("damage-aware", damage_aware),
+        }
+    }
+
+    /// Push gaps instead of frames identical to the previous one
+    pub fn drop_duplicates(self, drop_duplicates: bool) -> Self {
+        Self {
+            builder: self.builder.property("drop-duplicates", drop_duplicates),
+        }
+    }
+
+    /// Report damage as list of rects or as their union
+    pub fn damage_report(self, damage_report: DamageReport) -> Self {
+        Self {
+            builder: self.builder.property("damage-report", damage_report),
+        }
+    }
+
+    /// Drop frames that got old while downstream was blocked
+    pub fn leaky(self, leaky: bool) -> Self {
+        Self {
+            builder: self.builder.property("leaky", leaky),
+        }
+    }
+
+    /// Skip frames downstream reported to be too late for
+    pub fn qos(self, qos: bool) -> Self {
+        Self {
+            builder: self.builder.property("qos", qos),
+        }
+    }
+
+    pub fn reconnect(self, reconnect: bool) -> Self {
+        Self {
+            builder: self.builder.property("reconnect", reconnect),
+        }
+    }
+
+    pub fn max_retries(self, max_retries: u32) -> Self {
+        Self {
+            builder: self.builder.property("max-retries", max_retries),
+        }
+    }
+
+    /// Delay in milliseconds before retrying a failed frame
+    pub fn retry_delay(self, retry_delay: u32) -> Self {
+        Self {
+            builder: self.builder.property("retry-delay", retry_delay),
+        }
+    }
+
+    pub fn push_corrupted(self, push_corrupted: bool) -> Self {
+        Self {
+            builder: self.builder.property("push-corrupted", push_corrupted),
+        }
+    }
+
+    /// Pause capturing instead of failing once all retries of a frame failed
+    pub fn pause_on_failure(self, pause_on_failure: bool) -> Self {
+        Self {
+            builder: self.builder.property("pause-on-failure", pause_on_failure),
+        }
+    }
+
+    /// Time in milliseconds the compositor has to copy a frame, 0 waits forever
+    pub fn copy_timeout(self, copy_timeout: u32) -> Self {
+        Self {
+            builder: self.builder.property("copy-timeout", copy_timeout),
+        }
+    }
+
+    /// What happens when a copy took longer than the copy timeout
+    pub fn copy_timeout_policy(self, copy_timeout_policy: CopyTimeoutPolicy) -> Self {
+        Self {
+            builder: self
+                .builder
+                .property("copy-timeout-policy", copy_timeout_policy),
+        }
+    }
+
+    /// Push frames as fast as the compositor copies them with timestamps starting at zero
+    pub fn is_live(self, is_live: bool) -> Self {
+        Self {
+            builder: self.builder.property("is-live", is_live),
+        }
+    }
+
+    /// What is pushed while the captured output is powered off
+    pub fn power_off_mode(self, power_off_mode: PowerOffMode) -> Self {
+        Self {
+            builder: self.builder.property("power-off-mode", power_off_mode),
+        }
+    }
+
+    /// Interval in seconds for posting statistics, 0 disables them
+    pub fn stats_interval(self, stats_interval: u32) -> Self {
+        Self {
+            builder: self.builder.property("stats-interval", stats_interval),
+        }
+    }
+
+    /// Attach a [`gstreamer_video::VideoTimeCodeMeta`] to every frame
+    pub fn time_code(self, time_code: bool) -> Self {
+        Self {
+            builder: self.builder.property("time-code", time_code),
+        }
+    }
+
+    /// Scale frames to `width`x`height`, a 0 dimension keeps the aspect ratio of the
+    /// output and `0`x`0` disables scaling
+    pub fn scale(self, width: u32, height: u32) -> Self {
+        Self {
+            builder: self
+                .builder
+                .property("scale-width", width)
+                .property("scale-height", height),
+        }
+    }
+
+    /// Offer NV12 and I420 in addition to the formats of the compositor
+    pub fn convert(self, convert: bool) -> Self {
+        Self {
+            builder: self.builder.property("convert", convert),
+        }
+    }
+
+    /// Offer memory:GLMemory caps, frames are imported as GL textures
+    pub fn gl_memory(self, gl_memory: bool) -> Self {
+        Self {
+            builder: self.builder.property("gl-memory", gl_memory),
+        }
+    }
+
+    /// Idle time in milliseconds after which unused buffers are freed, 0 keeps them
+    pub fn trim_timeout(self, trim_timeout: u32) -> Self {
+        Self {
+            builder: self.builder.property("trim-timeout", trim_timeout),
+        }
+    }
+
+    /// Time in milliseconds to wait for a free buffer before skipping the frame,
+    /// 0 waits forever
+    pub fn acquire_timeout(self, acquire_timeout: u32) -> Self {
+        Self {
+            builder: self.builder.property("acquire-timeout", acquire_timeout),
+        }
+    }
+
+    /// Minimum number of buffers in the pool, downstream may require more
+    pub fn min_buffers(self, min_buffers: u32) -> Self {
+        Self {
+            builder: self.builder.property("min-buffers", min_buffers),
+        }
+    }
+
+    /// Maximum number of buffers in the pool, 0 for no limit of our own
+    pub fn max_buffers(self, max_buffers: u32) -> Self {
+        Self {
+            builder: self.builder.property("max-buffers", max_buffers),
+        }
+    }
+
+    /// Number of buffers to output before sending EOS, -1 for unlimited
+    pub fn num_buffers(self, num_buffers: i32) -> Self {
+        Self {
+            builder: self.builder.property("num-buffers", num_buffers),
+        }
+    }
+
+    pub fn build(self) -> WlrScreencopySrc {
+        self.builder.build()
+    }
+}
+
 pub fn register(plugin: &gstreamer::Plugin) -> Result<(), glib::BoolError> {
     gstreamer::Element::register(
         Some(plugin),
@@ -0,0 +1,63 @@
+//! Hiding frames while a private application is focused, see the
+//! `privacy-app-ids` property.
+
+use gstreamer::glib;
+
+/// ARGB color of [`PrivacyFill::Black`](super::PrivacyFill::Black)
+pub(super) const BLACK: u32 = 0xff00_0000;
+
+/// Overwrite every byte of `frame` with the ARGB `color`, alpha is kept opaque.
+pub(super) fn fill_frame(
+    frame: &mut gstreamer_video::VideoFrameRef<&mut gstreamer::BufferRef>,
+    color: u32,
+) -> Result<(), glib::BoolError> {
+    let info = frame.format_info();
+    let (r, g, b) = (
+        ((color >> 16) & 0xff) as f32,
+        ((color >> 8) & 0xff) as f32,
+        (color & 0xff) as f32,
+    );
+    // BT.709 limited range like the GPU conversion
+    let values = if info.is_yuv() {
+        [
+            16. + 0.1826 * r + 0.6142 * g + 0.0620 * b,
+            128. - 0.1006 * r - 0.3386 * g + 0.4392 * b,
+            128. + 0.4392 * r - 0.3989 * g - 0.0403 * b,
+            255.,
+        ]
+    } else {
+        [r, g, b, 255.]
+    };
+
+    let (width, height) = (frame.width(), frame.height());
+    for plane in 0..frame.n_planes() {
+        // Padding and row ends must not keep any of the captured content
+        frame.plane_data_mut(plane)?.fill(0);
+    }
+    for component in 0..info.n_components() {
+        let plane = info.plane()[component as usize];
+        let offset = info.poffset()[component as usize] as usize;
+        let pixel_stride = info.pixel_stride()[component as usize] as usize;
+        let stride = frame.plane_stride()[plane as usize] as usize;
+        let component_width = info.scale_width(component as u8, width) as usize;
+        let component_height = info.scale_height(component as u8, height) as usize;
+        let value = values[component as usize].round().clamp(0., 255.) as u8;
+
+        let data = frame.plane_data_mut(plane)?;
+        for row in 0..component_height {
+            let line = &mut data[row * stride..];
+            for x in 0..component_width {
+                line[offset + x * pixel_stride] = value;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The first of `focused` that is listed in `app_ids`
+pub(super) fn private_app_id<'a>(app_ids: &[String], focused: &'a [String]) -> Option<&'a str> {
+    focused
+        .iter()
+        .find(|app_id| app_ids.contains(app_id))
+        .map(String::as_str)
+}
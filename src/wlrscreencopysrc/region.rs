@@ -0,0 +1,390 @@
+//! Capture of a region in global logical coordinates that may span several
+//! outputs, composed into one frame in system memory.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use gstreamer::prelude::{BufferPoolExt, BufferPoolExtManual};
+use once_cell::sync::Lazy;
+
+use crate::buffer_pool::{
+    WaylandBufferMeta, WaylandBufferPool, WaylandBufferPoolConfig, WaylandMemoryType,
+};
+use crate::session::{
+    ConnectCancel, CopiedFrame, FrameState, OutputInfo, Rect, ScreencopySession, SessionError,
+};
+use crate::utils::gst_video_format_from_wl_shm;
+
+static CAT: Lazy<gstreamer::DebugCategory> = Lazy::new(|| {
+    gstreamer::DebugCategory::new(
+        "wlrscreencopyregion",
+        gstreamer::DebugColorFlags::empty(),
+        Some("wlr-screencopy region capture"),
+    )
+});
+
+/// Format of the composed frames
+pub(super) const REGION_FORMAT: gstreamer_video::VideoFormat = gstreamer_video::VideoFormat::Bgrx;
+
+/// A rectangle in the global logical coordinate space, parsed from `x,y,width,height`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct Region {
+    pub(super) x: i32,
+    pub(super) y: i32,
+    pub(super) width: u32,
+    pub(super) height: u32,
+}
+
+impl std::str::FromStr for Region {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields = s.split(',').map(str::trim).collect::<Vec<_>>();
+        let [x, y, width, height] = fields[..] else {
+            return Err(format!("expected x,y,width,height, got {:?}", s));
+        };
+        let region = Region {
+            x: x.parse().map_err(|_| format!("invalid x {:?}", x))?,
+            y: y.parse().map_err(|_| format!("invalid y {:?}", y))?,
+            width: width
+                .parse()
+                .map_err(|_| format!("invalid width {:?}", width))?,
+            height: height
+                .parse()
+                .map_err(|_| format!("invalid height {:?}", height))?,
+        };
+        if region.width == 0 || region.height == 0 {
+            return Err(format!("empty region {:?}", s));
+        }
+        Ok(region)
+    }
+}
+
+impl std::fmt::Display for Region {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{},{},{},{}", self.x, self.y, self.width, self.height)
+    }
+}
+
+/// The logical rectangle of an output, the logical size falls back to the mode
+/// divided by the scale.
+fn output_rect(output_info: &OutputInfo) -> Option<(i32, i32, i32, i32)> {
+    let (x, y) = output_info.logical_position?;
+    let (width, height) = output_info.logical_size.or_else(|| {
+        (output_info.scale > 0).then(|| {
+            (
+                output_info.mode.width / output_info.scale,
+                output_info.mode.height / output_info.scale,
+            )
+        })
+    })?;
+    Some((x, y, width, height))
+}
+
+/// The part of `region` covered by `output_info` in output-local logical coordinates,
+/// together with its offset inside the region.
+pub(super) fn intersect(region: &Region, output_info: &OutputInfo) -> Option<(Rect, (u32, u32))> {
+    let (output_x, output_y, output_width, output_height) = output_rect(output_info)?;
+    let x1 = std::cmp::max(region.x, output_x);
+    let y1 = std::cmp::max(region.y, output_y);
+    let x2 = std::cmp::min(region.x + region.width as i32, output_x + output_width);
+    let y2 = std::cmp::min(region.y + region.height as i32, output_y + output_height);
+    if x2 <= x1 || y2 <= y1 {
+        return None;
+    }
+
+    let local = Rect {
+        x: (x1 - output_x) as u32,
+        y: (y1 - output_y) as u32,
+        width: (x2 - x1) as u32,
+        height: (y2 - y1) as u32,
+    };
+    Some((local, ((x1 - region.x) as u32, (y1 - region.y) as u32)))
+}
+
+/// One output contributing to the region
+struct Part {
+    session: Arc<ScreencopySession>,
+    pool: WaylandBufferPool,
+    video_info: gstreamer_video::VideoInfo,
+    converter: Mutex<gstreamer_video::VideoConverter>,
+    /// Buffer of a copy that has not been waited for, e.g. after flushing
+    pending_copy: Mutex<Option<gstreamer::Buffer>>,
+}
+
+/// Captures of all outputs intersecting a region, copied in parallel and scaled
+/// into the composed frame.
+pub(super) struct RegionCapture {
+    parts: Vec<Part>,
+    video_info: gstreamer_video::VideoInfo,
+    /// Refresh rate of the fastest output in mHz
+    refresh: i32,
+    /// Whether parts of the region are not covered by any output
+    has_holes: bool,
+}
+
+impl std::fmt::Debug for RegionCapture {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RegionCapture")
+            .field("sessions", &self.sessions().collect::<Vec<_>>())
+            .field("video_info", &self.video_info)
+            .finish()
+    }
+}
+
+impl RegionCapture {
+    /// Connect to every output in `outputs` intersecting `region`.
+    ///
+    /// The frame is composed at the largest scale of the intersecting outputs,
+    /// parts of the region not covered by an output stay black. Connecting fails
+    /// once `cancel` is cancelled.
+    pub(super) fn connect(
+        wayland_display: Option<&str>,
+        region: &Region,
+        overlay_cursor: bool,
+        outputs: &[OutputInfo],
+        cancel: &ConnectCancel,
+    ) -> Result<Self, gstreamer::ErrorMessage> {
+        let intersecting = outputs
+            .iter()
+            .filter_map(|output_info| {
+                intersect(region, output_info).map(|(local, offset)| (output_info, local, offset))
+            })
+            .collect::<Vec<_>>();
+        let scale = intersecting
+            .iter()
+            .map(|(output_info, _, _)| std::cmp::max(output_info.scale, 1) as u32)
+            .max()
+            .unwrap_or(1);
+        let refresh = intersecting
+            .iter()
+            .map(|(output_info, _, _)| output_info.mode.refresh)
+            .max()
+            .unwrap_or(0);
+        let covered = intersecting
+            .iter()
+            .map(|(_, local, _)| local.width as u64 * local.height as u64)
+            .sum::<u64>();
+        let has_holes = covered < region.width as u64 * region.height as u64;
+
+        let video_info = gstreamer_video::VideoInfo::builder(
+            REGION_FORMAT,
+            region.width * scale,
+            region.height * scale,
+        )
+        .build()
+        .map_err(|err| {
+            gstreamer::error_msg!(
+                gstreamer::ResourceError::Settings,
+                ["invalid region {}: {}", region, err]
+            )
+        })?;
+
+        let mut parts = Vec::with_capacity(intersecting.len());
+        for (output_info, local, (offset_x, offset_y)) in intersecting {
+            gstreamer::debug!(
+                CAT,
+                "capturing {:?} of {} at {},{}",
+                local,
+                output_info.name,
+                offset_x,
+                offset_y
+            );
+            let session = ScreencopySession::connect_cancellable(
+                wayland_display,
+                Some(&output_info.name),
+                overlay_cursor,
+                Some(local),
+                cancel,
+            )
+            .map_err(super::imp::session_error_msg)?;
+            let dest = Rect {
+                x: offset_x * scale,
+                y: offset_y * scale,
+                width: local.width * scale,
+                height: local.height * scale,
+            };
+            parts.push(Part::new(session, dest, &video_info)?);
+        }
+
+        Ok(RegionCapture {
+            parts,
+            video_info,
+            refresh,
+            has_holes,
+        })
+    }
+
+    /// Layout of the composed frames
+    pub(super) fn video_info(&self) -> &gstreamer_video::VideoInfo {
+        &self.video_info
+    }
+
+    /// Refresh rate of the fastest output in mHz, 0 if unknown
+    pub(super) fn refresh(&self) -> i32 {
+        self.refresh
+    }
+
+    pub(super) fn sessions(&self) -> impl Iterator<Item = &Arc<ScreencopySession>> {
+        self.parts.iter().map(|part| &part.session)
+    }
+
+    /// Copy all parts and compose them into `frame`, returns the capture time of the
+    /// most recent part or `None` if the compositor failed to copy a part.
+    pub(super) fn capture(
+        &self,
+        frame: &mut gstreamer_video::VideoFrame<gstreamer_video::video_frame::Writable>,
+    ) -> Result<Option<Duration>, SessionError> {
+        // Let the compositor copy all outputs at once
+        for part in self.parts.iter() {
+            let mut pending_copy = part.pending_copy.lock().unwrap();
+            if pending_copy.is_some() {
+                continue;
+            }
+            let buffer = match part.pool.acquire_buffer(None) {
+                Ok(buffer) => buffer,
+                Err(err) => {
+                    gstreamer::warning!(CAT, "failed to acquire buffer: {:?}", err);
+                    return Ok(None);
+                }
+            };
+            let wl_buffer_meta = buffer
+                .meta::<WaylandBufferMeta>()
+                .expect("no wayland buffer meta");
+            part.pool.mark_busy(&buffer);
+            part.session.copy(wl_buffer_meta.wl_buffer(), false)?;
+            *pending_copy = Some(buffer);
+        }
+
+        let mut copied_frames = Vec::with_capacity(self.parts.len());
+        for part in self.parts.iter() {
+            let copied_frame = part.session.wait_copied(None)?.expect("no deadline given");
+            let buffer = part.pending_copy.lock().unwrap().take().unwrap();
+            copied_frames.push((buffer, copied_frame));
+        }
+
+        if self.has_holes {
+            for plane in 0..frame.n_planes() {
+                if let Ok(data) = frame.plane_data_mut(plane) {
+                    data.fill(0);
+                }
+            }
+        }
+
+        let mut timestamp = Duration::ZERO;
+        for (part, (buffer, copied_frame)) in self.parts.iter().zip(copied_frames) {
+            let CopiedFrame {
+                state: FrameState::Ready(part_timestamp),
+                y_invert,
+                ..
+            } = copied_frame
+            else {
+                return Ok(None);
+            };
+            if y_invert {
+                gstreamer::warning!(
+                    CAT,
+                    "y-inverted frames are not supported, part is upside down"
+                );
+            }
+            timestamp = std::cmp::max(timestamp, part_timestamp);
+
+            let Ok(src_frame) =
+                gstreamer_video::VideoFrame::from_buffer_readable(buffer, &part.video_info)
+            else {
+                gstreamer::warning!(CAT, "failed to map captured part");
+                return Ok(None);
+            };
+            part.converter.lock().unwrap().frame(&src_frame, frame);
+        }
+
+        Ok(Some(timestamp))
+    }
+}
+
+impl Part {
+    /// Set up a pool for the shm frames of `session` and a converter scaling them
+    /// into `dest` of the composed frame.
+    fn new(
+        session: Arc<ScreencopySession>,
+        dest: Rect,
+        out_info: &gstreamer_video::VideoInfo,
+    ) -> Result<Self, gstreamer::ErrorMessage> {
+        let formats = session.buffer_formats();
+        let Some((format, shm_format)) = formats.shm.iter().find_map(|shm_format| {
+            gst_video_format_from_wl_shm(shm_format.format).map(|format| (format, shm_format))
+        }) else {
+            return Err(gstreamer::error_msg!(
+                gstreamer::CoreError::Negotiation,
+                [
+                    "no supported shm format for output {:?}",
+                    session.output_name()
+                ]
+            ));
+        };
+
+        let video_info =
+            gstreamer_video::VideoInfo::builder(format, shm_format.width, shm_format.height)
+                .build()
+                .map_err(|err| {
+                    gstreamer::error_msg!(gstreamer::CoreError::Negotiation, ["{}", err])
+                })?;
+        let caps = video_info
+            .to_caps()
+            .map_err(|err| gstreamer::error_msg!(gstreamer::CoreError::Negotiation, ["{}", err]))?;
+
+        let pool = WaylandBufferPool::new(&session.wl_shm(), None);
+        let mut config = pool.config();
+        config.set_memory_type(WaylandMemoryType::Shm);
+        if video_info.stride()[0] != shm_format.stride as i32 {
+            config.set_shm_stride(Some(shm_format.stride));
+        }
+        let size = shm_format.stride * shm_format.height;
+        config.set_params(Some(&caps), size, 2, 2);
+        pool.set_config(config).map_err(|err| {
+            gstreamer::error_msg!(
+                gstreamer::ResourceError::Settings,
+                ["failed to configure pool: {}", err]
+            )
+        })?;
+        pool.set_active(true).map_err(|err| {
+            gstreamer::error_msg!(
+                gstreamer::ResourceError::Settings,
+                ["failed to activate pool: {}", err]
+            )
+        })?;
+        // The pool knows the stride override
+        let video_info = pool.video_info().unwrap_or(video_info);
+
+        let mut config = gstreamer_video::VideoConverterConfig::new();
+        config.set_dest_x(dest.x as i32);
+        config.set_dest_y(dest.y as i32);
+        config.set_dest_width(Some(dest.width as i32));
+        config.set_dest_height(Some(dest.height as i32));
+        config.set_fill_border(false);
+        let converter = gstreamer_video::VideoConverter::new(&video_info, out_info, Some(config))
+            .map_err(|err| {
+            gstreamer::error_msg!(
+                gstreamer::CoreError::Negotiation,
+                ["failed to create converter: {}", err]
+            )
+        })?;
+
+        Ok(Part {
+            session,
+            pool,
+            video_info,
+            converter: Mutex::new(converter),
+            pending_copy: Mutex::new(None),
+        })
+    }
+}
+
+impl Drop for RegionCapture {
+    fn drop(&mut self) {
+        for part in self.parts.iter() {
+            part.pending_copy.lock().unwrap().take();
+            let _ = part.pool.set_active(false);
+        }
+    }
+}
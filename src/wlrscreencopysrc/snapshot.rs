@@ -0,0 +1,168 @@
+//! Capturing single frames without a pipeline, see
+//! [`WlrScreencopySrc::capture_snapshot`](super::WlrScreencopySrc::capture_snapshot).
+
+use std::time::{Duration, Instant};
+
+use gstreamer::prelude::{BufferPoolExt, BufferPoolExtManual};
+
+use super::region::RegionCapture;
+use super::REFERENCE_TIMESTAMP_CAPS;
+use crate::buffer_pool::{
+    WaylandBufferMeta, WaylandBufferPool, WaylandBufferPoolConfig, WaylandMemoryType,
+};
+use crate::session::{FrameState, ScreencopySession};
+use crate::utils::gst_video_format_from_wl_shm;
+
+/// Copy the next frame of `session` into a shm buffer, waiting for the copy until
+/// `deadline`. The buffer is packed in the default layout of the returned video info.
+pub(super) fn capture_output(
+    session: &ScreencopySession,
+    par: gstreamer::Fraction,
+    deadline: Option<Instant>,
+) -> Result<(gstreamer::Buffer, gstreamer_video::VideoInfo), gstreamer::ErrorMessage> {
+    let formats = session.buffer_formats();
+    let Some((format, shm_format)) = formats.shm.iter().find_map(|shm_format| {
+        gst_video_format_from_wl_shm(shm_format.format).map(|format| (format, shm_format))
+    }) else {
+        return Err(gstreamer::error_msg!(
+            gstreamer::CoreError::Negotiation,
+            [
+                "no supported shm format for output {:?}",
+                session.output_name()
+            ]
+        ));
+    };
+
+    let video_info =
+        gstreamer_video::VideoInfo::builder(format, shm_format.width, shm_format.height)
+            .par(par)
+            .fps(gstreamer::Fraction::new(0, 1))
+            .build()
+            .map_err(|err| gstreamer::error_msg!(gstreamer::CoreError::Negotiation, ["{}", err]))?;
+    let caps = video_info
+        .to_caps()
+        .map_err(|err| gstreamer::error_msg!(gstreamer::CoreError::Negotiation, ["{}", err]))?;
+
+    let pool = WaylandBufferPool::new(&session.wl_shm(), None);
+    let mut config = pool.config();
+    config.set_memory_type(WaylandMemoryType::Shm);
+    if video_info.stride()[0] != shm_format.stride as i32 {
+        config.set_shm_stride(Some(shm_format.stride));
+    }
+    config.set_params(Some(&caps), shm_format.stride * shm_format.height, 1, 1);
+    pool.set_config(config).map_err(|err| {
+        gstreamer::error_msg!(
+            gstreamer::ResourceError::Settings,
+            ["failed to configure pool: {}", err]
+        )
+    })?;
+    pool.set_active(true).map_err(|err| {
+        gstreamer::error_msg!(
+            gstreamer::ResourceError::Settings,
+            ["failed to activate pool: {}", err]
+        )
+    })?;
+    let result = copy_frame(session, &pool, &video_info, deadline);
+    let _ = pool.set_active(false);
+    result.map(|buffer| (buffer, video_info))
+}
+
+fn copy_frame(
+    session: &ScreencopySession,
+    pool: &WaylandBufferPool,
+    video_info: &gstreamer_video::VideoInfo,
+    deadline: Option<Instant>,
+) -> Result<gstreamer::Buffer, gstreamer::ErrorMessage> {
+    session
+        .start_capture()
+        .map_err(super::imp::session_error_msg)?;
+    let buffer = pool.acquire_buffer(None).map_err(|err| {
+        gstreamer::error_msg!(
+            gstreamer::ResourceError::NoSpaceLeft,
+            ["failed to acquire buffer: {:?}", err]
+        )
+    })?;
+    let wl_buffer_meta = buffer
+        .meta::<WaylandBufferMeta>()
+        .expect("no wayland buffer meta");
+    pool.mark_busy(&buffer);
+    session
+        .copy(wl_buffer_meta.wl_buffer(), false)
+        .map_err(super::imp::session_error_msg)?;
+
+    let copied_frame = match session.wait_copied(deadline) {
+        Ok(Some(copied_frame)) => copied_frame,
+        Ok(None) => {
+            return Err(gstreamer::error_msg!(
+                gstreamer::ResourceError::Read,
+                ["compositor did not copy a frame in time"]
+            ))
+        }
+        Err(err) => return Err(super::imp::session_error_msg(err)),
+    };
+    let FrameState::Ready(timestamp) = copied_frame.state else {
+        return Err(gstreamer::error_msg!(
+            gstreamer::ResourceError::Read,
+            ["compositor failed to copy the frame"]
+        ));
+    };
+
+    // Copy out of the pool, its buffers belong to the Wayland connection
+    let pool_info = pool.video_info().unwrap_or_else(|| video_info.clone());
+    let input_frame = gstreamer_video::VideoFrame::from_buffer_readable(buffer, &pool_info)
+        .map_err(|_| {
+            gstreamer::error_msg!(
+                gstreamer::ResourceError::Read,
+                ["failed to map captured frame"]
+            )
+        })?;
+    let output_buffer = gstreamer::Buffer::with_size(video_info.size())
+        .map_err(|err| gstreamer::error_msg!(gstreamer::ResourceError::NoSpaceLeft, ["{}", err]))?;
+    let mut output_frame =
+        gstreamer_video::VideoFrame::from_buffer_writable(output_buffer, video_info).map_err(
+            |_| gstreamer::error_msg!(gstreamer::ResourceError::Write, ["failed to map snapshot"]),
+        )?;
+    input_frame
+        .copy(&mut output_frame)
+        .map_err(|err| gstreamer::error_msg!(gstreamer::ResourceError::Write, ["{}", err]))?;
+
+    let mut buffer = output_frame.into_buffer();
+    add_timestamp(buffer.get_mut().unwrap(), timestamp);
+    Ok(buffer)
+}
+
+/// Capture and compose one frame of a region spanning several outputs.
+pub(super) fn capture_region(
+    region_capture: &RegionCapture,
+) -> Result<(gstreamer::Buffer, gstreamer_video::VideoInfo), gstreamer::ErrorMessage> {
+    let video_info = region_capture.video_info().clone();
+    let buffer = gstreamer::Buffer::with_size(video_info.size())
+        .map_err(|err| gstreamer::error_msg!(gstreamer::ResourceError::NoSpaceLeft, ["{}", err]))?;
+    let mut frame = gstreamer_video::VideoFrame::from_buffer_writable(buffer, &video_info)
+        .map_err(|_| {
+            gstreamer::error_msg!(gstreamer::ResourceError::Write, ["failed to map snapshot"])
+        })?;
+    let timestamp = match region_capture.capture(&mut frame) {
+        Ok(Some(timestamp)) => timestamp,
+        Ok(None) => {
+            return Err(gstreamer::error_msg!(
+                gstreamer::ResourceError::Read,
+                ["compositor failed to copy the frame"]
+            ))
+        }
+        Err(err) => return Err(super::imp::session_error_msg(err)),
+    };
+
+    let mut buffer = frame.into_buffer();
+    add_timestamp(buffer.get_mut().unwrap(), timestamp);
+    Ok((buffer, video_info))
+}
+
+fn add_timestamp(buffer: &mut gstreamer::BufferRef, timestamp: Duration) {
+    gstreamer::ReferenceTimestampMeta::add(
+        buffer,
+        &gstreamer::Caps::new_empty_simple(REFERENCE_TIMESTAMP_CAPS),
+        gstreamer::ClockTime::from_nseconds(timestamp.as_nanos() as u64),
+        gstreamer::ClockTime::NONE,
+    );
+}
@@ -0,0 +1,77 @@
+//! Capture statistics periodically posted as element messages.
+
+use std::time::{Duration, Instant};
+
+/// Name of the element message structure
+pub const STATS_MESSAGE_NAME: &str = "wlrscreencopysrc-stats";
+
+/// Statistics of one reporting interval
+#[derive(Debug)]
+pub(super) struct Stats {
+    start: Instant,
+    frames: u64,
+    dropped: u64,
+    /// Time between the compositor capturing a frame and it being pushed
+    latencies: Vec<Duration>,
+    memory_type: &'static str,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Stats {
+            start: Instant::now(),
+            frames: 0,
+            dropped: 0,
+            latencies: Vec::new(),
+            memory_type: "none",
+        }
+    }
+}
+
+impl Stats {
+    pub(super) fn record_frame(
+        &mut self,
+        latency: Option<Duration>,
+        dropped: u64,
+        memory_type: &'static str,
+    ) {
+        self.frames += 1;
+        self.dropped += dropped;
+        self.latencies.extend(latency);
+        self.memory_type = memory_type;
+    }
+
+    /// The statistics since the last report if `interval` passed, the interval
+    /// starts over afterwards.
+    pub(super) fn take_report(&mut self, interval: Duration) -> Option<gstreamer::Structure> {
+        let elapsed = self.start.elapsed();
+        if elapsed < interval {
+            return None;
+        }
+
+        self.latencies.sort_unstable();
+        let percentile = |p: usize| -> u64 {
+            if self.latencies.is_empty() {
+                return 0;
+            }
+            let index = (self.latencies.len() - 1) * p / 100;
+            self.latencies[index].as_nanos() as u64
+        };
+
+        let report = gstreamer::Structure::builder(STATS_MESSAGE_NAME)
+            .field("fps", self.frames as f64 / elapsed.as_secs_f64())
+            .field("frames", self.frames)
+            .field("dropped", self.dropped)
+            .field("latency-p50", percentile(50))
+            .field("latency-p90", percentile(90))
+            .field("latency-p99", percentile(99))
+            .field("memory-type", self.memory_type)
+            .build();
+
+        *self = Stats {
+            memory_type: self.memory_type,
+            ..Default::default()
+        };
+        Some(report)
+    }
+}
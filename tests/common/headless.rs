@@ -0,0 +1,108 @@
+//! A headless sway instance for tests against a real wlroots compositor.
+
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// How long to wait for the compositor to create its socket
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+/// Name of the output created by the headless backend
+pub const HEADLESS_OUTPUT: &str = "HEADLESS-1";
+
+/// A running sway, killed and cleaned up when dropped.
+#[derive(Debug)]
+pub struct HeadlessSway {
+    dir: PathBuf,
+    socket: PathBuf,
+    child: Child,
+}
+
+impl HeadlessSway {
+    /// Spawn sway with the headless backend and wait for its socket, the
+    /// binary can be overridden with `SWAY`.
+    pub fn start() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+        let dir = std::env::temp_dir().join(format!(
+            "wlrscreencopysrc-sway-{}-{}",
+            std::process::id(),
+            NEXT_ID.fetch_add(1, Ordering::SeqCst)
+        ));
+        std::fs::create_dir_all(&dir).expect("failed to create runtime dir");
+        let config = dir.join("config");
+        std::fs::write(
+            &config,
+            format!("output {} mode 640x480@60Hz\n", HEADLESS_OUTPUT),
+        )
+        .expect("failed to write sway config");
+
+        let sway = std::env::var_os("SWAY").unwrap_or_else(|| "sway".into());
+        let mut child = Command::new(sway)
+            .arg("--config")
+            .arg(&config)
+            .env("XDG_RUNTIME_DIR", &dir)
+            .env("WLR_BACKENDS", "headless")
+            .env("WLR_RENDERER", "pixman")
+            .env("WLR_LIBINPUT_NO_DEVICES", "1")
+            .env_remove("WAYLAND_DISPLAY")
+            .env_remove("DISPLAY")
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .expect("failed to spawn sway");
+
+        let deadline = Instant::now() + STARTUP_TIMEOUT;
+        let socket = loop {
+            if let Some(socket) = find_socket(&dir) {
+                break socket;
+            }
+            if let Some(status) = child.try_wait().expect("failed to wait for sway") {
+                panic!("sway exited during startup: {}", status);
+            }
+            if Instant::now() > deadline {
+                let _ = child.kill();
+                panic!("sway did not create a wayland socket");
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        };
+
+        HeadlessSway { dir, socket, child }
+    }
+
+    /// Absolute path of the compositor socket, usable as `display`.
+    pub fn socket(&self) -> &Path {
+        &self.socket
+    }
+
+    /// Whether the compositor is still running, it should survive every test.
+    pub fn is_running(&mut self) -> bool {
+        self.child
+            .try_wait()
+            .expect("failed to wait for sway")
+            .is_none()
+    }
+}
+
+impl Drop for HeadlessSway {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn find_socket(dir: &Path) -> Option<PathBuf> {
+    std::fs::read_dir(dir)
+        .ok()?
+        .flatten()
+        .map(|entry| entry.path())
+        .find(|path| {
+            let name = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .unwrap_or_default();
+            name.starts_with("wayland-") && !name.ends_with(".lock")
+        })
+}
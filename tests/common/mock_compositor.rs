@@ -0,0 +1,685 @@
+//! In-process Wayland server implementing just enough of wl_shm, linux-dmabuf and
+//! wlr-screencopy to drive `wlrscreencopysrc` without a real compositor.
+//!
+//! The server exposes a single output and fills every copied shm buffer with
+//! [`OutputConfig::pixel`], dmabuf copies always fail.
+//! Copied buffers are released as configured by [`OutputConfig::release`].
+//! Dmabuf imports succeed unless [`OutputConfig::reject_dmabuf_import`] is set.
+//! With [`OutputConfig::focused_app_id`] a single activated toplevel is announced
+//! through wlr-foreign-toplevel-management.
+
+use std::os::unix::io::{AsRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use wayland_protocols::wp::linux_dmabuf::zv1::server::{
+    zwp_linux_buffer_params_v1::{self, ZwpLinuxBufferParamsV1},
+    zwp_linux_dmabuf_v1::{self, ZwpLinuxDmabufV1},
+};
+use wayland_protocols_wlr::foreign_toplevel::v1::server::{
+    zwlr_foreign_toplevel_handle_v1::{self, ZwlrForeignToplevelHandleV1},
+    zwlr_foreign_toplevel_manager_v1::{self, ZwlrForeignToplevelManagerV1},
+};
+use wayland_protocols_wlr::screencopy::v1::server::{
+    zwlr_screencopy_frame_v1::{self, ZwlrScreencopyFrameV1},
+    zwlr_screencopy_manager_v1::{self, ZwlrScreencopyManagerV1},
+};
+use wayland_server::backend::{ClientData, ClientId, DisconnectReason};
+use wayland_server::protocol::{
+    wl_buffer::{self, WlBuffer},
+    wl_output::{self, WlOutput},
+    wl_shm::{self, WlShm},
+    wl_shm_pool::{self, WlShmPool},
+};
+use wayland_server::{
+    Client, DataInit, Dispatch, Display, DisplayHandle, GlobalDispatch, ListeningSocket, New,
+    Resource,
+};
+
+/// DRM fourcc of XRGB8888
+const DRM_FORMAT_XRGB8888: u32 = 0x3432_5258;
+/// Poll timeout of the server loop, bounds how long stopping the server takes
+const POLL_INTERVAL_MS: i32 = 10;
+
+/// Description of the single output advertised by the mock compositor.
+#[derive(Debug, Clone)]
+pub struct OutputConfig {
+    pub name: String,
+    pub width: i32,
+    pub height: i32,
+    /// Refresh rate in mHz
+    pub refresh: i32,
+    /// XRGB8888 value every pixel of a copied frame is set to
+    pub pixel: u32,
+    /// Advertise the frames as copyable to dmabufs as well
+    pub dmabuf: bool,
+    /// Answer dmabuf imports with `failed`
+    pub reject_dmabuf_import: bool,
+    pub release: Release,
+    /// Answer every copy with `failed`
+    pub fail_copies: bool,
+    /// App id of a toplevel activated on the output
+    pub focused_app_id: Option<String>,
+}
+
+/// When the mock compositor sends `wl_buffer.release` for a copied buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Release {
+    /// Right after the copy
+    Immediately,
+    /// Once the given time passed after the copy
+    After(Duration),
+    Never,
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        OutputConfig {
+            name: "MOCK-1".into(),
+            width: 64,
+            height: 48,
+            refresh: 60_000,
+            pixel: 0x00ff_8040,
+            dmabuf: false,
+            release: Release::Immediately,
+            reject_dmabuf_import: false,
+            fail_copies: false,
+            focused_app_id: None,
+        }
+    }
+}
+
+/// Counters of requests received by the mock compositor.
+#[derive(Debug, Default)]
+pub struct Counters {
+    pub frames_captured: AtomicU64,
+    pub frames_copied: AtomicU64,
+    pub frames_failed: AtomicU64,
+    /// Copies into a buffer whose delayed release was not sent yet
+    pub copies_unreleased: AtomicU64,
+}
+
+/// A running mock compositor, stopped and cleaned up when dropped.
+#[derive(Debug)]
+pub struct MockCompositor {
+    dir: PathBuf,
+    socket: PathBuf,
+    counters: Arc<Counters>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MockCompositor {
+    /// Start a compositor listening on a socket in a fresh temporary directory.
+    pub fn start(config: OutputConfig) -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+        let dir = std::env::temp_dir().join(format!(
+            "wlrscreencopysrc-test-{}-{}",
+            std::process::id(),
+            NEXT_ID.fetch_add(1, Ordering::SeqCst)
+        ));
+        std::fs::create_dir_all(&dir).expect("failed to create runtime dir");
+        let socket = dir.join("wayland-mock");
+
+        let listener =
+            ListeningSocket::bind_absolute(socket.clone()).expect("failed to bind wayland socket");
+        let mut display = Display::<State>::new().expect("failed to create display");
+        let dh = display.handle();
+        dh.create_global::<State, WlShm, ()>(1, ());
+        dh.create_global::<State, WlOutput, ()>(4, ());
+        dh.create_global::<State, ZwpLinuxDmabufV1, ()>(3, ());
+        dh.create_global::<State, ZwlrScreencopyManagerV1, ()>(3, ());
+        if config.focused_app_id.is_some() {
+            dh.create_global::<State, ZwlrForeignToplevelManagerV1, ()>(3, ());
+        }
+
+        let counters = Arc::new(Counters::default());
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut state = State {
+            config,
+            counters: counters.clone(),
+            pending_releases: Vec::new(),
+            outputs: Vec::new(),
+            toplevels: Vec::new(),
+        };
+
+        let thread = {
+            let stop = stop.clone();
+            std::thread::Builder::new()
+                .name("mock-compositor".into())
+                .spawn(move || {
+                    let mut dh = display.handle();
+                    while !stop.load(Ordering::SeqCst) {
+                        while let Some(stream) = listener.accept().expect("failed to accept client")
+                        {
+                            dh.insert_client(stream, Arc::new(ClientState))
+                                .expect("failed to insert client");
+                        }
+
+                        display
+                            .dispatch_clients(&mut state)
+                            .expect("failed to dispatch clients");
+                        state.send_due_releases();
+                        // Clients going away while we flush are not an error
+                        let _ = display.flush_clients();
+
+                        let mut fds = [
+                            nix::poll::PollFd::new(
+                                listener.as_raw_fd(),
+                                nix::poll::PollFlags::POLLIN,
+                            ),
+                            nix::poll::PollFd::new(
+                                display.backend().poll_fd().as_raw_fd(),
+                                nix::poll::PollFlags::POLLIN,
+                            ),
+                        ];
+                        match nix::poll::poll(&mut fds, POLL_INTERVAL_MS) {
+                            Ok(_) | Err(nix::errno::Errno::EINTR) => (),
+                            Err(err) => panic!("failed to poll: {}", err),
+                        }
+                    }
+                })
+                .expect("failed to spawn compositor thread")
+        };
+
+        MockCompositor {
+            dir,
+            socket,
+            counters,
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    /// Absolute path of the listening socket, usable as `display`.
+    pub fn socket(&self) -> &Path {
+        &self.socket
+    }
+
+    pub fn counters(&self) -> &Counters {
+        &self.counters
+    }
+}
+
+impl Drop for MockCompositor {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+struct State {
+    config: OutputConfig,
+    counters: Arc<Counters>,
+    /// Buffers to release with `Release::After` and when
+    pending_releases: Vec<(Instant, WlBuffer)>,
+    /// Bound wl_outputs and toplevel handles of all clients
+    outputs: Vec<WlOutput>,
+    toplevels: Vec<ZwlrForeignToplevelHandleV1>,
+}
+
+impl State {
+    fn send_due_releases(&mut self) {
+        let now = Instant::now();
+        self.pending_releases.retain(|(due, buffer)| {
+            if *due > now {
+                return true;
+            }
+            buffer.release();
+            false
+        });
+    }
+
+    fn stride(&self) -> i32 {
+        self.config.width * 4
+    }
+
+    /// Fill `buffer` with the configured pixel, false if it can not hold a frame.
+    fn fill(&self, buffer: &BufferData) -> bool {
+        let BufferData::Shm(shm) = buffer else {
+            return false;
+        };
+        if shm.format != wl_shm::Format::Xrgb8888
+            || shm.width != self.config.width
+            || shm.height != self.config.height
+            || shm.stride < self.stride()
+            || shm.offset + shm.stride * shm.height > *shm.pool.size.lock().unwrap()
+        {
+            return false;
+        }
+
+        let row = self
+            .config
+            .pixel
+            .to_le_bytes()
+            .repeat(self.config.width as usize);
+        (0..shm.height).all(|y| {
+            let offset = (shm.offset + y * shm.stride) as i64;
+            nix::sys::uio::pwrite(shm.pool.fd.as_raw_fd(), &row, offset) == Ok(row.len())
+        })
+    }
+}
+
+struct ClientState;
+
+impl ClientData for ClientState {
+    fn initialized(&self, _client_id: ClientId) {}
+    fn disconnected(&self, _client_id: ClientId, _reason: DisconnectReason) {}
+}
+
+struct ShmPool {
+    fd: OwnedFd,
+    size: Mutex<i32>,
+}
+
+struct ShmBuffer {
+    pool: Arc<ShmPool>,
+    offset: i32,
+    width: i32,
+    height: i32,
+    stride: i32,
+    format: wl_shm::Format,
+}
+
+enum BufferData {
+    Shm(ShmBuffer),
+    /// Imported dmabufs are accepted but never written to
+    Dmabuf,
+}
+
+impl GlobalDispatch<WlShm, ()> for State {
+    fn bind(
+        _state: &mut Self,
+        _handle: &DisplayHandle,
+        _client: &Client,
+        resource: New<WlShm>,
+        _global_data: &(),
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        let shm = data_init.init(resource, ());
+        shm.format(wl_shm::Format::Argb8888);
+        shm.format(wl_shm::Format::Xrgb8888);
+    }
+}
+
+impl Dispatch<WlShm, ()> for State {
+    fn request(
+        _state: &mut Self,
+        _client: &Client,
+        _resource: &WlShm,
+        request: wl_shm::Request,
+        _data: &(),
+        _dhandle: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        if let wl_shm::Request::CreatePool { id, fd, size } = request {
+            data_init.init(
+                id,
+                Arc::new(ShmPool {
+                    fd,
+                    size: Mutex::new(size),
+                }),
+            );
+        }
+    }
+}
+
+impl Dispatch<WlShmPool, Arc<ShmPool>> for State {
+    fn request(
+        _state: &mut Self,
+        _client: &Client,
+        _resource: &WlShmPool,
+        request: wl_shm_pool::Request,
+        data: &Arc<ShmPool>,
+        _dhandle: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        match request {
+            wl_shm_pool::Request::CreateBuffer {
+                id,
+                offset,
+                width,
+                height,
+                stride,
+                format,
+            } => {
+                let format = format.into_result().unwrap_or(wl_shm::Format::C8);
+                data_init.init(
+                    id,
+                    BufferData::Shm(ShmBuffer {
+                        pool: data.clone(),
+                        offset,
+                        width,
+                        height,
+                        stride,
+                        format,
+                    }),
+                );
+            }
+            wl_shm_pool::Request::Resize { size } => *data.size.lock().unwrap() = size,
+            _ => (),
+        }
+    }
+}
+
+impl Dispatch<WlBuffer, BufferData> for State {
+    fn request(
+        _state: &mut Self,
+        _client: &Client,
+        _resource: &WlBuffer,
+        _request: wl_buffer::Request,
+        _data: &BufferData,
+        _dhandle: &DisplayHandle,
+        _data_init: &mut DataInit<'_, Self>,
+    ) {
+        // Only destroy, the resource is cleaned up by the server
+    }
+}
+
+impl GlobalDispatch<WlOutput, ()> for State {
+    fn bind(
+        state: &mut Self,
+        _handle: &DisplayHandle,
+        _client: &Client,
+        resource: New<WlOutput>,
+        _global_data: &(),
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        let output = data_init.init(resource, ());
+        let config = &state.config;
+        output.geometry(
+            0,
+            0,
+            0,
+            0,
+            wl_output::Subpixel::Unknown,
+            "mock".into(),
+            "mock".into(),
+            wl_output::Transform::Normal,
+        );
+        output.mode(
+            wl_output::Mode::Current | wl_output::Mode::Preferred,
+            config.width,
+            config.height,
+            config.refresh,
+        );
+        if output.version() >= 2 {
+            output.scale(1);
+        }
+        if output.version() >= 4 {
+            output.name(config.name.clone());
+            output.description(format!("Mock output {}", config.name));
+        }
+        if output.version() >= 2 {
+            output.done();
+        }
+
+        // The toplevel manager may be bound before the output
+        for toplevel in state.toplevels.iter() {
+            if toplevel.id().same_client_as(&output.id()) {
+                toplevel.output_enter(&output);
+                toplevel.done();
+            }
+        }
+        state.outputs.push(output);
+    }
+}
+
+impl Dispatch<WlOutput, ()> for State {
+    fn request(
+        _state: &mut Self,
+        _client: &Client,
+        _resource: &WlOutput,
+        _request: wl_output::Request,
+        _data: &(),
+        _dhandle: &DisplayHandle,
+        _data_init: &mut DataInit<'_, Self>,
+    ) {
+        // Only release
+    }
+}
+
+impl GlobalDispatch<ZwpLinuxDmabufV1, ()> for State {
+    fn bind(
+        _state: &mut Self,
+        _handle: &DisplayHandle,
+        _client: &Client,
+        resource: New<ZwpLinuxDmabufV1>,
+        _global_data: &(),
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        let dmabuf = data_init.init(resource, ());
+        dmabuf.format(DRM_FORMAT_XRGB8888);
+        if dmabuf.version() >= 3 {
+            // DRM_FORMAT_MOD_LINEAR
+            dmabuf.modifier(DRM_FORMAT_XRGB8888, 0, 0);
+        }
+    }
+}
+
+impl Dispatch<ZwpLinuxDmabufV1, ()> for State {
+    fn request(
+        _state: &mut Self,
+        _client: &Client,
+        _resource: &ZwpLinuxDmabufV1,
+        request: zwp_linux_dmabuf_v1::Request,
+        _data: &(),
+        _dhandle: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        if let zwp_linux_dmabuf_v1::Request::CreateParams { params_id } = request {
+            data_init.init(params_id, ());
+        }
+    }
+}
+
+impl Dispatch<ZwpLinuxBufferParamsV1, ()> for State {
+    fn request(
+        state: &mut Self,
+        client: &Client,
+        resource: &ZwpLinuxBufferParamsV1,
+        request: zwp_linux_buffer_params_v1::Request,
+        _data: &(),
+        dhandle: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        match request {
+            zwp_linux_buffer_params_v1::Request::Create { .. } => {
+                if state.config.reject_dmabuf_import {
+                    resource.failed();
+                    return;
+                }
+                match client.create_resource::<WlBuffer, _, Self>(dhandle, 1, BufferData::Dmabuf) {
+                    Ok(buffer) => resource.created(&buffer),
+                    Err(_) => resource.failed(),
+                }
+            }
+            zwp_linux_buffer_params_v1::Request::CreateImmed { buffer_id, .. } => {
+                data_init.init(buffer_id, BufferData::Dmabuf);
+            }
+            _ => (),
+        }
+    }
+}
+
+impl GlobalDispatch<ZwlrScreencopyManagerV1, ()> for State {
+    fn bind(
+        _state: &mut Self,
+        _handle: &DisplayHandle,
+        _client: &Client,
+        resource: New<ZwlrScreencopyManagerV1>,
+        _global_data: &(),
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        data_init.init(resource, ());
+    }
+}
+
+impl Dispatch<ZwlrScreencopyManagerV1, ()> for State {
+    fn request(
+        state: &mut Self,
+        _client: &Client,
+        _resource: &ZwlrScreencopyManagerV1,
+        request: zwlr_screencopy_manager_v1::Request,
+        _data: &(),
+        _dhandle: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        let frame = match request {
+            zwlr_screencopy_manager_v1::Request::CaptureOutput { frame, .. }
+            | zwlr_screencopy_manager_v1::Request::CaptureOutputRegion { frame, .. } => {
+                data_init.init(frame, ())
+            }
+            _ => return,
+        };
+        state
+            .counters
+            .frames_captured
+            .fetch_add(1, Ordering::SeqCst);
+
+        let config = &state.config;
+        frame.buffer(
+            wl_shm::Format::Xrgb8888,
+            config.width as u32,
+            config.height as u32,
+            state.stride() as u32,
+        );
+        if frame.version() >= 3 {
+            if config.dmabuf {
+                frame.linux_dmabuf(
+                    DRM_FORMAT_XRGB8888,
+                    config.width as u32,
+                    config.height as u32,
+                );
+            }
+            frame.buffer_done();
+        }
+    }
+}
+
+impl Dispatch<ZwlrScreencopyFrameV1, ()> for State {
+    fn request(
+        state: &mut Self,
+        _client: &Client,
+        resource: &ZwlrScreencopyFrameV1,
+        request: zwlr_screencopy_frame_v1::Request,
+        _data: &(),
+        _dhandle: &DisplayHandle,
+        _data_init: &mut DataInit<'_, Self>,
+    ) {
+        let (buffer, with_damage) = match request {
+            zwlr_screencopy_frame_v1::Request::Copy { buffer } => (buffer, false),
+            zwlr_screencopy_frame_v1::Request::CopyWithDamage { buffer } => (buffer, true),
+            _ => return,
+        };
+
+        let filled = buffer
+            .data::<BufferData>()
+            .map(|data| state.fill(data))
+            .unwrap_or(false);
+        if let Some(index) = state
+            .pending_releases
+            .iter()
+            .position(|(_, pending)| pending == &buffer)
+        {
+            state
+                .counters
+                .copies_unreleased
+                .fetch_add(1, Ordering::SeqCst);
+            state.pending_releases.remove(index);
+        }
+        match state.config.release {
+            Release::Immediately => buffer.release(),
+            Release::After(delay) => state
+                .pending_releases
+                .push((Instant::now() + delay, buffer)),
+            Release::Never => (),
+        }
+        if !filled || state.config.fail_copies {
+            state.counters.frames_failed.fetch_add(1, Ordering::SeqCst);
+            resource.failed();
+            return;
+        }
+        state.counters.frames_copied.fetch_add(1, Ordering::SeqCst);
+
+        resource.flags(zwlr_screencopy_frame_v1::Flags::empty());
+        if with_damage {
+            resource.damage(0, 0, state.config.width as u32, state.config.height as u32);
+        }
+        let now = nix::time::clock_gettime(nix::time::ClockId::CLOCK_MONOTONIC)
+            .expect("failed to get time");
+        let secs = now.tv_sec() as u64;
+        resource.ready((secs >> 32) as u32, secs as u32, now.tv_nsec() as u32);
+    }
+}
+
+impl GlobalDispatch<ZwlrForeignToplevelManagerV1, ()> for State {
+    fn bind(
+        state: &mut Self,
+        handle: &DisplayHandle,
+        client: &Client,
+        resource: New<ZwlrForeignToplevelManagerV1>,
+        _global_data: &(),
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        let manager = data_init.init(resource, ());
+        let Some(app_id) = state.config.focused_app_id.clone() else {
+            return;
+        };
+        let Ok(toplevel) = client.create_resource::<ZwlrForeignToplevelHandleV1, _, Self>(
+            handle,
+            manager.version(),
+            (),
+        ) else {
+            return;
+        };
+        manager.toplevel(&toplevel);
+        toplevel.app_id(app_id);
+        for output in state.outputs.iter() {
+            if output.id().same_client_as(&toplevel.id()) {
+                toplevel.output_enter(output);
+            }
+        }
+        let activated = zwlr_foreign_toplevel_handle_v1::State::Activated as u32;
+        toplevel.state(activated.to_ne_bytes().to_vec());
+        toplevel.done();
+        state.toplevels.push(toplevel);
+    }
+}
+
+impl Dispatch<ZwlrForeignToplevelManagerV1, ()> for State {
+    fn request(
+        _state: &mut Self,
+        _client: &Client,
+        resource: &ZwlrForeignToplevelManagerV1,
+        request: zwlr_foreign_toplevel_manager_v1::Request,
+        _data: &(),
+        _dhandle: &DisplayHandle,
+        _data_init: &mut DataInit<'_, Self>,
+    ) {
+        if let zwlr_foreign_toplevel_manager_v1::Request::Stop = request {
+            resource.finished();
+        }
+    }
+}
+
+impl Dispatch<ZwlrForeignToplevelHandleV1, ()> for State {
+    fn request(
+        _state: &mut Self,
+        _client: &Client,
+        _resource: &ZwlrForeignToplevelHandleV1,
+        _request: zwlr_foreign_toplevel_handle_v1::Request,
+        _data: &(),
+        _dhandle: &DisplayHandle,
+        _data_init: &mut DataInit<'_, Self>,
+    ) {
+        // Activating, closing and the like are not supported
+    }
+}
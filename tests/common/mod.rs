@@ -0,0 +1,20 @@
+//! Helpers shared by the integration tests.
+
+// Every test binary only uses some of the helpers
+#![allow(dead_code)]
+
+#[cfg(feature = "headless-tests")]
+pub mod headless;
+pub mod mock_compositor;
+
+use std::sync::Once;
+
+/// Initialize gstreamer and register the plugin once per test binary.
+pub fn init() {
+    static INIT: Once = Once::new();
+
+    INIT.call_once(|| {
+        gstreamer::init().unwrap();
+        gstwlrscreencopy::plugin_register_static().expect("failed to register plugin");
+    });
+}
@@ -0,0 +1,220 @@
+//! Consistency of the format table shared by wl_shm and dmabuf capture.
+
+mod common;
+
+use gstwlrscreencopy::{
+    drm_format_code_from_string, drm_format_code_to_string, drm_fourcc_requires_little_endian,
+    gst_video_format_from_drm_fourcc_code, gst_video_format_from_wl_shm,
+    gst_video_format_to_drm_fourcc_code, gst_video_format_to_wl_shm,
+    wl_shm_format_from_drm_fourcc_code, wl_shm_format_to_drm_fourcc_code, VideoInfoDmaDrm,
+    DRM_FORMAT_BIG_ENDIAN, DRM_FORMAT_MOD_LINEAR, FORMATS,
+};
+use wayland_client::protocol::wl_shm;
+
+/// Formats of the table the compositor can offer, the big-endian variants are
+/// only listed on big-endian hosts
+fn little_endian_formats() -> impl Iterator<Item = &'static (gstreamer_video::VideoFormat, u32)> {
+    FORMATS
+        .iter()
+        .filter(|(_, fourcc)| fourcc & DRM_FORMAT_BIG_ENDIAN == 0)
+}
+
+fn fourcc_name(fourcc: u32) -> String {
+    String::from_utf8_lossy(&fourcc.to_le_bytes()).into_owned()
+}
+
+#[test]
+fn formats_are_unique() {
+    for (index, (format, fourcc)) in FORMATS.iter().enumerate() {
+        for (other_format, other_fourcc) in &FORMATS[index + 1..] {
+            assert_ne!(format, other_format, "{} listed twice", format);
+            assert_ne!(
+                fourcc,
+                other_fourcc,
+                "{} listed twice",
+                fourcc_name(*fourcc)
+            );
+        }
+    }
+}
+
+#[test]
+fn drm_fourcc_round_trips() {
+    for (format, fourcc) in FORMATS {
+        assert_eq!(gst_video_format_to_drm_fourcc_code(*format), Some(*fourcc));
+        assert_eq!(
+            gst_video_format_from_drm_fourcc_code(*fourcc),
+            Some(*format)
+        );
+    }
+    assert_eq!(
+        gst_video_format_to_drm_fourcc_code(gstreamer_video::VideoFormat::Encoded),
+        None
+    );
+}
+
+#[test]
+fn wl_shm_matches_drm_fourcc() {
+    for (format, fourcc) in little_endian_formats() {
+        let shm_format = gst_video_format_to_wl_shm(*format)
+            .unwrap_or_else(|| panic!("no wl_shm format for {}", fourcc_name(*fourcc)));
+        assert_eq!(wl_shm_format_to_drm_fourcc_code(shm_format), *fourcc);
+        assert_eq!(
+            wl_shm_format_from_drm_fourcc_code(*fourcc),
+            Some(shm_format)
+        );
+        assert_eq!(gst_video_format_from_wl_shm(shm_format), Some(*format));
+    }
+
+    // The only wl_shm formats whose value is not their fourcc
+    assert_eq!(
+        gst_video_format_from_wl_shm(wl_shm::Format::Argb8888),
+        Some(gstreamer_video::VideoFormat::Bgra)
+    );
+    assert_eq!(
+        gst_video_format_from_wl_shm(wl_shm::Format::Xrgb8888),
+        Some(gstreamer_video::VideoFormat::Bgrx)
+    );
+    assert_eq!(wl_shm_format_from_drm_fourcc_code(0), None);
+}
+
+#[test]
+fn rgb_component_order_follows_endianness() {
+    common::init();
+
+    // DRM names 32 bit RGB formats by the components from the most significant
+    // byte, GStreamer by their order in memory, which is the reverse on
+    // little-endian words
+    for (format, fourcc) in FORMATS {
+        let info = gstreamer_video::VideoFormatInfo::from_format(*format);
+        if !info.is_rgb() || info.bits() != 8 || info.pixel_stride()[0] != 4 {
+            continue;
+        }
+
+        let code = fourcc.to_le_bytes();
+        let drm_name = match (code[0], code[1]) {
+            (first @ (b'A' | b'X'), b'R') => format!("{}RGB", first as char),
+            (first @ (b'A' | b'X'), b'B') => format!("{}BGR", first as char),
+            (b'R', last) => format!("RGB{}", last as char),
+            (b'B', last) => format!("BGR{}", last as char),
+            _ => panic!("unexpected fourcc {}", fourcc_name(*fourcc)),
+        };
+        let memory_order = drm_name.chars().rev().collect::<String>().replace('X', "x");
+        assert_eq!(
+            format.to_str().as_str(),
+            memory_order,
+            "{}",
+            fourcc_name(*fourcc)
+        );
+    }
+}
+
+#[test]
+fn native_endian_formats_follow_host() {
+    let rgb565 = u32::from_le_bytes(*b"RG16");
+    let rgb16 = gst_video_format_to_drm_fourcc_code(gstreamer_video::VideoFormat::Rgb16);
+    if cfg!(target_endian = "little") {
+        assert_eq!(rgb16, Some(rgb565));
+        assert!(!drm_fourcc_requires_little_endian(rgb565));
+        assert_eq!(
+            gst_video_format_from_wl_shm(wl_shm::Format::Rgb565),
+            Some(gstreamer_video::VideoFormat::Rgb16)
+        );
+    } else {
+        assert_eq!(rgb16, Some(rgb565 | DRM_FORMAT_BIG_ENDIAN));
+        assert!(drm_fourcc_requires_little_endian(rgb565));
+        assert_eq!(gst_video_format_from_wl_shm(wl_shm::Format::Rgb565), None);
+    }
+
+    // Explicitly big-endian formats are never captured
+    for format in [
+        gstreamer_video::VideoFormat::Gray16Be,
+        gstreamer_video::VideoFormat::P01010be,
+        gstreamer_video::VideoFormat::P016Be,
+    ] {
+        assert_eq!(
+            gst_video_format_to_drm_fourcc_code(format),
+            None,
+            "{}",
+            format
+        );
+    }
+    assert!(!drm_fourcc_requires_little_endian(u32::from_le_bytes(
+        *b"NV12"
+    )));
+}
+
+/// `I915_FORMAT_MOD_Y_TILED`
+const Y_TILED: u64 = 0x0100_0000_0000_0002;
+
+#[test]
+fn drm_format_strings_round_trip() {
+    let nv12 = u32::from_le_bytes(*b"NV12");
+    assert_eq!(
+        drm_format_code_to_string(nv12, DRM_FORMAT_MOD_LINEAR),
+        "NV12"
+    );
+    assert_eq!(
+        drm_format_code_to_string(nv12, Y_TILED),
+        "NV12:0x0100000000000002"
+    );
+    assert_eq!(
+        drm_format_code_from_string("NV12"),
+        Some((nv12, DRM_FORMAT_MOD_LINEAR))
+    );
+
+    // Fourccs shorter than 4 characters are padded with spaces
+    let r8 = u32::from_le_bytes(*b"R8  ");
+    assert_eq!(
+        drm_format_code_to_string(r8, Y_TILED),
+        "R8:0x0100000000000002"
+    );
+    assert_eq!(
+        drm_format_code_from_string("R8:0x0100000000000002"),
+        Some((r8, Y_TILED))
+    );
+
+    for (_, fourcc) in little_endian_formats() {
+        for modifier in [DRM_FORMAT_MOD_LINEAR, Y_TILED] {
+            let drm_format = drm_format_code_to_string(*fourcc, modifier);
+            assert_eq!(
+                drm_format_code_from_string(&drm_format),
+                Some((*fourcc, modifier))
+            );
+        }
+    }
+
+    assert_eq!(drm_format_code_from_string(""), None);
+    assert_eq!(drm_format_code_from_string("NV12:tiled"), None);
+    assert_eq!(drm_format_code_from_string("NV12NV12"), None);
+}
+
+#[test]
+fn dma_drm_caps_round_trip() {
+    common::init();
+
+    for (format, fourcc) in little_endian_formats() {
+        let vinfo = gstreamer_video::VideoInfo::builder(*format, 64, 32)
+            .fps(gstreamer::Fraction::new(30, 1))
+            .build()
+            .unwrap();
+        let info = VideoInfoDmaDrm::from_video_info(vinfo.clone(), Y_TILED).unwrap();
+        assert_eq!(info.drm_fourcc, *fourcc);
+
+        let caps = info.to_caps().unwrap();
+        assert!(VideoInfoDmaDrm::is_dma_drm_caps(&caps));
+        let parsed = VideoInfoDmaDrm::from_caps(&caps).unwrap();
+        assert_eq!(parsed.drm_fourcc, *fourcc);
+        assert_eq!(parsed.drm_modifier, Y_TILED);
+        assert_eq!(parsed.vinfo.format(), *format);
+        assert_eq!((parsed.vinfo.width(), parsed.vinfo.height()), (64, 32));
+    }
+
+    let caps = gstreamer_video::VideoCapsBuilder::new()
+        .format(gstreamer_video::VideoFormat::Bgrx)
+        .width(64)
+        .height(32)
+        .build();
+    assert!(!VideoInfoDmaDrm::is_dma_drm_caps(&caps));
+    assert!(VideoInfoDmaDrm::from_caps(&caps).is_err());
+}
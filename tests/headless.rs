@@ -0,0 +1,130 @@
+//! Tests of `wlrscreencopysrc` against a headless sway.
+//!
+//! Enabled with the `headless-tests` feature, sway has to be installed.
+
+#![cfg(feature = "headless-tests")]
+
+mod common;
+
+use std::sync::{Arc, Mutex};
+
+use common::headless::{HeadlessSway, HEADLESS_OUTPUT};
+use gstreamer::glib;
+use gstreamer::prelude::*;
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// Launch `description` behind `wlrscreencopysrc name=src` and run it until
+/// EOS, returning the caps of the source and the buffers reaching `sink`.
+fn run_pipeline(
+    sway: &HeadlessSway,
+    description: &str,
+) -> Result<(gstreamer::Caps, Vec<gstreamer::Buffer>), glib::Error> {
+    common::init();
+
+    let pipeline = gstreamer::parse_launch(&format!(
+        "wlrscreencopysrc name=src display=\"{}\" output-name={} {}",
+        sway.socket().display(),
+        HEADLESS_OUTPUT,
+        description
+    ))?
+    .downcast::<gstreamer::Pipeline>()
+    .unwrap();
+    let src = pipeline.by_name("src").unwrap();
+    let sink = pipeline.by_name("sink").unwrap();
+
+    let buffers = Arc::new(Mutex::new(Vec::new()));
+    sink.static_pad("sink")
+        .unwrap()
+        .add_probe(gstreamer::PadProbeType::BUFFER, {
+            let buffers = buffers.clone();
+            move |_pad, info| {
+                if let Some(gstreamer::PadProbeData::Buffer(buffer)) = &info.data {
+                    buffers.lock().unwrap().push(buffer.copy_deep().unwrap());
+                }
+                gstreamer::PadProbeReturn::Ok
+            }
+        });
+
+    pipeline.set_state(gstreamer::State::Playing).unwrap();
+
+    let bus = pipeline.bus().unwrap();
+    let result = loop {
+        let msg = bus
+            .timed_pop_filtered(
+                gstreamer::ClockTime::from_seconds(30),
+                &[gstreamer::MessageType::Eos, gstreamer::MessageType::Error],
+            )
+            .expect("pipeline timed out");
+        match msg.view() {
+            gstreamer::MessageView::Eos(..) => break Ok(()),
+            gstreamer::MessageView::Error(err) => break Err(err.error()),
+            _ => (),
+        }
+    };
+    let caps = src.static_pad("src").unwrap().current_caps();
+
+    // Shutting down must neither hang nor fail
+    assert_eq!(
+        pipeline.set_state(gstreamer::State::Null),
+        Ok(gstreamer::StateChangeSuccess::Success)
+    );
+    result?;
+
+    let buffers = std::mem::take(&mut *buffers.lock().unwrap());
+    Ok((caps.expect("no caps negotiated"), buffers))
+}
+
+#[test]
+fn captures_to_fakesink() {
+    let mut sway = HeadlessSway::start();
+    let (caps, buffers) =
+        run_pipeline(&sway, "num-buffers=30 ! fakesink name=sink sync=false").unwrap();
+
+    let info = gstreamer_video::VideoInfo::from_caps(&caps).unwrap();
+    assert!(
+        matches!(
+            info.format(),
+            gstreamer_video::VideoFormat::Bgrx | gstreamer_video::VideoFormat::Bgra
+        ),
+        "unexpected format {:?}",
+        info.format()
+    );
+    assert_eq!((info.width(), info.height()), (640, 480));
+    assert_eq!(buffers.len(), 30);
+
+    let mut last_pts = None;
+    for buffer in &buffers {
+        assert!(buffer.pts().is_some());
+        assert!(buffer.pts() > last_pts);
+        last_pts = buffer.pts();
+    }
+
+    assert!(sway.is_running());
+}
+
+#[test]
+fn encodes_png() {
+    let sway = HeadlessSway::start();
+    let (_, buffers) = run_pipeline(
+        &sway,
+        "num-buffers=1 ! videoconvert ! pngenc ! fakesink name=sink sync=false",
+    )
+    .unwrap();
+
+    assert_eq!(buffers.len(), 1);
+    let map = buffers[0].map_readable().unwrap();
+    assert!(map.starts_with(PNG_SIGNATURE));
+}
+
+#[test]
+fn restarts_cleanly() {
+    let mut sway = HeadlessSway::start();
+    for _ in 0..3 {
+        let (_, buffers) =
+            run_pipeline(&sway, "num-buffers=5 ! fakesink name=sink sync=false").unwrap();
+        assert_eq!(buffers.len(), 5);
+    }
+
+    assert!(sway.is_running());
+}
@@ -0,0 +1,424 @@
+//! Tests of `wlrscreencopysrc` against the in-process mock compositor.
+
+mod common;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use common::mock_compositor::{MockCompositor, OutputConfig, Release};
+use gstreamer::glib;
+use gstreamer::prelude::*;
+use gstreamer_base::prelude::*;
+
+/// Run `wlrscreencopysrc ! fakesink` until EOS, returning the negotiated caps and
+/// the pushed buffers.
+fn run_pipeline(
+    compositor: &MockCompositor,
+    configure: impl FnOnce(&gstreamer::Element),
+) -> Result<(gstreamer::Caps, Vec<gstreamer::Buffer>), glib::Error> {
+    common::init();
+
+    let pipeline = gstreamer::Pipeline::new(None);
+    let src = gstreamer::ElementFactory::make("wlrscreencopysrc")
+        .property("display", compositor.socket().to_str().unwrap())
+        .property("num-buffers", 5i32)
+        .build()
+        .unwrap();
+    let sink = gstreamer::ElementFactory::make("fakesink")
+        .property("sync", false)
+        .build()
+        .unwrap();
+    configure(&src);
+    pipeline.add_many(&[&src, &sink]).unwrap();
+    src.link(&sink).unwrap();
+
+    let buffers = Arc::new(Mutex::new(Vec::new()));
+    sink.static_pad("sink")
+        .unwrap()
+        .add_probe(gstreamer::PadProbeType::BUFFER, {
+            let buffers = buffers.clone();
+            move |_pad, info| {
+                if let Some(gstreamer::PadProbeData::Buffer(buffer)) = &info.data {
+                    buffers.lock().unwrap().push(buffer.copy_deep().unwrap());
+                }
+                gstreamer::PadProbeReturn::Ok
+            }
+        });
+
+    pipeline.set_state(gstreamer::State::Playing).unwrap();
+
+    let bus = pipeline.bus().unwrap();
+    let result = loop {
+        let msg = bus
+            .timed_pop_filtered(
+                gstreamer::ClockTime::from_seconds(10),
+                &[gstreamer::MessageType::Eos, gstreamer::MessageType::Error],
+            )
+            .expect("pipeline timed out");
+        match msg.view() {
+            gstreamer::MessageView::Eos(..) => break Ok(()),
+            gstreamer::MessageView::Error(err) => break Err(err.error()),
+            _ => (),
+        }
+    };
+    let caps = src.static_pad("src").unwrap().current_caps();
+
+    pipeline.set_state(gstreamer::State::Null).unwrap();
+    result?;
+
+    let buffers = std::mem::take(&mut *buffers.lock().unwrap());
+    Ok((caps.expect("no caps negotiated"), buffers))
+}
+
+#[test]
+fn negotiates_output_mode() {
+    let compositor = MockCompositor::start(OutputConfig::default());
+    let (caps, _) = run_pipeline(&compositor, |_| ()).unwrap();
+
+    let info = gstreamer_video::VideoInfo::from_caps(&caps).unwrap();
+    assert_eq!(info.format(), gstreamer_video::VideoFormat::Bgrx);
+    assert_eq!(info.width(), 64);
+    assert_eq!(info.height(), 48);
+    assert_eq!(info.fps(), gstreamer::Fraction::new(60, 1));
+}
+
+#[test]
+fn pushes_copied_frames() {
+    let config = OutputConfig::default();
+    let compositor = MockCompositor::start(config.clone());
+    let (caps, buffers) = run_pipeline(&compositor, |_| ()).unwrap();
+
+    assert_eq!(buffers.len(), 5);
+    assert!(compositor.counters().frames_copied.load(Ordering::SeqCst) >= 5);
+
+    let info = gstreamer_video::VideoInfo::from_caps(&caps).unwrap();
+    let mut last_pts = None;
+    for buffer in buffers {
+        assert!(buffer.pts() > last_pts);
+        last_pts = buffer.pts();
+
+        let frame = gstreamer_video::VideoFrame::from_buffer_readable(buffer, &info).unwrap();
+        let stride = frame.plane_stride()[0] as usize;
+        let data = frame.plane_data(0).unwrap();
+        let pixel = config.pixel.to_le_bytes();
+        for y in 0..info.height() as usize {
+            let row = &data[y * stride..][..info.width() as usize * 4];
+            assert!(row.chunks_exact(4).all(|p| p == pixel), "row {} differs", y);
+        }
+
+        let meta = frame
+            .buffer()
+            .meta::<gstwlrscreencopy::ScreencopyFrameMeta>()
+            .expect("no frame meta");
+        assert_eq!(meta.dropped(), 0);
+    }
+}
+
+#[test]
+fn non_live_timestamps_start_at_zero() {
+    let compositor = MockCompositor::start(OutputConfig::default());
+    let (_, buffers) = run_pipeline(&compositor, |src| src.set_property("is-live", false)).unwrap();
+
+    assert_eq!(buffers.len(), 5);
+    assert_eq!(buffers[0].pts(), Some(gstreamer::ClockTime::ZERO));
+    assert!(buffers.windows(2).all(|pair| pair[1].pts() > pair[0].pts()));
+}
+
+#[test]
+fn damage_aware_attaches_damage() {
+    let compositor = MockCompositor::start(OutputConfig::default());
+    let (_, buffers) =
+        run_pipeline(&compositor, |src| src.set_property("damage-aware", true)).unwrap();
+
+    for buffer in buffers {
+        let meta = buffer
+            .meta::<gstwlrscreencopy::ScreencopyDamageMeta>()
+            .expect("no damage meta");
+        assert_eq!(
+            meta.rects(),
+            &[gstreamer_video::VideoRectangle::new(0, 0, 64, 48)]
+        );
+    }
+}
+
+#[test]
+fn wayland_buffer_meta_follows_shared_memory() {
+    let compositor = MockCompositor::start(OutputConfig::default());
+    let checked = Arc::new(Mutex::new(Vec::new()));
+    run_pipeline(&compositor, |src| {
+        let checked = checked.clone();
+        src.static_pad("src").unwrap().add_probe(
+            gstreamer::PadProbeType::BUFFER,
+            move |_pad, info| {
+                if let Some(gstreamer::PadProbeData::Buffer(buffer)) = &info.data {
+                    let has_meta = |buffer: &gstreamer::BufferRef| {
+                        buffer
+                            .meta::<gstwlrscreencopy::WaylandBufferMeta>()
+                            .is_some()
+                    };
+                    checked.lock().unwrap().push((
+                        has_meta(buffer),
+                        has_meta(&buffer.copy()),
+                        has_meta(&buffer.copy_deep().unwrap()),
+                    ));
+                }
+                gstreamer::PadProbeReturn::Ok
+            },
+        );
+    })
+    .unwrap();
+
+    let checked = checked.lock().unwrap();
+    assert!(!checked.is_empty());
+    assert!(checked
+        .iter()
+        .all(|checked| *checked == (true, true, false)));
+}
+
+#[test]
+fn falls_back_to_shm_when_dmabuf_copy_fails() {
+    let config = OutputConfig {
+        dmabuf: true,
+        ..Default::default()
+    };
+    let compositor = MockCompositor::start(config);
+    let (_, buffers) = run_pipeline(&compositor, |_| ()).unwrap();
+
+    assert_eq!(buffers.len(), 5);
+}
+
+#[test]
+fn falls_back_to_shm_when_dmabuf_import_fails() {
+    let config = OutputConfig {
+        dmabuf: true,
+        reject_dmabuf_import: true,
+        ..Default::default()
+    };
+    let compositor = MockCompositor::start(config);
+    let (_, buffers) = run_pipeline(&compositor, |_| ()).unwrap();
+
+    assert_eq!(buffers.len(), 5);
+}
+
+#[test]
+fn reports_capture_memory() {
+    let compositor = MockCompositor::start(OutputConfig::default());
+    let capture_memory = Arc::new(Mutex::new(None));
+    run_pipeline(&compositor, |src| {
+        let capture_memory = capture_memory.clone();
+        src.connect_notify(Some("capture-memory"), move |src, _| {
+            *capture_memory.lock().unwrap() = src.property::<Option<String>>("capture-memory");
+        });
+    })
+    .unwrap();
+
+    assert_eq!(capture_memory.lock().unwrap().as_deref(), Some("shm"));
+}
+
+#[test]
+fn unknown_output_errors() {
+    let compositor = MockCompositor::start(OutputConfig::default());
+    let err = run_pipeline(&compositor, |src| {
+        src.set_property("output-name", "DOES-NOT-EXIST")
+    })
+    .unwrap_err();
+
+    assert!(err.matches(gstreamer::ResourceError::NotFound), "{}", err);
+}
+
+#[test]
+fn output_name_pattern_matches_prefix() {
+    let compositor = MockCompositor::start(OutputConfig::default());
+    let (_, buffers) =
+        run_pipeline(&compositor, |src| src.set_property("output-name", "MOCK-*")).unwrap();
+    assert_eq!(buffers.len(), 5);
+
+    let err = run_pipeline(&compositor, |src| src.set_property("output-name", "DP-*")).unwrap_err();
+    assert!(err.matches(gstreamer::ResourceError::NotFound), "{}", err);
+}
+
+#[test]
+fn restarts_after_stop() {
+    let compositor = MockCompositor::start(OutputConfig::default());
+    for _ in 0..3 {
+        let (_, buffers) = run_pipeline(&compositor, |_| ()).unwrap();
+        assert_eq!(buffers.len(), 5);
+    }
+}
+
+/// Run ten frames through the pipeline, returning the pushed buffers and the stats
+/// of the pool they were copied into.
+fn run_pipeline_with_pool_stats(
+    compositor: &MockCompositor,
+) -> (Vec<gstreamer::Buffer>, gstreamer::Structure) {
+    let pool = Arc::new(Mutex::new(None));
+    let (_, buffers) = run_pipeline(compositor, |src| {
+        src.set_property("num-buffers", 10i32);
+        let pool = pool.clone();
+        src.static_pad("src").unwrap().add_probe(
+            gstreamer::PadProbeType::BUFFER,
+            move |pad, _info| {
+                let src = pad
+                    .parent_element()
+                    .and_downcast::<gstreamer_base::BaseSrc>()
+                    .unwrap();
+                *pool.lock().unwrap() = src.buffer_pool();
+                gstreamer::PadProbeReturn::Remove
+            },
+        );
+    })
+    .unwrap();
+
+    let pool = pool.lock().unwrap().take().expect("no pool");
+    (buffers, pool.property::<gstreamer::Structure>("stats"))
+}
+
+#[test]
+fn reuses_buffers_of_never_releasing_compositor() {
+    let config = OutputConfig {
+        release: Release::Never,
+        ..Default::default()
+    };
+    let compositor = MockCompositor::start(config);
+    let (buffers, stats) = run_pipeline_with_pool_stats(&compositor);
+
+    assert_eq!(buffers.len(), 10);
+    assert_eq!(stats.get::<u64>("compositor-releases").unwrap(), 0);
+    // Only the first reuse waits for a release
+    assert_eq!(stats.get::<u64>("release-timeouts").unwrap(), 1);
+    assert_eq!(stats.get::<u64>("busy").unwrap(), 0);
+}
+
+#[test]
+fn waits_for_late_release() {
+    let config = OutputConfig {
+        release: Release::After(std::time::Duration::from_millis(30)),
+        ..Default::default()
+    };
+    let compositor = MockCompositor::start(config);
+    let (buffers, stats) = run_pipeline_with_pool_stats(&compositor);
+
+    assert_eq!(buffers.len(), 10);
+    assert!(stats.get::<u64>("compositor-releases").unwrap() > 0);
+    assert_eq!(stats.get::<u64>("release-timeouts").unwrap(), 0);
+    assert_eq!(
+        compositor
+            .counters()
+            .copies_unreleased
+            .load(Ordering::SeqCst),
+        0
+    );
+}
+
+#[test]
+fn defers_capture_until_playing() {
+    common::init();
+    let compositor = MockCompositor::start(OutputConfig::default());
+
+    let src = gstreamer::ElementFactory::make("wlrscreencopysrc")
+        .property("display", compositor.socket().to_str().unwrap())
+        .property("defer-capture", true)
+        .build()
+        .unwrap();
+    let sink = gstreamer::ElementFactory::make("fakesink").build().unwrap();
+    let pipeline = gstreamer::Pipeline::new(None);
+    pipeline.add_many(&[&src, &sink]).unwrap();
+    src.link(&sink).unwrap();
+
+    pipeline.set_state(gstreamer::State::Paused).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(100));
+    // Only the frame probed while connecting
+    assert_eq!(
+        compositor.counters().frames_captured.load(Ordering::SeqCst),
+        1
+    );
+    assert_eq!(
+        compositor.counters().frames_copied.load(Ordering::SeqCst),
+        0
+    );
+
+    pipeline.set_state(gstreamer::State::Playing).unwrap();
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+    while compositor.counters().frames_copied.load(Ordering::SeqCst) == 0 {
+        assert!(std::time::Instant::now() < deadline, "no frame copied");
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+
+    pipeline.set_state(gstreamer::State::Null).unwrap();
+}
+
+#[test]
+fn hides_corrupted_frames_of_private_applications() {
+    let config = OutputConfig {
+        fail_copies: true,
+        focused_app_id: Some("org.example.Private".into()),
+        ..Default::default()
+    };
+    let compositor = MockCompositor::start(config);
+    let (caps, buffers) = run_pipeline(&compositor, |src| {
+        src.set_property("push-corrupted", true);
+        src.set_property("max-retries", 0u32);
+        src.set_property(
+            "privacy-app-ids",
+            gstreamer::Array::new(["org.example.Private"]),
+        );
+        src.set_property_from_str("privacy-fill", "color");
+        src.set_property("privacy-color", 0xff00_ff00u32);
+    })
+    .unwrap();
+
+    assert_eq!(buffers.len(), 5);
+    let info = gstreamer_video::VideoInfo::from_caps(&caps).unwrap();
+    for buffer in buffers {
+        assert!(buffer.flags().contains(gstreamer::BufferFlags::CORRUPTED));
+
+        let frame = gstreamer_video::VideoFrame::from_buffer_readable(buffer, &info).unwrap();
+        let stride = frame.plane_stride()[0] as usize;
+        let data = frame.plane_data(0).unwrap();
+        for y in 0..info.height() as usize {
+            let row = &data[y * stride..][..info.width() as usize * 4];
+            // BGRx
+            assert!(
+                row.chunks_exact(4).all(|p| p[..3] == [0x00, 0xff, 0x00]),
+                "row {} is not hidden",
+                y
+            );
+        }
+    }
+}
+
+#[test]
+fn non_live_prerolls_in_paused() {
+    common::init();
+    let compositor = MockCompositor::start(OutputConfig::default());
+
+    let src = gstreamer::ElementFactory::make("wlrscreencopysrc")
+        .property("display", compositor.socket().to_str().unwrap())
+        .property("is-live", false)
+        .build()
+        .unwrap();
+    let sink = gstreamer::ElementFactory::make("fakesink").build().unwrap();
+    let pipeline = gstreamer::Pipeline::new(None);
+    pipeline.add_many(&[&src, &sink]).unwrap();
+    src.link(&sink).unwrap();
+
+    let buffers = Arc::new(AtomicUsize::new(0));
+    sink.static_pad("sink")
+        .unwrap()
+        .add_probe(gstreamer::PadProbeType::BUFFER, {
+            let buffers = buffers.clone();
+            move |_pad, _info| {
+                buffers.fetch_add(1, Ordering::SeqCst);
+                gstreamer::PadProbeReturn::Ok
+            }
+        });
+
+    pipeline.set_state(gstreamer::State::Paused).unwrap();
+    let (result, state, _) = pipeline.state(gstreamer::ClockTime::from_seconds(10));
+    assert_eq!(result, Ok(gstreamer::StateChangeSuccess::Success));
+    assert_eq!(state, gstreamer::State::Paused);
+    // The sink blocks on the prerolled buffer
+    assert_eq!(buffers.load(Ordering::SeqCst), 1);
+
+    pipeline.set_state(gstreamer::State::Null).unwrap();
+}